@@ -0,0 +1,125 @@
+//! Benchmarks for query strategies against a local database, run with:
+//!
+//! ```text
+//! cargo bench --features test-support
+//! ```
+//!
+//! The server is the one used for tests, see `test_support::SERVER_URL`.
+
+extern crate brdgme_db;
+extern crate chrono;
+extern crate uuid;
+
+use brdgme_db::fixtures::GameFixture;
+use brdgme_db::models::{NewGameLog, NewGameLogTarget, User, UserAuthToken, UserEmail};
+use brdgme_db::query;
+use brdgme_db::repository::PgRepository;
+use brdgme_db::sql::Select;
+use brdgme_db::test_support::{TestDb, SERVER_URL};
+
+use chrono::UTC;
+use uuid::Uuid;
+
+use std::time::{Duration, Instant};
+
+fn micros(d: Duration) -> f64 {
+    d.as_secs() as f64 * 1_000_000.0 + d.subsec_nanos() as f64 / 1_000.0
+}
+
+/// Prints the mean time of `f` over a number of runs, after a warm up run.
+fn bench<F>(name: &str, iterations: u32, f: F)
+    where F: Fn()
+{
+    f();
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    println!("{:<40} {:>10.1}µs",
+             name,
+             micros(start.elapsed()) / iterations as f64);
+}
+
+fn main() {
+    let db = match TestDb::new().expect("unable to create benchmark database") {
+        Some(db) => db,
+        None => {
+            println!("skipping, no Postgres server at {}", *SERVER_URL);
+            return;
+        }
+    };
+    let conn = db.conns().w.get().unwrap();
+    let created = GameFixture::new()
+        .players(4)
+        .create(&PgRepository::new(&*conn))
+        .unwrap();
+    let game_id = created.game.id;
+
+    bench("find_game_players_by_game, unprepared", 2000, || {
+        conn.query("
+            SELECT *
+            FROM game_players
+            WHERE game_id=$1",
+                   &[&game_id])
+            .unwrap();
+    });
+    bench("find_game_players_by_game, cached", 2000, || {
+        query::find_game_players_by_game(&game_id, &*conn).unwrap();
+    });
+
+    // Authentication runs on every request, so compare it against the same select uncached.
+    let email = created.users[0].user_email.email.clone();
+    let token = query::create_auth_token(&created.users[0].user.id, &*conn)
+        .unwrap()
+        .id;
+    let expired_before = UTC::now().naive_utc() - chrono::Duration::days(1);
+    bench("authenticate, unprepared", 2000, || {
+        Select::from::<User>("u")
+            .cols::<User>("u", "u_")
+            .cols::<UserEmail>("ue", "ue_")
+            .join::<UserEmail>("ue", "ue.user_id = u.id", &[])
+            .join::<UserAuthToken>("uat", "uat.user_id = u.id", &[])
+            .filter("ue.email = ?", &[&email])
+            .filter("uat.id = ?", &[&token])
+            .filter("uat.created_at > ?", &[&expired_before])
+            .limit(1)
+            .query(&*conn)
+            .unwrap();
+    });
+    bench("authenticate, cached", 2000, || {
+        query::authenticate(&email, &token, &*conn).unwrap();
+    });
+
+    // A busy turn, with 30 logs targeted at every player.
+    let player_ids: Vec<Uuid> = created.players.iter().map(|p| p.id).collect();
+    let logged_at = UTC::now().naive_utc();
+    let new_logs: Vec<(NewGameLog, Vec<Uuid>)> = (0..30)
+        .map(|_| {
+                 (NewGameLog {
+                      game_id: &game_id,
+                      body: "Something happened",
+                      is_public: false,
+                      logged_at: &logged_at,
+                  },
+                  player_ids.clone())
+             })
+        .collect();
+    bench("create 30 game logs, one at a time", 100, || {
+        let trans = conn.transaction().unwrap();
+        for &(ref log, ref to) in &new_logs {
+            let gl = query::create_game_log(log, &[], &trans).unwrap();
+            for player_id in to {
+                query::create_game_log_target(&NewGameLogTarget {
+                                                   game_log_id: &gl.game_log.id,
+                                                   player_id: player_id,
+                                               },
+                                              &trans)
+                        .unwrap();
+            }
+        }
+        trans.commit().unwrap();
+    });
+    bench("create 30 game logs, bulk", 100, || {
+        query::create_game_logs(&new_logs, &*conn).unwrap();
+    });
+}
@@ -0,0 +1,105 @@
+//! Derives for the row structs in `brdgme_db::models`, so column lists, `from_row` and insert SQL
+//! are generated from the struct fields and can't drift out of sync.
+//!
+//! `from_row` reads any `models::ModelRow`, so the same models serve the blocking and async
+//! clients.
+//!
+//! The generated code refers to `::postgres` and `::models`, so these derives are only meant to be
+//! used inside brdgme-db itself.
+
+extern crate proc_macro;
+extern crate syn;
+#[macro_use]
+extern crate quote;
+
+use proc_macro::TokenStream;
+
+/// Implements `Model` and a prefixed `from_row` over `ModelRow` for a row struct, with a column
+/// per field of the table given by `#[table = "..."]`.
+#[proc_macro_derive(Model, attributes(table))]
+pub fn derive_model(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    impl_model(&ast).parse().unwrap()
+}
+
+/// Implements `Insert` for a `New*` struct, binding each field to the column of the same name in
+/// the table given by `#[table = "..."]`.
+#[proc_macro_derive(Insert, attributes(table))]
+pub fn derive_insert(input: TokenStream) -> TokenStream {
+    let ast = syn::parse_derive_input(&input.to_string()).unwrap();
+    impl_insert(&ast).parse().unwrap()
+}
+
+fn fields(ast: &syn::DeriveInput) -> Vec<&syn::Ident> {
+    match ast.body {
+        syn::Body::Struct(syn::VariantData::Struct(ref fields)) => {
+            fields
+                .iter()
+                .map(|f| f.ident.as_ref().unwrap())
+                .collect()
+        }
+        _ => panic!("{} must be a struct with named fields", ast.ident),
+    }
+}
+
+fn table(ast: &syn::DeriveInput) -> String {
+    for attr in &ast.attrs {
+        if let syn::MetaItem::NameValue(ref name, syn::Lit::Str(ref value, _)) = attr.value {
+            if name == "table" {
+                return value.to_string();
+            }
+        }
+    }
+    panic!("{} needs a #[table = \"...\"] attribute", ast.ident)
+}
+
+fn impl_model(ast: &syn::DeriveInput) -> quote::Tokens {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let table = table(ast);
+    let fields = fields(ast);
+    let cols: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+    let from_row_cols = cols.clone();
+    quote! {
+        impl #impl_generics #name #ty_generics #where_clause {
+            pub fn from_row<R: ::models::ModelRow>(row: &R, prefix: &str) -> Self {
+                #name {
+                    #(#fields: row.get_col(format!("{}{}", prefix, #from_row_cols).as_str()),)*
+                }
+            }
+        }
+
+        impl #impl_generics ::models::Model for #name #ty_generics #where_clause {
+            fn table() -> &'static str {
+                #table
+            }
+
+            fn cols() -> Vec<String> {
+                vec![#(#cols.to_string()),*]
+            }
+        }
+    }
+}
+
+fn impl_insert(ast: &syn::DeriveInput) -> quote::Tokens {
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+    let table = table(ast);
+    let fields = fields(ast);
+    let cols: Vec<String> = fields.iter().map(|f| f.to_string()).collect();
+    quote! {
+        impl #impl_generics ::models::Insert for #name #ty_generics #where_clause {
+            fn table() -> &'static str {
+                #table
+            }
+
+            fn insert_cols() -> Vec<&'static str> {
+                vec![#(#cols),*]
+            }
+
+            fn params(&self) -> Vec<&::postgres::types::ToSql> {
+                vec![#(&self.#fields as &::postgres::types::ToSql),*]
+            }
+        }
+    }
+}
@@ -0,0 +1,6 @@
+DROP TABLE game_color_swaps;
+
+ALTER TABLE games DROP COLUMN revision;
+
+ALTER TABLE game_players DROP CONSTRAINT game_players_game_id_color_key;
+ALTER TABLE game_players ADD CONSTRAINT game_players_game_id_color_key UNIQUE (game_id, color);
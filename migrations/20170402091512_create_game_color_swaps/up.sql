@@ -0,0 +1,19 @@
+ALTER TABLE game_players DROP CONSTRAINT game_players_game_id_color_key;
+ALTER TABLE game_players ADD CONSTRAINT game_players_game_id_color_key
+  UNIQUE (game_id, color) DEFERRABLE INITIALLY IMMEDIATE;
+
+ALTER TABLE games ADD COLUMN revision INT NOT NULL DEFAULT 0;
+
+CREATE TABLE game_color_swaps (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  game_id UUID NOT NULL REFERENCES games (id),
+  from_player_id UUID NOT NULL REFERENCES game_players (id),
+  to_player_id UUID NOT NULL REFERENCES game_players (id),
+  game_revision INT NOT NULL,
+  accepted_at TIMESTAMP,
+  declined_at TIMESTAMP,
+  CHECK (from_player_id <> to_player_id)
+);
+CREATE TRIGGER update_game_color_swaps_updated_at BEFORE UPDATE ON game_color_swaps FOR EACH ROW EXECUTE PROCEDURE update_updated_at();
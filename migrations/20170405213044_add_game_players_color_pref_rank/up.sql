@@ -0,0 +1 @@
+ALTER TABLE game_players ADD COLUMN color_pref_rank INT;
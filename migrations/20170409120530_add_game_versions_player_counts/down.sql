@@ -0,0 +1,3 @@
+ALTER TABLE game_versions
+  DROP COLUMN max_players,
+  DROP COLUMN min_players;
@@ -0,0 +1,7 @@
+-- A NULL max_players means the version has no upper limit on players.
+ALTER TABLE game_versions
+  ADD COLUMN min_players INT NOT NULL DEFAULT 1,
+  ADD COLUMN max_players INT,
+  ADD CHECK (min_players >= 1),
+  ADD CHECK (max_players IS NULL OR max_players >= min_players);
+ALTER TABLE game_versions ALTER COLUMN min_players DROP DEFAULT;
@@ -0,0 +1 @@
+ALTER TABLE game_types DROP CONSTRAINT game_types_name_key;
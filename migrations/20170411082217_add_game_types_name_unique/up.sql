@@ -0,0 +1 @@
+ALTER TABLE game_types ADD CONSTRAINT game_types_name_key UNIQUE (name);
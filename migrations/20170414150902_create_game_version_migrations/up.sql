@@ -0,0 +1,16 @@
+CREATE TABLE game_version_migrations (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  run_id UUID NOT NULL,
+  game_id UUID NOT NULL REFERENCES games (id),
+  from_game_version_id UUID NOT NULL REFERENCES game_versions (id),
+  to_game_version_id UUID NOT NULL REFERENCES game_versions (id),
+  from_game_state TEXT NOT NULL,
+  to_game_state TEXT NOT NULL,
+  game_revision INT NOT NULL,
+  rolled_back_at TIMESTAMP
+);
+CREATE INDEX game_version_migrations_run_id_idx ON game_version_migrations (run_id);
+CREATE INDEX game_version_migrations_game_id_idx ON game_version_migrations (game_id);
+CREATE TRIGGER update_game_version_migrations_updated_at BEFORE UPDATE ON game_version_migrations FOR EACH ROW EXECUTE PROCEDURE update_updated_at();
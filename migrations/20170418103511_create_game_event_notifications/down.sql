@@ -0,0 +1,7 @@
+DROP TRIGGER notify_game_logs_created ON game_logs;
+DROP FUNCTION notify_game_log_created();
+DROP TRIGGER notify_game_players_updated ON game_players;
+DROP FUNCTION notify_game_player_updated();
+DROP TRIGGER notify_games_updated ON games;
+DROP FUNCTION notify_game_updated();
+DROP FUNCTION notify_game_event(TEXT, UUID, UUID[]);
@@ -0,0 +1,55 @@
+CREATE OR REPLACE FUNCTION notify_game_event(event_kind TEXT, event_game_id UUID, event_player_ids UUID[])
+RETURNS VOID AS $$
+BEGIN
+    PERFORM pg_notify('game_events', json_build_object(
+        'kind', event_kind,
+        'game_id', event_game_id,
+        'revision', (SELECT revision FROM games WHERE id = event_game_id),
+        'player_ids', event_player_ids
+    )::text);
+END;
+$$ language 'plpgsql';
+
+CREATE OR REPLACE FUNCTION notify_game_updated()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM notify_game_event('game_updated', NEW.id, ARRAY(
+        SELECT id FROM game_players WHERE game_id = NEW.id ORDER BY position
+    ));
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+CREATE TRIGGER notify_games_updated AFTER UPDATE ON games FOR EACH ROW EXECUTE PROCEDURE notify_game_updated();
+
+CREATE OR REPLACE FUNCTION notify_game_player_updated()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM notify_game_event('players_updated', NEW.game_id, ARRAY[NEW.id]);
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+CREATE TRIGGER notify_game_players_updated AFTER UPDATE ON game_players FOR EACH ROW
+WHEN (
+  OLD.has_accepted IS DISTINCT FROM NEW.has_accepted
+  OR OLD.is_turn IS DISTINCT FROM NEW.is_turn
+  OR OLD.is_eliminated IS DISTINCT FROM NEW.is_eliminated
+  OR OLD.is_winner IS DISTINCT FROM NEW.is_winner
+  OR OLD.color IS DISTINCT FROM NEW.color
+) EXECUTE PROCEDURE notify_game_player_updated();
+
+-- Deferred until commit so the targets of private logs have been inserted.
+CREATE OR REPLACE FUNCTION notify_game_log_created()
+RETURNS TRIGGER AS $$
+BEGIN
+    PERFORM notify_game_event('log_created', NEW.game_id, CASE
+        WHEN NEW.is_public THEN ARRAY(
+            SELECT id FROM game_players WHERE game_id = NEW.game_id ORDER BY position
+        )
+        ELSE ARRAY(
+            SELECT player_id FROM game_log_targets WHERE game_log_id = NEW.id
+        )
+    END);
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+CREATE CONSTRAINT TRIGGER notify_game_logs_created AFTER INSERT ON game_logs DEFERRABLE INITIALLY DEFERRED FOR EACH ROW EXECUTE PROCEDURE notify_game_log_created();
@@ -0,0 +1,2 @@
+DROP TABLE outbox_messages;
+DROP TYPE outbox_message_kind;
@@ -0,0 +1,23 @@
+CREATE TYPE outbox_message_kind AS ENUM (
+  'LoginCode',
+  'GameInvite',
+  'YourTurn'
+);
+
+CREATE TABLE outbox_messages (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  kind outbox_message_kind NOT NULL,
+  recipient VARCHAR NOT NULL,
+  payload JSON NOT NULL,
+  max_attempts INT NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  next_attempt_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  claimed_until TIMESTAMP,
+  last_error TEXT,
+  sent_at TIMESTAMP,
+  failed_at TIMESTAMP
+);
+CREATE INDEX outbox_messages_pending_idx ON outbox_messages (next_attempt_at) WHERE sent_at IS NULL AND failed_at IS NULL;
+CREATE TRIGGER update_outbox_messages_updated_at BEFORE UPDATE ON outbox_messages FOR EACH ROW EXECUTE PROCEDURE update_updated_at();
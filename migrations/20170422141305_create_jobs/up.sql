@@ -0,0 +1,26 @@
+CREATE TYPE job_status AS ENUM (
+  'Pending',
+  'Running',
+  'Done',
+  'Dead'
+);
+
+CREATE TABLE jobs (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  kind VARCHAR NOT NULL,
+  unique_key VARCHAR,
+  payload JSON NOT NULL,
+  status job_status NOT NULL DEFAULT 'Pending',
+  run_at TIMESTAMP NOT NULL,
+  max_attempts INT NOT NULL,
+  attempts INT NOT NULL DEFAULT 0,
+  heartbeat_at TIMESTAMP,
+  last_error TEXT,
+  finished_at TIMESTAMP
+);
+CREATE INDEX jobs_pending_idx ON jobs (run_at) WHERE status = 'Pending';
+CREATE INDEX jobs_running_idx ON jobs (heartbeat_at) WHERE status = 'Running';
+CREATE UNIQUE INDEX jobs_unique_key_idx ON jobs (unique_key) WHERE status IN ('Pending', 'Running');
+CREATE TRIGGER update_jobs_updated_at BEFORE UPDATE ON jobs FOR EACH ROW EXECUTE PROCEDURE update_updated_at();
@@ -0,0 +1,8 @@
+DROP TRIGGER track_game_players_turn ON game_players;
+DROP FUNCTION track_game_player_turn();
+ALTER TABLE game_players DROP COLUMN time_bank_secs;
+ALTER TABLE game_players DROP COLUMN turn_reminded_at;
+ALTER TABLE game_players DROP COLUMN turn_started_at;
+ALTER TABLE games DROP COLUMN time_limit_secs;
+ALTER TABLE games DROP COLUMN time_limit_kind;
+DROP TYPE time_limit_kind;
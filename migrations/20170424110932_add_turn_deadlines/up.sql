@@ -0,0 +1,44 @@
+CREATE TYPE time_limit_kind AS ENUM (
+  'PerTurn',
+  'TimeBank'
+);
+
+ALTER TABLE games ADD COLUMN time_limit_kind time_limit_kind;
+ALTER TABLE games ADD COLUMN time_limit_secs INT;
+ALTER TABLE games ADD CONSTRAINT games_time_limit_check
+  CHECK ((time_limit_kind IS NULL) = (time_limit_secs IS NULL));
+
+ALTER TABLE game_players ADD COLUMN turn_started_at TIMESTAMP;
+ALTER TABLE game_players ADD COLUMN turn_reminded_at TIMESTAMP;
+ALTER TABLE game_players ADD COLUMN time_bank_secs INT;
+
+UPDATE game_players SET turn_started_at = updated_at WHERE is_turn;
+
+-- Records when each player's turn starts, and charges the time taken to their time bank when it
+-- ends.
+CREATE OR REPLACE FUNCTION track_game_player_turn()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        IF NEW.is_turn THEN
+            NEW.turn_started_at = (now() AT TIME ZONE 'utc');
+        END IF;
+    ELSIF NEW.is_turn AND NOT OLD.is_turn THEN
+        NEW.turn_started_at = (now() AT TIME ZONE 'utc');
+        NEW.turn_reminded_at = NULL;
+    ELSIF OLD.is_turn AND NOT NEW.is_turn THEN
+        IF NEW.time_bank_secs IS NOT NULL AND OLD.turn_started_at IS NOT NULL THEN
+            NEW.time_bank_secs = GREATEST(0, NEW.time_bank_secs - floor(extract(EPOCH FROM
+                (now() AT TIME ZONE 'utc') - OLD.turn_started_at))::INT);
+        END IF;
+        NEW.turn_started_at = NULL;
+        NEW.turn_reminded_at = NULL;
+    END IF;
+    RETURN NEW;
+END;
+$$ language 'plpgsql';
+
+CREATE TRIGGER track_game_players_turn BEFORE INSERT OR UPDATE ON game_players FOR EACH ROW EXECUTE PROCEDURE track_game_player_turn();
+
+CREATE INDEX game_players_turn_started_at_idx ON game_players (turn_started_at)
+  WHERE is_turn AND NOT is_eliminated;
@@ -0,0 +1,4 @@
+ALTER TABLE games DROP COLUMN abandon_reason;
+ALTER TABLE games DROP COLUMN abandoned_at;
+ALTER TABLE game_players DROP COLUMN eliminated_reason;
+DROP TYPE elimination_reason;
@@ -0,0 +1,8 @@
+CREATE TYPE elimination_reason AS ENUM (
+  'Conceded',
+  'TimedOut'
+);
+
+ALTER TABLE game_players ADD COLUMN eliminated_reason elimination_reason;
+ALTER TABLE games ADD COLUMN abandoned_at TIMESTAMP;
+ALTER TABLE games ADD COLUMN abandon_reason TEXT;
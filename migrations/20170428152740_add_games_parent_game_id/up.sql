@@ -0,0 +1 @@
+ALTER TABLE games ADD COLUMN parent_game_id UUID UNIQUE REFERENCES games (id);
@@ -0,0 +1,2 @@
+ALTER TABLE game_players DROP COLUMN score;
+ALTER TABLE game_players DROP COLUMN place;
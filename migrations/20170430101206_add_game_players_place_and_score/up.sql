@@ -0,0 +1,2 @@
+ALTER TABLE game_players ADD COLUMN place INT CHECK (place >= 1);
+ALTER TABLE game_players ADD COLUMN score INT;
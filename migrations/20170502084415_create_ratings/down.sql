@@ -0,0 +1,5 @@
+DROP TABLE rating_changes;
+DROP TABLE ratings;
+DROP TRIGGER set_games_finished_at ON games;
+DROP FUNCTION set_game_finished_at();
+ALTER TABLE games DROP COLUMN finished_at;
@@ -0,0 +1,45 @@
+ALTER TABLE games ADD COLUMN finished_at TIMESTAMP;
+UPDATE games SET finished_at = updated_at WHERE is_finished;
+
+CREATE OR REPLACE FUNCTION set_game_finished_at()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        IF NEW.is_finished THEN
+            NEW.finished_at = (now() AT TIME ZONE 'utc');
+        END IF;
+    ELSIF NEW.is_finished AND NOT OLD.is_finished THEN
+        NEW.finished_at = (now() AT TIME ZONE 'utc');
+    ELSIF NOT NEW.is_finished THEN
+        NEW.finished_at = NULL;
+    END IF;
+    RETURN NEW;
+END;
+$$ language 'plpgsql';
+CREATE TRIGGER set_games_finished_at BEFORE INSERT OR UPDATE ON games FOR EACH ROW EXECUTE PROCEDURE set_game_finished_at();
+
+CREATE TABLE ratings (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  user_id UUID NOT NULL REFERENCES users (id),
+  game_type_id UUID NOT NULL REFERENCES game_types (id),
+  rating FLOAT8 NOT NULL,
+  games_played INT NOT NULL DEFAULT 0,
+  UNIQUE (user_id, game_type_id)
+);
+CREATE INDEX ratings_game_type_id_rating_idx ON ratings (game_type_id, rating DESC);
+CREATE TRIGGER update_ratings_updated_at BEFORE UPDATE ON ratings FOR EACH ROW EXECUTE PROCEDURE update_updated_at();
+
+CREATE TABLE rating_changes (
+  id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+  created_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  updated_at TIMESTAMP NOT NULL DEFAULT (now() AT TIME ZONE 'utc'),
+  rating_id UUID NOT NULL REFERENCES ratings (id),
+  game_id UUID NOT NULL REFERENCES games (id),
+  rating_before FLOAT8 NOT NULL,
+  rating_after FLOAT8 NOT NULL,
+  UNIQUE (rating_id, game_id)
+);
+CREATE INDEX rating_changes_game_id_idx ON rating_changes (game_id);
+CREATE TRIGGER update_rating_changes_updated_at BEFORE UPDATE ON rating_changes FOR EACH ROW EXECUTE PROCEDURE update_updated_at();
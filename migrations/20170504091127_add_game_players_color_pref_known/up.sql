@@ -0,0 +1,5 @@
+-- Players created before color_pref_rank was added have a NULL rank because it was never
+-- recorded, not because their color wasn't in their preferences. New players default to known.
+ALTER TABLE game_players ADD COLUMN color_pref_known BOOL NOT NULL DEFAULT FALSE;
+UPDATE game_players SET color_pref_known = TRUE WHERE color_pref_rank IS NOT NULL;
+ALTER TABLE game_players ALTER COLUMN color_pref_known SET DEFAULT TRUE;
@@ -0,0 +1,38 @@
+-- Players added to a game after its time bank was set start with a full bank, so they can become
+-- overdue like everyone else.
+UPDATE game_players gp
+SET time_bank_secs = g.time_limit_secs
+FROM games g
+WHERE g.id = gp.game_id
+AND g.time_limit_kind = 'TimeBank'
+AND gp.time_bank_secs IS NULL;
+
+CREATE OR REPLACE FUNCTION track_game_player_turn()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        IF NEW.time_bank_secs IS NULL THEN
+            NEW.time_bank_secs = (
+                SELECT time_limit_secs
+                FROM games
+                WHERE id = NEW.game_id
+                AND time_limit_kind = 'TimeBank'
+            );
+        END IF;
+        IF NEW.is_turn THEN
+            NEW.turn_started_at = (now() AT TIME ZONE 'utc');
+        END IF;
+    ELSIF NEW.is_turn AND NOT OLD.is_turn THEN
+        NEW.turn_started_at = (now() AT TIME ZONE 'utc');
+        NEW.turn_reminded_at = NULL;
+    ELSIF OLD.is_turn AND NOT NEW.is_turn THEN
+        IF NEW.time_bank_secs IS NOT NULL AND OLD.turn_started_at IS NOT NULL THEN
+            NEW.time_bank_secs = GREATEST(0, NEW.time_bank_secs - floor(extract(EPOCH FROM
+                (now() AT TIME ZONE 'utc') - OLD.turn_started_at))::INT);
+        END IF;
+        NEW.turn_started_at = NULL;
+        NEW.turn_reminded_at = NULL;
+    END IF;
+    RETURN NEW;
+END;
+$$ language 'plpgsql';
@@ -0,0 +1,692 @@
+//! Connections, transactions and pools on tokio-postgres for `async_query`, enabled with the
+//! `async` feature.
+//!
+//! Queries take a `Client`, either a connection or a transaction, by value and hand it back
+//! alongside their results as tokio-postgres itself does. A query which fails drops its client,
+//! which closes the connection and so rolls back any transaction on it.
+
+use futures::{future, stream, Future, Stream};
+use futures::future::{Either, Loop};
+use futures::unsync::oneshot;
+use futures_state_stream::StateStream;
+use postgres::error::Error as PgError;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_postgres::Connection;
+use tokio_postgres::error::{ConnectError, Error as AsyncPgError};
+use tokio_postgres::rows::Row;
+use tokio_postgres::stmt::Statement;
+use tokio_postgres::transaction::Transaction;
+use tokio_postgres::types::ToSql;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::Instant;
+
+use errors::*;
+use config::{DbConfig, PoolConfig};
+use transaction::{self, IsolationLevel};
+
+/// The result of a query along with the client it ran on, ready for the next query.
+pub type AsyncResult<'a, T, C> = Box<Future<Item = (T, C), Error = Error> + 'a>;
+
+/// Owned query parameters, as parameters have to outlive the statement being prepared.
+pub type Params<'a> = Vec<Box<ToSql + 'a>>;
+
+pub fn param<'a, T: ToSql + 'a>(value: T) -> Box<ToSql + 'a> {
+    Box::new(value)
+}
+
+/// A connection or transaction which queries can be run on.
+pub trait Client: Sized + 'static {
+    /// What `begin` starts, which `commit` turns back into this client.
+    type Transaction: Client;
+
+    fn prepare(self, sql: &str) -> AsyncResult<'static, Statement, Self>;
+    /// Runs a prepared statement, collecting its rows. Parameters are bound before this returns,
+    /// so they only need to live for the call.
+    fn query_prepared(self,
+                      statement: &Statement,
+                      params: &[&ToSql])
+                      -> AsyncResult<'static, Vec<Row>, Self>;
+    fn execute_prepared(self,
+                        statement: &Statement,
+                        params: &[&ToSql])
+                        -> AsyncResult<'static, u64, Self>;
+    fn batch_execute(self, sql: &str) -> AsyncResult<'static, (), Self>;
+    fn begin(self,
+             isolation: IsolationLevel)
+             -> Box<Future<Item = Self::Transaction, Error = Error>>;
+    fn commit(trans: Self::Transaction) -> Box<Future<Item = Self, Error = Error>>;
+}
+
+impl Client for Connection {
+    type Transaction = Transaction;
+
+    fn prepare(self, sql: &str) -> AsyncResult<'static, Statement, Self> {
+        Box::new(Connection::prepare(self, sql).map_err(pg_error))
+    }
+
+    fn query_prepared(self,
+                      statement: &Statement,
+                      params: &[&ToSql])
+                      -> AsyncResult<'static, Vec<Row>, Self> {
+        Box::new(Connection::query(self, statement, params)
+                     .collect()
+                     .map_err(pg_error))
+    }
+
+    fn execute_prepared(self,
+                        statement: &Statement,
+                        params: &[&ToSql])
+                        -> AsyncResult<'static, u64, Self> {
+        Box::new(Connection::execute(self, statement, params).map_err(pg_error))
+    }
+
+    fn batch_execute(self, sql: &str) -> AsyncResult<'static, (), Self> {
+        Box::new(Connection::batch_execute(self, sql)
+                     .map(|conn| ((), conn))
+                     .map_err(pg_error))
+    }
+
+    fn begin(self, isolation: IsolationLevel) -> Box<Future<Item = Transaction, Error = Error>> {
+        let set_isolation = format!("SET TRANSACTION ISOLATION LEVEL {}", isolation.sql());
+        Box::new(self.transaction()
+                     .map_err(pg_error)
+                     .and_then(move |trans| {
+                                   Transaction::batch_execute(trans, &set_isolation)
+                                       .map_err(pg_error)
+                               }))
+    }
+
+    fn commit(trans: Transaction) -> Box<Future<Item = Connection, Error = Error>> {
+        Box::new(trans.commit().map_err(pg_error))
+    }
+}
+
+/// Transactions begun in a transaction are savepoints, as isolation belongs to the outermost
+/// transaction like in `retry_transaction`. Savepoints share a name, which Postgres releases
+/// innermost first.
+impl Client for Transaction {
+    type Transaction = Transaction;
+
+    fn prepare(self, sql: &str) -> AsyncResult<'static, Statement, Self> {
+        Box::new(Transaction::prepare(self, sql).map_err(pg_error))
+    }
+
+    fn query_prepared(self,
+                      statement: &Statement,
+                      params: &[&ToSql])
+                      -> AsyncResult<'static, Vec<Row>, Self> {
+        Box::new(Transaction::query(self, statement, params)
+                     .collect()
+                     .map_err(pg_error))
+    }
+
+    fn execute_prepared(self,
+                        statement: &Statement,
+                        params: &[&ToSql])
+                        -> AsyncResult<'static, u64, Self> {
+        Box::new(Transaction::execute(self, statement, params).map_err(pg_error))
+    }
+
+    fn batch_execute(self, sql: &str) -> AsyncResult<'static, (), Self> {
+        Box::new(Transaction::batch_execute(self, sql)
+                     .map(|trans| ((), trans))
+                     .map_err(pg_error))
+    }
+
+    fn begin(self, _isolation: IsolationLevel) -> Box<Future<Item = Transaction, Error = Error>> {
+        Box::new(Transaction::batch_execute(self, "SAVEPOINT async_client").map_err(pg_error))
+    }
+
+    fn commit(trans: Transaction) -> Box<Future<Item = Transaction, Error = Error>> {
+        Box::new(Transaction::batch_execute(trans, "RELEASE SAVEPOINT async_client")
+                     .map_err(pg_error))
+    }
+}
+
+/// Converts a tokio-postgres error into the blocking client's, which has the same database
+/// errors, so `sql_state` and `constraint` work on both.
+fn pg_error<C>(e: AsyncPgError<C>) -> Error {
+    ErrorKind::Postgres(match e {
+                            AsyncPgError::Db(db, _) => PgError::Db(db),
+                            AsyncPgError::Io(e) => PgError::Io(e),
+                            AsyncPgError::Conversion(e, _) => PgError::Conversion(e),
+                        })
+            .into()
+}
+
+fn connect_error(e: ConnectError) -> Error {
+    match e {
+        ConnectError::Db(db) => ErrorKind::Postgres(PgError::Db(db)).into(),
+        ConnectError::Io(e) => ErrorKind::Postgres(PgError::Io(e)).into(),
+        e => format!("unable to connect to database: {}", e).into(),
+    }
+}
+
+/// A finished query, for returning early from a chain of queries.
+pub fn ready<'a, T: 'a, C: 'a>(value: T, conn: C) -> AsyncResult<'a, T, C> {
+    Box::new(future::ok((value, conn)))
+}
+
+/// A failed query, for returning early from a chain of queries.
+pub fn failed<'a, T: 'a, C: 'a>(e: Error) -> AsyncResult<'a, T, C> {
+    Box::new(future::err(e))
+}
+
+/// Boxes a chain of queries, so branches returning different chains have the same type.
+pub fn boxed<'a, T, C, F>(f: F) -> AsyncResult<'a, T, C>
+    where F: Future<Item = (T, C), Error = Error> + 'a
+{
+    Box::new(f)
+}
+
+/// Prepares `sql`, then runs it with `run` and reads each row with `from_row`. `run` binds the
+/// parameters once the statement is ready, so they can be built from values it owns, such as rows
+/// returned by earlier queries.
+pub fn prepared<'a, C, T, R, F>(sql: &str,
+                                run: R,
+                                from_row: F,
+                                conn: C)
+                                -> AsyncResult<'a, Vec<T>, C>
+    where C: Client,
+          T: 'a,
+          R: FnOnce(&Statement, C) -> AsyncResult<'static, Vec<Row>, C> + 'a,
+          F: Fn(&Row) -> T + 'a
+{
+    Box::new(conn.prepare(sql)
+                 .and_then(move |(statement, conn)| run(&statement, conn))
+                 .map(move |(rows, conn)| (rows.iter().map(|row| from_row(row)).collect(), conn)))
+}
+
+/// Prepares and runs `sql`, reading each row with `from_row`.
+pub fn query_rows<'a, C, T, F>(sql: &str,
+                               params: Params<'a>,
+                               from_row: F,
+                               conn: C)
+                               -> AsyncResult<'a, Vec<T>, C>
+    where C: Client,
+          T: 'a,
+          F: Fn(&Row) -> T + 'a
+{
+    prepared(sql,
+             move |statement, conn| {
+                 let bound: Vec<&ToSql> = params.iter().map(|p| &**p).collect();
+                 conn.query_prepared(statement, &bound)
+             },
+             from_row,
+             conn)
+}
+
+/// Like `query_rows`, but for queries returning at most one row.
+pub fn query_row<'a, C, T, F>(sql: &str,
+                              params: Params<'a>,
+                              from_row: F,
+                              conn: C)
+                              -> AsyncResult<'a, Option<T>, C>
+    where C: Client,
+          T: 'a,
+          F: Fn(&Row) -> T + 'a
+{
+    Box::new(query_rows(sql, params, from_row, conn)
+                 .map(|(rows, conn)| (rows.into_iter().next(), conn)))
+}
+
+/// Prepares and runs `sql`, giving the number of rows changed.
+pub fn execute<'a, C: Client>(sql: &str, params: Params<'a>, conn: C) -> AsyncResult<'a, u64, C> {
+    Box::new(conn.prepare(sql)
+                 .and_then(move |(statement, conn)| {
+                               let bound: Vec<&ToSql> = params.iter().map(|p| &**p).collect();
+                               conn.execute_prepared(&statement, &bound)
+                           }))
+}
+
+/// Runs `f` for each item in turn on the same client, threading `init` through like
+/// `Iterator::fold`.
+pub fn fold<'a, C, I, T, F>(items: I, init: T, f: F, conn: C) -> AsyncResult<'a, T, C>
+    where C: Client,
+          I: IntoIterator,
+          I::IntoIter: 'a,
+          T: 'a,
+          F: FnMut(T, I::Item, C) -> AsyncResult<'a, T, C> + 'a
+{
+    let mut f = f;
+    Box::new(stream::iter_ok::<_, Error>(items)
+                 .fold((init, conn), move |(acc, conn), item| f(acc, item, conn)))
+}
+
+/// Runs `f` in a transaction at the given isolation level, committing if it succeeds. Given a
+/// transaction, `f` is run in a savepoint at the outer transaction's isolation level instead.
+///
+/// Unlike `retry_transaction` failures aren't retried, as that needs another connection, see
+/// `Pool::run`.
+pub fn transaction<'a, C, T, F>(isolation: IsolationLevel, conn: C, f: F) -> AsyncResult<'a, T, C>
+    where C: Client,
+          T: 'a,
+          F: FnOnce(C::Transaction) -> AsyncResult<'a, T, C::Transaction> + 'a
+{
+    Box::new(conn.begin(isolation)
+                 .and_then(f)
+                 .and_then(|(value, trans)| C::commit(trans).map(move |conn| (value, conn))))
+}
+
+/// Connects to `addr` with the TLS mode and session settings in `config`, like the connections in
+/// `DbConfig::pool`.
+pub fn connect(config: &DbConfig,
+               addr: &str,
+               handle: &Handle)
+               -> Box<Future<Item = Connection, Error = Error>> {
+    let tls_mode = match config.async_tls_mode() {
+        Ok(tls_mode) => tls_mode,
+        Err(e) => return Box::new(future::err(e)),
+    };
+    let settings = config.session_settings();
+    Box::new(Connection::connect(addr, tls_mode, handle)
+                 .map_err(connect_error)
+                 .and_then(move |conn| {
+        fold(settings,
+             (),
+             |(), (name, value), conn| {
+                 Box::new(execute("SELECT set_config($1, $2, false)",
+                                  vec![param(name), param(value)],
+                                  conn)
+                                  .map(|(_, conn)| ((), conn)))
+             },
+             conn)
+    })
+                 .map(|((), conn)| conn))
+}
+
+/// Pools for the primary and the replica, like `Connections` for the blocking client.
+pub struct Connections {
+    pub w: Pool,
+    pub r: Pool,
+}
+
+pub fn connect_config(config: &DbConfig, handle: &Handle) -> Result<Connections> {
+    Ok(Connections {
+           w: Pool::new(config, &config.w_addr, &config.w_pool, handle)?,
+           r: Pool::new(config, &config.r_addr, &config.r_pool, handle)?,
+       })
+}
+
+/// A pool of connections for use on one reactor. Connections are opened as they are needed, up to
+/// the pool size, so `min_idle` is ignored. Clones share the same connections.
+#[derive(Clone)]
+pub struct Pool {
+    inner: Rc<PoolInner>,
+}
+
+struct PoolInner {
+    config: DbConfig,
+    addr: String,
+    pool_config: PoolConfig,
+    handle: Handle,
+    state: RefCell<PoolState>,
+}
+
+struct PoolState {
+    idle: Vec<IdleConnection>,
+    /// When each open connection was opened, by backend process id.
+    opened_at: HashMap<i32, Instant>,
+    /// Connections which are idle, in use or being opened.
+    open: u32,
+    waiting: VecDeque<oneshot::Sender<Result<Connection>>>,
+}
+
+struct IdleConnection {
+    conn: Connection,
+    since: Instant,
+}
+
+impl PoolState {
+    fn close(&mut self, process_id: i32) {
+        self.open -= 1;
+        self.opened_at.remove(&process_id);
+    }
+}
+
+impl Pool {
+    pub fn new(config: &DbConfig,
+               addr: &str,
+               pool_config: &PoolConfig,
+               handle: &Handle)
+               -> Result<Pool> {
+        pool_config.validate()?;
+        // Checked up front so a bad CA file fails here rather than on the first query.
+        config.async_tls_mode()?;
+        Ok(Pool {
+               inner: Rc::new(PoolInner {
+                                  config: config.clone(),
+                                  addr: addr.to_string(),
+                                  pool_config: pool_config.clone(),
+                                  handle: handle.clone(),
+                                  state: RefCell::new(PoolState {
+                                                          idle: vec![],
+                                                          opened_at: HashMap::new(),
+                                                          open: 0,
+                                                          waiting: VecDeque::new(),
+                                                      }),
+                              }),
+           })
+    }
+
+    /// Runs `f` on a connection from the pool, returning the connection to the pool if it
+    /// succeeds. If it fails the connection has been dropped, and if it failed with a
+    /// serialisation failure or a deadlock it is run again on another connection with backoff,
+    /// as `retry_transaction` does.
+    pub fn run<'a, T, F>(&self, f: F) -> Box<Future<Item = T, Error = Error> + 'a>
+        where T: 'a,
+              F: Fn(Connection) -> AsyncResult<'a, T, Connection> + 'a
+    {
+        let pool = self.clone();
+        let f = Rc::new(f);
+        Box::new(future::loop_fn(1, move |attempt| {
+            let pool = pool.clone();
+            let handle = pool.inner.handle.clone();
+            let f = f.clone();
+            pool.get()
+                .and_then(move |conn| {
+                    let process_id = conn.cancel_data().process_id;
+                    f(conn).then(move |result| match result {
+                                      Ok((value, conn)) => {
+                                          pool.put(conn);
+                                          Ok(value)
+                                      }
+                                      Err(e) => {
+                                          pool.discard(process_id);
+                                          Err(e)
+                                      }
+                                  })
+                })
+                .then(move |result| -> Box<Future<Item = Loop<T, u32>, Error = Error> + 'a> {
+                    match result {
+                        Ok(value) => Box::new(future::ok(Loop::Break(value))),
+                        Err(ref e) if attempt < transaction::MAX_ATTEMPTS &&
+                                      transaction::is_retryable(e) => {
+                            match Timeout::new(transaction::backoff(attempt), &handle) {
+                                Ok(timeout) => {
+                                    Box::new(timeout
+                                                 .map(move |_| Loop::Continue(attempt + 1))
+                                                 .map_err(|e| {
+                                                              Error::with_chain(e,
+                                                                                "backoff failed")
+                                                          }))
+                                }
+                                Err(e) => {
+                                    Box::new(future::err(Error::with_chain(e,
+                                                                           "backoff failed")))
+                                }
+                            }
+                        }
+                        Err(e) => Box::new(future::err(e)),
+                    }
+                })
+        }))
+    }
+
+    /// Takes an idle connection, or opens one if the pool isn't full, or otherwise waits for one to
+    /// be returned for up to the connection timeout.
+    fn get(&self) -> Box<Future<Item = Connection, Error = Error>> {
+        let (sender, receiver) = oneshot::channel();
+        let should_open = {
+            let mut state = self.inner.state.borrow_mut();
+            let now = Instant::now();
+            while let Some(idle) = state.idle.pop() {
+                if self.is_expired(&state, &idle, now) {
+                    state.close(idle.conn.cancel_data().process_id);
+                    continue;
+                }
+                return Box::new(future::ok(idle.conn));
+            }
+            state.waiting.push_back(sender);
+            if state.open < self.inner.pool_config.size {
+                state.open += 1;
+                true
+            } else {
+                false
+            }
+        };
+        if should_open {
+            self.open();
+        }
+        let timeout = match Timeout::new(self.inner.pool_config.connection_timeout,
+                                         &self.inner.handle) {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                return Box::new(future::err(Error::with_chain(e,
+                                                              "unable to start connection \
+                                                               timeout")))
+            }
+        };
+        Box::new(receiver
+                     .select2(timeout)
+                     .then(|result| match result {
+                               Ok(Either::A((conn, _))) => conn,
+                               Ok(Either::B(_)) => {
+                                   Err("timed out waiting for a database connection".into())
+                               }
+                               Err(Either::A(_)) => Err("database pool was dropped".into()),
+                               Err(Either::B((e, _))) => {
+                                   Err(Error::with_chain(e, "connection timeout failed"))
+                               }
+                           }))
+    }
+
+    /// Whether an idle connection has been idle or open for too long to be used.
+    fn is_expired(&self, state: &PoolState, idle: &IdleConnection, now: Instant) -> bool {
+        let config = &self.inner.pool_config;
+        let opened_at = state.opened_at.get(&idle.conn.cancel_data().process_id);
+        config.idle_timeout.map(|t| now - idle.since >= t).unwrap_or(false) ||
+        config
+            .max_lifetime
+            .and_then(|t| opened_at.map(|at| now - *at >= t))
+            .unwrap_or(false)
+    }
+
+    /// Opens a connection in the background, handing it to the longest waiting caller.
+    fn open(&self) {
+        let pool = self.clone();
+        let opening = connect(&self.inner.config, &self.inner.addr, &self.inner.handle)
+            .then(move |result| {
+                      match result {
+                          Ok(conn) => {
+                              pool.inner
+                                  .state
+                                  .borrow_mut()
+                                  .opened_at
+                                  .insert(conn.cancel_data().process_id, Instant::now());
+                              pool.put(conn);
+                          }
+                          Err(e) => pool.fail(e),
+                      }
+                      Ok::<(), ()>(())
+                  });
+        self.inner.handle.spawn(opening);
+    }
+
+    /// Returns a connection to the pool, handing it to the longest waiting caller if there is one.
+    fn put(&self, conn: Connection) {
+        let mut conn = conn;
+        let mut state = self.inner.state.borrow_mut();
+        while let Some(waiter) = state.waiting.pop_front() {
+            match waiter.send(Ok(conn)) {
+                Ok(()) => return,
+                // The caller stopped waiting, so try the next one.
+                Err(Ok(returned)) => conn = returned,
+                Err(Err(_)) => unreachable!(),
+            }
+        }
+        state
+            .idle
+            .push(IdleConnection {
+                      conn: conn,
+                      since: Instant::now(),
+                  });
+    }
+
+    /// Gives up on a connection which couldn't be opened, failing the longest waiting caller.
+    fn fail(&self, e: Error) {
+        let mut e = e;
+        let mut state = self.inner.state.borrow_mut();
+        state.open -= 1;
+        while let Some(waiter) = state.waiting.pop_front() {
+            match waiter.send(Err(e)) {
+                Ok(()) => return,
+                Err(Err(returned)) => e = returned,
+                Err(Ok(_)) => unreachable!(),
+            }
+        }
+    }
+
+    /// Forgets a connection which was dropped after an error, opening another if callers are
+    /// waiting for one.
+    fn discard(&self, process_id: i32) {
+        let should_open = {
+            let mut state = self.inner.state.borrow_mut();
+            state.close(process_id);
+            if !state.waiting.is_empty() && state.open < self.inner.pool_config.size {
+                state.open += 1;
+                true
+            } else {
+                false
+            }
+        };
+        if should_open {
+            self.open();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::ModelRow;
+    use test_support::test_db;
+
+    use tokio_core::reactor::Core;
+
+    use std::cell::Cell;
+
+    fn raise<C: Client>(code: &str, conn: C) -> AsyncResult<'static, (), C> {
+        conn.batch_execute(&format!("DO $$ BEGIN RAISE EXCEPTION 'failed' USING ERRCODE = '{}'; \
+                                     END $$",
+                                    code))
+    }
+
+    fn count_rows<C: Client>(conn: C) -> AsyncResult<'static, i64, C> {
+        Box::new(query_row("SELECT COUNT(*) AS count FROM game_types",
+                           vec![],
+                           |row| row.get_col::<i64>("count"),
+                           conn)
+                         .map(|(count, conn)| (count.unwrap_or(0), conn)))
+    }
+
+    fn insert_game_type<C: Client>(name: &'static str, conn: C) -> AsyncResult<'static, u64, C> {
+        execute("INSERT INTO game_types (name) VALUES ($1)",
+                vec![param(name)],
+                conn)
+    }
+
+    #[test]
+    fn errors_keep_their_sql_state() {
+        if let Some(db) = test_db() {
+            let mut core = Core::new().unwrap();
+            let config = DbConfig::new(&db.url, &db.url);
+            let e = core.run(connect(&config, &db.url, &core.handle())
+                                 .and_then(|conn| raise("23505", conn)))
+                .err()
+                .unwrap();
+            assert_eq!(Some(&::postgres::error::SqlState::UniqueViolation),
+                       sql_state(&e));
+            assert!(transaction::is_retryable(&core.run(connect(&config,
+                                                                 &db.url,
+                                                                 &core.handle())
+                                                             .and_then(|conn| {
+                                                                           raise("40001", conn)
+                                                                       }))
+                                                   .err()
+                                                   .unwrap()));
+        }
+    }
+
+    #[test]
+    fn connect_applies_session_settings() {
+        if let Some(db) = test_db() {
+            let mut core = Core::new().unwrap();
+            let mut config = DbConfig::new(&db.url, &db.url);
+            config.statement_timeout = Some(::std::time::Duration::from_millis(1234));
+            let (timeout, _) = core.run(connect(&config, &db.url, &core.handle())
+                                            .and_then(|conn| {
+                                                          query_row("SHOW statement_timeout",
+                                                                    vec![],
+                                                                    |row| {
+                                                                        row.get_col::<String>(
+                                                                            "statement_timeout")
+                                                                    },
+                                                                    conn)
+                                                      }))
+                .unwrap();
+            assert_eq!(Some("1234ms".to_string()), timeout);
+        }
+    }
+
+    #[test]
+    fn transaction_commits_and_rolls_back() {
+        if let Some(db) = test_db() {
+            let mut core = Core::new().unwrap();
+            let config = DbConfig::new(&db.url, &db.url);
+            let conn = core.run(connect(&config, &db.url, &core.handle())).unwrap();
+            let (_, conn) = core.run(transaction(IsolationLevel::Serializable, conn, |trans| {
+                    Box::new(insert_game_type("Committed", trans).and_then(|(_, trans)| {
+                        // A savepoint inside the transaction commits along with it.
+                        transaction(IsolationLevel::Serializable,
+                                    trans,
+                                    |trans| insert_game_type("Nested", trans))
+                    }))
+                }))
+                .unwrap();
+            let (count, conn) = core.run(count_rows(conn)).unwrap();
+            assert_eq!(2, count);
+            assert!(core.run(transaction(IsolationLevel::Serializable, conn, |trans| {
+                                 Box::new(insert_game_type("Rolled back", trans)
+                                              .and_then(|(_, trans)| raise("23505", trans)))
+                             }))
+                        .is_err());
+            let conn = core.run(connect(&config, &db.url, &core.handle())).unwrap();
+            let (count, _) = core.run(count_rows(conn)).unwrap();
+            assert_eq!(2, count);
+        }
+    }
+
+    #[test]
+    fn pool_run_retries_serialization_failures() {
+        if let Some(db) = test_db() {
+            let mut core = Core::new().unwrap();
+            let mut config = DbConfig::new(&db.url, &db.url);
+            config.w_pool.size = 1;
+            let conns = connect_config(&config, &core.handle()).unwrap();
+            let attempts = Cell::new(0);
+            let count = core.run(conns.w.run(|conn| {
+                    attempts.set(attempts.get() + 1);
+                    if attempts.get() < 3 {
+                        return Box::new(raise("40001", conn).map(|(_, conn)| (0, conn)));
+                    }
+                    count_rows(conn)
+                }))
+                .unwrap();
+            assert_eq!(0, count);
+            assert_eq!(3, attempts.get());
+            attempts.set(0);
+            assert!(core.run(conns.w.run(|conn| {
+                                             attempts.set(attempts.get() + 1);
+                                             raise("23505", conn)
+                                         }))
+                        .is_err());
+            assert_eq!(1, attempts.get());
+            // The pool replaces connections dropped by failures.
+            assert_eq!(0, core.run(conns.w.run(count_rows)).unwrap());
+        }
+    }
+}
@@ -0,0 +1,2527 @@
+//! An async counterpart to `query` on tokio-postgres, enabled with the `async` feature.
+//!
+//! Functions keep the names and arguments of their `query` counterparts, except that they take a
+//! `Client` by value and hand it back alongside the result, as tokio-postgres itself does, so
+//! calls can be chained on one connection. Functions which run in a transaction in `query` run in
+//! one here too at the same isolation level, or in a savepoint when given a transaction. Failed
+//! transactions aren't retried here, `Pool::run` does that.
+//!
+//! Arguments are copied so the returned futures don't borrow them, except rows to insert and
+//! selects, which are borrowed for as long as the future runs.
+
+use futures::{future, Future};
+use futures::future::Loop;
+use chrono::UTC;
+use serde_json::Value;
+use tokio_postgres::rows::Row;
+use tokio_postgres::stmt::Statement;
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use brdgme_cmd::cli::CliLog;
+
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::iter::FromIterator;
+use std::ops::Range;
+use std::rc::Rc;
+
+use errors::*;
+use models::*;
+use async_client::{Client, AsyncResult, param, prepared, query_rows, query_row, execute, fold,
+                   transaction, ready, failed, boxed};
+use color::{self, Color};
+use outbox::{self, OutboxMessageKind};
+use ratings;
+use sql::Select;
+use transaction::IsolationLevel;
+use turns;
+
+pub use query::{UserByEmail, UserColorPrefStats, CreatedGame, CreatedRematch, UpdatedGame,
+                Placing, Standing, CreatedGameLog, MigratedGameVersion, CONFIRMATION_EXPIRY,
+                TOKEN_EXPIRY, check_placings, create_players_for_users, games_select,
+                pending_game_color_swaps, rand_code, users_with_emails};
+
+/// Postgres allows at most this many parameters in a statement.
+static MAX_PARAMS: usize = 65535;
+
+/// Takes the only row of a query which should return one, failing with `msg` if it returned none.
+fn single<'a, T: 'a, C: Client>(rows: AsyncResult<'a, Vec<T>, C>,
+                                msg: &'static str)
+                                -> AsyncResult<'a, T, C> {
+    Box::new(rows.and_then(move |(rows, conn)| match rows.into_iter().next() {
+                               Some(row) => Ok((row, conn)),
+                               None => Err(msg.into()),
+                           }))
+}
+
+/// Takes the first row of a query, if there is one.
+fn first<'a, T: 'a, C: Client>(rows: AsyncResult<'a, Vec<T>, C>) -> AsyncResult<'a, Option<T>, C> {
+    Box::new(rows.map(|(rows, conn)| (rows.into_iter().next(), conn)))
+}
+
+/// Unwraps the result of a lookup which has to find something, failing with `msg` if it didn't.
+fn found<T, C>(result: Result<(Option<T>, C)>, msg: &'static str) -> Result<(T, C)> {
+    match result.chain_err(|| msg)? {
+        (Some(value), conn) => Ok((value, conn)),
+        (None, _) => Err(msg.into()),
+    }
+}
+
+/// Splits `count` rows into ranges of at most `size` rows.
+fn chunks(count: usize, size: usize) -> Vec<Range<usize>> {
+    (0..(count + size - 1) / size)
+        .map(|i| i * size..cmp::min((i + 1) * size, count))
+        .collect()
+}
+
+/// Orders created rows the same as the ids they were created with.
+fn in_order<T, G>(created: Vec<T>, ids: &[Uuid], id_of: G) -> Result<Vec<T>>
+    where G: Fn(&T) -> Uuid
+{
+    let mut by_id: HashMap<Uuid, T> = created.into_iter().map(|c| (id_of(&c), c)).collect();
+    ids.iter()
+        .map(|id| {
+                 by_id
+                     .remove(id)
+                     .ok_or_else::<Error, _>(|| format!("row {} was not created", id).into())
+             })
+        .collect()
+}
+
+/// Binds each row's id before its fields, for `insert_many_with_ids_sql`.
+fn with_ids<'r, N, I>(rows: I, ids: &'r [Uuid]) -> Vec<&'r ToSql>
+    where N: Insert + 'r,
+          I: IntoIterator<Item = &'r N>
+{
+    let mut params: Vec<&ToSql> = vec![];
+    for (row, id) in rows.into_iter().zip(ids) {
+        params.push(id);
+        params.extend(row.params());
+    }
+    params
+}
+
+/// Inserts a row, reading the created row with `from_row`.
+fn insert<'a, N, T, F, C>(row: &'a N,
+                          from_row: F,
+                          msg: &'static str,
+                          conn: C)
+                          -> AsyncResult<'a, T, C>
+    where N: Insert + 'a,
+          T: 'a,
+          F: Fn(&Row) -> T + 'a,
+          C: Client
+{
+    single(prepared(&N::insert_sql(),
+                    move |statement, conn| conn.query_prepared(statement, &row.params()),
+                    from_row,
+                    conn),
+           msg)
+}
+
+/// Inserts `count` rows using multi-row INSERT statements like `query::insert_all`, with `bind`
+/// binding the rows in a range. The created rows are returned in no particular order.
+fn insert_all<'a, N, C, T, B, F>(count: usize,
+                                 bind: B,
+                                 from_row: F,
+                                 conn: C)
+                                 -> AsyncResult<'a, Vec<T>, C>
+    where N: Insert + 'a,
+          C: Client,
+          T: 'a,
+          B: Fn(Range<usize>, &Statement, C) -> AsyncResult<'static, Vec<Row>, C> + 'a,
+          F: Fn(&Row) -> T + 'a
+{
+    let bind = Rc::new(bind);
+    let from_row = Rc::new(from_row);
+    fold(chunks(count, MAX_PARAMS / N::insert_cols().len()),
+         Vec::with_capacity(count),
+         move |mut created: Vec<T>, range: Range<usize>, conn| {
+        let bind = bind.clone();
+        let from_row = from_row.clone();
+        boxed(prepared(&N::insert_many_sql(range.len()),
+                       move |statement, conn| (*bind)(range, statement, conn),
+                       move |row| (*from_row)(row),
+                       conn)
+                      .map(move |(rows, conn)| {
+                               created.extend(rows);
+                               (created, conn)
+                           }))
+    },
+         conn)
+}
+
+/// Like `insert_all`, but generates the id of each row so the created rows can be returned in
+/// order. `bind` is given the ids of the rows in its range, to bind with `with_ids`.
+fn insert_all_with_ids<'a, N, C, T, B, F, G>(count: usize,
+                                             bind: B,
+                                             from_row: F,
+                                             id_of: G,
+                                             conn: C)
+                                             -> AsyncResult<'a, Vec<T>, C>
+    where N: Insert + 'a,
+          C: Client,
+          T: 'a,
+          B: Fn(Range<usize>, &[Uuid], &Statement, C) -> AsyncResult<'static, Vec<Row>, C> + 'a,
+          F: Fn(&Row) -> T + 'a,
+          G: Fn(&T) -> Uuid + 'a
+{
+    let ids: Rc<Vec<Uuid>> = Rc::new((0..count).map(|_| Uuid::new_v4()).collect());
+    let chunk_ids = ids.clone();
+    let bind = Rc::new(bind);
+    let from_row = Rc::new(from_row);
+    boxed(fold(chunks(count, MAX_PARAMS / (N::insert_cols().len() + 1)),
+               Vec::with_capacity(count),
+               move |mut created: Vec<T>, range: Range<usize>, conn| {
+        let bind = bind.clone();
+        let from_row = from_row.clone();
+        let chunk_ids = chunk_ids.clone();
+        boxed(prepared(&N::insert_many_with_ids_sql(range.len()),
+                       move |statement, conn| {
+                           (*bind)(range.clone(), &chunk_ids[range], statement, conn)
+                       },
+                       move |row| (*from_row)(row),
+                       conn)
+                      .map(move |(rows, conn)| {
+                               created.extend(rows);
+                               (created, conn)
+                           }))
+    },
+               conn)
+                  .and_then(move |(created, conn)| {
+                                in_order(created, &ids, id_of).map(|created| (created, conn))
+                            }))
+}
+
+/// Runs a select built by `build` from `data`, which the select's parameters borrow.
+fn select_rows<'a, C, D, T, B, F>(data: D,
+                                  build: B,
+                                  from_row: F,
+                                  conn: C)
+                                  -> AsyncResult<'a, Vec<T>, C>
+    where C: Client,
+          D: 'a,
+          T: 'a,
+          B: Fn(&D) -> Select + 'a,
+          F: Fn(&Row) -> T + 'a
+{
+    let sql = match build(&data).sql() {
+        Ok(sql) => sql,
+        Err(e) => return failed(e),
+    };
+    prepared(&sql,
+             move |statement, conn| conn.query_prepared(statement, &build(&data).params()),
+             from_row,
+             conn)
+}
+
+fn read_user_by_email(row: &Row) -> UserByEmail {
+    UserByEmail {
+        user: User::from_row(row, "u_"),
+        user_email: UserEmail::from_row(row, "ue_"),
+    }
+}
+
+fn check_player_count(game_version: &GameVersion, count: usize) -> Result<()> {
+    if game_version.supports_player_count(count) {
+        return Ok(());
+    }
+    Err(ErrorKind::InvalidPlayerCount(count, game_version.min_players, game_version.max_players)
+            .into())
+}
+
+pub fn create_user_by_name<C: Client>(name: &str, conn: C) -> AsyncResult<'static, User, C> {
+    let name = name.to_string();
+    single(prepared(&NewUser::insert_sql(),
+                    move |statement, conn| {
+                        conn.query_prepared(statement,
+                                            &NewUser {
+                                                     name: &name,
+                                                     pref_colors: &[],
+                                                     login_confirmation: None,
+                                                     login_confirmation_at: None,
+                                                 }
+                                                 .params())
+                    },
+                    |row| User::from_row(row, ""),
+                    conn),
+           "unable to create user")
+}
+
+pub fn create_user<'a, C: Client>(new_user: &'a NewUser, conn: C) -> AsyncResult<'a, User, C> {
+    let mut seen: HashSet<&Color> = HashSet::new();
+    for c in new_user.pref_colors.iter() {
+        if !seen.insert(*c) {
+            return failed(ErrorKind::DuplicateColor(format!("{:?}", c)).into());
+        }
+    }
+    insert(new_user,
+           |row| User::from_row(row, ""),
+           "unable to create user",
+           conn)
+}
+
+pub fn find_user<C: Client>(id: &Uuid, conn: C) -> AsyncResult<'static, Option<User>, C> {
+    query_row("
+        SELECT *
+        FROM users
+        WHERE id=$1
+        LIMIT 1",
+              vec![param(*id)],
+              |row| User::from_row(row, ""),
+              conn)
+}
+
+pub fn find_user_by_email<C: Client>(email: &str,
+                                     conn: C)
+                                     -> AsyncResult<'static, Option<UserByEmail>, C> {
+    first(select_rows(email.to_string(),
+                      |email| {
+                          users_with_emails()
+                              .filter("ue.email = ?", &[email])
+                              .limit(1)
+                      },
+                      read_user_by_email,
+                      conn))
+}
+
+pub fn find_or_create_user_by_email<C: Client>(email: &str,
+                                               conn: C)
+                                               -> AsyncResult<'static, UserByEmail, C> {
+    let email = email.to_string();
+    boxed(find_user_by_email(&email, conn).and_then(move |(found, conn)| match found {
+                                                        Some(u) => ready(u, conn),
+                                                        None => create_user_by_email(&email, conn),
+                                                    }))
+}
+
+pub fn create_user_by_email<C: Client>(email: &str,
+                                       conn: C)
+                                       -> AsyncResult<'static, UserByEmail, C> {
+    let email = email.to_string();
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(create_user_by_name(&email, trans).and_then(move |(user, trans)| {
+            let user_id = user.id;
+            single(prepared(&NewUserEmail::insert_sql(),
+                            move |statement, trans| {
+                                trans.query_prepared(statement,
+                                                     &NewUserEmail {
+                                                              user_id: &user_id,
+                                                              email: &email,
+                                                              is_primary: true,
+                                                          }
+                                                          .params())
+                            },
+                            |row| UserEmail::from_row(row, ""),
+                            trans),
+                   "could not create user email")
+                    .map(move |(user_email, trans)| {
+                             (UserByEmail {
+                                  user: user,
+                                  user_email: user_email,
+                              },
+                              trans)
+                         })
+        }))
+    })
+}
+
+/// Sets a user's preferred colors, most preferred first, like `query::set_user_pref_colors`.
+pub fn set_user_pref_colors<C: Client>(user_id: &Uuid,
+                                       colors: &[&str],
+                                       conn: C)
+                                       -> AsyncResult<'static, User, C> {
+    let prefs = match color::parse_prefs(colors) {
+        Ok(prefs) => prefs,
+        Err(e) => return failed(e),
+    };
+    single(query_rows("
+        UPDATE users
+        SET pref_colors=$1
+        WHERE id=$2
+        RETURNING *",
+                      vec![param(prefs), param(*user_id)],
+                      |row| User::from_row(row, ""),
+                      conn),
+           "could not find user")
+}
+
+pub fn find_user_color_pref_stats<C: Client>(conn: C)
+                                             -> AsyncResult<'static, Vec<UserColorPrefStats>, C> {
+    query_rows("
+        SELECT
+            user_id,
+            COUNT(*) AS games,
+            COUNT(*) FILTER (WHERE color_pref_rank = 0) AS first_choice,
+            COUNT(*) FILTER (WHERE color_pref_rank = 1) AS second_choice,
+            COUNT(*) FILTER (WHERE color_pref_rank >= 2) AS later_choice,
+            COUNT(*) FILTER (WHERE color_pref_rank IS NULL AND color_pref_known) AS unlisted,
+            COUNT(*) FILTER (WHERE NOT color_pref_known) AS unknown
+        FROM game_players
+        GROUP BY user_id
+        ORDER BY games DESC",
+               vec![],
+               |row| {
+                   UserColorPrefStats {
+                       user_id: row.get_col("user_id"),
+                       games: row.get_col("games"),
+                       first_choice: row.get_col("first_choice"),
+                       second_choice: row.get_col("second_choice"),
+                       later_choice: row.get_col("later_choice"),
+                       unlisted: row.get_col("unlisted"),
+                       unknown: row.get_col("unknown"),
+                   }
+               },
+               conn)
+}
+
+pub fn create_user_email<'a, C: Client>(ue: &'a NewUserEmail,
+                                        conn: C)
+                                        -> AsyncResult<'a, UserEmail, C> {
+    insert(ue,
+           |row| UserEmail::from_row(row, ""),
+           "could not create user email",
+           conn)
+}
+
+pub fn generate_user_login_confirmation<C: Client>(user_id: &Uuid,
+                                                   conn: C)
+                                                   -> AsyncResult<'static, String, C> {
+    let code = rand_code();
+    boxed(execute("
+        UPDATE users
+        SET
+            login_confirmation=$1,
+            login_confirmation_at=(now() AT TIME ZONE 'utc')
+        WHERE id=$2",
+                  vec![param(Some(code.clone())), param(*user_id)],
+                  conn)
+                  .and_then(move |(updated, conn)| match updated {
+                                0 => Err("could not update login confirmation".into()),
+                                _ => Ok((code, conn)),
+                            }))
+}
+
+/// Adds a message to the outbox like `outbox::enqueue`.
+fn enqueue<C: Client>(kind: OutboxMessageKind,
+                      recipient: String,
+                      payload: Value,
+                      conn: C)
+                      -> AsyncResult<'static, OutboxMessage, C> {
+    single(prepared(&NewOutboxMessage::insert_sql(),
+                    move |statement, conn| {
+                        conn.query_prepared(statement,
+                                            &NewOutboxMessage {
+                                                     kind: kind,
+                                                     recipient: &recipient,
+                                                     payload: &payload,
+                                                     max_attempts: outbox::DEFAULT_MAX_ATTEMPTS,
+                                                 }
+                                                 .params())
+                    },
+                    |row| OutboxMessage::from_row(row, ""),
+                    conn),
+           "error enqueueing outbox message")
+}
+
+/// Finds or generates a login confirmation code for the user with the email, and queues an email
+/// with the code in the outbox.
+pub fn user_login_request<C: Client>(email: &str, conn: C) -> AsyncResult<'static, String, C> {
+    let email = email.to_string();
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(find_or_create_user_by_email(&email, trans)
+                  .and_then(|(found, trans)| {
+            let user = found.user;
+            match (user.login_confirmation, user.login_confirmation_at) {
+                (Some(ref uc), Some(at)) if at + *CONFIRMATION_EXPIRY >
+                                            UTC::now().naive_utc() => {
+                    ready(uc.to_owned(), trans)
+                }
+                _ => generate_user_login_confirmation(&user.id, trans),
+            }
+        })
+                  .and_then(move |(confirmation, trans)| {
+                                let payload = json!({"code": confirmation.as_str()});
+                                enqueue(OutboxMessageKind::LoginCode, email, payload, trans)
+                                    .map(move |(_, trans)| (confirmation, trans))
+                            }))
+    })
+}
+
+pub fn user_login_confirm<C: Client>(email: &str,
+                                     confirmation: &str,
+                                     conn: C)
+                                     -> AsyncResult<'static, Option<UserAuthToken>, C> {
+    let confirmation = confirmation.to_string();
+    boxed(find_user_by_email(email, conn).and_then(move |(found, conn)| {
+        let user = match found {
+            Some(ube) => ube.user,
+            None => return ready(None, conn),
+        };
+        match (user.login_confirmation, user.login_confirmation_at) {
+            (Some(ref uc), Some(at)) if at + *CONFIRMATION_EXPIRY > UTC::now().naive_utc() &&
+                                        uc == &confirmation => {
+                boxed(create_auth_token(&user.id, conn).map(|(token, conn)| (Some(token), conn)))
+            }
+            _ => ready(None, conn),
+        }
+    }))
+}
+
+pub fn create_auth_token<C: Client>(user_id: &Uuid,
+                                    conn: C)
+                                    -> AsyncResult<'static, UserAuthToken, C> {
+    single(query_rows("
+        INSERT INTO user_auth_tokens
+        (
+            user_id
+        ) VALUES (
+            $1
+        ) RETURNING *",
+                      vec![param(*user_id)],
+                      |row| UserAuthToken::from_row(row, ""),
+                      conn),
+           "could not create user auth token")
+}
+
+pub fn authenticate<C: Client>(email: &str,
+                               token: &Uuid,
+                               conn: C)
+                               -> AsyncResult<'static, Option<UserByEmail>, C> {
+    let expired_before = UTC::now().naive_utc() - *TOKEN_EXPIRY;
+    first(select_rows((email.to_string(), *token, expired_before),
+                      |&(ref email, ref token, ref expired_before)| {
+                          users_with_emails()
+                              .join::<UserAuthToken>("uat", "uat.user_id = u.id", &[])
+                              .filter("ue.email = ?", &[email])
+                              .filter("uat.id = ?", &[token])
+                              .filter("uat.created_at > ?", &[expired_before])
+                              .limit(1)
+                      },
+                      read_user_by_email,
+                      conn))
+}
+
+pub fn find_game_version<C: Client>(id: &Uuid,
+                                    conn: C)
+                                    -> AsyncResult<'static, Option<GameVersion>, C> {
+    query_row("
+        SELECT *
+        FROM game_versions
+        WHERE id=$1
+        LIMIT 1",
+              vec![param(*id)],
+              |row| GameVersion::from_row(row, ""),
+              conn)
+}
+
+pub fn find_game_type<C: Client>(id: &Uuid, conn: C) -> AsyncResult<'static, Option<GameType>, C> {
+    query_row("
+        SELECT *
+        FROM game_types
+        WHERE id=$1
+        LIMIT 1",
+              vec![param(*id)],
+              |row| GameType::from_row(row, ""),
+              conn)
+}
+
+pub fn find_game_types<C: Client>(conn: C) -> AsyncResult<'static, Vec<GameType>, C> {
+    query_rows("
+        SELECT *
+        FROM game_types
+        ORDER BY name",
+               vec![],
+               |row| GameType::from_row(row, ""),
+               conn)
+}
+
+pub fn find_public_game_versions_by_game_type<C: Client>
+    (game_type_id: &Uuid,
+     conn: C)
+     -> AsyncResult<'static, Vec<GameVersion>, C> {
+    query_rows("
+        SELECT *
+        FROM game_versions
+        WHERE game_type_id=$1
+        AND is_public=TRUE
+        AND is_deprecated=FALSE
+        ORDER BY created_at DESC",
+               vec![param(*game_type_id)],
+               |row| GameVersion::from_row(row, ""),
+               conn)
+}
+
+pub fn find_latest_public_game_version<C: Client>
+    (game_type_id: &Uuid,
+     conn: C)
+     -> AsyncResult<'static, Option<GameVersion>, C> {
+    query_row("
+        SELECT *
+        FROM game_versions
+        WHERE game_type_id=$1
+        AND is_public=TRUE
+        AND is_deprecated=FALSE
+        ORDER BY created_at DESC
+        LIMIT 1",
+              vec![param(*game_type_id)],
+              |row| GameVersion::from_row(row, ""),
+              conn)
+}
+
+pub fn update_game_version_flags<C: Client>(id: &Uuid,
+                                            is_public: bool,
+                                            is_deprecated: bool,
+                                            conn: C)
+                                            -> AsyncResult<'static, Option<GameVersion>, C> {
+    query_row("
+        UPDATE game_versions
+        SET
+            is_public=$1,
+            is_deprecated=$2
+        WHERE id=$3
+        RETURNING *",
+              vec![param(is_public), param(is_deprecated), param(*id)],
+              |row| GameVersion::from_row(row, ""),
+              conn)
+}
+
+pub fn find_game<C: Client>(id: &Uuid, conn: C) -> AsyncResult<'static, Option<Game>, C> {
+    query_row("
+        SELECT *
+        FROM games
+        WHERE id=$1
+        LIMIT 1",
+              vec![param(*id)],
+              |row| Game::from_row(row, ""),
+              conn)
+}
+
+/// Finds which games have changed since they were last seen, given as `(id, revision)` pairs,
+/// like `query::find_games_changed_since`.
+pub fn find_games_changed_since<C: Client>(seen: &[(Uuid, i32)],
+                                           conn: C)
+                                           -> AsyncResult<'static, Vec<Game>, C> {
+    let ids: Vec<Uuid> = seen.iter().map(|&(id, _)| id).collect();
+    let revisions: Vec<i32> = seen.iter().map(|&(_, revision)| revision).collect();
+    query_rows("
+        SELECT g.*
+        FROM games g
+        INNER JOIN UNNEST($1::UUID[], $2::INT[]) AS seen(id, revision)
+        ON seen.id = g.id
+        WHERE g.revision > seen.revision",
+               vec![param(ids), param(revisions)],
+               |row| Game::from_row(row, ""),
+               conn)
+}
+
+pub fn create_game_with_users<'a, C: Client>(new_game: &'a NewGame,
+                                             whose_turn: &[usize],
+                                             eliminated: &[usize],
+                                             winners: &[usize],
+                                             creator_id: &Uuid,
+                                             opponent_ids: &[Uuid],
+                                             opponent_emails: &[String],
+                                             conn: C)
+                                             -> AsyncResult<'a, CreatedGame, C> {
+    let whose_turn = whose_turn.to_vec();
+    let eliminated = eliminated.to_vec();
+    let winners = winners.to_vec();
+    let creator_id = *creator_id;
+    let opponent_ids = opponent_ids.to_vec();
+    let opponent_emails = opponent_emails.to_vec();
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        // Check the player count is valid for the game version before creating anything.
+        let player_count = 1 + opponent_ids.len() + opponent_emails.len();
+        boxed(find_game_version(new_game.game_version_id, trans)
+                  .then(|result| found(result, "could not find game version"))
+                  .and_then(move |(game_version, trans)| {
+                                check_player_count(&game_version, player_count).map(|()| trans)
+                            })
+                  // Find or create users.
+                  .and_then(move |trans| {
+                                find_user(&creator_id, trans)
+                                    .then(|result| found(result, "could not find creator"))
+                            })
+                  .and_then(move |(creator, trans)| {
+                                create_game_users(&opponent_ids, &opponent_emails, trans)
+                                    .then(|result| {
+                                              result.chain_err(|| "could not create game users")
+                                          })
+                                    .map(move |(opponents, trans)| ((creator, opponents), trans))
+                            })
+                  .and_then(move |((creator, opponents), trans)| {
+                                create_game(new_game, trans)
+                                    .then(|result| result.chain_err(|| "could not create new game"))
+                                    .map(move |(game, trans)| ((creator, opponents, game), trans))
+                            })
+                  .and_then(move |((creator, opponents, game), trans)| {
+            let mut users: Vec<User> = opponents.iter().map(|o| o.user.clone()).collect();
+            users.push(creator);
+            create_players(game.id,
+                           users,
+                           creator_id,
+                           whose_turn,
+                           eliminated,
+                           winners,
+                           trans)
+                    .then(|result| result.chain_err(|| "could not create game players"))
+                    .map(move |(players, trans)| {
+                             (CreatedGame {
+                                  game: game,
+                                  opponents: opponents,
+                                  players: players,
+                              },
+                              trans)
+                         })
+        })
+                  .and_then(move |(created, trans)| {
+            // Tell the other players about the game, in the transaction so they're only told if
+            // it's created.
+            let game_id = created.game.id;
+            let opponent_ids: Vec<Uuid> = created.opponents.iter().map(|o| o.user.id).collect();
+            let turn_ids: Vec<Uuid> = created
+                .players
+                .iter()
+                .filter(|p| p.is_turn && p.user_id != creator_id)
+                .map(|p| p.user_id)
+                .collect();
+            enqueue_game_messages(OutboxMessageKind::GameInvite, &game_id, &opponent_ids, trans)
+                .and_then(move |((), trans)| {
+                              enqueue_game_messages(OutboxMessageKind::YourTurn,
+                                                    &game_id,
+                                                    &turn_ids,
+                                                    trans)
+                          })
+                .map(move |((), trans)| (created, trans))
+        }))
+    })
+}
+
+/// Creates players for users with `create_players_for_users` in one statement, returning them in
+/// the same order.
+fn create_players<C: Client>(game_id: Uuid,
+                             users: Vec<User>,
+                             creator_id: Uuid,
+                             whose_turn: Vec<usize>,
+                             eliminated: Vec<usize>,
+                             winners: Vec<usize>,
+                             conn: C)
+                             -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    let ids: Vec<Uuid> = users.iter().map(|_| Uuid::new_v4()).collect();
+    let order = ids.clone();
+    boxed(prepared(&NewGamePlayer::insert_many_with_ids_sql(users.len()),
+                   move |statement, conn| {
+                       create_players_for_users(&game_id,
+                                                users,
+                                                &creator_id,
+                                                &whose_turn,
+                                                &eliminated,
+                                                &winners,
+                                                |players| {
+                                                    conn.query_prepared(statement,
+                                                                        &with_ids(players, &ids))
+                                                })
+                   },
+                   |row| GamePlayer::from_row(row, ""),
+                   conn)
+                  .and_then(move |(created, conn)| {
+                                in_order(created, &order, |p| p.id).map(|players| (players, conn))
+                            }))
+}
+
+/// Creates a rematch of a finished game with the same players, like `query::create_rematch`.
+/// Returns `None` if the game isn't finished or `requested_by` didn't play in it.
+pub fn create_rematch<C: Client>(game_id: &Uuid,
+                                 requested_by: &Uuid,
+                                 conn: C)
+                                 -> AsyncResult<'static, Option<CreatedRematch>, C> {
+    let game_id = *game_id;
+    let requested_by = *requested_by;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(find_game(&game_id, trans).and_then(move |(game, trans)| {
+            let game = match game {
+                Some(g) => g,
+                None => return ready(None, trans),
+            };
+            if !game.is_finished {
+                return ready(None, trans);
+            }
+            boxed(find_game_player_by_user(&game_id, &requested_by, trans)
+                      .and_then(move |(player, trans)| {
+                if player.is_none() {
+                    return ready(None, trans);
+                }
+                boxed(find_rematch(&game_id, trans).and_then(move |(rematch, trans)| {
+                    match rematch {
+                        Some(rematch) => {
+                            let rematch_id = rematch.id;
+                            boxed(find_game_players_by_game(&rematch_id, trans).map(|(players,
+                                                                                     trans)| {
+                                (Some(CreatedRematch {
+                                          game: rematch,
+                                          players: players,
+                                      }),
+                                 trans)
+                            }))
+                        }
+                        None => {
+                            boxed(create_new_rematch(game, requested_by, trans)
+                                      .map(|(created, trans)| (Some(created), trans)))
+                        }
+                    }
+                }))
+            }))
+        }))
+    })
+}
+
+/// Creates the rematch for `create_rematch` once it has checked there isn't one already.
+fn create_new_rematch<C: Client>(game: Game,
+                                 requested_by: Uuid,
+                                 conn: C)
+                                 -> AsyncResult<'static, CreatedRematch, C> {
+    let game_id = game.id;
+    boxed(find_game_version(&game.game_version_id, conn)
+              .then(|result| found(result, "could not find game version"))
+              .and_then(|(game_version, conn)| {
+                            find_latest_public_game_version(&game_version.game_type_id, conn)
+                                .then(|result| {
+                                          found(result, "could not find a public game version")
+                                      })
+                        })
+              .and_then(move |(game_version, conn)| {
+                            find_users_by_game(&game_id, conn)
+                                .map(move |(users, conn)| ((game_version, users), conn))
+                        })
+              .and_then(move |((game_version, users), conn)| {
+        if let Err(e) = check_player_count(&game_version, users.len()) {
+            return failed(e);
+        }
+        boxed(single(prepared(&NewGame::insert_sql(),
+                              move |statement, conn| {
+                                  conn.query_prepared(statement,
+                                                      &NewGame {
+                                                               game_version_id: &game_version.id,
+                                                               is_finished: false,
+                                                               game_state: "",
+                                                               parent_game_id: Some(&game_id),
+                                                           }
+                                                           .params())
+                              },
+                              |row| Game::from_row(row, ""),
+                              conn),
+                     "error creating game")
+                      .then(|result| result.chain_err(|| "could not create rematch"))
+                      .map(move |(rematch, conn)| ((rematch, users), conn)))
+    })
+              .and_then(move |((rematch, users), conn)| {
+                            create_players(rematch.id,
+                                           users,
+                                           requested_by,
+                                           vec![],
+                                           vec![],
+                                           vec![],
+                                           conn)
+                                    .then(|result| {
+                                              let msg = "could not create rematch players";
+                                              result.chain_err(|| msg)
+                                          })
+                                    .map(move |(players, conn)| ((rematch, players), conn))
+                        })
+              .and_then(move |((rematch, players), conn)| {
+                            let rematch_id = rematch.id;
+                            let invited: Vec<Uuid> = players
+                                .iter()
+                                .filter(|p| p.user_id != requested_by)
+                                .map(|p| p.user_id)
+                                .collect();
+                            enqueue_game_messages(OutboxMessageKind::GameInvite,
+                                                  &rematch_id,
+                                                  &invited,
+                                                  conn)
+                                    .map(move |((), conn)| {
+                                             (CreatedRematch {
+                                                  game: rematch,
+                                                  players: players,
+                                              },
+                                              conn)
+                                         })
+                        }))
+}
+
+pub fn find_rematch<C: Client>(game_id: &Uuid, conn: C) -> AsyncResult<'static, Option<Game>, C> {
+    query_row("
+        SELECT *
+        FROM games
+        WHERE parent_game_id=$1",
+              vec![param(*game_id)],
+              |row| Game::from_row(row, ""),
+              conn)
+}
+
+/// Accepts the rematch of a game on behalf of one of its players. Returns `None` if the game
+/// hasn't been rematched or the user isn't playing in the rematch.
+pub fn accept_rematch<C: Client>(game_id: &Uuid,
+                                 user_id: &Uuid,
+                                 conn: C)
+                                 -> AsyncResult<'static, Option<GamePlayer>, C> {
+    let game_id = *game_id;
+    let user_id = *user_id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(find_rematch(&game_id, trans).and_then(move |(rematch, trans)| {
+            let rematch_id = match rematch {
+                Some(r) => r.id,
+                None => return ready(None, trans),
+            };
+            boxed(find_game_player_by_user(&rematch_id, &user_id, trans)
+                      .and_then(move |(player, trans)| {
+                let player = match player {
+                    Some(p) => p,
+                    None => return ready(None, trans),
+                };
+                if player.has_accepted {
+                    return ready(Some(player), trans);
+                }
+                boxed(execute("
+                    UPDATE games
+                    SET revision=revision+1
+                    WHERE id=$1",
+                              vec![param(rematch_id)],
+                              trans)
+                              .and_then(move |(_, trans)| {
+                                            query_row("
+                        UPDATE game_players
+                        SET has_accepted=TRUE
+                        WHERE id=$1
+                        RETURNING *",
+                                                      vec![param(player.id)],
+                                                      |row| GamePlayer::from_row(row, ""),
+                                                      trans)
+                                        }))
+            }))
+        }))
+    })
+}
+
+fn find_users_by_game<C: Client>(game_id: &Uuid, conn: C) -> AsyncResult<'static, Vec<User>, C> {
+    select_rows(*game_id,
+                |game_id| {
+                    Select::from::<User>("u")
+                        .cols::<User>("u", "")
+                        .join::<GamePlayer>("gp", "gp.user_id = u.id", &[])
+                        .filter("gp.game_id = ?", &[game_id])
+                        .order_by("gp.position")
+                },
+                |row| User::from_row(row, ""),
+                conn)
+}
+
+pub fn update_game_and_players<'a, C: Client>(game_id: &Uuid,
+                                              update: &'a NewGame,
+                                              whose_turn: &[usize],
+                                              eliminated: &[usize],
+                                              winners: &[usize],
+                                              conn: C)
+                                              -> AsyncResult<'a, UpdatedGame, C> {
+    let game_id = *game_id;
+    let eliminated = eliminated.to_vec();
+    let winners = winners.to_vec();
+    let whose_turn = whose_turn.to_vec();
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        // The players are updated first as they bump the revision of the game returned.
+        boxed(update_game_whose_turn(&game_id, &whose_turn, trans)
+                  .and_then(move |(whose_turn, trans)| {
+                                update_game_eliminated(&game_id, &eliminated, trans)
+                                    .map(move |(eliminated, trans)| {
+                                             ((whose_turn, eliminated), trans)
+                                         })
+                            })
+                  .and_then(move |((whose_turn, eliminated), trans)| {
+                                update_game_winners(&game_id, &winners, trans)
+                                    .map(move |(winners, trans)| {
+                                             ((whose_turn, eliminated, winners), trans)
+                                         })
+                            })
+                  .and_then(move |((whose_turn, eliminated, winners), trans)| {
+                                update_game(&game_id, update, trans).map(move |(game, trans)| {
+                    (UpdatedGame {
+                         game: game,
+                         whose_turn: whose_turn,
+                         eliminated: eliminated,
+                         winners: winners,
+                     },
+                     trans)
+                })
+                            }))
+    })
+}
+
+fn lock_unfinished_game<C: Client>(id: &Uuid, conn: C) -> AsyncResult<'static, Option<Game>, C> {
+    query_row("
+        SELECT *
+        FROM games
+        WHERE id=$1
+        AND is_finished=FALSE
+        FOR UPDATE",
+              vec![param(*id)],
+              |row| Game::from_row(row, ""),
+              conn)
+}
+
+fn create_public_game_log<C: Client>(game_id: Uuid,
+                                     body: String,
+                                     conn: C)
+                                     -> AsyncResult<'static, CreatedGameLog, C> {
+    let logged_at = UTC::now().naive_utc();
+    boxed(single(prepared(&NewGameLog::insert_sql(),
+                          move |statement, conn| {
+                              conn.query_prepared(statement,
+                                                  &NewGameLog {
+                                                           game_id: &game_id,
+                                                           body: &body,
+                                                           is_public: true,
+                                                           logged_at: &logged_at,
+                                                       }
+                                                       .params())
+                          },
+                          |row| GameLog::from_row(row, ""),
+                          conn),
+                 "error creating game log")
+                  .map(|(game_log, conn)| {
+                           (CreatedGameLog {
+                                game_log: game_log,
+                                targets: vec![],
+                            },
+                            conn)
+                       }))
+}
+
+/// Eliminates a player outside of the game's rules, like `query::eliminate_game_player`. This
+/// should be run in a transaction which has locked the game's players.
+pub fn eliminate_game_player<C: Client>(game_id: &Uuid,
+                                        player_id: &Uuid,
+                                        reason: EliminationReason,
+                                        conn: C)
+                                        -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    let game_id = *game_id;
+    let player_id = *player_id;
+    boxed(find_game_players_by_game(&game_id, conn).and_then(move |(players, conn)| {
+        let player = match players.iter().find(|p| p.id == player_id) {
+            Some(p) => p.clone(),
+            None => return failed("could not find game player".into()),
+        };
+        let remaining: Vec<usize> = players
+            .iter()
+            .filter(|p| !p.is_eliminated && p.id != player_id)
+            .map(|p| p.position as usize)
+            .collect();
+        let eliminated: Vec<usize> = players
+            .iter()
+            .filter(|p| p.is_eliminated || p.id == player_id)
+            .map(|p| p.position as usize)
+            .collect();
+        let log = match reason {
+            EliminationReason::Conceded => format!("{{{{player {}}}}} conceded", player.position),
+            EliminationReason::TimedOut => {
+                format!("{{{{player {}}}}} ran out of time and was eliminated",
+                        player.position)
+            }
+        };
+        boxed(update_game_eliminated(&game_id, &eliminated, conn)
+                  .and_then(move |(_, conn)| {
+                                execute("
+                    UPDATE game_players
+                    SET eliminated_reason=$2
+                    WHERE id=$1",
+                                        vec![param(player_id), param(reason)],
+                                        conn)
+                            })
+                  .and_then(move |(_, conn)| create_public_game_log(game_id, log, conn))
+                  .and_then(move |(_, conn)| {
+                                if remaining.len() == 1 {
+                                    return finish_game(game_id, remaining, conn);
+                                }
+                                if player.is_turn {
+                                    return update_game_whose_turn(&game_id,
+                                                                  &turns::next_turn(&players,
+                                                                                    &player),
+                                                                  conn);
+                                }
+                                find_game_players_by_game(&game_id, conn)
+                            }))
+    }))
+}
+
+/// Finds the players of a game by position and locks them, like `query::lock_game_players`.
+pub fn lock_game_players<C: Client>(game_id: &Uuid,
+                                    conn: C)
+                                    -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    query_rows("
+        SELECT *
+        FROM game_players
+        WHERE game_id=$1
+        ORDER BY position
+        FOR UPDATE",
+               vec![param(*game_id)],
+               |row| GamePlayer::from_row(row, ""),
+               conn)
+}
+
+/// Finishes a game outside of the game's rules with the players at `winners` winning, and rates
+/// it.
+fn finish_game<C: Client>(game_id: Uuid,
+                          winners: Vec<usize>,
+                          conn: C)
+                          -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    boxed(update_game_whose_turn(&game_id, &[], conn)
+              .and_then(move |(_, conn)| update_game_winners(&game_id, &winners, conn))
+              .and_then(move |(players, conn)| {
+                            execute("
+                UPDATE games
+                SET
+                    is_finished=TRUE,
+                    revision=revision+1
+                WHERE id=$1",
+                                    vec![param(game_id)],
+                                    conn)
+                                    .map(move |(_, conn)| (players, conn))
+                        })
+              .and_then(move |(players, conn)| {
+                            rate_game(game_id, conn)
+                                .then(|result| result.chain_err(|| "could not rate game"))
+                                .map(move |(_, conn)| (players, conn))
+                        }))
+}
+
+/// Rates a finished game like `ratings::rate_game`, in a transaction of its own or a savepoint.
+fn rate_game<C: Client>(game_id: Uuid, conn: C) -> AsyncResult<'static, Vec<RatingChange>, C> {
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(query_row("
+            SELECT *
+            FROM games
+            WHERE id=$1
+            FOR UPDATE",
+                        vec![param(game_id)],
+                        |row| Game::from_row(row, ""),
+                        trans)
+                      .then(|result| found(result, "could not find game"))
+                      .and_then(move |(game, trans)| {
+            if !game.is_finished || game.abandoned_at.is_some() {
+                return ready(vec![], trans);
+            }
+            boxed(query_rows("SELECT 1 AS rated FROM rating_changes WHERE game_id=$1 LIMIT 1",
+                             vec![param(game_id)],
+                             |_| (),
+                             trans)
+                          .and_then(move |(rated, trans)| {
+                if !rated.is_empty() {
+                    return ready(vec![], trans);
+                }
+                boxed(find_game_version(&game.game_version_id, trans)
+                          .then(|result| found(result, "could not find game version"))
+                          .and_then(move |(game_version, trans)| {
+                                        let game_type_id = game_version.game_type_id;
+                                        find_game_players_by_game(&game_id, trans)
+                                            .map(move |(players, trans)| {
+                                                     ((game_type_id, players), trans)
+                                                 })
+                                    })
+                          .and_then(move |((game_type_id, mut players), trans)| {
+                    if players.len() < 2 {
+                        return ready(vec![], trans);
+                    }
+                    players.sort_by_key(|p| p.position);
+                    let user_ids: Vec<Uuid> = players.iter().map(|p| p.user_id).collect();
+                    boxed(lock_ratings(game_type_id, user_ids, trans)
+                              .and_then(move |(ratings, trans)| {
+                        let results: Vec<(f64, (bool, Option<i32>, bool, bool))> = players
+                            .iter()
+                            .map(|p| (ratings[&p.user_id].rating, ratings::placing(p)))
+                            .collect();
+                        let updates: Vec<(Uuid, f64, f64)> = players
+                            .iter()
+                            .zip(ratings::elo(&results))
+                            .map(|(p, new_rating)| {
+                                     let rating = &ratings[&p.user_id];
+                                     (rating.id, rating.rating, new_rating)
+                                 })
+                            .collect();
+                        fold(updates, vec![], move |mut changes: Vec<RatingChange>,
+                              (rating_id, before, after),
+                              trans| {
+                            boxed(execute("
+                                UPDATE ratings
+                                SET
+                                    rating=$2,
+                                    games_played=games_played+1
+                                WHERE id=$1",
+                                          vec![param(rating_id), param(after)],
+                                          trans)
+                                          .and_then(move |(_, trans)| {
+                                single(prepared(&NewRatingChange::insert_sql(),
+                                                move |statement, trans| {
+                                                    trans.query_prepared(statement,
+                                                                         &NewRatingChange {
+                                                                              rating_id: &rating_id,
+                                                                              game_id: &game_id,
+                                                                              rating_before: before,
+                                                                              rating_after: after,
+                                                                          }
+                                                                          .params())
+                                                },
+                                                |row| RatingChange::from_row(row, ""),
+                                                trans),
+                                       "error creating rating change")
+                            })
+                                          .map(move |(change, trans)| {
+                                                   changes.push(change);
+                                                   (changes, trans)
+                                               }))
+                        },
+                             trans)
+                    }))
+                }))
+            }))
+        }))
+    })
+}
+
+/// Finds and locks the ratings of users for a game type, creating the ratings which don't exist
+/// yet, like `ratings::rate_game` does.
+fn lock_ratings<C: Client>(game_type_id: Uuid,
+                           user_ids: Vec<Uuid>,
+                           conn: C)
+                           -> AsyncResult<'static, HashMap<Uuid, Rating>, C> {
+    boxed(execute("
+        INSERT INTO ratings (user_id, game_type_id, rating)
+        SELECT user_id, $1, $3
+        FROM unnest($2::uuid[]) AS u (user_id)
+        ON CONFLICT (user_id, game_type_id) DO NOTHING",
+                  vec![param(game_type_id),
+                       param(user_ids.clone()),
+                       param(ratings::DEFAULT_RATING)],
+                  conn)
+                  .and_then(move |(_, conn)| {
+                                query_rows("
+            SELECT *
+            FROM ratings
+            WHERE game_type_id=$1
+            AND user_id = ANY($2)
+            ORDER BY user_id
+            FOR UPDATE",
+                                           vec![param(game_type_id), param(user_ids)],
+                                           |row| Rating::from_row(row, ""),
+                                           conn)
+                            })
+                  .map(|(ratings, conn)| {
+                           (ratings.into_iter().map(|r| (r.user_id, r)).collect(), conn)
+                       }))
+}
+
+/// Concedes a game on behalf of a player, eliminating them with `eliminate_game_player`. Returns
+/// `None` if the game is finished or the player has already been eliminated.
+pub fn concede_game<C: Client>(game_id: &Uuid,
+                               user_id: &Uuid,
+                               conn: C)
+                               -> AsyncResult<'static, Option<Vec<GamePlayer>>, C> {
+    let game_id = *game_id;
+    let user_id = *user_id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(lock_unfinished_game(&game_id, trans).and_then(move |(game, trans)| {
+            if game.is_none() {
+                return ready(None, trans);
+            }
+            boxed(lock_game_players(&game_id, trans).and_then(move |(players, trans)| {
+                let player_id = match players.iter().find(|p| p.user_id == user_id) {
+                    Some(p) if !p.is_eliminated => p.id,
+                    _ => return ready(None, trans),
+                };
+                boxed(eliminate_game_player(&game_id,
+                                            &player_id,
+                                            EliminationReason::Conceded,
+                                            trans)
+                              .map(|(players, trans)| (Some(players), trans)))
+            }))
+        }))
+    })
+}
+
+/// Ends a game which can't continue, without any winners. Returns `None` if the game is already
+/// finished.
+pub fn abandon_game<C: Client>(game_id: &Uuid,
+                               reason: &str,
+                               conn: C)
+                               -> AsyncResult<'static, Option<Game>, C> {
+    let game_id = *game_id;
+    let reason = reason.to_string();
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(lock_unfinished_game(&game_id, trans).and_then(move |(game, trans)| {
+            if game.is_none() {
+                return ready(None, trans);
+            }
+            // Players are cleared directly rather than through the update functions, so
+            // abandoning only bumps the revision once.
+            boxed(execute("
+                UPDATE game_players
+                SET
+                    is_turn=FALSE,
+                    is_winner=FALSE,
+                    place=NULL,
+                    score=NULL
+                WHERE game_id=$1",
+                          vec![param(game_id)],
+                          trans)
+                          .and_then(move |(_, trans)| {
+                                        let log = format!("The game was abandoned: {}", reason);
+                                        query_row("
+                    UPDATE games
+                    SET
+                        is_finished=TRUE,
+                        abandoned_at=(now() AT TIME ZONE 'utc'),
+                        abandon_reason=$2,
+                        revision=revision+1
+                    WHERE id=$1
+                    RETURNING *",
+                                                  vec![param(game_id), param(reason)],
+                                                  |row| Game::from_row(row, ""),
+                                                  trans)
+                                                .map(move |(game, trans)| ((game, log), trans))
+                                    })
+                          .and_then(move |((game, log), trans)| {
+                                        create_public_game_log(game_id, log, trans)
+                                            .map(move |(_, trans)| (game, trans))
+                                    }))
+        }))
+    })
+}
+
+/// Updates a game, rating it if it is finished, like `query::update_game`.
+pub fn update_game<'a, C: Client>(id: &Uuid,
+                                  update: &'a NewGame,
+                                  conn: C)
+                                  -> AsyncResult<'a, Option<Game>, C> {
+    let id = *id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(first(prepared(&NewGame::update_and_set_sql(&["revision = revision + 1"]),
+                             move |statement, trans| {
+                                 let mut params = update.params();
+                                 params.push(&id);
+                                 trans.query_prepared(statement, &params)
+                             },
+                             |row| Game::from_row(row, ""),
+                             trans))
+                      .and_then(move |(game, trans)| {
+                                    if game.is_none() || !update.is_finished {
+                                        return ready(game, trans);
+                                    }
+                                    boxed(rate_game(id, trans)
+                                              .then(|result| {
+                                                        result.chain_err(|| "could not rate game")
+                                                    })
+                                              .map(move |(_, trans)| (game, trans)))
+                                }))
+    })
+}
+
+fn positions_param(positions: &[usize]) -> Vec<i32> {
+    positions.iter().map(|p| *p as i32).collect()
+}
+
+/// Bumps the revision of a game if any of its players match `changed`, a condition on a player
+/// which can refer to `positions` as `$2`, like `query::bump_revision_for_players`.
+fn bump_revision_for_players<C: Client>(id: Uuid,
+                                        changed: &str,
+                                        positions: Vec<i32>,
+                                        conn: C)
+                                        -> AsyncResult<'static, (), C> {
+    boxed(execute(&format!("
+        UPDATE games
+        SET revision=revision+1
+        WHERE id=$1
+        AND EXISTS (
+            SELECT 1
+            FROM game_players
+            WHERE game_id=$1
+            AND ({})
+        )",
+                           changed),
+                  vec![param(id), param(positions)],
+                  conn)
+                  .map(|(_, conn)| ((), conn)))
+}
+
+/// Queues a message of `kind` about a game for each user, sent to their primary email.
+fn enqueue_game_messages<C: Client>(kind: OutboxMessageKind,
+                                    game_id: &Uuid,
+                                    user_ids: &[Uuid],
+                                    conn: C)
+                                    -> AsyncResult<'static, (), C> {
+    let game_id = *game_id;
+    boxed(find_users_with_primary_email(user_ids, conn).and_then(move |(users, conn)| {
+        fold(users,
+             (),
+             move |(), user: UserByEmail, conn| {
+                 let payload = json!({"game_id": game_id.to_string()});
+                 boxed(enqueue(kind, user.user_email.email, payload, conn)
+                           .map(|(_, conn)| ((), conn)))
+             },
+             conn)
+    }))
+}
+
+/// Gives the turn to the players at `positions`, queueing a message for each player whose turn it
+/// has become. Run this in the transaction changing the game so messages are only sent if the
+/// change is committed.
+pub fn update_game_whose_turn<C: Client>(id: &Uuid,
+                                         positions: &[usize],
+                                         conn: C)
+                                         -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    let id = *id;
+    let positions = positions_param(positions);
+    boxed(query_rows("
+        SELECT user_id
+        FROM game_players
+        WHERE game_id=$1
+        AND is_turn=FALSE
+        AND position = ANY($2)",
+                     vec![param(id), param(positions.clone())],
+                     |row| row.get_col::<Uuid>("user_id"),
+                     conn)
+                  .and_then(move |(starting, conn)| {
+        bump_revision_for_players(id,
+                                  "is_turn IS DISTINCT FROM (position = ANY($2))",
+                                  positions.clone(),
+                                  conn)
+                .and_then(move |((), conn)| {
+                              query_rows("
+                UPDATE game_players
+                SET is_turn=(position = ANY($2))
+                WHERE game_id=$1
+                RETURNING *",
+                                         vec![param(id), param(positions)],
+                                         |row| GamePlayer::from_row(row, ""),
+                                         conn)
+                          })
+                .and_then(move |(players, conn)| {
+                              enqueue_game_messages(OutboxMessageKind::YourTurn,
+                                                    &id,
+                                                    &starting,
+                                                    conn)
+                                      .map(move |((), conn)| (players, conn))
+                          })
+    }))
+}
+
+pub fn update_game_eliminated<C: Client>(id: &Uuid,
+                                         positions: &[usize],
+                                         conn: C)
+                                         -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    let id = *id;
+    let positions = positions_param(positions);
+    boxed(bump_revision_for_players(id,
+                                    "is_eliminated IS DISTINCT FROM (position = ANY($2))",
+                                    positions.clone(),
+                                    conn)
+                  .and_then(move |((), conn)| {
+                                query_rows("
+            UPDATE game_players
+            SET
+                is_eliminated=(position = ANY($2)),
+                eliminated_reason=CASE WHEN position = ANY($2) THEN eliminated_reason END
+            WHERE game_id=$1
+            RETURNING *",
+                                           vec![param(id), param(positions)],
+                                           |row| GamePlayer::from_row(row, ""),
+                                           conn)
+                            }))
+}
+
+/// Sets which players won, clearing any places and scores as they may not agree with the winners.
+/// Use `update_game_placings` to set winners along with places.
+pub fn update_game_winners<C: Client>(id: &Uuid,
+                                      positions: &[usize],
+                                      conn: C)
+                                      -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    let id = *id;
+    let positions = positions_param(positions);
+    boxed(bump_revision_for_players(id,
+                                    "is_winner IS DISTINCT FROM (position = ANY($2)) \
+                                     OR place IS NOT NULL OR score IS NOT NULL",
+                                    positions.clone(),
+                                    conn)
+                  .and_then(move |((), conn)| {
+                                query_rows("
+            UPDATE game_players
+            SET
+                is_winner=(position = ANY($2)),
+                place=NULL,
+                score=NULL
+            WHERE game_id=$1
+            RETURNING *",
+                                           vec![param(id), param(positions)],
+                                           |row| GamePlayer::from_row(row, ""),
+                                           conn)
+                            }))
+}
+
+/// Sets the final place and score of players, clearing them for players without a placing, like
+/// `query::update_game_placings`.
+pub fn update_game_placings<C: Client>(id: &Uuid,
+                                       placings: &[Placing],
+                                       conn: C)
+                                       -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    let id = *id;
+    let placings = placings.to_vec();
+    boxed(find_game_players_by_game(&id, conn).and_then(move |(current, conn)| {
+        if let Err(e) = check_placings(&placings, &current) {
+            return failed(e);
+        }
+        let changed = current
+            .iter()
+            .any(|p| {
+                     let placing = placings
+                         .iter()
+                         .find(|pl| pl.position == p.position as usize);
+                     let place = placing.map(|pl| pl.place as i32);
+                     p.place != place || p.score != placing.and_then(|pl| pl.score) ||
+                     p.is_winner != (place == Some(1))
+                 });
+        let bumped = if changed {
+            execute("
+                UPDATE games
+                SET revision=revision+1
+                WHERE id=$1",
+                    vec![param(id)],
+                    conn)
+        } else {
+            ready(0, conn)
+        };
+        let positions: Vec<i32> = placings.iter().map(|p| p.position as i32).collect();
+        let places: Vec<i32> = placings.iter().map(|p| p.place as i32).collect();
+        let scores: Vec<Option<i32>> = placings.iter().map(|p| p.score).collect();
+        boxed(bumped.and_then(move |(_, conn)| {
+                                  query_rows("
+                UPDATE game_players gp
+                SET
+                    place=pl.place,
+                    score=pl.score,
+                    is_winner=COALESCE(pl.place = 1, FALSE)
+                FROM game_players cur
+                LEFT JOIN unnest($2::int[], $3::int[], $4::int[]) AS pl (position, place, score)
+                ON pl.position = cur.position
+                WHERE cur.id = gp.id
+                AND gp.game_id=$1
+                RETURNING gp.*",
+                                             vec![param(id),
+                                                  param(positions),
+                                                  param(places),
+                                                  param(scores)],
+                                             |row| GamePlayer::from_row(row, ""),
+                                             conn)
+                              }))
+    }))
+}
+
+/// Like `update_game_and_players`, but sets the players' final placings instead of just the
+/// winners, so a game finished by this is rated on its places.
+pub fn update_game_and_players_with_placings<'a, C: Client>(game_id: &Uuid,
+                                                            update: &'a NewGame,
+                                                            whose_turn: &[usize],
+                                                            eliminated: &[usize],
+                                                            placings: &[Placing],
+                                                            conn: C)
+                                                            -> AsyncResult<'a, UpdatedGame, C> {
+    let game_id = *game_id;
+    let whose_turn = whose_turn.to_vec();
+    let eliminated = eliminated.to_vec();
+    let placings = placings.to_vec();
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        // The game is updated last so it is rated on the new placings if this finishes it.
+        boxed(update_game_whose_turn(&game_id, &whose_turn, trans)
+                  .and_then(move |(whose_turn, trans)| {
+                                update_game_eliminated(&game_id, &eliminated, trans)
+                                    .map(move |(eliminated, trans)| {
+                                             ((whose_turn, eliminated), trans)
+                                         })
+                            })
+                  .and_then(move |((whose_turn, eliminated), trans)| {
+                                update_game_placings(&game_id, &placings, trans)
+                                    .map(move |(winners, trans)| {
+                                             ((whose_turn, eliminated, winners), trans)
+                                         })
+                            })
+                  .and_then(move |((whose_turn, eliminated, winners), trans)| {
+                                update_game(&game_id, update, trans).map(move |(game, trans)| {
+                    (UpdatedGame {
+                         game: game,
+                         whose_turn: whose_turn,
+                         eliminated: eliminated,
+                         winners: winners,
+                     },
+                     trans)
+                })
+                            }))
+    })
+}
+
+/// Finds the players of a game in order of their final place, then score. Players without a place
+/// are last.
+pub fn find_game_standings<C: Client>(game_id: &Uuid,
+                                      conn: C)
+                                      -> AsyncResult<'static, Vec<Standing>, C> {
+    select_rows(*game_id,
+                |game_id| {
+                    Select::from::<GamePlayer>("gp")
+                        .cols::<GamePlayer>("gp", "gp_")
+                        .cols::<User>("u", "u_")
+                        .join::<User>("u", "u.id = gp.user_id", &[])
+                        .filter("gp.game_id = ?", &[game_id])
+                        .order_by("gp.place NULLS LAST")
+                        .order_by("gp.score DESC NULLS LAST")
+                        .order_by("gp.position")
+                },
+                |row| {
+                    Standing {
+                        player: GamePlayer::from_row(row, "gp_"),
+                        user: User::from_row(row, "u_"),
+                    }
+                },
+                conn)
+}
+
+pub fn create_game_logs_from_cli<C: Client>(game_id: &Uuid,
+                                            logs: Vec<CliLog>,
+                                            conn: C)
+                                            -> AsyncResult<'static, Vec<CreatedGameLog>, C> {
+    let game_id = *game_id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(find_game_players_by_game(&game_id, trans).and_then(move |(players, trans)| {
+            let player_id_by_position: HashMap<usize, Uuid> = players
+                .iter()
+                .map(|p| (p.position as usize, p.id))
+                .collect();
+            let mut to: Vec<Vec<Uuid>> = vec![];
+            for l in &logs {
+                let mut player_to: Vec<Uuid> = vec![];
+                for t in &l.to {
+                    match player_id_by_position.get(t) {
+                        Some(id) => player_to.push(*id),
+                        None => return failed("no player with that position exists".into()),
+                    }
+                }
+                to.push(player_to);
+            }
+            insert_game_logs(to,
+                             move |range, ids, statement, trans| {
+                let new_logs: Vec<NewGameLog> = logs[range]
+                    .iter()
+                    .map(|l| {
+                             NewGameLog {
+                                 game_id: &game_id,
+                                 body: &l.content,
+                                 is_public: l.public,
+                                 logged_at: &l.at,
+                             }
+                         })
+                    .collect();
+                trans.query_prepared(statement, &with_ids(&new_logs, ids))
+            },
+                             trans)
+        }))
+    })
+}
+
+pub fn find_game_players_by_game<C: Client>(game_id: &Uuid,
+                                            conn: C)
+                                            -> AsyncResult<'static, Vec<GamePlayer>, C> {
+    query_rows("
+        SELECT *
+        FROM game_players
+        WHERE game_id=$1",
+               vec![param(*game_id)],
+               |row| GamePlayer::from_row(row, ""),
+               conn)
+}
+
+pub fn find_game_player_by_user<C: Client>(game_id: &Uuid,
+                                           user_id: &Uuid,
+                                           conn: C)
+                                           -> AsyncResult<'static, Option<GamePlayer>, C> {
+    query_row("
+        SELECT *
+        FROM game_players
+        WHERE game_id=$1
+        AND user_id=$2
+        LIMIT 1",
+              vec![param(*game_id), param(*user_id)],
+              |row| GamePlayer::from_row(row, ""),
+              conn)
+}
+
+/// Creates logs along with the players each is targeted to, using one statement for the logs and
+/// one for the targets.
+pub fn create_game_logs<'a, C: Client>(logs: &'a [(NewGameLog, Vec<Uuid>)],
+                                       conn: C)
+                                       -> AsyncResult<'a, Vec<CreatedGameLog>, C> {
+    insert_game_logs(logs.iter().map(|&(_, ref to)| to.clone()).collect(),
+                     move |range, ids, statement, trans| {
+                         trans.query_prepared(statement,
+                                              &with_ids(logs[range].iter().map(|&(ref l, _)| l),
+                                                        ids))
+                     },
+                     conn)
+}
+
+/// Creates logs bound by `bind` along with the players each is targeted to, given by `to`, in a
+/// transaction.
+fn insert_game_logs<'a, C, B>(to: Vec<Vec<Uuid>>,
+                              bind: B,
+                              conn: C)
+                              -> AsyncResult<'a, Vec<CreatedGameLog>, C>
+    where C: Client,
+          B: Fn(Range<usize>, &[Uuid], &Statement, C::Transaction)
+             -> AsyncResult<'static, Vec<Row>, C::Transaction> + 'a
+{
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(insert_all_with_ids::<NewGameLog, _, _, _, _, _>(to.len(),
+                                                               bind,
+                                                               |row| GameLog::from_row(row, ""),
+                                                               |gl| gl.id,
+                                                               trans)
+                      .and_then(move |(game_logs, trans)| {
+            let targets: Vec<(Uuid, Uuid)> = game_logs
+                .iter()
+                .zip(&to)
+                .flat_map(|(gl, to)| to.iter().map(move |player_id| (gl.id, *player_id)))
+                .collect();
+            insert_game_log_targets(targets, trans).map(move |(targets, trans)| {
+                let mut targets_by_log: HashMap<Uuid, Vec<GameLogTarget>> = HashMap::new();
+                for t in targets {
+                    targets_by_log
+                        .entry(t.game_log_id)
+                        .or_insert_with(Vec::new)
+                        .push(t);
+                }
+                let created = game_logs
+                    .into_iter()
+                    .map(|gl| {
+                             CreatedGameLog {
+                                 targets: targets_by_log.remove(&gl.id).unwrap_or_default(),
+                                 game_log: gl,
+                             }
+                         })
+                    .collect();
+                (created, trans)
+            })
+        }))
+    })
+}
+
+pub fn create_game_log<'a, C: Client>(log: &'a NewGameLog,
+                                      to: &[Uuid],
+                                      conn: C)
+                                      -> AsyncResult<'a, CreatedGameLog, C> {
+    let to = to.to_vec();
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(insert(log,
+                     |row| GameLog::from_row(row, ""),
+                     "error creating game log",
+                     trans)
+                      .and_then(move |(game_log, trans)| {
+                                    create_game_log_targets(&game_log.id, &to, trans)
+                                        .map(move |(targets, trans)| {
+                                                 (CreatedGameLog {
+                                                      game_log: game_log,
+                                                      targets: targets,
+                                                  },
+                                                  trans)
+                                             })
+                                }))
+    })
+}
+
+pub fn create_game_log_targets<C: Client>(log_id: &Uuid,
+                                          player_ids: &[Uuid],
+                                          conn: C)
+                                          -> AsyncResult<'static, Vec<GameLogTarget>, C> {
+    insert_game_log_targets(player_ids.iter().map(|id| (*log_id, *id)).collect(), conn)
+}
+
+/// Creates targets given as `(game_log_id, player_id)` pairs.
+fn insert_game_log_targets<C: Client>(targets: Vec<(Uuid, Uuid)>,
+                                      conn: C)
+                                      -> AsyncResult<'static, Vec<GameLogTarget>, C> {
+    insert_all::<NewGameLogTarget, _, _, _, _>(targets.len(),
+                                               move |range, statement, conn| {
+        let new_targets: Vec<NewGameLogTarget> = targets[range]
+            .iter()
+            .map(|&(ref game_log_id, ref player_id)| {
+                     NewGameLogTarget {
+                         game_log_id: game_log_id,
+                         player_id: player_id,
+                     }
+                 })
+            .collect();
+        let params: Vec<&ToSql> = new_targets.iter().flat_map(|t| t.params()).collect();
+        conn.query_prepared(statement, &params)
+    },
+                                               |row| GameLogTarget::from_row(row, ""),
+                                               conn)
+}
+
+pub fn create_game_log_target<'a, C: Client>(new_target: &'a NewGameLogTarget,
+                                             conn: C)
+                                             -> AsyncResult<'a, GameLogTarget, C> {
+    insert(new_target,
+           |row| GameLogTarget::from_row(row, ""),
+           "error creating game log target",
+           conn)
+}
+
+/// Finds users by id, then by email, creating users for emails which aren't found. Users are
+/// returned in the order they are given, using a fixed number of queries.
+pub fn create_game_users<C: Client>(ids: &[Uuid],
+                                    emails: &[String],
+                                    conn: C)
+                                    -> AsyncResult<'static, Vec<UserByEmail>, C> {
+    let ids = ids.to_vec();
+    let emails = emails.to_vec();
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(find_users_with_primary_email(&ids, trans).and_then(move |(found, trans)| {
+            let by_id: HashMap<Uuid, UserByEmail> =
+                HashMap::from_iter(found.into_iter().map(|u| (u.user.id, u)));
+            let mut users: Vec<UserByEmail> = vec![];
+            for id in &ids {
+                match by_id.get(id) {
+                    Some(u) => users.push(u.clone()),
+                    None => return failed("unable to find user".into()),
+                }
+            }
+            boxed(find_users_with_primary_email_by_emails(&emails, trans)
+                      .and_then(move |(mut by_email, trans)| {
+                let created = {
+                    let mut missing: Vec<&str> = vec![];
+                    for email in &emails {
+                        if !by_email.contains_key(email) && !missing.contains(&email.as_str()) {
+                            missing.push(email);
+                        }
+                    }
+                    create_users_by_emails(&missing, trans)
+                };
+                created.and_then(move |(created, trans)| {
+                    for c in created {
+                        by_email.insert(c.user_email.email.clone(), c);
+                    }
+                    for email in &emails {
+                        match by_email.get(email) {
+                            Some(u) => users.push(u.clone()),
+                            None => return Err("unable to create user".into()),
+                        }
+                    }
+                    Ok((users, trans))
+                })
+            }))
+        }))
+    })
+}
+
+/// Creates a user for each email, named after the email, using one statement for the users and
+/// one for the emails, and returns them in the same order as `emails`.
+pub fn create_users_by_emails<C: Client>(emails: &[&str],
+                                         conn: C)
+                                         -> AsyncResult<'static, Vec<UserByEmail>, C> {
+    let emails: Vec<String> = emails.iter().map(|e| e.to_string()).collect();
+    let count = emails.len();
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        let names = emails.clone();
+        boxed(insert_all_with_ids::<NewUser, _, _, _, _, _>(count,
+                                                            move |range, ids, statement, trans| {
+            let new_users: Vec<NewUser> = names[range]
+                .iter()
+                .map(|email| {
+                         NewUser {
+                             name: email,
+                             pref_colors: &[],
+                             login_confirmation: None,
+                             login_confirmation_at: None,
+                         }
+                     })
+                .collect();
+            trans.query_prepared(statement, &with_ids(&new_users, ids))
+        },
+                                                            |row| User::from_row(row, ""),
+                                                            |u| u.id,
+                                                            trans)
+                      .and_then(move |(users, trans)| {
+            let user_ids: Vec<Uuid> = users.iter().map(|u| u.id).collect();
+            insert_all_with_ids::<NewUserEmail, _, _, _, _, _>(count,
+                                                               move |range,
+                                                                     ids,
+                                                                     statement,
+                                                                     trans| {
+                let new_emails: Vec<NewUserEmail> = user_ids[range.clone()]
+                    .iter()
+                    .zip(&emails[range])
+                    .map(|(user_id, email)| {
+                             NewUserEmail {
+                                 user_id: user_id,
+                                 email: email,
+                                 is_primary: true,
+                             }
+                         })
+                    .collect();
+                trans.query_prepared(statement, &with_ids(&new_emails, ids))
+            },
+                                                               |row| UserEmail::from_row(row, ""),
+                                                               |ue| ue.id,
+                                                               trans)
+                    .map(move |(user_emails, trans)| {
+                             (users
+                                  .into_iter()
+                                  .zip(user_emails)
+                                  .map(|(u, ue)| {
+                                           UserByEmail {
+                                               user: u,
+                                               user_email: ue,
+                                           }
+                                       })
+                                  .collect(),
+                              trans)
+                         })
+        }))
+    })
+}
+
+pub fn find_users_with_primary_email<C: Client>(ids: &[Uuid],
+                                                conn: C)
+                                                -> AsyncResult<'static, Vec<UserByEmail>, C> {
+    select_rows(ids.to_vec(),
+                |ids| {
+                    users_with_emails()
+                        .filter("u.id = ANY(?)", &[ids])
+                        .filter("ue.is_primary = TRUE", &[])
+                },
+                read_user_by_email,
+                conn)
+}
+
+/// Finds users with any of the emails, keyed by the email they were found by.
+pub fn find_users_with_primary_email_by_emails<C: Client>
+    (emails: &[String],
+     conn: C)
+     -> AsyncResult<'static, HashMap<String, UserByEmail>, C> {
+    boxed(select_rows(emails.to_vec(),
+                      |emails| {
+                          users_with_emails()
+                              .col("uef.email AS uef_email")
+                              .join::<UserEmail>("uef", "u.id = uef.user_id", &[])
+                              .filter("uef.email = ANY(?)", &[emails])
+                              .filter("ue.is_primary = TRUE", &[])
+                      },
+                      |row| (row.get_col::<String>("uef_email"), read_user_by_email(row)),
+                      conn)
+                  .map(|(users, conn)| (users.into_iter().collect(), conn)))
+}
+
+pub fn find_user_with_primary_email<C: Client>(id: &Uuid,
+                                               conn: C)
+                                               -> AsyncResult<'static, Option<UserByEmail>, C> {
+    first(select_rows(*id,
+                      |id| {
+                          users_with_emails()
+                              .filter("u.id = ?", &[id])
+                              .filter("ue.is_primary = TRUE", &[])
+                              .limit(1)
+                      },
+                      read_user_by_email,
+                      conn))
+}
+
+pub fn find_user_with_primary_email_by_email<C: Client>
+    (email: &str,
+     conn: C)
+     -> AsyncResult<'static, Option<UserByEmail>, C> {
+    first(select_rows(email.to_string(),
+                      |email| {
+                          users_with_emails()
+                              .join::<UserEmail>("uef", "u.id = uef.user_id", &[])
+                              .filter("uef.email = ?", &[email])
+                              .filter("ue.is_primary = TRUE", &[])
+                              .limit(1)
+                      },
+                      read_user_by_email,
+                      conn))
+}
+
+pub fn create_game<'a, C: Client>(new_game: &'a NewGame, conn: C) -> AsyncResult<'a, Game, C> {
+    insert(new_game,
+           |row| Game::from_row(row, ""),
+           "error creating game",
+           conn)
+}
+
+pub fn create_game_version<'a, C: Client>(new_game_version: &'a NewGameVersion,
+                                          conn: C)
+                                          -> AsyncResult<'a, GameVersion, C> {
+    insert(new_game_version,
+           |row| GameVersion::from_row(row, ""),
+           "error creating game version",
+           conn)
+}
+
+/// Finds game types with a public, non-deprecated version which can be played with the given
+/// number of players.
+pub fn find_game_types_by_player_count<C: Client>(count: usize,
+                                                  conn: C)
+                                                  -> AsyncResult<'static, Vec<GameType>, C> {
+    query_rows("
+        SELECT DISTINCT gt.*
+        FROM game_types gt
+        INNER JOIN game_versions gv
+        ON (gv.game_type_id = gt.id)
+        WHERE gv.is_public = TRUE
+        AND gv.is_deprecated = FALSE
+        AND gv.min_players <= $1
+        AND (gv.max_players IS NULL OR gv.max_players >= $1)
+        ORDER BY gt.name",
+               vec![param(count as i32)],
+               |row| GameType::from_row(row, ""),
+               conn)
+}
+
+pub fn create_game_type<'a, C: Client>(new_game_type: &'a NewGameType,
+                                       conn: C)
+                                       -> AsyncResult<'a, GameType, C> {
+    insert(new_game_type,
+           |row| GameType::from_row(row, ""),
+           "error creating game type",
+           conn)
+}
+
+/// Creates the players in one statement, returning them in the same order.
+pub fn create_game_players<'a, C: Client>(players: &'a [NewGamePlayer],
+                                          conn: C)
+                                          -> AsyncResult<'a, Vec<GamePlayer>, C> {
+    insert_all_with_ids::<NewGamePlayer, _, _, _, _, _>(players.len(),
+                                                        move |range, ids, statement, conn| {
+                                                            let params =
+                                                                with_ids(&players[range], ids);
+                                                            conn.query_prepared(statement, &params)
+                                                        },
+                                                        |row| GamePlayer::from_row(row, ""),
+                                                        |p| p.id,
+                                                        conn)
+}
+
+pub fn create_game_player<'a, C: Client>(player: &'a NewGamePlayer,
+                                         conn: C)
+                                         -> AsyncResult<'a, GamePlayer, C> {
+    insert(player,
+           |row| GamePlayer::from_row(row, ""),
+           "error creating game player",
+           conn)
+}
+
+pub fn request_game_color_swap<C: Client>(game_id: &Uuid,
+                                          from_user_id: &Uuid,
+                                          to_user_id: &Uuid,
+                                          conn: C)
+                                          -> AsyncResult<'static, GameColorSwap, C> {
+    let game_id = *game_id;
+    let from_user_id = *from_user_id;
+    let to_user_id = *to_user_id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(find_game(&game_id, trans)
+                  .then(|result| found(result, "could not find game"))
+                  .and_then(|(game, trans)| -> Result<(Game, C::Transaction)> {
+                                if game.is_finished {
+                                    return Err("game is already finished".into());
+                                }
+                                Ok((game, trans))
+                            })
+                  .and_then(move |(game, trans)| {
+                                find_game_player_by_user(&game_id, &from_user_id, trans)
+                                    .then(|result| {
+                                              found(result, "requesting user is not in the game")
+                                          })
+                                    .map(move |(from, trans)| ((game, from), trans))
+                            })
+                  .and_then(move |((game, from), trans)| {
+                                find_game_player_by_user(&game_id, &to_user_id, trans)
+                                    .then(|result| found(result, "target user is not in the game"))
+                                    .map(move |(to, trans)| ((game, from, to), trans))
+                            })
+                  .and_then(move |((game, from, to), trans)| {
+            if from.id == to.id {
+                return failed("cannot swap colors with yourself".into());
+            }
+            single(prepared(&NewGameColorSwap::insert_sql(),
+                            move |statement, trans| {
+                                trans.query_prepared(statement,
+                                                     &NewGameColorSwap {
+                                                              game_id: &game_id,
+                                                              from_player_id: &from.id,
+                                                              to_player_id: &to.id,
+                                                              game_revision: game.revision,
+                                                          }
+                                                          .params())
+                            },
+                            |row| GameColorSwap::from_row(row, ""),
+                            trans),
+                   "error creating game color swap")
+        }))
+    })
+}
+
+pub fn create_game_color_swap<'a, C: Client>(swap: &'a NewGameColorSwap,
+                                             conn: C)
+                                             -> AsyncResult<'a, GameColorSwap, C> {
+    insert(swap,
+           |row| GameColorSwap::from_row(row, ""),
+           "error creating game color swap",
+           conn)
+}
+
+pub fn find_pending_game_color_swaps<C: Client>(game_id: &Uuid,
+                                                conn: C)
+                                                -> AsyncResult<'static, Vec<GameColorSwap>, C> {
+    select_rows(*game_id,
+                |game_id| {
+                    pending_game_color_swaps()
+                        .filter("gcs.game_id = ?", &[game_id])
+                        .order_by("gcs.created_at")
+                },
+                |row| GameColorSwap::from_row(row, ""),
+                conn)
+}
+
+/// Finds and locks a pending swap targeting the user, along with its game so the game can't
+/// progress until the swap is accepted or declined.
+fn lock_pending_swap<C: Client>(id: Uuid,
+                                user_id: Uuid,
+                                conn: C)
+                                -> AsyncResult<'static, Option<GameColorSwap>, C> {
+    first(select_rows((id, user_id),
+                      |&(ref id, ref user_id)| {
+                          pending_game_color_swaps()
+                              .join::<GamePlayer>("tp", "gcs.to_player_id = tp.id", &[])
+                              .filter("gcs.id = ?", &[id])
+                              .filter("tp.user_id = ?", &[user_id])
+                              .limit(1)
+                              .for_update_of(&["gcs", "g"])
+                      },
+                      |row| GameColorSwap::from_row(row, ""),
+                      conn))
+}
+
+/// Accepts a pending color swap on behalf of the target user, like
+/// `query::accept_game_color_swap`. Returns `None` if there is no pending swap with that id
+/// targeting the user.
+pub fn accept_game_color_swap<C: Client>(id: &Uuid,
+                                         user_id: &Uuid,
+                                         conn: C)
+                                         -> AsyncResult<'static, Option<Vec<GamePlayer>>, C> {
+    let id = *id;
+    let user_id = *user_id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(lock_pending_swap(id, user_id, trans).and_then(move |(swap, trans)| {
+            let swap = match swap {
+                Some(s) => s,
+                None => return ready(None, trans),
+            };
+            // Both rows are updated in a single statement, but uniqueness is still checked row by
+            // row unless the constraint is deferred to the end of the transaction.
+            boxed(trans
+                      .batch_execute("SET CONSTRAINTS game_players_game_id_color_key DEFERRED")
+                      .and_then(move |((), trans)| {
+                                    query_rows("
+                    UPDATE game_players gp
+                    SET
+                        color = other.color,
+                        color_pref_rank = array_position(u.pref_colors, other.color) - 1,
+                        color_pref_known = TRUE
+                    FROM game_players other, users u
+                    WHERE gp.id IN ($1, $2)
+                    AND other.id IN ($1, $2)
+                    AND other.id <> gp.id
+                    AND u.id = gp.user_id
+                    RETURNING gp.*",
+                                               vec![param(swap.from_player_id),
+                                                    param(swap.to_player_id)],
+                                               |row| GamePlayer::from_row(row, ""),
+                                               trans)
+                                })
+                      .and_then(move |(players, trans)| {
+                                    execute("
+                    UPDATE game_color_swaps
+                    SET accepted_at=(now() AT TIME ZONE 'utc')
+                    WHERE id=$1",
+                                            vec![param(id)],
+                                            trans)
+                                            .map(move |(_, trans)| (Some(players), trans))
+                                }))
+        }))
+    })
+}
+
+/// Declines a pending color swap on behalf of the target user. Returns `None` if there is no
+/// pending swap with that id targeting the user, including swaps which have expired.
+pub fn decline_game_color_swap<C: Client>(id: &Uuid,
+                                          user_id: &Uuid,
+                                          conn: C)
+                                          -> AsyncResult<'static, Option<GameColorSwap>, C> {
+    let id = *id;
+    let user_id = *user_id;
+    transaction(IsolationLevel::Serializable, conn, move |trans| {
+        boxed(lock_pending_swap(id, user_id, trans).and_then(move |(swap, trans)| {
+            if swap.is_none() {
+                return ready(None, trans);
+            }
+            query_row("
+                UPDATE game_color_swaps
+                SET declined_at=(now() AT TIME ZONE 'utc')
+                WHERE id=$1
+                RETURNING *",
+                      vec![param(id)],
+                      |row| GameColorSwap::from_row(row, ""),
+                      trans)
+        }))
+    })
+}
+
+pub fn find_games<'a, C: Client>(select: &'a Select, conn: C) -> AsyncResult<'a, Vec<Game>, C> {
+    let sql = match select.sql() {
+        Ok(sql) => sql,
+        Err(e) => return failed(e),
+    };
+    prepared(&sql,
+             move |statement, conn| conn.query_prepared(statement, &select.params()),
+             |row| Game::from_row(row, ""),
+             conn)
+}
+
+pub fn find_unfinished_games_by_game_version<C: Client>
+    (game_version_id: &Uuid,
+     conn: C)
+     -> AsyncResult<'static, Vec<Game>, C> {
+    select_rows(*game_version_id,
+                |game_version_id| {
+                    games_select()
+                        .filter("g.game_version_id = ?", &[game_version_id])
+                        .filter("g.is_finished = FALSE", &[])
+                        .order_by("g.created_at")
+                },
+                |row| Game::from_row(row, ""),
+                conn)
+}
+
+/// Moves every unfinished game on one game version to another version of the same game type,
+/// committing in batches of `batch_size` games, like `query::migrate_game_version`.
+///
+/// If a batch fails after earlier batches were committed, the error is an
+/// `IncompleteGameVersionMigration` carrying the run id and the migrations already made.
+pub fn migrate_game_version<'a, C, F>(from_game_version_id: &Uuid,
+                                      to_game_version_id: &Uuid,
+                                      batch_size: usize,
+                                      transform: F,
+                                      conn: C)
+                                      -> AsyncResult<'a, MigratedGameVersion, C>
+    where C: Client,
+          F: Fn(&Game) -> Result<String> + 'a
+{
+    if from_game_version_id == to_game_version_id {
+        return failed("cannot migrate games to the version they are already on".into());
+    }
+    if batch_size == 0 {
+        return failed("batch size must be greater than zero".into());
+    }
+    let from_id = *from_game_version_id;
+    let to_id = *to_game_version_id;
+    let transform = Rc::new(transform);
+    boxed(find_game_version(&from_id, conn)
+              .then(|result| found(result, "could not find game version to migrate from"))
+              .and_then(move |(from, conn)| {
+                            find_game_version(&to_id, conn)
+                                .then(|result| {
+                                          found(result, "could not find game version to migrate to")
+                                      })
+                                .map(move |(to, conn)| ((from, to), conn))
+                        })
+              .and_then(move |((from, to), conn)| -> Result<(GameVersion, C)> {
+                            if from.game_type_id != to.game_type_id {
+                                return Err("cannot migrate games to a version of a different \
+                                            game type"
+                                                   .into());
+                            }
+                            if to.is_deprecated {
+                                return Err("cannot migrate games to a deprecated version".into());
+                            }
+                            Ok((to, conn))
+                        })
+              .and_then(move |(to, conn)| {
+                            query_rows("
+                SELECT COUNT(gp.id) AS player_count
+                FROM games g
+                LEFT OUTER JOIN game_players gp
+                ON gp.game_id = g.id
+                WHERE g.game_version_id=$1
+                AND g.is_finished=FALSE
+                GROUP BY g.id",
+                                       vec![param(from_id)],
+                                       |row| row.get_col::<i64>("player_count"),
+                                       conn)
+                                    .and_then(move |(player_counts, conn)| {
+                                                  for count in player_counts {
+                                                      check_player_count(&to, count as usize)?;
+                                                  }
+                                                  Ok(conn)
+                                              })
+                        })
+              .and_then(move |conn| {
+        let run_id = Uuid::new_v4();
+        future::loop_fn((vec![], conn), move |(mut migrations, conn)| {
+            migrate_game_version_batch(run_id, from_id, to_id, batch_size, transform.clone(), conn)
+                .then(move |result| match result {
+                          Ok((batch, conn)) => {
+                              if batch.is_empty() {
+                                  return Ok(Loop::Break((migrations, conn)));
+                              }
+                              migrations.extend(batch);
+                              Ok(Loop::Continue((migrations, conn)))
+                          }
+                          Err(e) => {
+                              if migrations.is_empty() {
+                                  return Err(e);
+                              }
+                              Err(e.chain_err(|| {
+                                  ErrorKind::IncompleteGameVersionMigration(run_id, migrations)
+                              }))
+                          }
+                      })
+        })
+                .map(move |(migrations, conn)| {
+                         (MigratedGameVersion {
+                              run_id: run_id,
+                              migrations: migrations,
+                          },
+                          conn)
+                     })
+    }))
+}
+
+fn migrate_game_version_batch<'a, C, F>(run_id: Uuid,
+                                        from_id: Uuid,
+                                        to_id: Uuid,
+                                        batch_size: usize,
+                                        transform: Rc<F>,
+                                        conn: C)
+                                        -> AsyncResult<'a, Vec<GameVersionMigration>, C>
+    where C: Client,
+          F: Fn(&Game) -> Result<String> + 'a
+{
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(query_rows("
+            SELECT *
+            FROM games
+            WHERE game_version_id=$1
+            AND is_finished=FALSE
+            ORDER BY created_at
+            LIMIT $2
+            FOR UPDATE",
+                         vec![param(from_id), param(batch_size as i64)],
+                         |row| Game::from_row(row, ""),
+                         trans)
+                      .and_then(move |(games, trans)| {
+            fold(games, vec![], move |mut migrations: Vec<GameVersionMigration>,
+                  game: Game,
+                  trans| {
+                let game_state = match (*transform)(&game).chain_err(|| {
+                    format!("could not transform game state for game {}", game.id)
+                }) {
+                    Ok(game_state) => game_state,
+                    Err(e) => return failed(e),
+                };
+                boxed(execute("
+                    UPDATE games
+                    SET
+                        game_version_id=$1,
+                        game_state=$2,
+                        revision=revision+1
+                    WHERE id=$3",
+                              vec![param(to_id), param(game_state.clone()), param(game.id)],
+                              trans)
+                              .and_then(move |(_, trans)| {
+                    single(prepared(&NewGameVersionMigration::insert_sql(),
+                                    move |statement, trans| {
+                                        trans.query_prepared(statement,
+                                                             &NewGameVersionMigration {
+                                                                  run_id: &run_id,
+                                                                  game_id: &game.id,
+                                                                  from_game_version_id: &from_id,
+                                                                  to_game_version_id: &to_id,
+                                                                  from_game_state: &game.game_state,
+                                                                  to_game_state: &game_state,
+                                                                  game_revision: game.revision + 1,
+                                                              }
+                                                              .params())
+                                    },
+                                    |row| GameVersionMigration::from_row(row, ""),
+                                    trans),
+                           "error creating game version migration")
+                })
+                              .map(move |(migration, trans)| {
+                                       migrations.push(migration);
+                                       (migrations, trans)
+                                   }))
+            },
+                 trans)
+        }))
+    })
+}
+
+pub fn create_game_version_migration<'a, C: Client>(migration: &'a NewGameVersionMigration,
+                                                    conn: C)
+                                                    -> AsyncResult<'a, GameVersionMigration, C> {
+    insert(migration,
+           |row| GameVersionMigration::from_row(row, ""),
+           "error creating game version migration",
+           conn)
+}
+
+pub fn find_game_version_migrations_by_game<C: Client>
+    (game_id: &Uuid,
+     conn: C)
+     -> AsyncResult<'static, Vec<GameVersionMigration>, C> {
+    query_rows("
+        SELECT *
+        FROM game_version_migrations
+        WHERE game_id=$1
+        ORDER BY created_at",
+               vec![param(*game_id)],
+               |row| GameVersionMigration::from_row(row, ""),
+               conn)
+}
+
+/// Restores the version and state of every game migrated in a run. Games which have been played
+/// since they were migrated are left alone, and are not included in the returned migrations.
+pub fn rollback_game_version_migration_run<C: Client>
+    (run_id: &Uuid,
+     conn: C)
+     -> AsyncResult<'static, Vec<GameVersionMigration>, C> {
+    let run_id = *run_id;
+    transaction(IsolationLevel::ReadCommitted, conn, move |trans| {
+        boxed(query_rows(&format!("
+            UPDATE game_version_migrations gvm
+            SET rolled_back_at=(now() AT TIME ZONE 'utc')
+            FROM games g
+            WHERE gvm.game_id = g.id
+            AND gvm.run_id = $1
+            AND gvm.rolled_back_at IS NULL
+            AND g.game_version_id = gvm.to_game_version_id
+            AND g.revision = gvm.game_revision
+            RETURNING {}",
+                                  GameVersionMigration::select_cols("gvm", "")),
+                         vec![param(run_id)],
+                         |row| GameVersionMigration::from_row(row, ""),
+                         trans)
+                      .and_then(|(rolled_back, trans)| {
+            let restores: Vec<(Uuid, String, Uuid)> = rolled_back
+                .iter()
+                .map(|m| (m.from_game_version_id, m.from_game_state.clone(), m.game_id))
+                .collect();
+            fold(restores,
+                 (),
+                 |(), (game_version_id, game_state, game_id), trans| {
+                     boxed(execute("
+                        UPDATE games
+                        SET
+                            game_version_id=$1,
+                            game_state=$2,
+                            revision=revision+1
+                        WHERE id=$3",
+                                   vec![param(game_version_id), param(game_state), param(game_id)],
+                                   trans)
+                                   .map(|(_, trans)| ((), trans)))
+                 },
+                 trans)
+                    .map(move |((), trans)| (rolled_back, trans))
+        }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_client::connect;
+    use config::DbConfig;
+    use fixtures::GameFixture;
+    use models::ModelRow;
+    use repository::PgRepository;
+    use test_support::test_db;
+
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn login_works() {
+        if let Some(db) = test_db() {
+            let mut core = Core::new().unwrap();
+            let config = DbConfig::new(&db.url, &db.url);
+            let conn = core.run(connect(&config, &db.url, &core.handle())).unwrap();
+            let (code, conn) = core.run(user_login_request("beefsack@gmail.com", conn))
+                .unwrap();
+            let (token, conn) = core.run(user_login_confirm("beefsack@gmail.com", &code, conn))
+                .unwrap();
+            let token = token.unwrap();
+            let (found, conn) = core.run(authenticate("beefsack@gmail.com", &token.id, conn))
+                .unwrap();
+            assert_eq!(token.user_id, found.unwrap().user.id);
+            let (found, _) = core.run(authenticate("beefsack@gmail.com", &Uuid::new_v4(), conn))
+                .unwrap();
+            assert!(found.is_none());
+        }
+    }
+
+    #[test]
+    fn concede_game_works() {
+        if let Some(db) = test_db() {
+            let created = GameFixture::new()
+                .players(2)
+                .no_logs()
+                .create(&PgRepository::new(&*db.conns().w.get().unwrap()))
+                .unwrap();
+            let mut core = Core::new().unwrap();
+            let config = DbConfig::new(&db.url, &db.url);
+            let conn = core.run(connect(&config, &db.url, &core.handle())).unwrap();
+            let user_id = created.players[0].user_id;
+            let (players, conn) = core.run(concede_game(&created.game.id, &user_id, conn))
+                .unwrap();
+            let players = players.unwrap();
+            let player = players.iter().find(|p| p.user_id == user_id).unwrap();
+            assert!(player.is_eliminated);
+            assert_eq!(Some(EliminationReason::Conceded), player.eliminated_reason);
+            // The last player left wins, and the game is rated.
+            assert_eq!(vec![1],
+                       players
+                           .iter()
+                           .filter(|p| p.is_winner)
+                           .map(|p| p.position)
+                           .collect::<Vec<i32>>());
+            let (game, conn) = core.run(find_game(&created.game.id, conn)).unwrap();
+            assert!(game.unwrap().is_finished);
+            let (rated, conn) = core.run(query_rows("
+                SELECT id
+                FROM rating_changes
+                WHERE game_id=$1",
+                                                    vec![param(created.game.id)],
+                                                    |row| row.get_col::<Uuid>("id"),
+                                                    conn))
+                .unwrap();
+            assert_eq!(2, rated.len());
+            let (conceded, _) = core.run(concede_game(&created.game.id, &user_id, conn))
+                .unwrap();
+            assert_eq!(None, conceded);
+        }
+    }
+}
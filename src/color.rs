@@ -1,9 +1,12 @@
 use rand::{self, Rng};
 
 use std::collections::{HashSet, HashMap};
+use std::str::FromStr;
 
 use brdgme_color;
 
+use errors::*;
+
 #[derive(Debug, ToSql, FromSql, PartialEq, Eq, Hash, Clone, Copy)]
 #[postgres(name = "color")]
 pub enum Color {
@@ -38,6 +41,36 @@ impl Into<brdgme_color::Color> for Color {
     }
 }
 
+impl FromStr for Color {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+               "Green" => Color::Green,
+               "Red" => Color::Red,
+               "Blue" => Color::Blue,
+               "Amber" => Color::Amber,
+               "Purple" => Color::Purple,
+               "Brown" => Color::Brown,
+               "BlueGrey" => Color::BlueGrey,
+               _ => return Err(ErrorKind::UnknownColor(s.to_string()).into()),
+           })
+    }
+}
+
+/// Parses a ranked list of preferred colors, rejecting unknown and duplicate colors.
+pub fn parse_prefs(colors: &[&str]) -> Result<Vec<Color>> {
+    let mut prefs: Vec<Color> = vec![];
+    for c in colors {
+        let color: Color = c.parse()?;
+        if prefs.contains(&color) {
+            return Err(ErrorKind::DuplicateColor(c.to_string()).into());
+        }
+        prefs.push(color);
+    }
+    Ok(prefs)
+}
+
 type LocPref = (usize, Vec<Color>);
 
 /// Choose chooses colors based on preferences. First it tries to assign all first preferences, then
@@ -140,4 +173,19 @@ mod tests {
                    choose(&HashSet::from_iter(vec![Color::Amber].iter()),
                           &[vec![], vec![Color::Blue, Color::Green], vec![Color::Green]]));
     }
+
+    #[test]
+    fn parse_prefs_works() {
+        assert_eq!(vec![Color::BlueGrey, Color::Green],
+                   parse_prefs(&["BlueGrey", "Green"]).unwrap());
+        assert!(parse_prefs(&[]).unwrap().is_empty());
+        match parse_prefs(&["Green", "Pink"]) {
+            Err(Error(ErrorKind::UnknownColor(ref c), _)) => assert_eq!("Pink", c),
+            _ => panic!("expected unknown color error"),
+        }
+        match parse_prefs(&["Green", "Red", "Green"]) {
+            Err(Error(ErrorKind::DuplicateColor(ref c), _)) => assert_eq!("Green", c),
+            _ => panic!("expected duplicate color error"),
+        }
+    }
 }
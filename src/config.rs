@@ -0,0 +1,354 @@
+use r2d2::{self, CustomizeConnection};
+use r2d2_postgres::{TlsMode, PostgresConnectionManager};
+use postgres::Connection;
+use postgres::tls::openssl::OpenSsl;
+use openssl::ssl::{SslMethod, SslConnector, SslConnectorBuilder};
+
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsConfig {
+    Disable,
+    Prefer,
+    Require,
+}
+
+impl FromStr for TlsConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+               "disable" => TlsConfig::Disable,
+               "prefer" => TlsConfig::Prefer,
+               "require" => TlsConfig::Require,
+               _ => return Err(format!("unknown TLS mode '{}'", s).into()),
+           })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolConfig {
+    pub size: u32,
+    pub min_idle: Option<u32>,
+    pub connection_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 10,
+            min_idle: None,
+            connection_timeout: Duration::from_secs(30),
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+            max_lifetime: Some(Duration::from_secs(30 * 60)),
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Checks the pool can be built, as r2d2 panics on a size of zero or more idle connections
+    /// than the pool can hold.
+    pub fn validate(&self) -> Result<()> {
+        if self.size == 0 {
+            return Err("pool size must be greater than zero".into());
+        }
+        if let Some(min_idle) = self.min_idle {
+            if min_idle > self.size {
+                return Err(format!("minimum idle connections ({}) must not be more than the pool \
+                                    size ({})",
+                                   min_idle,
+                                   self.size)
+                                   .into());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DbConfig {
+    pub w_addr: String,
+    pub r_addr: String,
+    pub w_pool: PoolConfig,
+    pub r_pool: PoolConfig,
+    pub statement_timeout: Option<Duration>,
+    pub application_name: Option<String>,
+    pub tls_mode: TlsConfig,
+    pub tls_ca_path: Option<PathBuf>,
+}
+
+impl DbConfig {
+    pub fn new(w_addr: &str, r_addr: &str) -> Self {
+        DbConfig {
+            w_addr: w_addr.to_string(),
+            r_addr: r_addr.to_string(),
+            w_pool: PoolConfig::default(),
+            r_pool: PoolConfig::default(),
+            statement_timeout: None,
+            application_name: None,
+            tls_mode: TlsConfig::Disable,
+            tls_ca_path: None,
+        }
+    }
+
+    /// Loads config from the environment. Only `DATABASE_URL` is required, `DATABASE_URL_R`
+    /// defaults to it, and durations are given in seconds except for the statement timeout which
+    /// is in milliseconds to match Postgres.
+    pub fn from_env() -> Result<Self> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    pub fn from_lookup<F>(lookup: F) -> Result<Self>
+        where F: Fn(&str) -> Option<String>
+    {
+        let w_addr = lookup("DATABASE_URL")
+            .ok_or_else::<Error, _>(|| "DATABASE_URL not set".into())?;
+        let r_addr = lookup("DATABASE_URL_R").unwrap_or_else(|| w_addr.to_owned());
+        let mut config = DbConfig::new(&w_addr, &r_addr);
+
+        let parse = |key: &str| -> Result<Option<u64>> {
+            match lookup(key) {
+                Some(v) => {
+                    Ok(Some(v.parse()
+                                .chain_err(|| format!("{} is not a valid number", key))?))
+                }
+                None => Ok(None),
+            }
+        };
+        let parse_max = |key: &str, max: u64| -> Result<Option<u64>> {
+            match parse(key)? {
+                Some(v) if v > max => Err(format!("{} must be at most {}", key, max).into()),
+                v => Ok(v),
+            }
+        };
+        let parse_u32 = |key: &str| -> Result<Option<u32>> {
+            Ok(parse_max(key, u32::max_value() as u64)?.map(|v| v as u32))
+        };
+        if let Some(size) = parse_u32("DATABASE_POOL_SIZE")? {
+            config.w_pool.size = size;
+        }
+        config.r_pool.size = parse_u32("DATABASE_POOL_SIZE_R")?
+            .unwrap_or(config.w_pool.size);
+        if let Some(min_idle) = parse_u32("DATABASE_POOL_MIN_IDLE")? {
+            config.w_pool.min_idle = Some(min_idle);
+            config.r_pool.min_idle = Some(min_idle);
+        }
+        if let Some(secs) = parse("DATABASE_CONNECTION_TIMEOUT")? {
+            config.w_pool.connection_timeout = Duration::from_secs(secs);
+            config.r_pool.connection_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = parse("DATABASE_IDLE_TIMEOUT")? {
+            config.w_pool.idle_timeout = Some(Duration::from_secs(secs));
+            config.r_pool.idle_timeout = Some(Duration::from_secs(secs));
+        }
+        if let Some(secs) = parse("DATABASE_MAX_LIFETIME")? {
+            config.w_pool.max_lifetime = Some(Duration::from_secs(secs));
+            config.r_pool.max_lifetime = Some(Duration::from_secs(secs));
+        }
+        // Postgres stores the statement timeout as a 32 bit count of milliseconds.
+        config.statement_timeout = parse_max("DATABASE_STATEMENT_TIMEOUT",
+                                             i32::max_value() as u64)?
+                .map(Duration::from_millis);
+        config.application_name = lookup("DATABASE_APPLICATION_NAME");
+        if let Some(mode) = lookup("DATABASE_TLS_MODE") {
+            config.tls_mode = mode.parse()?;
+        }
+        config.tls_ca_path = lookup("DATABASE_TLS_CA").map(PathBuf::from);
+        config
+            .w_pool
+            .validate()
+            .chain_err(|| "invalid primary pool config")?;
+        config
+            .r_pool
+            .validate()
+            .chain_err(|| "invalid replica pool config")?;
+        Ok(config)
+    }
+
+    /// Builds the connector for TLS connections, trusting the CA at `tls_ca_path` if it is set.
+    fn ssl_connector(&self) -> Result<SslConnector> {
+        let mut connector = SslConnectorBuilder::new(SslMethod::tls())
+            .chain_err(|| "unable to create TLS connector")?;
+        if let Some(ref ca_path) = self.tls_ca_path {
+            connector
+                .builder_mut()
+                .set_ca_file(ca_path)
+                .chain_err(|| format!("unable to load CA file {}", ca_path.display()))?;
+        }
+        Ok(connector.build())
+    }
+
+    fn tls_mode(&self) -> Result<TlsMode> {
+        if self.tls_mode == TlsConfig::Disable {
+            return Ok(TlsMode::None);
+        }
+        let handshake = Box::new(OpenSsl::from(self.ssl_connector()?));
+        Ok(match self.tls_mode {
+               TlsConfig::Prefer => TlsMode::Prefer(handshake),
+               _ => TlsMode::Require(handshake),
+           })
+    }
+
+    /// Like `tls_mode`, but for connecting with tokio-postgres.
+    #[cfg(feature = "async")]
+    pub fn async_tls_mode(&self) -> Result<::tokio_postgres::TlsMode> {
+        use tokio_postgres::TlsMode;
+        use tokio_postgres::tls::openssl::OpenSsl;
+
+        if self.tls_mode == TlsConfig::Disable {
+            return Ok(TlsMode::None);
+        }
+        let handshake = Box::new(OpenSsl::from(self.ssl_connector()?));
+        Ok(match self.tls_mode {
+               TlsConfig::Prefer => TlsMode::Prefer(handshake),
+               _ => TlsMode::Require(handshake),
+           })
+    }
+
+    /// The per-session settings applied to each connection, as `(name, value)` pairs for
+    /// `set_config`.
+    pub fn session_settings(&self) -> Vec<(&'static str, String)> {
+        let mut settings: Vec<(&'static str, String)> = vec![];
+        if let Some(timeout) = self.statement_timeout {
+            let ms = timeout.as_secs() * 1000 + (timeout.subsec_nanos() / 1_000_000) as u64;
+            settings.push(("statement_timeout", ms.to_string()));
+        }
+        if let Some(ref name) = self.application_name {
+            settings.push(("application_name", name.clone()));
+        }
+        settings
+    }
+
+    pub fn pool(&self,
+                addr: &str,
+                pool_config: &PoolConfig)
+                -> Result<r2d2::Pool<PostgresConnectionManager>> {
+        pool_config.validate()?;
+        let config = r2d2::Config::builder()
+            .pool_size(pool_config.size)
+            .min_idle(pool_config.min_idle)
+            .connection_timeout(pool_config.connection_timeout)
+            .idle_timeout(pool_config.idle_timeout)
+            .max_lifetime(pool_config.max_lifetime)
+            .connection_customizer(Box::new(SessionSettings(self.session_settings())))
+            .build();
+        r2d2::Pool::new(config,
+                        PostgresConnectionManager::new(addr, self.tls_mode()?)
+                            .chain_err(|| "unable to create connection manager")?)
+                .chain_err(|| "unable to connect to database")
+    }
+}
+
+/// Applies per-session settings to each connection as the pool opens it.
+#[derive(Debug)]
+struct SessionSettings(Vec<(&'static str, String)>);
+
+impl CustomizeConnection<Connection, ::postgres::error::Error> for SessionSettings {
+    fn on_acquire(&self,
+                  conn: &mut Connection)
+                  -> ::std::result::Result<(), ::postgres::error::Error> {
+        for &(name, ref value) in &self.0 {
+            conn.execute("SELECT set_config($1, $2, false)", &[&name, value])?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use connect_config;
+
+    use std::collections::HashMap;
+
+    fn lookup(vars: &[(&str, &str)]) -> Result<DbConfig> {
+        let vars: HashMap<String, String> = vars.iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        DbConfig::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn from_lookup_works() {
+        let config = lookup(&[("DATABASE_URL", "postgres://w"),
+                              ("DATABASE_POOL_SIZE", "20"),
+                              ("DATABASE_POOL_SIZE_R", "40"),
+                              ("DATABASE_CONNECTION_TIMEOUT", "5"),
+                              ("DATABASE_STATEMENT_TIMEOUT", "1500"),
+                              ("DATABASE_APPLICATION_NAME", "brdgme-api"),
+                              ("DATABASE_TLS_MODE", "require"),
+                              ("DATABASE_TLS_CA", "/etc/ssl/db.crt")])
+                .unwrap();
+        assert_eq!("postgres://w", config.r_addr);
+        assert_eq!(20, config.w_pool.size);
+        assert_eq!(40, config.r_pool.size);
+        assert_eq!(Duration::from_secs(5), config.r_pool.connection_timeout);
+        assert_eq!(Some(Duration::from_millis(1500)), config.statement_timeout);
+        assert_eq!(Some("brdgme-api".to_string()), config.application_name);
+        assert_eq!(TlsConfig::Require, config.tls_mode);
+        assert_eq!(Some(PathBuf::from("/etc/ssl/db.crt")), config.tls_ca_path);
+        assert_eq!(vec![("statement_timeout", "1500".to_string()),
+                        ("application_name", "brdgme-api".to_string())],
+                   config.session_settings());
+    }
+
+    #[test]
+    fn from_lookup_defaults_work() {
+        let config = lookup(&[("DATABASE_URL", "postgres://w")]).unwrap();
+        assert_eq!(DbConfig::new("postgres://w", "postgres://w"), config);
+        assert!(lookup(&[]).is_err());
+        assert!(lookup(&[("DATABASE_URL", "postgres://w"), ("DATABASE_TLS_MODE", "maybe")])
+                    .is_err());
+        assert!(lookup(&[("DATABASE_URL", "postgres://w"), ("DATABASE_POOL_SIZE", "lots")])
+                    .is_err());
+    }
+
+    #[test]
+    fn from_lookup_rejects_invalid_pools() {
+        let invalid: &[&[(&str, &str)]] = &[&[("DATABASE_POOL_SIZE", "0")],
+                                            &[("DATABASE_POOL_SIZE_R", "0")],
+                                            &[("DATABASE_POOL_SIZE", "-1")],
+                                            &[("DATABASE_POOL_SIZE", "4294967296")],
+                                            &[("DATABASE_POOL_SIZE", "5"),
+                                              ("DATABASE_POOL_MIN_IDLE", "6")],
+                                            &[("DATABASE_POOL_SIZE_R", "5"),
+                                              ("DATABASE_POOL_MIN_IDLE", "6")],
+                                            &[("DATABASE_STATEMENT_TIMEOUT", "2147483648")]];
+        for vars in invalid {
+            let mut vars = vars.to_vec();
+            vars.push(("DATABASE_URL", "postgres://w"));
+            assert!(lookup(&vars).is_err(), "{:?} should be invalid", vars);
+        }
+        let config = lookup(&[("DATABASE_URL", "postgres://w"),
+                              ("DATABASE_POOL_SIZE", "5"),
+                              ("DATABASE_POOL_MIN_IDLE", "5")])
+                .unwrap();
+        assert_eq!(Some(5), config.r_pool.min_idle);
+    }
+
+    /// Expects `DATABASE_URL` to point at a local server using a self-signed certificate, and
+    /// `DATABASE_TLS_CA` to point at that certificate.
+    #[test]
+    #[ignore]
+    fn connect_with_tls_works() {
+        let mut config = DbConfig::from_env().unwrap();
+        config.tls_mode = TlsConfig::Require;
+        config.statement_timeout = Some(Duration::from_millis(1234));
+        config.application_name = Some("brdgme-db-test".to_string());
+        let conns = connect_config(&config).unwrap();
+        let conn = conns.w.get().unwrap();
+        let rows = conn.query("SHOW statement_timeout", &[]).unwrap();
+        assert_eq!("1234ms", rows.get(0).get::<_, String>(0));
+        let rows = conn.query("SELECT ssl FROM pg_stat_ssl WHERE pid = pg_backend_pid()",
+                              &[])
+            .unwrap();
+        assert!(rows.get(0).get::<_, bool>(0));
+    }
+}
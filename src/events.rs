@@ -0,0 +1,249 @@
+use fallible_iterator::FallibleIterator;
+use r2d2::{self, PooledConnection};
+use r2d2_postgres::PostgresConnectionManager;
+use serde_json::{self, Value};
+use uuid::Uuid;
+
+use std::cmp;
+use std::thread;
+use std::time::Duration;
+
+use errors::*;
+use config::{DbConfig, PoolConfig};
+
+/// The channel game events are sent on by the triggers in the database.
+pub static CHANNEL: &'static str = "game_events";
+/// Delay before the first attempt to reconnect, doubled for each following attempt.
+pub static MIN_RECONNECT_DELAY_MS: u64 = 100;
+pub static MAX_RECONNECT_DELAY_MS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GameEventKind {
+    GameUpdated,
+    PlayersUpdated,
+    LogCreated,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameEvent {
+    pub kind: GameEventKind,
+    pub game_id: Uuid,
+    pub revision: i32,
+    /// For game updates these are all players in the game, for player updates the players which
+    /// changed, and for logs the players who can see the log.
+    pub player_ids: Vec<Uuid>,
+}
+
+impl GameEvent {
+    pub fn from_payload(payload: &str) -> Result<Self> {
+        let v: Value = serde_json::from_str(payload)
+            .chain_err(|| format!("invalid game event payload: {}", payload))?;
+        let kind = match v["kind"].as_str() {
+            Some("game_updated") => GameEventKind::GameUpdated,
+            Some("players_updated") => GameEventKind::PlayersUpdated,
+            Some("log_created") => GameEventKind::LogCreated,
+            _ => return Err(format!("unknown game event kind in payload: {}", payload).into()),
+        };
+        let mut player_ids: Vec<Uuid> = vec![];
+        if let Some(ids) = v["player_ids"].as_array() {
+            for id in ids {
+                player_ids.push(parse_uuid(id)?);
+            }
+        }
+        let revision = v["revision"]
+            .as_i64()
+            .ok_or_else::<Error, _>(|| "game event has no revision".into())?;
+        Ok(GameEvent {
+               kind: kind,
+               game_id: parse_uuid(&v["game_id"])?,
+               revision: revision as i32,
+               player_ids: player_ids,
+           })
+    }
+}
+
+fn parse_uuid(v: &Value) -> Result<Uuid> {
+    let s = v.as_str()
+        .ok_or_else::<Error, _>(|| format!("expected a UUID, got {}", v).into())?;
+    Uuid::parse_str(s).chain_err(|| format!("invalid UUID {}", s))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    Game(GameEvent),
+    /// The connection dropped and has been reestablished. Events sent while disconnected are
+    /// lost, so games should be checked for changes with `query::find_games_changed_since`.
+    Reconnected,
+}
+
+type Conn = PooledConnection<PostgresConnectionManager>;
+
+/// A Subscription listens for game events on its own connection to the primary.
+pub struct Subscription {
+    pool: r2d2::Pool<PostgresConnectionManager>,
+    conn: Option<Conn>,
+}
+
+/// Starts listening for game events. Events are only received for changes committed after this
+/// returns.
+pub fn subscribe(config: &DbConfig) -> Result<Subscription> {
+    let pool = config.pool(&config.w_addr,
+                           &PoolConfig {
+                                size: 1,
+                                idle_timeout: None,
+                                max_lifetime: None,
+                                ..PoolConfig::default()
+                            })?;
+    let conn = listen(&pool)?;
+    Ok(Subscription {
+           pool: pool,
+           conn: Some(conn),
+       })
+}
+
+fn listen(pool: &r2d2::Pool<PostgresConnectionManager>) -> Result<Conn> {
+    let conn = pool.get()
+        .chain_err(|| "unable to get connection to listen on")?;
+    conn.batch_execute(&format!("LISTEN {}", CHANNEL))?;
+    Ok(conn)
+}
+
+impl Subscription {
+    /// Waits up to `timeout` for the next event, returning `None` if there wasn't one. If the
+    /// connection has dropped this blocks until it is reestablished, and then returns
+    /// `Event::Reconnected`.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<Option<Event>> {
+        if self.conn.is_none() {
+            self.reconnect();
+            return Ok(Some(Event::Reconnected));
+        }
+        let received = {
+            let conn = self.conn.as_ref().unwrap();
+            let notifications = conn.notifications();
+            let mut iter = notifications.timeout_iter(timeout);
+            iter.next()
+        };
+        match received {
+            Ok(Some(n)) => GameEvent::from_payload(&n.payload).map(|e| Some(Event::Game(e))),
+            Ok(None) => Ok(None),
+            Err(_) => {
+                // The pool replaces the broken connection when we next ask for one.
+                self.conn = None;
+                self.reconnect();
+                Ok(Some(Event::Reconnected))
+            }
+        }
+    }
+
+    fn reconnect(&mut self) {
+        let mut delay = MIN_RECONNECT_DELAY_MS;
+        loop {
+            if let Ok(conn) = listen(&self.pool) {
+                self.conn = Some(conn);
+                return;
+            }
+            thread::sleep(Duration::from_millis(delay));
+            delay = cmp::min(delay * 2, MAX_RECONNECT_DELAY_MS);
+        }
+    }
+}
+
+/// Iterating a subscription blocks until each event arrives.
+impl Iterator for Subscription {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Result<Event>> {
+        loop {
+            match self.next_timeout(Duration::from_secs(60)) {
+                Ok(None) => continue,
+                Ok(Some(e)) => return Some(Ok(e)),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures::GameFixture;
+    use models::NewGame;
+    use query;
+    use repository::PgRepository;
+    use test_support::test_db;
+
+    #[test]
+    fn from_payload_works() {
+        let game_id = Uuid::new_v4();
+        let player_id = Uuid::new_v4();
+        assert_eq!(GameEvent {
+                       kind: GameEventKind::PlayersUpdated,
+                       game_id: game_id,
+                       revision: 3,
+                       player_ids: vec![player_id],
+                   },
+                   GameEvent::from_payload(&format!("{{\"kind\": \"players_updated\", \
+                                                     \"game_id\": \"{}\", \"revision\": 3, \
+                                                     \"player_ids\": [\"{}\"]}}",
+                                                    game_id,
+                                                    player_id))
+                           .unwrap());
+        assert!(GameEvent::from_payload("{\"kind\": \"exploded\"}").is_err());
+        assert!(GameEvent::from_payload("not json").is_err());
+    }
+
+    fn next_game_event(sub: &mut Subscription, kind: GameEventKind) -> GameEvent {
+        loop {
+            match sub.next_timeout(Duration::from_secs(5)).unwrap() {
+                Some(Event::Game(e)) => {
+                    if e.kind == kind {
+                        return e;
+                    }
+                }
+                Some(Event::Reconnected) => continue,
+                None => panic!("timed out waiting for {:?}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn subscribe_works() {
+        let db = match test_db() {
+            Some(db) => db,
+            None => return,
+        };
+        let mut sub = subscribe(&DbConfig::new(&db.url, &db.url)).unwrap();
+        let conn = db.conns().w.get().unwrap();
+        let created = GameFixture::new()
+            .create(&PgRepository::new(&*conn))
+            .unwrap();
+        let e = next_game_event(&mut sub, GameEventKind::LogCreated);
+        assert_eq!(created.game.id, e.game_id);
+        assert_eq!(2, e.player_ids.len());
+
+        let update = NewGame {
+            game_version_id: &created.game.game_version_id,
+            is_finished: false,
+            game_state: "egg",
+            parent_game_id: None,
+        };
+        query::update_game(&created.game.id, &update, &*conn).unwrap();
+        let e = next_game_event(&mut sub, GameEventKind::GameUpdated);
+        assert_eq!(1, e.revision);
+
+        // Drop the listening connection, and check events are received after reconnecting.
+        conn.execute("
+            SELECT pg_terminate_backend(pid)
+            FROM pg_stat_activity
+            WHERE datname = $1
+            AND query LIKE 'LISTEN%'",
+                     &[&db.name])
+            .unwrap();
+        assert_eq!(Some(Event::Reconnected),
+                   sub.next_timeout(Duration::from_secs(5)).unwrap());
+        query::update_game_whose_turn(&created.game.id, &[1], &*conn).unwrap();
+        let e = next_game_event(&mut sub, GameEventKind::PlayersUpdated);
+        assert_eq!(1, e.player_ids.len());
+        assert_eq!(2, e.revision);
+    }
+}
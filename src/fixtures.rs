@@ -0,0 +1,285 @@
+use uuid::Uuid;
+use chrono::UTC;
+
+use errors::*;
+use models::*;
+use color::{Color, COLORS};
+use query::{UserByEmail, CreatedGameLog};
+use repository::Repository;
+
+/// A short random suffix so fixtures can be created many times in one database without
+/// violating unique constraints.
+fn suffix() -> String {
+    Uuid::new_v4().simple().to_string()[..8].to_string()
+}
+
+pub struct UserFixture {
+    pub name: String,
+    pub email: String,
+    pub pref_colors: Vec<Color>,
+}
+
+impl UserFixture {
+    pub fn new() -> Self {
+        let name = format!("user-{}", suffix());
+        UserFixture {
+            email: format!("{}@example.com", name),
+            name: name,
+            pref_colors: vec![],
+        }
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+
+    pub fn email(mut self, email: &str) -> Self {
+        self.email = email.to_string();
+        self
+    }
+
+    pub fn pref_colors(mut self, pref_colors: &[Color]) -> Self {
+        self.pref_colors = pref_colors.to_owned();
+        self
+    }
+
+    pub fn create(&self, repo: &Repository) -> Result<UserByEmail> {
+        let pref_colors: Vec<&Color> = self.pref_colors.iter().collect();
+        let user = repo.create_user(&NewUser {
+                                         name: &self.name,
+                                         pref_colors: &pref_colors,
+                                         login_confirmation: None,
+                                         login_confirmation_at: None,
+                                     })?;
+        let user_email = repo.create_user_email(&NewUserEmail {
+                                                     user_id: &user.id,
+                                                     email: &self.email,
+                                                     is_primary: true,
+                                                 })?;
+        Ok(UserByEmail {
+               user: user,
+               user_email: user_email,
+           })
+    }
+}
+
+pub struct LogFixture {
+    pub body: String,
+    pub is_public: bool,
+    pub to: Vec<usize>,
+}
+
+/// Builds a game with its type, version, users, players and logs. Every field has a default, so
+/// tests only set what they care about:
+///
+/// ```ignore
+/// let created = GameFixture::new().players(3).whose_turn(&[2]).create(&repo)?;
+/// ```
+pub struct GameFixture {
+    pub game_type_name: String,
+    pub game_version_name: String,
+    pub game_state: String,
+    pub is_finished: bool,
+    pub users: Vec<UserFixture>,
+    pub whose_turn: Vec<usize>,
+    pub eliminated: Vec<usize>,
+    pub winners: Vec<usize>,
+    pub logs: Vec<LogFixture>,
+}
+
+impl GameFixture {
+    pub fn new() -> Self {
+        GameFixture {
+            game_type_name: format!("Game {}", suffix()),
+            game_version_name: "v1".to_string(),
+            game_state: "{}".to_string(),
+            is_finished: false,
+            users: vec![UserFixture::new(), UserFixture::new()],
+            whose_turn: vec![0],
+            eliminated: vec![],
+            winners: vec![],
+            logs: vec![LogFixture {
+                           body: "The game has started".to_string(),
+                           is_public: true,
+                           to: vec![],
+                       }],
+        }
+    }
+
+    /// Replaces the users with `count` default users.
+    pub fn players(mut self, count: usize) -> Self {
+        self.users = (0..count).map(|_| UserFixture::new()).collect();
+        self
+    }
+
+    /// Replaces the users, one per position.
+    pub fn users(mut self, users: Vec<UserFixture>) -> Self {
+        self.users = users;
+        self
+    }
+
+    pub fn game_state(mut self, game_state: &str) -> Self {
+        self.game_state = game_state.to_string();
+        self
+    }
+
+    pub fn finished(mut self, is_finished: bool) -> Self {
+        self.is_finished = is_finished;
+        self
+    }
+
+    pub fn whose_turn(mut self, positions: &[usize]) -> Self {
+        self.whose_turn = positions.to_owned();
+        self
+    }
+
+    pub fn eliminated(mut self, positions: &[usize]) -> Self {
+        self.eliminated = positions.to_owned();
+        self
+    }
+
+    pub fn winners(mut self, positions: &[usize]) -> Self {
+        self.winners = positions.to_owned();
+        self
+    }
+
+    /// Adds a log, targeted at the players in the `to` positions.
+    pub fn log(mut self, body: &str, is_public: bool, to: &[usize]) -> Self {
+        self.logs.push(LogFixture {
+                           body: body.to_string(),
+                           is_public: is_public,
+                           to: to.to_owned(),
+                       });
+        self
+    }
+
+    pub fn no_logs(mut self) -> Self {
+        self.logs = vec![];
+        self
+    }
+
+    pub fn create(&self, repo: &Repository) -> Result<CreatedGameFixture> {
+        if self.users.len() > COLORS.len() {
+            return Err("game fixtures can't have more players than there are colors".into());
+        }
+        let game_type = repo.create_game_type(&NewGameType { name: &self.game_type_name })?;
+        let game_version = repo.create_game_version(&NewGameVersion {
+                                                         game_type_id: &game_type.id,
+                                                         name: &self.game_version_name,
+                                                         uri: "https://example.com/game",
+                                                         is_public: true,
+                                                         is_deprecated: false,
+                                                         min_players: 1,
+                                                         max_players: None,
+                                                     })?;
+        let game = repo.create_game(&NewGame {
+                                         game_version_id: &game_version.id,
+                                         is_finished: self.is_finished,
+                                         game_state: &self.game_state,
+                                         parent_game_id: None,
+                                     })?;
+        let mut users: Vec<UserByEmail> = vec![];
+        let mut players: Vec<GamePlayer> = vec![];
+        for (pos, u) in self.users.iter().enumerate() {
+            let user = u.create(repo)?;
+            let color = &COLORS[pos];
+            players.push(repo.create_game_player(&NewGamePlayer {
+                                                      game_id: &game.id,
+                                                      user_id: &user.user.id,
+                                                      position: pos as i32,
+                                                      color: color,
+                                                      has_accepted: true,
+                                                      is_turn: self.whose_turn.contains(&pos),
+                                                      is_eliminated: self.eliminated
+                                                          .contains(&pos),
+                                                      is_winner: self.winners.contains(&pos),
+                                                      color_pref_rank: u.pref_colors
+                                                          .iter()
+                                                          .position(|c| c == color)
+                                                          .map(|r| r as i32),
+                                                  })?);
+            users.push(user);
+        }
+        let mut logs: Vec<CreatedGameLog> = vec![];
+        for l in &self.logs {
+            let mut to: Vec<Uuid> = vec![];
+            for pos in &l.to {
+                let player = players
+                    .get(*pos)
+                    .ok_or_else::<Error, _>(|| "no player with that position exists".into())?;
+                to.push(player.id);
+            }
+            logs.push(repo.create_game_log(&NewGameLog {
+                                                game_id: &game.id,
+                                                body: &l.body,
+                                                is_public: l.is_public,
+                                                logged_at: &UTC::now().naive_utc(),
+                                            },
+                                           &to)?);
+        }
+        Ok(CreatedGameFixture {
+               game_type: game_type,
+               game_version: game_version,
+               game: game,
+               users: users,
+               players: players,
+               logs: logs,
+           })
+    }
+}
+
+/// Everything created by a `GameFixture`. `users` and `players` are ordered by position.
+pub struct CreatedGameFixture {
+    pub game_type: GameType,
+    pub game_version: GameVersion,
+    pub game: Game,
+    pub users: Vec<UserByEmail>,
+    pub players: Vec<GamePlayer>,
+    pub logs: Vec<CreatedGameLog>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::MemoryRepository;
+    use repository::PgRepository;
+    use test_support::with_test_db;
+
+    #[test]
+    fn game_fixture_works() {
+        let repo = MemoryRepository::new();
+        let created = GameFixture::new()
+            .players(3)
+            .whose_turn(&[2])
+            .log("Secret", false, &[1])
+            .create(&repo)
+            .unwrap();
+        assert_eq!(3, created.players.len());
+        assert_eq!(vec![false, false, true],
+                   created
+                       .players
+                       .iter()
+                       .map(|p| p.is_turn)
+                       .collect::<Vec<bool>>());
+        assert_eq!(2, created.logs.len());
+        assert_eq!(created.players[1].id, created.logs[1].targets[0].player_id);
+
+        // Fixtures can be created repeatedly without violating constraints.
+        assert!(GameFixture::new().create(&repo).is_ok());
+    }
+
+    #[test]
+    fn game_fixture_works_with_postgres() {
+        with_test_db(|conns| {
+            let conn = conns.w.get().unwrap();
+            let created = GameFixture::new()
+                .users(vec![UserFixture::new().pref_colors(&[Color::Green]),
+                            UserFixture::new().pref_colors(&[Color::Green])])
+                .create(&PgRepository::new(&*conn))
+                .unwrap();
+            assert_eq!(Some(0), created.players[0].color_pref_rank);
+            assert_eq!(None, created.players[1].color_pref_rank);
+        });
+    }
+}
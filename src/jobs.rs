@@ -0,0 +1,323 @@
+use postgres::GenericConnection;
+use chrono::Duration;
+use uuid::Uuid;
+
+use errors::*;
+use models::*;
+
+/// Number of times a job is attempted before it is moved to the dead letter state.
+pub static DEFAULT_MAX_ATTEMPTS: i32 = 5;
+/// Delay before the first retry of a failed job, doubled for each following retry.
+pub static BASE_RETRY_SECS: i32 = 10;
+pub static MAX_RETRY_SECS: i32 = 60 * 60;
+/// Number of times `enqueue` retries when the job holding a unique key keeps changing under it.
+pub static MAX_ENQUEUE_TRIES: usize = 5;
+
+#[derive(Debug, ToSql, FromSql, PartialEq, Eq, Clone, Copy)]
+#[postgres(name = "job_status")]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    /// The job failed on every attempt, and won't be run again unless it is requeued.
+    Dead,
+}
+
+/// Adds a job to the queue, to be run at `run_at`. Pass the transaction making the change the job
+/// is for, so the job is only queued if the change is committed.
+///
+/// If a job with the same unique key is already pending or running, that job is returned instead
+/// of queueing another.
+pub fn enqueue(job: &NewJob, conn: &GenericConnection) -> Result<Job> {
+    for _ in 0..MAX_ENQUEUE_TRIES {
+        for row in &conn.query("
+            INSERT INTO jobs (
+                kind,
+                unique_key,
+                payload,
+                run_at,
+                max_attempts
+            ) VALUES (
+                $1,
+                $2,
+                $3,
+                $4,
+                $5
+            )
+            ON CONFLICT (unique_key) WHERE status IN ('Pending', 'Running') DO NOTHING
+            RETURNING *",
+                               &job.params())? {
+            return Ok(Job::from_row(&row, ""));
+        }
+        for row in &conn.query("
+            SELECT *
+            FROM jobs
+            WHERE unique_key=$1
+            AND status IN ('Pending', 'Running')
+            LIMIT 1",
+                               &[&job.unique_key])? {
+            return Ok(Job::from_row(&row, ""));
+        }
+        // The conflicting job finished in between, so the key is free again.
+    }
+    Err(format!("unable to enqueue job with unique key {:?}, it kept conflicting",
+                job.unique_key)
+                .into())
+}
+
+/// Claims up to `limit` due jobs of the given kinds, counting an attempt for each. Running jobs
+/// which haven't sent a heartbeat for `stale_after` are assumed to have crashed and are claimed
+/// again, or marked as dead if they have no attempts left.
+///
+/// Jobs locked by concurrent claims are skipped rather than waited for.
+pub fn claim(kinds: &[&str],
+             limit: i64,
+             stale_after: Duration,
+             conn: &GenericConnection)
+             -> Result<Vec<Job>> {
+    let stale_secs = stale_after.num_milliseconds() as f64 / 1000.0;
+    conn.execute("
+        UPDATE jobs
+        SET
+            status='Dead',
+            finished_at=(now() AT TIME ZONE 'utc'),
+            last_error='worker lost'
+        WHERE id IN (
+            SELECT id
+            FROM jobs
+            WHERE kind = ANY($1)
+            AND status = 'Running'
+            AND attempts >= max_attempts
+            AND heartbeat_at <= (now() AT TIME ZONE 'utc') - $2::float8 * INTERVAL '1 second'
+            FOR UPDATE SKIP LOCKED
+        )",
+                 &[&kinds, &stale_secs])?;
+    let mut jobs: Vec<Job> = vec![];
+    for row in &conn.query("
+        UPDATE jobs
+        SET
+            status='Running',
+            attempts=attempts+1,
+            heartbeat_at=(now() AT TIME ZONE 'utc')
+        WHERE id IN (
+            SELECT id
+            FROM jobs
+            WHERE kind = ANY($1)
+            AND (
+                (status = 'Pending' AND run_at <= (now() AT TIME ZONE 'utc'))
+                OR (
+                    status = 'Running'
+                    AND attempts < max_attempts
+                    AND heartbeat_at <=
+                        (now() AT TIME ZONE 'utc') - $3::float8 * INTERVAL '1 second'
+                )
+            )
+            ORDER BY run_at
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *",
+                           &[&kinds, &limit, &stale_secs])? {
+        jobs.push(Job::from_row(&row, ""));
+    }
+    jobs.sort_by(|a, b| a.run_at.cmp(&b.run_at));
+    Ok(jobs)
+}
+
+/// Records that a claimed job is still being worked on. Returns false if the job has since been
+/// claimed again, in which case the work should be abandoned.
+pub fn heartbeat(job: &Job, conn: &GenericConnection) -> Result<bool> {
+    Ok(conn.execute("
+        UPDATE jobs
+        SET heartbeat_at=(now() AT TIME ZONE 'utc')
+        WHERE id=$1
+        AND attempts=$2
+        AND status='Running'",
+                    &[&job.id, &job.attempts])? > 0)
+}
+
+/// Marks a claimed job as done. Returns `None` if the job has since been claimed again.
+pub fn complete(job: &Job, conn: &GenericConnection) -> Result<Option<Job>> {
+    for row in &conn.query("
+        UPDATE jobs
+        SET
+            status='Done',
+            finished_at=(now() AT TIME ZONE 'utc')
+        WHERE id=$1
+        AND attempts=$2
+        AND status='Running'
+        RETURNING *",
+                           &[&job.id, &job.attempts])? {
+        return Ok(Some(Job::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+/// Records a failed attempt at a claimed job. The job is retried with backoff until it has been
+/// attempted `max_attempts` times, after which it is marked as dead. Returns `None` if the job has
+/// since been claimed again.
+pub fn fail(job: &Job, error: &str, conn: &GenericConnection) -> Result<Option<Job>> {
+    for row in &conn.query("
+        UPDATE jobs
+        SET
+            status=CASE
+                WHEN attempts >= max_attempts THEN 'Dead'::job_status
+                ELSE 'Pending'::job_status
+            END,
+            finished_at=CASE
+                WHEN attempts >= max_attempts THEN (now() AT TIME ZONE 'utc')
+            END,
+            last_error=$3,
+            run_at=(now() AT TIME ZONE 'utc') +
+                LEAST(power(2, attempts - 1) * $4::int, $5::int) * INTERVAL '1 second'
+        WHERE id=$1
+        AND attempts=$2
+        AND status='Running'
+        RETURNING *",
+                           &[&job.id, &job.attempts, &error, &BASE_RETRY_SECS, &MAX_RETRY_SECS])? {
+        return Ok(Some(Job::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+pub fn find_dead_jobs(kind: &str, conn: &GenericConnection) -> Result<Vec<Job>> {
+    let mut jobs: Vec<Job> = vec![];
+    for row in &conn.query("
+        SELECT *
+        FROM jobs
+        WHERE kind=$1
+        AND status='Dead'
+        ORDER BY finished_at",
+                           &[&kind])? {
+        jobs.push(Job::from_row(&row, ""));
+    }
+    Ok(jobs)
+}
+
+/// Moves a dead job back into the queue to run now, with its attempts reset. This fails if another
+/// job with the same unique key has been queued since.
+pub fn requeue_dead_job(id: &Uuid, conn: &GenericConnection) -> Result<Option<Job>> {
+    for row in &conn.query("
+        UPDATE jobs
+        SET
+            status='Pending',
+            attempts=0,
+            run_at=(now() AT TIME ZONE 'utc'),
+            finished_at=NULL
+        WHERE id=$1
+        AND status='Dead'
+        RETURNING *",
+                           &[id])? {
+        return Ok(Some(Job::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, UTC};
+    use serde_json::Value;
+    use test_support::with_db;
+
+    fn new_job<'a>(unique_key: Option<&'a str>,
+                   payload: &'a Value,
+                   run_at: &'a NaiveDateTime)
+                   -> NewJob<'a> {
+        NewJob {
+            kind: "archive_game",
+            unique_key: unique_key,
+            payload: payload,
+            run_at: run_at,
+            max_attempts: 2,
+        }
+    }
+
+    #[test]
+    fn job_queue_works() {
+        with_db(|conn| {
+            let payload = Value::Null;
+            let now = UTC::now().naive_utc() - Duration::seconds(1);
+            let later = now + Duration::hours(1);
+            let job = enqueue(&new_job(Some("game-1"), &payload, &now), conn).unwrap();
+            assert_eq!(job.id,
+                       enqueue(&new_job(Some("game-1"), &payload, &now), conn)
+                           .unwrap()
+                           .id);
+            enqueue(&new_job(None, &payload, &later), conn).unwrap();
+
+            assert!(claim(&["other"], 10, Duration::minutes(1), conn)
+                        .unwrap()
+                        .is_empty());
+            let claimed = claim(&["archive_game"], 10, Duration::minutes(1), conn).unwrap();
+            assert_eq!(vec![job.id], claimed.iter().map(|j| j.id).collect::<Vec<Uuid>>());
+            let job = claimed[0].clone();
+            assert!(heartbeat(&job, conn).unwrap());
+
+            let failed = fail(&job, "disk full", conn).unwrap().unwrap();
+            assert_eq!(JobStatus::Pending, failed.status);
+            assert!(!heartbeat(&job, conn).unwrap());
+
+            // Bring the retry forward, then fail it for the last time.
+            conn.execute("UPDATE jobs SET run_at=created_at WHERE id=$1", &[&job.id])
+                .unwrap();
+            let job = claim(&["archive_game"], 10, Duration::minutes(1), conn)
+                .unwrap()
+                .remove(0);
+            let dead = fail(&job, "disk full", conn).unwrap().unwrap();
+            assert_eq!(JobStatus::Dead, dead.status);
+            assert_eq!(vec![job.id],
+                       find_dead_jobs("archive_game", conn)
+                           .unwrap()
+                           .iter()
+                           .map(|j| j.id)
+                           .collect::<Vec<Uuid>>());
+
+            // The unique key is free once the job is no longer queued.
+            let next = enqueue(&new_job(Some("game-1"), &payload, &now), conn).unwrap();
+            assert!(next.id != job.id);
+            let claimed = claim(&["archive_game"], 10, Duration::minutes(1), conn).unwrap();
+            assert_eq!(vec![next.id], claimed.iter().map(|j| j.id).collect::<Vec<Uuid>>());
+            assert_eq!(JobStatus::Done,
+                       complete(&claimed[0], conn).unwrap().unwrap().status);
+
+            requeue_dead_job(&job.id, conn).unwrap().unwrap();
+            let claimed = claim(&["archive_game"], 10, Duration::minutes(1), conn).unwrap();
+            assert_eq!(vec![job.id], claimed.iter().map(|j| j.id).collect::<Vec<Uuid>>());
+            assert_eq!(1, claimed[0].attempts);
+        });
+    }
+
+    #[test]
+    fn stale_jobs_are_reclaimed() {
+        with_db(|conn| {
+            let payload = Value::Null;
+            let now = UTC::now().naive_utc() - Duration::seconds(1);
+            enqueue(&new_job(None, &payload, &now), conn).unwrap();
+            let first = claim(&["archive_game"], 10, Duration::minutes(1), conn)
+                .unwrap()
+                .remove(0);
+            assert!(claim(&["archive_game"], 10, Duration::minutes(1), conn)
+                        .unwrap()
+                        .is_empty());
+            let second = claim(&["archive_game"], 10, Duration::zero(), conn)
+                .unwrap()
+                .remove(0);
+            assert_eq!(first.id, second.id);
+            // The first worker has lost the job.
+            assert_eq!(None, complete(&first, conn).unwrap());
+            assert!(complete(&second, conn).unwrap().is_some());
+
+            // A stale job on its last attempt is dead rather than claimed again.
+            let job = enqueue(&new_job(None, &payload, &now), conn).unwrap();
+            claim(&["archive_game"], 10, Duration::zero(), conn).unwrap();
+            claim(&["archive_game"], 10, Duration::zero(), conn).unwrap();
+            assert!(claim(&["archive_game"], 10, Duration::zero(), conn)
+                        .unwrap()
+                        .is_empty());
+            let dead = find_dead_jobs("archive_game", conn).unwrap();
+            assert_eq!(vec![job.id], dead.iter().map(|j| j.id).collect::<Vec<Uuid>>());
+            assert_eq!(Some("worker lost".to_string()), dead[0].last_error);
+        });
+    }
+}
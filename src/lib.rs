@@ -6,16 +6,30 @@ extern crate chrono;
 extern crate postgres;
 #[macro_use]
 extern crate postgres_derive;
+#[macro_use]
+extern crate brdgme_db_derive;
 extern crate r2d2;
 extern crate r2d2_postgres;
+extern crate openssl;
 #[macro_use]
 extern crate error_chain;
 extern crate rand;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate serde_json;
+extern crate fallible_iterator;
 
 extern crate brdgme_cmd;
 extern crate brdgme_color;
+#[cfg(feature = "async")]
+extern crate tokio_postgres;
+#[cfg(feature = "async")]
+extern crate tokio_core;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate futures_state_stream;
 
 pub mod errors {
     error_chain!{
@@ -24,40 +38,127 @@ pub mod errors {
             EnvVar(::std::env::VarError);
             Chrono(::chrono::ParseError);
         }
+
+        errors {
+            UnknownColor(color: String) {
+                description("unknown color")
+                display("unknown color: '{}'", color)
+            }
+            DuplicateColor(color: String) {
+                description("duplicate color")
+                display("color appears more than once: '{}'", color)
+            }
+            InvalidPlayerCount(count: usize, min: i32, max: Option<i32>) {
+                description("invalid player count")
+                display("{} players is invalid, must be at least {}{}",
+                        count,
+                        min,
+                        max.map(|m| format!(" and at most {}", m)).unwrap_or_default())
+            }
+            UniqueViolation(constraint: String) {
+                description("unique constraint violated")
+                display("unique constraint violated: '{}'", constraint)
+            }
+            ForeignKeyViolation(constraint: String) {
+                description("foreign key constraint violated")
+                display("foreign key constraint violated: '{}'", constraint)
+            }
+            IncompleteGameVersionMigration(run_id: ::uuid::Uuid,
+                                           migrations: Vec<::models::GameVersionMigration>) {
+                description("game version migration did not complete")
+                display("game version migration run {} stopped after migrating {} games",
+                        run_id,
+                        migrations.len())
+            }
+        }
+    }
+
+    use postgres::error::{Error as PgError, SqlState};
+
+    /// Finds the SQL state of the database error behind `e`, looking through any context added
+    /// with `chain_err`.
+    pub fn sql_state(e: &Error) -> Option<&SqlState> {
+        match *e.kind() {
+            ErrorKind::Postgres(PgError::Db(ref db)) => Some(&db.code),
+            ErrorKind::Postgres(_) => None,
+            _ => {
+                e.1
+                    .next_error
+                    .as_ref()
+                    .and_then(|next| next.downcast_ref::<Error>())
+                    .and_then(sql_state)
+            }
+        }
+    }
+
+    /// Like `sql_state`, but gives the name of the constraint which was violated, if any.
+    pub fn constraint(e: &Error) -> Option<&str> {
+        match *e.kind() {
+            ErrorKind::Postgres(PgError::Db(ref db)) => db.constraint.as_ref().map(|c| c.as_ref()),
+            ErrorKind::Postgres(_) => None,
+            _ => {
+                e.1
+                    .next_error
+                    .as_ref()
+                    .and_then(|next| next.downcast_ref::<Error>())
+                    .and_then(constraint)
+            }
+        }
     }
 }
 pub mod query;
+#[cfg(feature = "async")]
+pub mod async_client;
+#[cfg(feature = "async")]
+pub mod async_query;
 pub mod models;
 pub mod color;
+pub mod config;
+pub mod events;
+pub mod jobs;
+pub mod memory;
+pub mod outbox;
+pub mod ratings;
+pub mod repository;
+pub mod session;
+pub mod sql;
+pub mod transaction;
+pub mod turns;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fixtures;
 
-use r2d2_postgres::{TlsMode, PostgresConnectionManager};
-use std::env;
+use r2d2_postgres::PostgresConnectionManager;
 use errors::*;
+use config::DbConfig;
+use session::{Session, DEFAULT_MAX_REPLICA_LAG};
 
 pub struct Connections {
     pub w: r2d2::Pool<PostgresConnectionManager>,
     pub r: r2d2::Pool<PostgresConnectionManager>,
 }
 
+impl Connections {
+    /// Starts a session which routes reads to the replica and writes to the primary.
+    pub fn session(&self) -> Session {
+        Session::new(self, *DEFAULT_MAX_REPLICA_LAG)
+    }
+}
+
 pub fn connect(w_addr: &str, r_addr: &str) -> Result<Connections> {
-    Ok(Connections {
-           w: conn(w_addr)?,
-           r: conn(r_addr)?,
-       })
+    connect_config(&DbConfig::new(w_addr, r_addr))
 }
 
 pub fn connect_env() -> Result<Connections> {
-    let w_addr = env::var("DATABASE_URL")
-        .chain_err(|| "DATABASE_URL not set")?;
-    connect(&w_addr,
-            &env::var("DATABASE_URL_R").unwrap_or(w_addr.to_owned()))
+    connect_config(&DbConfig::from_env()?)
 }
 
-fn conn(addr: &str) -> Result<r2d2::Pool<PostgresConnectionManager>> {
-    r2d2::Pool::new(r2d2::Config::default(),
-                    PostgresConnectionManager::new(addr, TlsMode::None)
-                        .chain_err(|| "unable to create connection manager")?)
-            .chain_err(|| "unable to connect to database")
+pub fn connect_config(config: &DbConfig) -> Result<Connections> {
+    Ok(Connections {
+           w: config.pool(&config.w_addr, &config.w_pool)?,
+           r: config.pool(&config.r_addr, &config.r_pool)?,
+       })
 }
 
 #[cfg(test)]
@@ -0,0 +1,936 @@
+use uuid::Uuid;
+use chrono::{NaiveDateTime, UTC};
+
+use brdgme_cmd::cli::CliLog;
+
+use std::cell::RefCell;
+
+use color;
+use errors::*;
+use models::*;
+use query::{UserByEmail, CreatedGame, UpdatedGame, CreatedGameLog, Placing, CONFIRMATION_EXPIRY,
+            TOKEN_EXPIRY, check_placings, create_players_for_users, rand_code};
+use repository::Repository;
+
+#[derive(Default, Clone)]
+struct State {
+    users: Vec<User>,
+    user_emails: Vec<UserEmail>,
+    user_auth_tokens: Vec<UserAuthToken>,
+    game_types: Vec<GameType>,
+    game_versions: Vec<GameVersion>,
+    games: Vec<Game>,
+    game_players: Vec<GamePlayer>,
+    game_logs: Vec<GameLog>,
+    game_log_targets: Vec<GameLogTarget>,
+}
+
+/// An in-memory `Repository` for tests which don't need a database. It enforces the same unique
+/// and foreign key constraints as the schema, and reports violations with the same constraint
+/// names.
+///
+/// Outbox messages and ratings aren't kept, so login codes aren't sent and finished games aren't
+/// rated.
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: RefCell<State>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f`, restoring the state from before it ran if it fails, like a transaction.
+    fn atomic<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce() -> Result<T>
+    {
+        let before = self.state.borrow().clone();
+        let result = f();
+        if result.is_err() {
+            *self.state.borrow_mut() = before;
+        }
+        result
+    }
+
+    fn create_user_by_email(&self, email: &str) -> Result<UserByEmail> {
+        let user = self.create_user(&NewUser {
+                                         name: email,
+                                         pref_colors: &[],
+                                         login_confirmation: None,
+                                         login_confirmation_at: None,
+                                     })?;
+        let user_email = self.create_user_email(&NewUserEmail {
+                                                     user_id: &user.id,
+                                                     email: email,
+                                                     is_primary: true,
+                                                 })?;
+        Ok(UserByEmail {
+               user: user,
+               user_email: user_email,
+           })
+    }
+
+    fn create_game_users(&self, ids: &[Uuid], emails: &[String]) -> Result<Vec<UserByEmail>> {
+        let mut users: Vec<UserByEmail> = vec![];
+        for id in ids {
+            users.push(self.find_user_with_primary_email(id)?
+                           .ok_or_else::<Error, _>(|| "unable to find user".into())?);
+        }
+        for email in emails {
+            users.push(match self.find_user_with_primary_email_by_email(email)? {
+                           Some(u) => u,
+                           None => self.create_user_by_email(email)?,
+                       });
+        }
+        Ok(users)
+    }
+}
+
+fn now() -> NaiveDateTime {
+    UTC::now().naive_utc()
+}
+
+fn unique(violated: bool, constraint: &str) -> Result<()> {
+    if violated {
+        return Err(ErrorKind::UniqueViolation(constraint.to_string()).into());
+    }
+    Ok(())
+}
+
+fn foreign_key(exists: bool, constraint: &str) -> Result<()> {
+    if !exists {
+        return Err(ErrorKind::ForeignKeyViolation(constraint.to_string()).into());
+    }
+    Ok(())
+}
+
+fn in_positions(player: &GamePlayer, positions: &[usize]) -> bool {
+    positions.contains(&(player.position as usize))
+}
+
+impl State {
+    fn user_by_email(&self, user_id: &Uuid, email: &UserEmail) -> Option<UserByEmail> {
+        self.users
+            .iter()
+            .find(|u| &u.id == user_id)
+            .map(|u| {
+                     UserByEmail {
+                         user: u.clone(),
+                         user_email: email.clone(),
+                     }
+                 })
+    }
+
+    fn update_game_players<F>(&mut self, game_id: &Uuid, f: F) -> Vec<GamePlayer>
+        where F: Fn(&mut GamePlayer)
+    {
+        let mut updated = vec![];
+        let mut changed = false;
+        for p in self.game_players
+                .iter_mut()
+                .filter(|p| &p.game_id == game_id) {
+            let before = p.clone();
+            f(p);
+            changed = changed || *p != before;
+            p.updated_at = now();
+            updated.push(p.clone());
+        }
+        // Player changes bump the game's revision, as they do in Postgres.
+        if changed {
+            if let Some(g) = self.games.iter_mut().find(|g| &g.id == game_id) {
+                g.revision += 1;
+            }
+        }
+        updated
+    }
+}
+
+impl Repository for MemoryRepository {
+    fn create_user(&self, new_user: &NewUser) -> Result<User> {
+        let mut state = self.state.borrow_mut();
+        unique(state.users.iter().any(|u| u.name == new_user.name),
+               "users_name_key")?;
+        let mut pref_colors = vec![];
+        for c in new_user.pref_colors.iter() {
+            if pref_colors.contains(*c) {
+                return Err(ErrorKind::DuplicateColor(format!("{:?}", c)).into());
+            }
+            pref_colors.push(**c);
+        }
+        let user = User {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            name: new_user.name.to_string(),
+            pref_colors: pref_colors,
+            login_confirmation: new_user.login_confirmation.map(|c| c.to_string()),
+            login_confirmation_at: new_user.login_confirmation_at.cloned(),
+        };
+        state.users.push(user.clone());
+        Ok(user)
+    }
+
+    fn find_user(&self, id: &Uuid) -> Result<Option<User>> {
+        Ok(self.state
+               .borrow()
+               .users
+               .iter()
+               .find(|u| &u.id == id)
+               .cloned())
+    }
+
+    fn set_user_pref_colors(&self, user_id: &Uuid, colors: &[&str]) -> Result<User> {
+        let prefs = color::parse_prefs(colors)?;
+        let mut state = self.state.borrow_mut();
+        let user = state
+            .users
+            .iter_mut()
+            .find(|u| &u.id == user_id)
+            .ok_or_else::<Error, _>(|| "could not find user".into())?;
+        user.pref_colors = prefs;
+        user.updated_at = now();
+        Ok(user.clone())
+    }
+
+    fn create_user_email(&self, ue: &NewUserEmail) -> Result<UserEmail> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state.users.iter().any(|u| &u.id == ue.user_id),
+                    "user_emails_user_id_fkey")?;
+        unique(state.user_emails.iter().any(|e| e.email == ue.email),
+               "user_emails_email_key")?;
+        let user_email = UserEmail {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            user_id: ue.user_id.to_owned(),
+            email: ue.email.to_string(),
+            is_primary: ue.is_primary,
+        };
+        state.user_emails.push(user_email.clone());
+        Ok(user_email)
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<UserByEmail>> {
+        let state = self.state.borrow();
+        Ok(state
+               .user_emails
+               .iter()
+               .find(|ue| ue.email == email)
+               .and_then(|ue| state.user_by_email(&ue.user_id, ue)))
+    }
+
+    fn find_user_with_primary_email(&self, id: &Uuid) -> Result<Option<UserByEmail>> {
+        let state = self.state.borrow();
+        Ok(state
+               .user_emails
+               .iter()
+               .find(|ue| &ue.user_id == id && ue.is_primary)
+               .and_then(|ue| state.user_by_email(&ue.user_id, ue)))
+    }
+
+    fn find_user_with_primary_email_by_email(&self, email: &str) -> Result<Option<UserByEmail>> {
+        let user_id = match self.find_user_by_email(email)? {
+            Some(u) => u.user.id,
+            None => return Ok(None),
+        };
+        self.find_user_with_primary_email(&user_id)
+    }
+
+    fn user_login_request(&self, email: &str) -> Result<String> {
+        self.atomic(|| {
+            let user = match self.find_user_by_email(email)? {
+                Some(u) => u.user,
+                None => self.create_user_by_email(email)?.user,
+            };
+            if let (Some(uc), Some(at)) = (user.login_confirmation, user.login_confirmation_at) {
+                if at + *CONFIRMATION_EXPIRY > now() {
+                    return Ok(uc);
+                }
+            }
+            let code = rand_code();
+            let mut state = self.state.borrow_mut();
+            let user = state
+                .users
+                .iter_mut()
+                .find(|u| u.id == user.id)
+                .ok_or_else::<Error, _>(|| "could not update login confirmation".into())?;
+            user.login_confirmation = Some(code.clone());
+            user.login_confirmation_at = Some(now());
+            user.updated_at = now();
+            Ok(code)
+        })
+    }
+
+    fn user_login_confirm(&self,
+                          email: &str,
+                          confirmation: &str)
+                          -> Result<Option<UserAuthToken>> {
+        let user = match self.find_user_by_email(email)? {
+            Some(ube) => ube.user,
+            None => return Ok(None),
+        };
+        Ok(match (user.login_confirmation, user.login_confirmation_at) {
+               (Some(ref uc), Some(at)) if at + *CONFIRMATION_EXPIRY > now() &&
+                                           uc == confirmation => {
+                   Some(self.create_auth_token(&user.id)?)
+               }
+               _ => None,
+           })
+    }
+
+    fn create_auth_token(&self, user_id: &Uuid) -> Result<UserAuthToken> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state.users.iter().any(|u| &u.id == user_id),
+                    "user_auth_tokens_user_id_fkey")?;
+        let token = UserAuthToken {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            user_id: user_id.to_owned(),
+        };
+        state.user_auth_tokens.push(token.clone());
+        Ok(token)
+    }
+
+    fn authenticate(&self, email: &str, token: &Uuid) -> Result<Option<UserByEmail>> {
+        let state = self.state.borrow();
+        let uat = match state
+                  .user_auth_tokens
+                  .iter()
+                  .find(|t| &t.id == token && t.created_at > now() - *TOKEN_EXPIRY) {
+            Some(uat) => uat,
+            None => return Ok(None),
+        };
+        Ok(state
+               .user_emails
+               .iter()
+               .find(|ue| ue.email == email && ue.user_id == uat.user_id)
+               .and_then(|ue| state.user_by_email(&ue.user_id, ue)))
+    }
+
+    fn create_game_type(&self, new_game_type: &NewGameType) -> Result<GameType> {
+        let mut state = self.state.borrow_mut();
+        unique(state
+                   .game_types
+                   .iter()
+                   .any(|gt| gt.name == new_game_type.name),
+               "game_types_name_key")?;
+        let game_type = GameType {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            name: new_game_type.name.to_string(),
+        };
+        state.game_types.push(game_type.clone());
+        Ok(game_type)
+    }
+
+    fn create_game_version(&self, new_game_version: &NewGameVersion) -> Result<GameVersion> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state
+                        .game_types
+                        .iter()
+                        .any(|gt| &gt.id == new_game_version.game_type_id),
+                    "game_versions_game_type_id_fkey")?;
+        unique(state
+                   .game_versions
+                   .iter()
+                   .any(|gv| {
+                            &gv.game_type_id == new_game_version.game_type_id &&
+                            gv.name == new_game_version.name
+                        }),
+               "game_versions_game_type_id_name_key")?;
+        let game_version = GameVersion {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            game_type_id: new_game_version.game_type_id.to_owned(),
+            name: new_game_version.name.to_string(),
+            uri: new_game_version.uri.to_string(),
+            is_public: new_game_version.is_public,
+            is_deprecated: new_game_version.is_deprecated,
+            min_players: new_game_version.min_players,
+            max_players: new_game_version.max_players,
+        };
+        state.game_versions.push(game_version.clone());
+        Ok(game_version)
+    }
+
+    fn find_game_version(&self, id: &Uuid) -> Result<Option<GameVersion>> {
+        Ok(self.state
+               .borrow()
+               .game_versions
+               .iter()
+               .find(|gv| &gv.id == id)
+               .cloned())
+    }
+
+    fn create_game(&self, new_game: &NewGame) -> Result<Game> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state
+                        .game_versions
+                        .iter()
+                        .any(|gv| &gv.id == new_game.game_version_id),
+                    "games_game_version_id_fkey")?;
+        if let Some(parent_game_id) = new_game.parent_game_id {
+            foreign_key(state.games.iter().any(|g| &g.id == parent_game_id),
+                        "games_parent_game_id_fkey")?;
+            unique(state
+                       .games
+                       .iter()
+                       .any(|g| g.parent_game_id.as_ref() == Some(parent_game_id)),
+                   "games_parent_game_id_key")?;
+        }
+        let game = Game {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            game_version_id: new_game.game_version_id.to_owned(),
+            is_finished: new_game.is_finished,
+            game_state: new_game.game_state.to_string(),
+            revision: 0,
+            time_limit_kind: None,
+            time_limit_secs: None,
+            abandoned_at: None,
+            abandon_reason: None,
+            parent_game_id: new_game.parent_game_id.cloned(),
+            finished_at: if new_game.is_finished { Some(now()) } else { None },
+        };
+        state.games.push(game.clone());
+        Ok(game)
+    }
+
+    fn find_game(&self, id: &Uuid) -> Result<Option<Game>> {
+        Ok(self.state
+               .borrow()
+               .games
+               .iter()
+               .find(|g| &g.id == id)
+               .cloned())
+    }
+
+    fn update_game(&self, id: &Uuid, update: &NewGame) -> Result<Option<Game>> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state
+                        .game_versions
+                        .iter()
+                        .any(|gv| &gv.id == update.game_version_id),
+                    "games_game_version_id_fkey")?;
+        if let Some(parent_game_id) = update.parent_game_id {
+            foreign_key(state.games.iter().any(|g| &g.id == parent_game_id),
+                        "games_parent_game_id_fkey")?;
+            unique(state
+                       .games
+                       .iter()
+                       .any(|g| &g.id != id && g.parent_game_id.as_ref() == Some(parent_game_id)),
+                   "games_parent_game_id_key")?;
+        }
+        Ok(state
+               .games
+               .iter_mut()
+               .find(|g| &g.id == id)
+               .map(|g| {
+                        g.game_version_id = update.game_version_id.to_owned();
+                        g.parent_game_id = update.parent_game_id.cloned();
+                        if !update.is_finished {
+                            g.finished_at = None;
+                        } else if !g.is_finished {
+                            g.finished_at = Some(now());
+                        }
+                        g.is_finished = update.is_finished;
+                        g.game_state = update.game_state.to_string();
+                        g.revision += 1;
+                        g.updated_at = now();
+                        g.clone()
+                    }))
+    }
+
+    fn create_game_with_users(&self,
+                              new_game: &NewGame,
+                              whose_turn: &[usize],
+                              eliminated: &[usize],
+                              winners: &[usize],
+                              creator_id: &Uuid,
+                              opponent_ids: &[Uuid],
+                              opponent_emails: &[String])
+                              -> Result<CreatedGame> {
+        self.atomic(|| {
+            let game_version = self.find_game_version(new_game.game_version_id)?
+                .ok_or_else::<Error, _>(|| "could not find game version".into())?;
+            let player_count = 1 + opponent_ids.len() + opponent_emails.len();
+            if !game_version.supports_player_count(player_count) {
+                return Err(ErrorKind::InvalidPlayerCount(player_count,
+                                                         game_version.min_players,
+                                                         game_version.max_players)
+                                   .into());
+            }
+            let creator = self.find_user(creator_id)?
+                .ok_or_else::<Error, _>(|| "could not find creator".into())?;
+            let opponents = self.create_game_users(opponent_ids, opponent_emails)?;
+            let mut users: Vec<User> = opponents.iter().map(|o| o.user.clone()).collect();
+            users.push(creator);
+            let game = self.create_game(new_game)?;
+            let players = create_players_for_users(&game.id,
+                                                   users,
+                                                   creator_id,
+                                                   whose_turn,
+                                                   eliminated,
+                                                   winners,
+                                                   |players| self.create_game_players(players))?;
+            Ok(CreatedGame {
+                   game: game,
+                   opponents: opponents,
+                   players: players,
+               })
+        })
+    }
+
+    fn update_game_and_players(&self,
+                               game_id: &Uuid,
+                               update: &NewGame,
+                               whose_turn: &[usize],
+                               eliminated: &[usize],
+                               winners: &[usize])
+                               -> Result<UpdatedGame> {
+        self.atomic(|| {
+            let whose_turn = self.update_game_whose_turn(game_id, whose_turn)?;
+            let eliminated = self.update_game_eliminated(game_id, eliminated)?;
+            let winners = self.update_game_winners(game_id, winners)?;
+            Ok(UpdatedGame {
+                   game: self.update_game(game_id, update)?,
+                   whose_turn: whose_turn,
+                   eliminated: eliminated,
+                   winners: winners,
+               })
+        })
+    }
+
+    fn create_game_player(&self, player: &NewGamePlayer) -> Result<GamePlayer> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state.games.iter().any(|g| &g.id == player.game_id),
+                    "game_players_game_id_fkey")?;
+        foreign_key(state.users.iter().any(|u| &u.id == player.user_id),
+                    "game_players_user_id_fkey")?;
+        {
+            let in_game: Vec<&GamePlayer> = state
+                .game_players
+                .iter()
+                .filter(|p| &p.game_id == player.game_id)
+                .collect();
+            unique(in_game.iter().any(|p| &p.user_id == player.user_id),
+                   "game_players_game_id_user_id_key")?;
+            unique(in_game.iter().any(|p| &p.color == player.color),
+                   "game_players_game_id_color_key")?;
+            unique(in_game.iter().any(|p| p.position == player.position),
+                   "game_players_game_id_position_key")?;
+        }
+        let game_player = GamePlayer {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            game_id: player.game_id.to_owned(),
+            user_id: player.user_id.to_owned(),
+            position: player.position,
+            color: player.color.to_owned(),
+            has_accepted: player.has_accepted,
+            is_turn: player.is_turn,
+            is_eliminated: player.is_eliminated,
+            is_winner: player.is_winner,
+            color_pref_rank: player.color_pref_rank,
+            color_pref_known: true,
+            turn_started_at: if player.is_turn { Some(now()) } else { None },
+            turn_reminded_at: None,
+            time_bank_secs: None,
+            eliminated_reason: None,
+            place: None,
+            score: None,
+        };
+        state.game_players.push(game_player.clone());
+        Ok(game_player)
+    }
+
+    fn create_game_players(&self, players: &[NewGamePlayer]) -> Result<Vec<GamePlayer>> {
+        self.atomic(|| players.iter().map(|p| self.create_game_player(p)).collect())
+    }
+
+    fn find_game_players_by_game(&self, game_id: &Uuid) -> Result<Vec<GamePlayer>> {
+        Ok(self.state
+               .borrow()
+               .game_players
+               .iter()
+               .filter(|p| &p.game_id == game_id)
+               .cloned()
+               .collect())
+    }
+
+    fn update_game_whose_turn(&self, id: &Uuid, positions: &[usize]) -> Result<Vec<GamePlayer>> {
+        Ok(self.state
+               .borrow_mut()
+               .update_game_players(id, |p| {
+            let is_turn = in_positions(p, positions);
+            if !is_turn {
+                p.turn_started_at = None;
+                p.turn_reminded_at = None;
+            } else if !p.is_turn {
+                p.turn_started_at = Some(now());
+            }
+            p.is_turn = is_turn;
+        }))
+    }
+
+    fn update_game_eliminated(&self, id: &Uuid, positions: &[usize]) -> Result<Vec<GamePlayer>> {
+        Ok(self.state
+               .borrow_mut()
+               .update_game_players(id, |p| {
+            p.is_eliminated = in_positions(p, positions);
+            if !p.is_eliminated {
+                p.eliminated_reason = None;
+            }
+        }))
+    }
+
+    fn update_game_winners(&self, id: &Uuid, positions: &[usize]) -> Result<Vec<GamePlayer>> {
+        Ok(self.state
+               .borrow_mut()
+               .update_game_players(id, |p| {
+                                        p.is_winner = in_positions(p, positions);
+                                        p.place = None;
+                                        p.score = None;
+                                    }))
+    }
+
+    fn update_game_placings(&self, id: &Uuid, placings: &[Placing]) -> Result<Vec<GamePlayer>> {
+        check_placings(placings, &self.find_game_players_by_game(id)?)?;
+        Ok(self.state
+               .borrow_mut()
+               .update_game_players(id, |p| {
+            let placing = placings
+                .iter()
+                .find(|pl| pl.position == p.position as usize);
+            p.place = placing.map(|pl| pl.place as i32);
+            p.score = placing.and_then(|pl| pl.score);
+            p.is_winner = p.place == Some(1);
+        }))
+    }
+
+    fn create_game_log(&self, log: &NewGameLog, to: &[Uuid]) -> Result<CreatedGameLog> {
+        let mut state = self.state.borrow_mut();
+        foreign_key(state.games.iter().any(|g| &g.id == log.game_id),
+                    "game_logs_game_id_fkey")?;
+        for player_id in to {
+            foreign_key(state.game_players.iter().any(|p| &p.id == player_id),
+                        "game_log_targets_player_id_fkey")?;
+        }
+        let game_log = GameLog {
+            id: Uuid::new_v4(),
+            created_at: now(),
+            updated_at: now(),
+            game_id: log.game_id.to_owned(),
+            body: log.body.to_string(),
+            is_public: log.is_public,
+            logged_at: log.logged_at.to_owned(),
+        };
+        let targets: Vec<GameLogTarget> = to.iter()
+            .map(|player_id| {
+                     GameLogTarget {
+                         id: Uuid::new_v4(),
+                         created_at: now(),
+                         updated_at: now(),
+                         game_log_id: game_log.id,
+                         player_id: player_id.to_owned(),
+                     }
+                 })
+            .collect();
+        state.game_logs.push(game_log.clone());
+        state.game_log_targets.extend(targets.iter().cloned());
+        Ok(CreatedGameLog {
+               game_log: game_log,
+               targets: targets,
+           })
+    }
+
+    fn create_game_logs_from_cli(&self,
+                                 game_id: &Uuid,
+                                 logs: Vec<CliLog>)
+                                 -> Result<Vec<CreatedGameLog>> {
+        self.atomic(|| {
+            let players = self.find_game_players_by_game(game_id)?;
+            let mut created: Vec<CreatedGameLog> = vec![];
+            for l in &logs {
+                let mut to: Vec<Uuid> = vec![];
+                for t in &l.to {
+                    to.push(players
+                                .iter()
+                                .find(|p| p.position as usize == *t)
+                                .map(|p| p.id)
+                                .ok_or_else::<Error, _>(|| {
+                                                            "no player with that position exists"
+                                                                .into()
+                                                        })?);
+                }
+                created.push(self.create_game_log(&NewGameLog {
+                                                       game_id: game_id,
+                                                       body: &l.content,
+                                                       is_public: l.public,
+                                                       logged_at: &l.at,
+                                                   },
+                                                  &to)?);
+            }
+            Ok(created)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color::Color;
+
+    fn game(repo: &MemoryRepository) -> Game {
+        let game_type = repo.create_game_type(&NewGameType { name: "Lost Cities" })
+            .unwrap();
+        let game_version = repo.create_game_version(&NewGameVersion {
+                                                         game_type_id: &game_type.id,
+                                                         uri: "https://example.com/lost-cities-1",
+                                                         name: "v1",
+                                                         is_public: true,
+                                                         is_deprecated: false,
+                                                         min_players: 2,
+                                                         max_players: Some(2),
+                                                     })
+            .unwrap();
+        repo.create_game(&NewGame {
+                              game_version_id: &game_version.id,
+                              is_finished: false,
+                              game_state: "egg",
+                              parent_game_id: None,
+                          })
+            .unwrap()
+    }
+
+    fn user(repo: &MemoryRepository, name: &str) -> User {
+        repo.create_user(&NewUser {
+                              name: name,
+                              pref_colors: &[],
+                              login_confirmation: None,
+                              login_confirmation_at: None,
+                          })
+            .unwrap()
+    }
+
+    fn new_player<'a>(game_id: &'a Uuid,
+                      user_id: &'a Uuid,
+                      position: i32,
+                      color: &'a Color)
+                      -> NewGamePlayer<'a> {
+        NewGamePlayer {
+            game_id: game_id,
+            user_id: user_id,
+            position: position,
+            color: color,
+            has_accepted: true,
+            is_turn: false,
+            is_eliminated: false,
+            is_winner: false,
+            color_pref_rank: None,
+        }
+    }
+
+    fn assert_unique_violation<T>(result: Result<T>, expected: &str) {
+        match result {
+            Err(Error(ErrorKind::UniqueViolation(ref c), _)) => assert_eq!(expected, c),
+            _ => panic!("expected unique violation of {}", expected),
+        }
+    }
+
+    #[test]
+    fn users_and_emails_are_unique() {
+        let repo = MemoryRepository::new();
+        let u = user(&repo, "beefsack");
+        assert_unique_violation(repo.create_user(&NewUser {
+                                                      name: "beefsack",
+                                                      pref_colors: &[],
+                                                      login_confirmation: None,
+                                                      login_confirmation_at: None,
+                                                  }),
+                                "users_name_key");
+        repo.create_user_email(&NewUserEmail {
+                                    user_id: &u.id,
+                                    email: "beefsack@gmail.com",
+                                    is_primary: true,
+                                })
+            .unwrap();
+        assert_unique_violation(repo.create_user_email(&NewUserEmail {
+                                                            user_id: &u.id,
+                                                            email: "beefsack@gmail.com",
+                                                            is_primary: false,
+                                                        }),
+                                "user_emails_email_key");
+        assert_eq!(u.id,
+                   repo.find_user_by_email("beefsack@gmail.com")
+                       .unwrap()
+                       .unwrap()
+                       .user
+                       .id);
+    }
+
+    #[test]
+    fn authenticate_works() {
+        let repo = MemoryRepository::new();
+        let u = user(&repo, "beefsack");
+        repo.create_user_email(&NewUserEmail {
+                                    user_id: &u.id,
+                                    email: "beefsack@gmail.com",
+                                    is_primary: true,
+                                })
+            .unwrap();
+        let uat = repo.create_auth_token(&u.id).unwrap();
+        assert!(repo.authenticate("beefsack@gmail.com", &uat.id)
+                    .unwrap()
+                    .is_some());
+        assert!(repo.authenticate("beefsacke@gmail.com", &uat.id)
+                    .unwrap()
+                    .is_none());
+        assert!(repo.authenticate("beefsack@gmail.com", &Uuid::new_v4())
+                    .unwrap()
+                    .is_none());
+    }
+
+    #[test]
+    fn game_players_are_unique_per_game() {
+        let repo = MemoryRepository::new();
+        let game = game(&repo);
+        let (p1, p2) = (user(&repo, "beefsack"), user(&repo, "baconheist"));
+        let g = &game.id;
+        repo.create_game_player(&new_player(g, &p1.id, 0, &Color::Green))
+            .unwrap();
+        assert_unique_violation(repo.create_game_player(&new_player(g, &p2.id, 1, &Color::Green)),
+                                "game_players_game_id_color_key");
+        assert_unique_violation(repo.create_game_player(&new_player(g, &p2.id, 0, &Color::Red)),
+                                "game_players_game_id_position_key");
+        assert_unique_violation(repo.create_game_player(&new_player(g, &p1.id, 1, &Color::Red)),
+                                "game_players_game_id_user_id_key");
+        repo.create_game_player(&new_player(g, &p2.id, 1, &Color::Red))
+            .unwrap();
+
+        let players = repo.update_game_whose_turn(&game.id, &[1]).unwrap();
+        assert_eq!(vec![false, true],
+                   players.iter().map(|p| p.is_turn).collect::<Vec<bool>>());
+        repo.update_game_whose_turn(&game.id, &[1]).unwrap();
+        assert_eq!(game.revision + 1,
+                   repo.find_game(&game.id).unwrap().unwrap().revision);
+    }
+
+    #[test]
+    fn update_game_placings_works() {
+        let repo = MemoryRepository::new();
+        let game = game(&repo);
+        let (p1, p2) = (user(&repo, "beefsack"), user(&repo, "baconheist"));
+        repo.create_game_player(&new_player(&game.id, &p1.id, 0, &Color::Green))
+            .unwrap();
+        repo.create_game_player(&new_player(&game.id, &p2.id, 1, &Color::Red))
+            .unwrap();
+        let placings = [Placing {
+                            position: 1,
+                            place: 1,
+                            score: Some(47),
+                        }];
+        let players = repo.update_game_placings(&game.id, &placings).unwrap();
+        assert_eq!(vec![(None, None, false), (Some(1), Some(47), true)],
+                   players
+                       .iter()
+                       .map(|p| (p.place, p.score, p.is_winner))
+                       .collect::<Vec<(Option<i32>, Option<i32>, bool)>>());
+        let missing = [Placing {
+                           position: 2,
+                           place: 1,
+                           score: None,
+                       }];
+        assert!(repo.update_game_placings(&game.id, &missing).is_err());
+
+        let players = repo.update_game_winners(&game.id, &[0]).unwrap();
+        assert_eq!(vec![(None, None, true), (None, None, false)],
+                   players
+                       .iter()
+                       .map(|p| (p.place, p.score, p.is_winner))
+                       .collect::<Vec<(Option<i32>, Option<i32>, bool)>>());
+    }
+
+    #[test]
+    fn login_works() {
+        let repo = MemoryRepository::new();
+        let code = repo.user_login_request("beefsack@gmail.com").unwrap();
+        assert_eq!(code, repo.user_login_request("beefsack@gmail.com").unwrap());
+        assert!(repo.user_login_confirm("beefsack@gmail.com", "nope")
+                    .unwrap()
+                    .is_none());
+        let uat = repo.user_login_confirm("beefsack@gmail.com", &code)
+            .unwrap()
+            .unwrap();
+        assert!(repo.authenticate("beefsack@gmail.com", &uat.id)
+                    .unwrap()
+                    .is_some());
+    }
+
+    #[test]
+    fn create_game_with_users_works() {
+        let repo = MemoryRepository::new();
+        let game_version_id = game(&repo).game_version_id;
+        let creator = user(&repo, "beefsack");
+        let new_game = NewGame {
+            game_version_id: &game_version_id,
+            is_finished: false,
+            game_state: "egg",
+            parent_game_id: None,
+        };
+        match repo.create_game_with_users(&new_game, &[0], &[], &[], &creator.id, &[], &[]) {
+            Err(Error(ErrorKind::InvalidPlayerCount(1, 2, Some(2)), _)) => {}
+            _ => panic!("expected invalid player count"),
+        }
+        let created = repo.create_game_with_users(&new_game,
+                                                  &[0],
+                                                  &[],
+                                                  &[],
+                                                  &creator.id,
+                                                  &[],
+                                                  &["baconheist@gmail.com".to_string()])
+            .unwrap();
+        assert_eq!(2, created.players.len());
+        assert_eq!(created.opponents[0].user.id,
+                   repo.find_user_with_primary_email_by_email("baconheist@gmail.com")
+                       .unwrap()
+                       .unwrap()
+                       .user
+                       .id);
+
+        // Failures part way through leave nothing behind.
+        let logs = repo.state.borrow().game_logs.len();
+        assert!(repo.create_game_logs_from_cli(&created.game.id,
+                                               vec![CliLog {
+                                                        content: "egg".to_string(),
+                                                        at: now(),
+                                                        public: true,
+                                                        to: vec![],
+                                                    },
+                                                    CliLog {
+                                                        content: "bacon".to_string(),
+                                                        at: now(),
+                                                        public: false,
+                                                        to: vec![5],
+                                                    }])
+                    .is_err());
+        assert_eq!(logs, repo.state.borrow().game_logs.len());
+    }
+
+    #[test]
+    fn foreign_keys_are_checked() {
+        let repo = MemoryRepository::new();
+        match repo.create_auth_token(&Uuid::new_v4()) {
+            Err(Error(ErrorKind::ForeignKeyViolation(ref c), _)) => {
+                assert_eq!("user_auth_tokens_user_id_fkey", c)
+            }
+            _ => panic!("expected foreign key violation"),
+        }
+    }
+}
@@ -1,10 +1,15 @@
 use uuid::Uuid;
 use chrono::NaiveDateTime;
-use postgres::rows::Row;
+use postgres::types::{FromSql, ToSql};
+use serde_json::Value;
 
 use color::Color;
+use outbox::OutboxMessageKind;
+use jobs::JobStatus;
+use turns::TimeLimitKind;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "users"]
 pub struct User {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -15,32 +20,8 @@ pub struct User {
     pub login_confirmation_at: Option<NaiveDateTime>,
 }
 
-impl User {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            name: row.get(format!("{}name", prefix).as_ref()),
-            pref_colors: row.get(format!("{}pref_colors", prefix).as_ref()),
-            login_confirmation: row.get(format!("{}login_confirmation", prefix).as_ref()),
-            login_confirmation_at: row.get(format!("{}login_confirmation_at", prefix).as_ref()),
-        }
-    }
-}
-
-impl Model for User {
-    fn cols() -> Vec<String> {
-        vec!["id".to_string(),
-             "created_at".to_string(),
-             "updated_at".to_string(),
-             "name".to_string(),
-             "pref_colors".to_string(),
-             "login_confirmation".to_string(),
-             "login_confirmation_at".to_string()]
-    }
-}
-
+#[derive(Insert)]
+#[table = "users"]
 pub struct NewUser<'a> {
     pub name: &'a str,
     pub pref_colors: &'a [&'a Color],
@@ -48,6 +29,8 @@ pub struct NewUser<'a> {
     pub login_confirmation_at: Option<&'a NaiveDateTime>,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "user_emails"]
 pub struct UserEmail {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -57,36 +40,16 @@ pub struct UserEmail {
     pub is_primary: bool,
 }
 
-impl UserEmail {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            user_id: row.get(format!("{}user_id", prefix).as_ref()),
-            email: row.get(format!("{}email", prefix).as_ref()),
-            is_primary: row.get(format!("{}is_primary", prefix).as_ref()),
-        }
-    }
-}
-
-impl Model for UserEmail {
-    fn cols() -> Vec<String> {
-        vec!["id".to_string(),
-             "created_at".to_string(),
-             "updated_at".to_string(),
-             "user_id".to_string(),
-             "email".to_string(),
-             "is_primary".to_string()]
-    }
-}
-
+#[derive(Insert)]
+#[table = "user_emails"]
 pub struct NewUserEmail<'a> {
     pub user_id: &'a Uuid,
     pub email: &'a str,
     pub is_primary: bool,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "user_auth_tokens"]
 pub struct UserAuthToken {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -94,30 +57,14 @@ pub struct UserAuthToken {
     pub user_id: Uuid,
 }
 
-impl UserAuthToken {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            user_id: row.get(format!("{}user_id", prefix).as_ref()),
-        }
-    }
-}
-
-impl Model for UserAuthToken {
-    fn cols() -> Vec<String> {
-        vec!["id".to_string(),
-             "created_at".to_string(),
-             "updated_at".to_string(),
-             "user_id".to_string()]
-    }
-}
-
+#[derive(Insert)]
+#[table = "user_auth_tokens"]
 pub struct NewUserAuthToken<'a> {
     pub user_id: &'a Uuid,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_types"]
 pub struct GameType {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -125,21 +72,14 @@ pub struct GameType {
     pub name: String,
 }
 
-impl GameType {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            name: row.get(format!("{}name", prefix).as_ref()),
-        }
-    }
-}
-
+#[derive(Insert)]
+#[table = "game_types"]
 pub struct NewGameType<'a> {
     pub name: &'a str,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_versions"]
 pub struct GameVersion {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -149,31 +89,38 @@ pub struct GameVersion {
     pub uri: String,
     pub is_public: bool,
     pub is_deprecated: bool,
+    pub min_players: i32,
+    pub max_players: Option<i32>,
 }
 
 impl GameVersion {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            game_type_id: row.get(format!("{}game_type_id", prefix).as_ref()),
-            name: row.get(format!("{}name", prefix).as_ref()),
-            uri: row.get(format!("{}uri", prefix).as_ref()),
-            is_public: row.get(format!("{}is_public", prefix).as_ref()),
-            is_deprecated: row.get(format!("{}is_deprecated", prefix).as_ref()),
-        }
+    pub fn supports_player_count(&self, count: usize) -> bool {
+        let count = count as i32;
+        count >= self.min_players && self.max_players.map(|m| count <= m).unwrap_or(true)
     }
 }
 
+#[derive(Insert)]
+#[table = "game_versions"]
 pub struct NewGameVersion<'a> {
     pub game_type_id: &'a Uuid,
     pub name: &'a str,
     pub uri: &'a str,
     pub is_public: bool,
     pub is_deprecated: bool,
+    pub min_players: i32,
+    pub max_players: Option<i32>,
+}
+
+#[derive(Debug, ToSql, FromSql, PartialEq, Eq, Clone, Copy)]
+#[postgres(name = "elimination_reason")]
+pub enum EliminationReason {
+    Conceded,
+    TimedOut,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "games"]
 pub struct Game {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -181,27 +128,27 @@ pub struct Game {
     pub game_version_id: Uuid,
     pub is_finished: bool,
     pub game_state: String,
+    pub revision: i32,
+    pub time_limit_kind: Option<TimeLimitKind>,
+    pub time_limit_secs: Option<i32>,
+    pub abandoned_at: Option<NaiveDateTime>,
+    pub abandon_reason: Option<String>,
+    /// The game this is a rematch of.
+    pub parent_game_id: Option<Uuid>,
+    pub finished_at: Option<NaiveDateTime>,
 }
 
-impl Game {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            game_version_id: row.get(format!("{}game_version_id", prefix).as_ref()),
-            is_finished: row.get(format!("{}is_finished", prefix).as_ref()),
-            game_state: row.get(format!("{}game_state", prefix).as_ref()),
-        }
-    }
-}
-
+#[derive(Insert)]
+#[table = "games"]
 pub struct NewGame<'a> {
     pub game_version_id: &'a Uuid,
     pub is_finished: bool,
     pub game_state: &'a str,
+    pub parent_game_id: Option<&'a Uuid>,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_players"]
 pub struct GamePlayer {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -214,26 +161,24 @@ pub struct GamePlayer {
     pub is_turn: bool,
     pub is_eliminated: bool,
     pub is_winner: bool,
+    pub color_pref_rank: Option<i32>,
+    /// False for players created before color preference ranks were recorded, whose rank is
+    /// unknown rather than unlisted.
+    pub color_pref_known: bool,
+    /// When the player's current turn started, set by the database when `is_turn` becomes true.
+    pub turn_started_at: Option<NaiveDateTime>,
+    pub turn_reminded_at: Option<NaiveDateTime>,
+    /// Seconds left in the player's time bank, for games with a time bank.
+    pub time_bank_secs: Option<i32>,
+    /// Why the player was eliminated, if it wasn't by the game's rules.
+    pub eliminated_reason: Option<EliminationReason>,
+    /// The player's final place, starting from 1, with tied players sharing a place.
+    pub place: Option<i32>,
+    pub score: Option<i32>,
 }
 
-impl GamePlayer {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            game_id: row.get(format!("{}game_id", prefix).as_ref()),
-            user_id: row.get(format!("{}user_id", prefix).as_ref()),
-            position: row.get(format!("{}position", prefix).as_ref()),
-            color: row.get(format!("{}color", prefix).as_ref()),
-            has_accepted: row.get(format!("{}has_accepted", prefix).as_ref()),
-            is_turn: row.get(format!("{}is_turn", prefix).as_ref()),
-            is_eliminated: row.get(format!("{}is_eliminated", prefix).as_ref()),
-            is_winner: row.get(format!("{}is_winner", prefix).as_ref()),
-        }
-    }
-}
-
+#[derive(Insert)]
+#[table = "game_players"]
 pub struct NewGamePlayer<'a> {
     pub game_id: &'a Uuid,
     pub user_id: &'a Uuid,
@@ -243,8 +188,11 @@ pub struct NewGamePlayer<'a> {
     pub is_turn: bool,
     pub is_eliminated: bool,
     pub is_winner: bool,
+    pub color_pref_rank: Option<i32>,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_logs"]
 pub struct GameLog {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -255,20 +203,8 @@ pub struct GameLog {
     pub logged_at: NaiveDateTime,
 }
 
-impl GameLog {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            game_id: row.get(format!("{}game_id", prefix).as_ref()),
-            body: row.get(format!("{}body", prefix).as_ref()),
-            is_public: row.get(format!("{}is_public", prefix).as_ref()),
-            logged_at: row.get(format!("{}logged_at", prefix).as_ref()),
-        }
-    }
-}
-
+#[derive(Insert)]
+#[table = "game_logs"]
 pub struct NewGameLog<'a> {
     pub game_id: &'a Uuid,
     pub body: &'a str,
@@ -276,6 +212,8 @@ pub struct NewGameLog<'a> {
     pub logged_at: &'a NaiveDateTime,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_log_targets"]
 pub struct GameLogTarget {
     pub id: Uuid,
     pub created_at: NaiveDateTime,
@@ -284,24 +222,183 @@ pub struct GameLogTarget {
     pub player_id: Uuid,
 }
 
-impl GameLogTarget {
-    pub fn from_row(row: &Row, prefix: &str) -> Self {
-        Self {
-            id: row.get(format!("{}id", prefix).as_ref()),
-            created_at: row.get(format!("{}created_at", prefix).as_ref()),
-            updated_at: row.get(format!("{}updated_at", prefix).as_ref()),
-            game_log_id: row.get(format!("{}game_log_id", prefix).as_ref()),
-            player_id: row.get(format!("{}player_id", prefix).as_ref()),
-        }
-    }
-}
-
+#[derive(Insert)]
+#[table = "game_log_targets"]
 pub struct NewGameLogTarget<'a> {
     pub game_log_id: &'a Uuid,
     pub player_id: &'a Uuid,
 }
 
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_color_swaps"]
+pub struct GameColorSwap {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub game_id: Uuid,
+    pub from_player_id: Uuid,
+    pub to_player_id: Uuid,
+    pub game_revision: i32,
+    pub accepted_at: Option<NaiveDateTime>,
+    pub declined_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insert)]
+#[table = "game_color_swaps"]
+pub struct NewGameColorSwap<'a> {
+    pub game_id: &'a Uuid,
+    pub from_player_id: &'a Uuid,
+    pub to_player_id: &'a Uuid,
+    pub game_revision: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "game_version_migrations"]
+pub struct GameVersionMigration {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub run_id: Uuid,
+    pub game_id: Uuid,
+    pub from_game_version_id: Uuid,
+    pub to_game_version_id: Uuid,
+    pub from_game_state: String,
+    pub to_game_state: String,
+    /// The revision of the game after it was migrated, so rollbacks can skip games which have
+    /// been played since.
+    pub game_revision: i32,
+    pub rolled_back_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insert)]
+#[table = "game_version_migrations"]
+pub struct NewGameVersionMigration<'a> {
+    pub run_id: &'a Uuid,
+    pub game_id: &'a Uuid,
+    pub from_game_version_id: &'a Uuid,
+    pub to_game_version_id: &'a Uuid,
+    pub from_game_state: &'a str,
+    pub to_game_state: &'a str,
+    pub game_revision: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "outbox_messages"]
+pub struct OutboxMessage {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub kind: OutboxMessageKind,
+    pub recipient: String,
+    pub payload: Value,
+    pub max_attempts: i32,
+    pub attempts: i32,
+    pub next_attempt_at: NaiveDateTime,
+    pub claimed_until: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub sent_at: Option<NaiveDateTime>,
+    pub failed_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insert)]
+#[table = "outbox_messages"]
+pub struct NewOutboxMessage<'a> {
+    pub kind: OutboxMessageKind,
+    pub recipient: &'a str,
+    pub payload: &'a Value,
+    pub max_attempts: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "jobs"]
+pub struct Job {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub kind: String,
+    pub unique_key: Option<String>,
+    pub payload: Value,
+    pub status: JobStatus,
+    pub run_at: NaiveDateTime,
+    pub max_attempts: i32,
+    pub attempts: i32,
+    pub heartbeat_at: Option<NaiveDateTime>,
+    pub last_error: Option<String>,
+    pub finished_at: Option<NaiveDateTime>,
+}
+
+#[derive(Insert)]
+#[table = "jobs"]
+pub struct NewJob<'a> {
+    pub kind: &'a str,
+    pub unique_key: Option<&'a str>,
+    pub payload: &'a Value,
+    pub run_at: &'a NaiveDateTime,
+    pub max_attempts: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "ratings"]
+pub struct Rating {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub user_id: Uuid,
+    pub game_type_id: Uuid,
+    pub rating: f64,
+    pub games_played: i32,
+}
+
+#[derive(Debug, PartialEq, Clone, Model)]
+#[table = "rating_changes"]
+pub struct RatingChange {
+    pub id: Uuid,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub rating_id: Uuid,
+    pub game_id: Uuid,
+    pub rating_before: f64,
+    pub rating_after: f64,
+}
+
+#[derive(Insert)]
+#[table = "rating_changes"]
+pub struct NewRatingChange<'a> {
+    pub rating_id: &'a Uuid,
+    pub game_id: &'a Uuid,
+    pub rating_before: f64,
+    pub rating_after: f64,
+}
+
+/// A result row from the blocking client or, with the `async` feature, from tokio-postgres, so the
+/// derived `from_row` can read models from either.
+pub trait ModelRow {
+    fn get_col<T: FromSql>(&self, col: &str) -> T;
+}
+
+impl<'a> ModelRow for ::postgres::rows::Row<'a> {
+    fn get_col<T: FromSql>(&self, col: &str) -> T {
+        self.get(col)
+    }
+}
+
+impl<'r, R: ModelRow> ModelRow for &'r R {
+    fn get_col<T: FromSql>(&self, col: &str) -> T {
+        (**self).get_col(col)
+    }
+}
+
+#[cfg(feature = "async")]
+impl ModelRow for ::tokio_postgres::rows::Row {
+    fn get_col<T: FromSql>(&self, col: &str) -> T {
+        self.get(col)
+    }
+}
+
+/// Model is derived for row structs with `#[derive(Model)]` and `#[table = "..."]`, along with a
+/// prefixed `from_row`.
 pub trait Model {
+    fn table() -> &'static str;
     fn cols() -> Vec<String>;
 
     fn select_cols(table: &str, prefix: &str) -> String {
@@ -321,3 +418,97 @@ pub trait Model {
             .join(", ")
     }
 }
+
+/// Insert is derived for `New*` structs with `#[derive(Insert)]` and `#[table = "..."]`, binding
+/// each field to the column of the same name.
+pub trait Insert {
+    fn table() -> &'static str;
+    fn insert_cols() -> Vec<&'static str>;
+    /// The field values, in the same order as `insert_cols`.
+    fn params(&self) -> Vec<&ToSql>;
+
+    fn insert_sql() -> String {
+        Self::insert_many_sql(1)
+    }
+
+    /// Inserts `count` rows in one statement, with the fields of each row bound in turn. Postgres
+    /// doesn't promise to return the created rows in any particular order.
+    fn insert_many_sql(count: usize) -> String {
+        values_sql(Self::table(), &Self::insert_cols(), count)
+    }
+
+    /// Like `insert_many_sql`, but with each row's id bound before its fields, so the created rows
+    /// can be matched up with the rows given.
+    fn insert_many_with_ids_sql(count: usize) -> String {
+        let mut cols = vec!["id"];
+        cols.extend(Self::insert_cols());
+        values_sql(Self::table(), &cols, count)
+    }
+
+    /// Updates every column from the fields, with the id of the row to update bound after them.
+    fn update_sql() -> String {
+        Self::update_and_set_sql(&[])
+    }
+
+    /// Like `update_sql`, but also makes the given assignments, such as bumping a revision.
+    fn update_and_set_sql(sets: &[&str]) -> String {
+        let cols = Self::insert_cols();
+        let mut assignments: Vec<String> = cols.iter()
+            .enumerate()
+            .map(|(i, c)| format!("{} = ${}", c, i + 1))
+            .collect();
+        assignments.extend(sets.iter().map(|s| s.to_string()));
+        format!("UPDATE {} SET {} WHERE id = ${} RETURNING *",
+                Self::table(),
+                assignments.join(", "),
+                cols.len() + 1)
+    }
+}
+
+fn values_sql(table: &str, cols: &[&str], count: usize) -> String {
+    format!("INSERT INTO {} ({}) VALUES {} RETURNING *",
+            table,
+            cols.join(", "),
+            (0..count)
+                .map(|r| {
+                         format!("({})",
+                                 (1..cols.len() + 1)
+                                     .map(|c| format!("${}", r * cols.len() + c))
+                                     .collect::<Vec<String>>()
+                                     .join(", "))
+                     })
+                .collect::<Vec<String>>()
+                .join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_cols_match_fields() {
+        assert_eq!(vec!["id", "created_at", "updated_at", "name"], GameType::cols());
+        assert_eq!("gt.id AS gt_id, gt.created_at AS gt_created_at, \
+                    gt.updated_at AS gt_updated_at, gt.name AS gt_name",
+                   GameType::select_cols("gt", "gt_"));
+    }
+
+    #[test]
+    fn insert_sql_works() {
+        assert_eq!("INSERT INTO game_log_targets (game_log_id, player_id) VALUES ($1, $2) \
+                    RETURNING *",
+                   NewGameLogTarget::insert_sql());
+        assert_eq!("INSERT INTO game_log_targets (game_log_id, player_id) VALUES ($1, $2), \
+                    ($3, $4) RETURNING *",
+                   NewGameLogTarget::insert_many_sql(2));
+        assert_eq!("INSERT INTO game_log_targets (id, game_log_id, player_id) VALUES ($1, $2, $3), \
+                    ($4, $5, $6) RETURNING *",
+                   NewGameLogTarget::insert_many_with_ids_sql(2));
+        assert_eq!("UPDATE game_log_targets SET game_log_id = $1, player_id = $2 WHERE id = $3 \
+                    RETURNING *",
+                   NewGameLogTarget::update_sql());
+        assert_eq!("UPDATE game_log_targets SET game_log_id = $1, player_id = $2, revision = \
+                    revision + 1 WHERE id = $3 RETURNING *",
+                   NewGameLogTarget::update_and_set_sql(&["revision = revision + 1"]));
+    }
+}
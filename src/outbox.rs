@@ -0,0 +1,205 @@
+use postgres::GenericConnection;
+use chrono::Duration;
+use uuid::Uuid;
+
+use errors::*;
+use models::*;
+
+/// Number of times a message is attempted before it is marked as failed.
+pub static DEFAULT_MAX_ATTEMPTS: i32 = 5;
+/// Delay before the first retry of a failed send, doubled for each following retry.
+pub static BASE_RETRY_SECS: i32 = 30;
+pub static MAX_RETRY_SECS: i32 = 60 * 60;
+
+#[derive(Debug, ToSql, FromSql, PartialEq, Eq, Clone, Copy)]
+#[postgres(name = "outbox_message_kind")]
+pub enum OutboxMessageKind {
+    LoginCode,
+    GameInvite,
+    YourTurn,
+}
+
+/// Adds a message to the outbox. Pass the transaction making the change the message is about, so
+/// the message is only sent if the change is committed.
+pub fn enqueue(message: &NewOutboxMessage, conn: &GenericConnection) -> Result<OutboxMessage> {
+    for row in &conn.query(&NewOutboxMessage::insert_sql(), &message.params())? {
+        return Ok(OutboxMessage::from_row(&row, ""));
+    }
+    Err("error enqueueing outbox message".into())
+}
+
+/// Claims up to `limit` messages which are due to be sent, counting an attempt for each. Messages
+/// are held for `lease`, after which they can be claimed again if they haven't been marked as
+/// sent or failed, so messages claimed by a crashed sender aren't lost. Messages whose lease
+/// expired on their last attempt are marked as failed instead.
+///
+/// Messages locked by concurrent claims are skipped rather than waited for.
+pub fn claim(limit: i64, lease: Duration, conn: &GenericConnection) -> Result<Vec<OutboxMessage>> {
+    conn.execute("
+        UPDATE outbox_messages
+        SET
+            failed_at=(now() AT TIME ZONE 'utc'),
+            claimed_until=NULL,
+            last_error=COALESCE(last_error, 'lease expired on the last attempt')
+        WHERE id IN (
+            SELECT id
+            FROM outbox_messages
+            WHERE sent_at IS NULL
+            AND failed_at IS NULL
+            AND attempts >= max_attempts
+            AND claimed_until <= (now() AT TIME ZONE 'utc')
+            FOR UPDATE SKIP LOCKED
+        )",
+                 &[])?;
+    let mut messages: Vec<OutboxMessage> = vec![];
+    for row in &conn.query("
+        UPDATE outbox_messages
+        SET
+            attempts=attempts+1,
+            claimed_until=(now() AT TIME ZONE 'utc') + $2::float8 * INTERVAL '1 second'
+        WHERE id IN (
+            SELECT id
+            FROM outbox_messages
+            WHERE sent_at IS NULL
+            AND failed_at IS NULL
+            AND attempts < max_attempts
+            AND next_attempt_at <= (now() AT TIME ZONE 'utc')
+            AND (claimed_until IS NULL OR claimed_until <= (now() AT TIME ZONE 'utc'))
+            ORDER BY next_attempt_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING *",
+                           &[&limit, &(lease.num_milliseconds() as f64 / 1000.0)])? {
+        messages.push(OutboxMessage::from_row(&row, ""));
+    }
+    messages.sort_by(|a, b| a.next_attempt_at.cmp(&b.next_attempt_at));
+    Ok(messages)
+}
+
+pub fn mark_sent(id: &Uuid, conn: &GenericConnection) -> Result<Option<OutboxMessage>> {
+    for row in &conn.query("
+        UPDATE outbox_messages
+        SET
+            sent_at=(now() AT TIME ZONE 'utc'),
+            claimed_until=NULL
+        WHERE id=$1
+        AND sent_at IS NULL
+        AND failed_at IS NULL
+        RETURNING *",
+                           &[id])? {
+        return Ok(Some(OutboxMessage::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+/// Records a failed attempt to send a message. The message is retried with backoff until it has
+/// been attempted `max_attempts` times, after which it is marked as failed.
+pub fn mark_failed(id: &Uuid,
+                   error: &str,
+                   conn: &GenericConnection)
+                   -> Result<Option<OutboxMessage>> {
+    for row in &conn.query("
+        UPDATE outbox_messages
+        SET
+            last_error=$2,
+            claimed_until=NULL,
+            failed_at=CASE
+                WHEN attempts >= max_attempts THEN (now() AT TIME ZONE 'utc')
+            END,
+            next_attempt_at=(now() AT TIME ZONE 'utc') +
+                LEAST(power(2, attempts - 1) * $3::int, $4::int) * INTERVAL '1 second'
+        WHERE id=$1
+        AND sent_at IS NULL
+        AND failed_at IS NULL
+        RETURNING *",
+                           &[id, &error, &BASE_RETRY_SECS, &MAX_RETRY_SECS])? {
+        return Ok(Some(OutboxMessage::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use test_support::with_db;
+
+    fn new_message<'a>(payload: &'a Value, max_attempts: i32) -> NewOutboxMessage<'a> {
+        NewOutboxMessage {
+            kind: OutboxMessageKind::YourTurn,
+            recipient: "beefsack@gmail.com",
+            payload: payload,
+            max_attempts: max_attempts,
+        }
+    }
+
+    #[test]
+    fn outbox_works() {
+        with_db(|conn| {
+            let payload = Value::Null;
+            let message = enqueue(&new_message(&payload, 2), conn).unwrap();
+
+            // Messages enqueued in a transaction which is rolled back are never sent.
+            {
+                let trans = conn.transaction().unwrap();
+                enqueue(&new_message(&payload, 2), &trans).unwrap();
+            }
+
+            let claimed = claim(10, Duration::minutes(5), conn).unwrap();
+            assert_eq!(vec![message.id],
+                       claimed.iter().map(|m| m.id).collect::<Vec<Uuid>>());
+            assert_eq!(1, claimed[0].attempts);
+            assert!(claim(10, Duration::minutes(5), conn).unwrap().is_empty());
+
+            let failed = mark_failed(&message.id, "mail server down", conn)
+                .unwrap()
+                .unwrap();
+            assert_eq!(None, failed.failed_at);
+            assert!(failed.next_attempt_at > message.next_attempt_at);
+            assert!(claim(10, Duration::minutes(5), conn).unwrap().is_empty());
+
+            // Bring the retry forward rather than waiting for it.
+            conn.execute("UPDATE outbox_messages SET next_attempt_at=created_at", &[])
+                .unwrap();
+            assert_eq!(1, claim(10, Duration::minutes(5), conn).unwrap().len());
+            let failed = mark_failed(&message.id, "mail server down", conn)
+                .unwrap()
+                .unwrap();
+            assert!(failed.failed_at.is_some());
+            assert_eq!(None, mark_sent(&message.id, conn).unwrap());
+
+            let message = enqueue(&new_message(&payload, 2), conn).unwrap();
+            assert_eq!(1, claim(10, Duration::minutes(5), conn).unwrap().len());
+            assert!(mark_sent(&message.id, conn)
+                        .unwrap()
+                        .unwrap()
+                        .sent_at
+                        .is_some());
+            assert!(claim(10, Duration::minutes(5), conn).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn expired_claims_are_reclaimed() {
+        with_db(|conn| {
+            let payload = Value::Null;
+            enqueue(&new_message(&payload, 2), conn).unwrap();
+            assert_eq!(1, claim(10, Duration::zero(), conn).unwrap().len());
+            let reclaimed = claim(10, Duration::zero(), conn).unwrap();
+            assert_eq!(2, reclaimed[0].attempts);
+
+            // Once the last attempt's lease expires, the message is failed rather than claimed.
+            assert!(claim(10, Duration::minutes(5), conn).unwrap().is_empty());
+            let failed: i64 = conn.query("
+                SELECT COUNT(*) AS failed
+                FROM outbox_messages
+                WHERE failed_at IS NOT NULL",
+                                         &[])
+                .unwrap()
+                .get(0)
+                .get("failed");
+            assert_eq!(1, failed);
+        });
+    }
+}
@@ -1,4 +1,6 @@
 use postgres::GenericConnection;
+use postgres::rows::Row;
+use postgres::types::ToSql;
 use uuid::Uuid;
 use rand::{self, Rng};
 use chrono::{Duration, UTC};
@@ -11,27 +13,94 @@ use std::iter::FromIterator;
 use errors::*;
 use models::*;
 use color::{self, Color};
+use transaction::{retry_transaction, IsolationLevel};
+use sql::Select;
+use outbox::{self, OutboxMessageKind};
+use turns;
+use ratings;
 
 lazy_static! {
-    static ref CONFIRMATION_EXPIRY: Duration = Duration::minutes(30);
-    static ref TOKEN_EXPIRY: Duration = Duration::days(30);
+    pub static ref CONFIRMATION_EXPIRY: Duration = Duration::minutes(30);
+    pub static ref TOKEN_EXPIRY: Duration = Duration::days(30);
 }
 
+#[derive(Debug, PartialEq, Clone)]
 pub struct UserByEmail {
     pub user: User,
     pub user_email: UserEmail,
 }
 
 pub fn create_user_by_name(name: &str, conn: &GenericConnection) -> Result<User> {
-    for row in &conn.query("
-        INSERT INTO users
-        (
-            name
-        ) VALUES (
-            $1
-        )
-        RETURNING *",
-                           &[&name])
+    create_user(&NewUser {
+                     name: name,
+                     pref_colors: &[],
+                     login_confirmation: None,
+                     login_confirmation_at: None,
+                 },
+                conn)
+}
+
+/// Postgres allows at most this many parameters in a statement.
+static MAX_PARAMS: usize = 65535;
+
+/// Inserts rows using multi-row INSERT statements, only splitting them when there are more
+/// parameters than a statement allows. The created rows are returned in no particular order.
+fn insert_all<N, T, F>(rows: &[&N], conn: &GenericConnection, from_row: F) -> Result<Vec<T>>
+    where N: Insert,
+          F: Fn(&Row) -> T
+{
+    let mut created: Vec<T> = Vec::with_capacity(rows.len());
+    for chunk in rows.chunks(MAX_PARAMS / N::insert_cols().len()) {
+        let params: Vec<&ToSql> = chunk.iter().flat_map(|&r| r.params()).collect();
+        for row in &conn.query(&N::insert_many_sql(chunk.len()), &params)? {
+            created.push(from_row(&row));
+        }
+    }
+    Ok(created)
+}
+
+/// Like `insert_all`, but generates the id of each row so the created rows can be returned in the
+/// same order as `rows`. `id_of` gives the id of a created row.
+fn insert_all_with_ids<N, T, F, G>(rows: &[&N],
+                                   conn: &GenericConnection,
+                                   from_row: F,
+                                   id_of: G)
+                                   -> Result<Vec<T>>
+    where N: Insert,
+          F: Fn(&Row) -> T,
+          G: Fn(&T) -> Uuid
+{
+    let ids: Vec<Uuid> = rows.iter().map(|_| Uuid::new_v4()).collect();
+    let chunk_size = MAX_PARAMS / (N::insert_cols().len() + 1);
+    let mut by_id: HashMap<Uuid, T> = HashMap::with_capacity(rows.len());
+    for (chunk, chunk_ids) in rows.chunks(chunk_size).zip(ids.chunks(chunk_size)) {
+        let mut params: Vec<&ToSql> = vec![];
+        for (&r, id) in chunk.iter().zip(chunk_ids) {
+            params.push(id);
+            params.extend(r.params());
+        }
+        for row in &conn.query(&N::insert_many_with_ids_sql(chunk.len()), &params)? {
+            let created = from_row(&row);
+            by_id.insert(id_of(&created), created);
+        }
+    }
+    ids.iter()
+        .map(|id| {
+                 by_id
+                     .remove(id)
+                     .ok_or_else::<Error, _>(|| format!("row {} was not created", id).into())
+             })
+        .collect()
+}
+
+pub fn create_user(new_user: &NewUser, conn: &GenericConnection) -> Result<User> {
+    let mut seen: HashSet<&Color> = HashSet::new();
+    for c in new_user.pref_colors.iter() {
+        if !seen.insert(*c) {
+            return Err(ErrorKind::DuplicateColor(format!("{:?}", c)).into());
+        }
+    }
+    for row in &conn.query(&NewUser::insert_sql(), &new_user.params())
                     .chain_err(|| "error creating user")? {
         return Ok(User::from_row(&row, ""));
     }
@@ -39,30 +108,31 @@ pub fn create_user_by_name(name: &str, conn: &GenericConnection) -> Result<User>
 }
 
 pub fn find_user(id: &Uuid, conn: &GenericConnection) -> Result<Option<User>> {
-    for row in &conn.query("
+    for row in &conn.prepare_cached("
         SELECT *
         FROM users
         WHERE id=$1
         LIMIT 1
-    ",
-                           &[id])? {
+    ")?
+                    .query(&[id])? {
         return Ok(Some(User::from_row(&row, "")));
     }
     Ok(None)
 }
 
+/// Selects users joined to their emails as `ue`, for reading into `UserByEmail`.
+pub fn users_with_emails<'a>() -> Select<'a> {
+    Select::from::<User>("u")
+        .cols::<User>("u", "u_")
+        .cols::<UserEmail>("ue", "ue_")
+        .join::<UserEmail>("ue", "ue.user_id = u.id", &[])
+}
+
 pub fn find_user_by_email(email: &str, conn: &GenericConnection) -> Result<Option<UserByEmail>> {
-    for row in &conn.query(&format!("
-        SELECT
-            {}, {}
-        FROM user_emails ue
-        INNER JOIN users u
-        ON (ue.user_id = u.id)
-        WHERE ue.email = $1
-        LIMIT 1",
-                                    User::select_cols("u", "u_"),
-                                    UserEmail::select_cols("ue", "ue_")),
-                           &[&email])? {
+    for row in &users_with_emails()
+                    .filter("ue.email = ?", &[&email])
+                    .limit(1)
+                    .query_cached(conn)? {
         return Ok(Some(UserByEmail {
                            user: User::from_row(&row, "u_"),
                            user_email: UserEmail::from_row(&row, "ue_"),
@@ -94,25 +164,74 @@ pub fn create_user_by_email(email: &str, conn: &GenericConnection) -> Result<Use
        })
 }
 
-pub fn create_user_email(ue: &NewUserEmail, conn: &GenericConnection) -> Result<UserEmail> {
+/// Sets a user's preferred colors, most preferred first. Colors are given by name so that unknown
+/// colors can be rejected along with duplicates.
+pub fn set_user_pref_colors(user_id: &Uuid,
+                            colors: &[&str],
+                            conn: &GenericConnection)
+                            -> Result<User> {
+    let prefs = color::parse_prefs(colors)?;
     for row in &conn.query("
-        INSERT INTO user_emails
-        (
-            email,
+        UPDATE users
+        SET pref_colors=$1
+        WHERE id=$2
+        RETURNING *",
+                           &[&prefs, user_id])? {
+        return Ok(User::from_row(&row, ""));
+    }
+    Err("could not find user".into())
+}
+
+pub struct UserColorPrefStats {
+    pub user_id: Uuid,
+    pub games: i64,
+    pub first_choice: i64,
+    pub second_choice: i64,
+    pub later_choice: i64,
+    pub unlisted: i64,
+    /// Games from before preference ranks were recorded, which aren't in any of the other counts.
+    pub unknown: i64,
+}
+
+/// Reports how often each user was given their first, second or a later preferred color when
+/// joining a game, and how often they were given a color not in their preferences at all.
+pub fn find_user_color_pref_stats(conn: &GenericConnection) -> Result<Vec<UserColorPrefStats>> {
+    let mut stats: Vec<UserColorPrefStats> = vec![];
+    for row in &conn.query("
+        SELECT
             user_id,
-            is_primary
-        ) VALUES (
-            $1,
-            $2,
-            $3
-        ) RETURNING *",
-                           &[&ue.email, &ue.user_id, &ue.is_primary])? {
+            COUNT(*) AS games,
+            COUNT(*) FILTER (WHERE color_pref_rank = 0) AS first_choice,
+            COUNT(*) FILTER (WHERE color_pref_rank = 1) AS second_choice,
+            COUNT(*) FILTER (WHERE color_pref_rank >= 2) AS later_choice,
+            COUNT(*) FILTER (WHERE color_pref_rank IS NULL AND color_pref_known) AS unlisted,
+            COUNT(*) FILTER (WHERE NOT color_pref_known) AS unknown
+        FROM game_players
+        GROUP BY user_id
+        ORDER BY games DESC",
+                           &[])? {
+        stats.push(UserColorPrefStats {
+                       user_id: row.get("user_id"),
+                       games: row.get("games"),
+                       first_choice: row.get("first_choice"),
+                       second_choice: row.get("second_choice"),
+                       later_choice: row.get("later_choice"),
+                       unlisted: row.get("unlisted"),
+                       unknown: row.get("unknown"),
+                   });
+    }
+    Ok(stats)
+}
+
+pub fn create_user_email(ue: &NewUserEmail, conn: &GenericConnection) -> Result<UserEmail> {
+    for row in &conn.query(&NewUserEmail::insert_sql(), &ue.params())? {
         return Ok(UserEmail::from_row(&row, ""));
     }
     Err("could not create user email".into())
 }
 
-fn rand_code() -> String {
+/// Generates a six digit login confirmation code.
+pub fn rand_code() -> String {
     let mut rng = rand::thread_rng();
     format!("{}{:05}",
             (rng.gen::<usize>() % 9) + 1,
@@ -136,6 +255,8 @@ pub fn generate_user_login_confirmation(user_id: &Uuid,
     }
 }
 
+/// Finds or generates a login confirmation code for the user with the email, and queues an email
+/// with the code in the outbox.
 pub fn user_login_request(email: &str, conn: &GenericConnection) -> Result<String> {
     let trans = conn.transaction()?;
     let user = find_or_create_user_by_email(email, &trans)?.user;
@@ -146,6 +267,13 @@ pub fn user_login_request(email: &str, conn: &GenericConnection) -> Result<Strin
         }
         _ => generate_user_login_confirmation(&user.id, &trans)?,
     };
+    outbox::enqueue(&NewOutboxMessage {
+                         kind: OutboxMessageKind::LoginCode,
+                         recipient: email,
+                         payload: &json!({"code": confirmation.as_str()}),
+                         max_attempts: outbox::DEFAULT_MAX_ATTEMPTS,
+                     },
+                    &trans)?;
     trans.commit()?;
     Ok(confirmation)
 }
@@ -185,23 +313,14 @@ pub fn authenticate(email: &str,
                     token: &Uuid,
                     conn: &GenericConnection)
                     -> Result<Option<UserByEmail>> {
-    for row in &conn.query(&format!("
-        SELECT
-            {}, {}, {}
-        FROM users u
-        INNER JOIN user_auth_tokens uat
-        ON (uat.user_id = u.id)
-        INNER JOIN user_emails ue
-        ON (ue.user_id = u.id)
-        WHERE ue.email = $1
-        AND uat.id = $2
-        AND uat.created_at > $3
-        LIMIT 1",
-                                    User::select_cols("u", "u_"),
-                                    UserEmail::select_cols("ue", "ue_"),
-                                    UserAuthToken::select_cols("uat", "uat_"),
-                                    ),
-                           &[&email, token, &(UTC::now().naive_utc() - *TOKEN_EXPIRY)])? {
+    let expired_before = UTC::now().naive_utc() - *TOKEN_EXPIRY;
+    for row in &users_with_emails()
+                    .join::<UserAuthToken>("uat", "uat.user_id = u.id", &[])
+                    .filter("ue.email = ?", &[&email])
+                    .filter("uat.id = ?", &[token])
+                    .filter("uat.created_at > ?", &[&expired_before])
+                    .limit(1)
+                    .query_cached(conn)? {
         return Ok(Some(UserByEmail {
                            user: User::from_row(&row, "u_"),
                            user_email: UserEmail::from_row(&row, "ue_"),
@@ -211,18 +330,129 @@ pub fn authenticate(email: &str,
 }
 
 pub fn find_game_version(id: &Uuid, conn: &GenericConnection) -> Result<Option<GameVersion>> {
-    for row in &conn.query("
+    for row in &conn.prepare_cached("
         SELECT *
         FROM game_versions
         WHERE id=$1
         LIMIT 1
+    ")?
+                    .query(&[id])? {
+        return Ok(Some(GameVersion::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+pub fn find_game_type(id: &Uuid, conn: &GenericConnection) -> Result<Option<GameType>> {
+    for row in &conn.query("
+        SELECT *
+        FROM game_types
+        WHERE id=$1
+        LIMIT 1
     ",
                            &[id])? {
+        return Ok(Some(GameType::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+pub fn find_game_types(conn: &GenericConnection) -> Result<Vec<GameType>> {
+    let mut game_types: Vec<GameType> = vec![];
+    for row in &conn.query("
+        SELECT *
+        FROM game_types
+        ORDER BY name",
+                           &[])? {
+        game_types.push(GameType::from_row(&row, ""));
+    }
+    Ok(game_types)
+}
+
+pub fn find_public_game_versions_by_game_type(game_type_id: &Uuid,
+                                              conn: &GenericConnection)
+                                              -> Result<Vec<GameVersion>> {
+    let mut game_versions: Vec<GameVersion> = vec![];
+    for row in &conn.query("
+        SELECT *
+        FROM game_versions
+        WHERE game_type_id=$1
+        AND is_public=TRUE
+        AND is_deprecated=FALSE
+        ORDER BY created_at DESC",
+                           &[game_type_id])? {
+        game_versions.push(GameVersion::from_row(&row, ""));
+    }
+    Ok(game_versions)
+}
+
+pub fn find_latest_public_game_version(game_type_id: &Uuid,
+                                       conn: &GenericConnection)
+                                       -> Result<Option<GameVersion>> {
+    for row in &conn.query("
+        SELECT *
+        FROM game_versions
+        WHERE game_type_id=$1
+        AND is_public=TRUE
+        AND is_deprecated=FALSE
+        ORDER BY created_at DESC
+        LIMIT 1",
+                           &[game_type_id])? {
+        return Ok(Some(GameVersion::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+pub fn update_game_version_flags(id: &Uuid,
+                                 is_public: bool,
+                                 is_deprecated: bool,
+                                 conn: &GenericConnection)
+                                 -> Result<Option<GameVersion>> {
+    for row in &conn.query("
+        UPDATE game_versions
+        SET
+            is_public=$1,
+            is_deprecated=$2
+        WHERE id=$3
+        RETURNING *",
+                           &[&is_public, &is_deprecated, id])? {
         return Ok(Some(GameVersion::from_row(&row, "")));
     }
     Ok(None)
 }
 
+pub fn find_game(id: &Uuid, conn: &GenericConnection) -> Result<Option<Game>> {
+    for row in &conn.prepare_cached("
+        SELECT *
+        FROM games
+        WHERE id=$1
+        LIMIT 1
+    ")?
+                    .query(&[id])? {
+        return Ok(Some(Game::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+/// Finds which games have changed since they were last seen, given as `(id, revision)` pairs. Game
+/// events sent while a subscription was disconnected are lost, so this lets a subscriber catch up
+/// after `Event::Reconnected`.
+pub fn find_games_changed_since(seen: &[(Uuid, i32)],
+                                conn: &GenericConnection)
+                                -> Result<Vec<Game>> {
+    let ids: Vec<Uuid> = seen.iter().map(|&(id, _)| id).collect();
+    let revisions: Vec<i32> = seen.iter().map(|&(_, revision)| revision).collect();
+    let mut games: Vec<Game> = vec![];
+    for row in &conn.query("
+        SELECT g.*
+        FROM games g
+        INNER JOIN UNNEST($1::UUID[], $2::INT[]) AS seen(id, revision)
+        ON seen.id = g.id
+        WHERE g.revision > seen.revision",
+                           &[&ids, &revisions])? {
+        games.push(Game::from_row(&row, ""));
+    }
+    Ok(games)
+}
+
 pub struct CreatedGame {
     pub game: Game,
     pub opponents: Vec<UserByEmail>,
@@ -238,16 +468,71 @@ pub fn create_game_with_users(new_game: &NewGame,
                               opponent_emails: &[String],
                               conn: &GenericConnection)
                               -> Result<CreatedGame> {
-    let trans = conn.transaction()?;
-    // Find or create users.
-    let creator = find_user(creator_id, &trans)
-        .chain_err(|| "could not find creator")?
-        .ok_or_else::<Error, _>(|| "could not find creator".into())?;
-    let opponents = create_game_users(opponent_ids, opponent_emails, &trans)
-        .chain_err(|| "could not create game users")?;
-    let mut users: Vec<User> = opponents.iter().map(|o| o.user.clone()).collect();
-    users.push(creator);
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        // Check the player count is valid for the game version before creating anything.
+        let game_version = find_game_version(new_game.game_version_id, trans)
+            .chain_err(|| "could not find game version")?
+            .ok_or_else::<Error, _>(|| "could not find game version".into())?;
+        let player_count = 1 + opponent_ids.len() + opponent_emails.len();
+        if !game_version.supports_player_count(player_count) {
+            return Err(ErrorKind::InvalidPlayerCount(player_count,
+                                                     game_version.min_players,
+                                                     game_version.max_players)
+                               .into());
+        }
+
+        // Find or create users.
+        let creator = find_user(creator_id, trans)
+            .chain_err(|| "could not find creator")?
+            .ok_or_else::<Error, _>(|| "could not find creator".into())?;
+        let opponents = create_game_users(opponent_ids, opponent_emails, trans)
+            .chain_err(|| "could not create game users")?;
+        let mut users: Vec<User> = opponents.iter().map(|o| o.user.clone()).collect();
+        users.push(creator);
+
+        // Create game record.
+        let game = create_game(new_game, trans)
+            .chain_err(|| "could not create new game")?;
 
+        let players = create_players_for_users(&game.id,
+                                               users,
+                                               creator_id,
+                                               whose_turn,
+                                               eliminated,
+                                               winners,
+                                               |players| create_game_players(players, trans))
+                .chain_err(|| "could not create game players")?;
+
+        // Tell the other players about the game, in the transaction so they're only told if it's
+        // created.
+        let opponent_ids: Vec<Uuid> = opponents.iter().map(|o| o.user.id).collect();
+        enqueue_game_messages(OutboxMessageKind::GameInvite, &game.id, &opponent_ids, trans)?;
+        let turn_ids: Vec<Uuid> = players
+            .iter()
+            .filter(|p| p.is_turn && &p.user_id != creator_id)
+            .map(|p| p.user_id)
+            .collect();
+        enqueue_game_messages(OutboxMessageKind::YourTurn, &game.id, &turn_ids, trans)?;
+        Ok(CreatedGame {
+               game: game,
+               opponents: opponents,
+               players: players,
+           })
+    })
+}
+
+/// Creates a player for each user in a random order with `create`, with colors chosen from their
+/// preferences. Only the creator has accepted.
+pub fn create_players_for_users<F, R>(game_id: &Uuid,
+                                      mut users: Vec<User>,
+                                      creator_id: &Uuid,
+                                      whose_turn: &[usize],
+                                      eliminated: &[usize],
+                                      winners: &[usize],
+                                      create: F)
+                                      -> R
+    where F: FnOnce(&[NewGamePlayer]) -> R
+{
     // Randomise the users so player order is random.
     let mut rnd = rand::thread_rng();
     rnd.shuffle(&mut users);
@@ -256,32 +541,162 @@ pub fn create_game_with_users(new_game: &NewGame,
     let color_prefs: Vec<Vec<Color>> = users.iter().map(|u| u.pref_colors.clone()).collect();
     let player_colors = color::choose(&HashSet::from_iter(color::COLORS.iter()), &color_prefs);
 
-    // Create game record.
-    let game = create_game(new_game, &trans)
-        .chain_err(|| "could not create new game")?;
-
     // Create a player record for each user.
-    let mut players: Vec<GamePlayer> = vec![];
-    for (pos, ref user) in users.iter().enumerate() {
-        players.push(create_game_player(&NewGamePlayer {
-                                             game_id: &game.id,
-                                             user_id: &user.id,
-                                             position: pos as i32,
-                                             color: &player_colors[pos],
-                                             has_accepted: &user.id == creator_id,
-                                             is_turn: whose_turn.contains(&pos),
-                                             is_eliminated: eliminated.contains(&pos),
-                                             is_winner: winners.contains(&pos),
-                                         },
-                                        &trans)
-                             .chain_err(|| "could not create game player")?);
+    let new_players: Vec<NewGamePlayer> = users
+        .iter()
+        .enumerate()
+        .map(|(pos, user)| {
+            NewGamePlayer {
+                game_id: game_id,
+                user_id: &user.id,
+                position: pos as i32,
+                color: &player_colors[pos],
+                has_accepted: &user.id == creator_id,
+                is_turn: whose_turn.contains(&pos),
+                is_eliminated: eliminated.contains(&pos),
+                is_winner: winners.contains(&pos),
+                color_pref_rank: user.pref_colors
+                    .iter()
+                    .position(|c| c == &player_colors[pos])
+                    .map(|r| r as i32),
+            }
+        })
+        .collect();
+    create(&new_players)
+}
+
+pub struct CreatedRematch {
+    pub game: Game,
+    pub players: Vec<GamePlayer>,
+}
+
+/// Creates a rematch of a finished game with the same players, on the latest public version of
+/// its game type. Only the player who asked for the rematch has accepted it, and the other players
+/// are sent invites. A game only has one rematch, so if it has already been created that is
+/// returned instead.
+///
+/// The rematch is created with an empty state and nobody's turn, as the game engine hasn't been
+/// run for it yet, and should be started with `update_game_and_players`.
+///
+/// Returns `None` if the game isn't finished or `requested_by` didn't play in it.
+pub fn create_rematch(game_id: &Uuid,
+                      requested_by: &Uuid,
+                      conn: &GenericConnection)
+                      -> Result<Option<CreatedRematch>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let game = match find_game(game_id, trans)? {
+            Some(ref g) if g.is_finished => g.clone(),
+            _ => return Ok(None),
+        };
+        if find_game_player_by_user(game_id, requested_by, trans)?.is_none() {
+            return Ok(None);
+        }
+        if let Some(rematch) = find_rematch(game_id, trans)? {
+            return Ok(Some(CreatedRematch {
+                               players: find_game_players_by_game(&rematch.id, trans)?,
+                               game: rematch,
+                           }));
+        }
+
+        let game_type_id = find_game_version(&game.game_version_id, trans)?
+            .ok_or_else::<Error, _>(|| "could not find game version".into())?
+            .game_type_id;
+        let game_version = find_latest_public_game_version(&game_type_id, trans)?
+            .ok_or_else::<Error, _>(|| "could not find a public game version".into())?;
+        let users = find_users_by_game(game_id, trans)?;
+        if !game_version.supports_player_count(users.len()) {
+            return Err(ErrorKind::InvalidPlayerCount(users.len(),
+                                                     game_version.min_players,
+                                                     game_version.max_players)
+                               .into());
+        }
+
+        let rematch = create_game(&NewGame {
+                                       game_version_id: &game_version.id,
+                                       is_finished: false,
+                                       game_state: "",
+                                       parent_game_id: Some(game_id),
+                                   },
+                                  trans)
+                .chain_err(|| "could not create rematch")?;
+        let players = create_players_for_users(&rematch.id,
+                                               users,
+                                               requested_by,
+                                               &[],
+                                               &[],
+                                               &[],
+                                               |players| create_game_players(players, trans))
+                .chain_err(|| "could not create rematch players")?;
+        let invited: Vec<Uuid> = players
+            .iter()
+            .filter(|p| &p.user_id != requested_by)
+            .map(|p| p.user_id)
+            .collect();
+        enqueue_game_messages(OutboxMessageKind::GameInvite, &rematch.id, &invited, trans)?;
+        Ok(Some(CreatedRematch {
+                    game: rematch,
+                    players: players,
+                }))
+    })
+}
+
+pub fn find_rematch(game_id: &Uuid, conn: &GenericConnection) -> Result<Option<Game>> {
+    for row in &conn.query("
+        SELECT *
+        FROM games
+        WHERE parent_game_id=$1",
+                           &[game_id])? {
+        return Ok(Some(Game::from_row(&row, "")));
     }
-    trans.commit()?;
-    Ok(CreatedGame {
-           game: game,
-           opponents: opponents,
-           players: players,
-       })
+    Ok(None)
+}
+
+/// Accepts the rematch of a game on behalf of one of its players. Returns `None` if the game
+/// hasn't been rematched or the user isn't playing in the rematch.
+pub fn accept_rematch(game_id: &Uuid,
+                      user_id: &Uuid,
+                      conn: &GenericConnection)
+                      -> Result<Option<GamePlayer>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let rematch = match find_rematch(game_id, trans)? {
+            Some(r) => r,
+            None => return Ok(None),
+        };
+        let player = match find_game_player_by_user(&rematch.id, user_id, trans)? {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        if player.has_accepted {
+            return Ok(Some(player));
+        }
+        trans.execute("
+            UPDATE games
+            SET revision=revision+1
+            WHERE id=$1",
+                      &[&rematch.id])?;
+        for row in &trans.query("
+            UPDATE game_players
+            SET has_accepted=TRUE
+            WHERE id=$1
+            RETURNING *",
+                                &[&player.id])? {
+            return Ok(Some(GamePlayer::from_row(&row, "")));
+        }
+        Ok(None)
+    })
+}
+
+fn find_users_by_game(game_id: &Uuid, conn: &GenericConnection) -> Result<Vec<User>> {
+    let mut users: Vec<User> = vec![];
+    for row in &Select::from::<User>("u")
+                    .cols::<User>("u", "")
+                    .join::<GamePlayer>("gp", "gp.user_id = u.id", &[])
+                    .filter("gp.game_id = ?", &[game_id])
+                    .order_by("gp.position")
+                    .query(conn)? {
+        users.push(User::from_row(&row, ""));
+    }
+    Ok(users)
 }
 
 pub struct UpdatedGame {
@@ -297,62 +712,284 @@ pub fn update_game_and_players(game_id: &Uuid,
                                winners: &[usize],
                                conn: &GenericConnection)
                                -> Result<UpdatedGame> {
-    let trans = conn.transaction()?;
-    let result = UpdatedGame {
-        game: update_game(game_id, update, &trans)?,
-        whose_turn: update_game_whose_turn(game_id, whose_turn, &trans)?,
-        eliminated: update_game_eliminated(game_id, eliminated, &trans)?,
-        winners: update_game_winners(game_id, winners, &trans)?,
-    };
-    trans.commit()?;
-    Ok(result)
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        // The players are updated first as they bump the revision of the game returned.
+        let whose_turn = update_game_whose_turn(game_id, whose_turn, trans)?;
+        let eliminated = update_game_eliminated(game_id, eliminated, trans)?;
+        let winners = update_game_winners(game_id, winners, trans)?;
+        Ok(UpdatedGame {
+               game: update_game(game_id, update, trans)?,
+               whose_turn: whose_turn,
+               eliminated: eliminated,
+               winners: winners,
+           })
+    })
 }
 
-pub fn update_game(id: &Uuid, update: &NewGame, conn: &GenericConnection) -> Result<Option<Game>> {
+fn lock_unfinished_game(id: &Uuid, conn: &GenericConnection) -> Result<Option<Game>> {
     for row in &conn.query("
-        UPDATE games
-        SET
-            game_version_id=$1,
-            is_finished=$2,
-            game_state=$3
-        WHERE id=$4
-        RETURNING *",
-                           &[&update.game_version_id,
-                             &update.is_finished,
-                             &update.game_state,
-                             &id])? {
+        SELECT *
+        FROM games
+        WHERE id=$1
+        AND is_finished=FALSE
+        FOR UPDATE",
+                           &[id])? {
         return Ok(Some(Game::from_row(&row, "")));
     }
     Ok(None)
 }
 
-fn position_update_clause(positions: &[usize]) -> String {
-    if positions.is_empty() {
-        "1".to_string()
-    } else {
-        format!("(position IN ({}))",
-                positions
-                    .iter()
-                    .map(|p| p.to_string())
-                    .collect::<Vec<String>>()
-                    .join(","))
+fn create_public_game_log(game_id: &Uuid,
+                          body: &str,
+                          conn: &GenericConnection)
+                          -> Result<CreatedGameLog> {
+    create_game_log(&NewGameLog {
+                         game_id: game_id,
+                         body: body,
+                         is_public: true,
+                         logged_at: &UTC::now().naive_utc(),
+                     },
+                    &[],
+                    conn)
+}
+
+/// Eliminates a player outside of the game's rules, recording why and telling the other players,
+/// and passes the turn on if it was theirs. If only one player is left they win the game. This
+/// should be called in a transaction which has locked the game's players.
+pub fn eliminate_game_player(game_id: &Uuid,
+                             player_id: &Uuid,
+                             reason: EliminationReason,
+                             conn: &GenericConnection)
+                             -> Result<Vec<GamePlayer>> {
+    let players = find_game_players_by_game(game_id, conn)?;
+    let player = players
+        .iter()
+        .find(|p| &p.id == player_id)
+        .ok_or_else::<Error, _>(|| "could not find game player".into())?;
+    let remaining: Vec<usize> = players
+        .iter()
+        .filter(|p| !p.is_eliminated && &p.id != player_id)
+        .map(|p| p.position as usize)
+        .collect();
+    let eliminated: Vec<usize> = players
+        .iter()
+        .filter(|p| p.is_eliminated || &p.id == player_id)
+        .map(|p| p.position as usize)
+        .collect();
+    update_game_eliminated(game_id, &eliminated, conn)?;
+    conn.execute("
+        UPDATE game_players
+        SET eliminated_reason=$2
+        WHERE id=$1",
+                 &[player_id, &reason])?;
+    let log = match reason {
+        EliminationReason::Conceded => format!("{{{{player {}}}}} conceded", player.position),
+        EliminationReason::TimedOut => {
+            format!("{{{{player {}}}}} ran out of time and was eliminated",
+                    player.position)
+        }
+    };
+    create_public_game_log(game_id, &log, conn)?;
+    if remaining.len() == 1 {
+        return finish_game(game_id, &remaining, conn);
+    }
+    if player.is_turn {
+        return update_game_whose_turn(game_id, &turns::next_turn(&players, player), conn);
+    }
+    find_game_players_by_game(game_id, conn)
+}
+
+/// Finds the players of a game by position and locks them, so changes to whose turn it is and who
+/// is eliminated made outside of the game's rules don't race each other.
+pub fn lock_game_players(game_id: &Uuid, conn: &GenericConnection) -> Result<Vec<GamePlayer>> {
+    let mut players: Vec<GamePlayer> = vec![];
+    for row in &conn.query("
+        SELECT *
+        FROM game_players
+        WHERE game_id=$1
+        ORDER BY position
+        FOR UPDATE",
+                           &[game_id])? {
+        players.push(GamePlayer::from_row(&row, ""));
+    }
+    Ok(players)
+}
+
+/// Finishes a game outside of the game's rules with the players at `winners` winning, and rates
+/// it.
+fn finish_game(game_id: &Uuid,
+               winners: &[usize],
+               conn: &GenericConnection)
+               -> Result<Vec<GamePlayer>> {
+    update_game_whose_turn(game_id, &[], conn)?;
+    let players = update_game_winners(game_id, winners, conn)?;
+    conn.execute("
+        UPDATE games
+        SET
+            is_finished=TRUE,
+            revision=revision+1
+        WHERE id=$1",
+                 &[game_id])?;
+    ratings::rate_game(game_id, conn)
+        .chain_err(|| "could not rate game")?;
+    Ok(players)
+}
+
+/// Concedes a game on behalf of a player, eliminating them with `eliminate_game_player`. Returns
+/// `None` if the game is finished or the player has already been eliminated.
+pub fn concede_game(game_id: &Uuid,
+                    user_id: &Uuid,
+                    conn: &GenericConnection)
+                    -> Result<Option<Vec<GamePlayer>>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        if lock_unfinished_game(game_id, trans)?.is_none() {
+            return Ok(None);
+        }
+        let players = lock_game_players(game_id, trans)?;
+        let player = match players.iter().find(|p| &p.user_id == user_id) {
+            Some(p) if !p.is_eliminated => p.clone(),
+            _ => return Ok(None),
+        };
+        eliminate_game_player(game_id, &player.id, EliminationReason::Conceded, trans).map(Some)
+    })
+}
+
+/// Ends a game which can't continue, without any winners. Returns `None` if the game is already
+/// finished.
+pub fn abandon_game(game_id: &Uuid,
+                    reason: &str,
+                    conn: &GenericConnection)
+                    -> Result<Option<Game>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        if lock_unfinished_game(game_id, trans)?.is_none() {
+            return Ok(None);
+        }
+        // Players are cleared directly rather than through the update functions, so abandoning
+        // only bumps the revision once.
+        trans.execute("
+            UPDATE game_players
+            SET
+                is_turn=FALSE,
+                is_winner=FALSE,
+                place=NULL,
+                score=NULL
+            WHERE game_id=$1",
+                      &[game_id])?;
+        let mut game: Option<Game> = None;
+        for row in &trans.query("
+            UPDATE games
+            SET
+                is_finished=TRUE,
+                abandoned_at=(now() AT TIME ZONE 'utc'),
+                abandon_reason=$2,
+                revision=revision+1
+            WHERE id=$1
+            RETURNING *",
+                                &[game_id, &reason])? {
+            game = Some(Game::from_row(&row, ""));
+        }
+        create_public_game_log(game_id,
+                               &format!("The game was abandoned: {}", reason),
+                               trans)?;
+        Ok(game)
+    })
+}
+
+/// Updates a game, rating it if it is finished. Ratings use the players' places, so set them
+/// before finishing the game, or use `update_game_and_players_with_placings` to do both at once.
+pub fn update_game(id: &Uuid, update: &NewGame, conn: &GenericConnection) -> Result<Option<Game>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let mut game: Option<Game> = None;
+        let mut params = update.params();
+        params.push(id);
+        for row in &trans.query(&NewGame::update_and_set_sql(&["revision = revision + 1"]),
+                                &params)? {
+            game = Some(Game::from_row(&row, ""));
+        }
+        if game.is_some() && update.is_finished {
+            ratings::rate_game(id, trans)
+                .chain_err(|| "could not rate game")?;
+        }
+        Ok(game)
+    })
+}
+
+fn positions_param(positions: &[usize]) -> Vec<i32> {
+    positions.iter().map(|p| *p as i32).collect()
+}
+
+/// Bumps the revision of a game if any of its players match `changed`, a condition on a player
+/// which can refer to `positions` as `$2`, so player changes can be told apart by revision like
+/// game changes.
+fn bump_revision_for_players(id: &Uuid,
+                             changed: &str,
+                             positions: &[usize],
+                             conn: &GenericConnection)
+                             -> Result<()> {
+    conn.execute(&format!("
+        UPDATE games
+        SET revision=revision+1
+        WHERE id=$1
+        AND EXISTS (
+            SELECT 1
+            FROM game_players
+            WHERE game_id=$1
+            AND ({})
+        )",
+                          changed),
+                 &[id, &positions_param(positions)])?;
+    Ok(())
+}
+
+/// Queues a message of `kind` about a game for each user, sent to their primary email.
+fn enqueue_game_messages(kind: OutboxMessageKind,
+                         game_id: &Uuid,
+                         user_ids: &[Uuid],
+                         conn: &GenericConnection)
+                         -> Result<()> {
+    for user in find_users_with_primary_email(user_ids, conn)? {
+        outbox::enqueue(&NewOutboxMessage {
+                             kind: kind,
+                             recipient: &user.user_email.email,
+                             payload: &json!({"game_id": game_id.to_string()}),
+                             max_attempts: outbox::DEFAULT_MAX_ATTEMPTS,
+                         },
+                        conn)?;
     }
+    Ok(())
 }
 
+/// Gives the turn to the players at `positions`, queueing a message for each player whose turn it
+/// has become. Call this in the transaction changing the game so messages are only sent if the
+/// change is committed.
 pub fn update_game_whose_turn(id: &Uuid,
                               positions: &[usize],
                               conn: &GenericConnection)
                               -> Result<Vec<GamePlayer>> {
+    let mut starting: Vec<Uuid> = vec![];
+    for row in &conn.query("
+        SELECT user_id
+        FROM game_players
+        WHERE game_id=$1
+        AND is_turn=FALSE
+        AND position = ANY($2)",
+                           &[&id, &positions_param(positions)])? {
+        starting.push(row.get("user_id"));
+    }
+    bump_revision_for_players(id,
+                              "is_turn IS DISTINCT FROM (position = ANY($2))",
+                              positions,
+                              conn)?;
     let mut players: Vec<GamePlayer> = vec![];
-    for row in &conn.query(&format!("
+    for row in &conn.query("
         UPDATE game_players
-        SET is_turn={}
+        SET is_turn=(position = ANY($2))
         WHERE game_id=$1
         RETURNING *",
-                                    position_update_clause(positions)),
-                           &[&id])? {
+                           &[&id, &positions_param(positions)])? {
         players.push(GamePlayer::from_row(&row, ""));
     }
+    enqueue_game_messages(OutboxMessageKind::YourTurn, id, &starting, conn)?;
     Ok(players)
 }
 
@@ -360,109 +997,288 @@ pub fn update_game_eliminated(id: &Uuid,
                               positions: &[usize],
                               conn: &GenericConnection)
                               -> Result<Vec<GamePlayer>> {
+    bump_revision_for_players(id,
+                              "is_eliminated IS DISTINCT FROM (position = ANY($2))",
+                              positions,
+                              conn)?;
     let mut players: Vec<GamePlayer> = vec![];
-    for row in &conn.query(&format!("
+    for row in &conn.query("
         UPDATE game_players
-        SET is_eliminated={}
+        SET
+            is_eliminated=(position = ANY($2)),
+            eliminated_reason=CASE WHEN position = ANY($2) THEN eliminated_reason END
         WHERE game_id=$1
         RETURNING *",
-                                    position_update_clause(positions)),
-                           &[&id])? {
+                           &[&id, &positions_param(positions)])? {
         players.push(GamePlayer::from_row(&row, ""));
     }
     Ok(players)
 }
 
+/// Sets which players won, clearing any places and scores as they may not agree with the winners.
+/// Use `update_game_placings` to set winners along with places.
 pub fn update_game_winners(id: &Uuid,
                            positions: &[usize],
                            conn: &GenericConnection)
                            -> Result<Vec<GamePlayer>> {
+    bump_revision_for_players(id,
+                              "is_winner IS DISTINCT FROM (position = ANY($2)) \
+                               OR place IS NOT NULL OR score IS NOT NULL",
+                              positions,
+                              conn)?;
     let mut players: Vec<GamePlayer> = vec![];
-    for row in &conn.query(&format!("
+    for row in &conn.query("
         UPDATE game_players
-        SET is_winner={}
+        SET
+            is_winner=(position = ANY($2)),
+            place=NULL,
+            score=NULL
         WHERE game_id=$1
         RETURNING *",
-                                    position_update_clause(positions)),
-                           &[&id])? {
+                           &[&id, &positions_param(positions)])? {
+        players.push(GamePlayer::from_row(&row, ""));
+    }
+    Ok(players)
+}
+
+/// A player's final place and score, by position.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Placing {
+    pub position: usize,
+    /// Places start from 1, and tied players share a place.
+    pub place: usize,
+    pub score: Option<i32>,
+}
+
+/// Checks placings are valid for the players of a game.
+pub fn check_placings(placings: &[Placing], players: &[GamePlayer]) -> Result<()> {
+    let mut seen: HashSet<usize> = HashSet::new();
+    for p in placings {
+        if !players.iter().any(|gp| gp.position as usize == p.position) {
+            return Err(format!("there is no player in position {}", p.position).into());
+        }
+        if p.place < 1 {
+            return Err(format!("place for position {} must be at least 1", p.position).into());
+        }
+        if !seen.insert(p.position) {
+            return Err(format!("position {} is placed more than once", p.position).into());
+        }
+    }
+    Ok(())
+}
+
+/// Sets the final place and score of players, clearing them for players without a placing.
+/// Players placed first are marked as winners, and all others aren't.
+pub fn update_game_placings(id: &Uuid,
+                            placings: &[Placing],
+                            conn: &GenericConnection)
+                            -> Result<Vec<GamePlayer>> {
+    let current = find_game_players_by_game(id, conn)?;
+    check_placings(placings, &current)?;
+    let changed = current
+        .iter()
+        .any(|p| {
+                 let placing = placings
+                     .iter()
+                     .find(|pl| pl.position == p.position as usize);
+                 let place = placing.map(|pl| pl.place as i32);
+                 p.place != place || p.score != placing.and_then(|pl| pl.score) ||
+                 p.is_winner != (place == Some(1))
+             });
+    if changed {
+        conn.execute("
+            UPDATE games
+            SET revision=revision+1
+            WHERE id=$1",
+                     &[id])?;
+    }
+    let positions: Vec<i32> = placings.iter().map(|p| p.position as i32).collect();
+    let places: Vec<i32> = placings.iter().map(|p| p.place as i32).collect();
+    let scores: Vec<Option<i32>> = placings.iter().map(|p| p.score).collect();
+    let mut players: Vec<GamePlayer> = vec![];
+    for row in &conn.query("
+        UPDATE game_players gp
+        SET
+            place=pl.place,
+            score=pl.score,
+            is_winner=COALESCE(pl.place = 1, FALSE)
+        FROM game_players cur
+        LEFT JOIN unnest($2::int[], $3::int[], $4::int[]) AS pl (position, place, score)
+        ON pl.position = cur.position
+        WHERE cur.id = gp.id
+        AND gp.game_id=$1
+        RETURNING gp.*",
+                           &[&id, &positions, &places, &scores])? {
         players.push(GamePlayer::from_row(&row, ""));
     }
     Ok(players)
 }
 
+/// Like `update_game_and_players`, but sets the players' final placings instead of just the
+/// winners, so a game finished by this is rated on its places.
+pub fn update_game_and_players_with_placings(game_id: &Uuid,
+                                             update: &NewGame,
+                                             whose_turn: &[usize],
+                                             eliminated: &[usize],
+                                             placings: &[Placing],
+                                             conn: &GenericConnection)
+                                             -> Result<UpdatedGame> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        // The game is updated last so it is rated on the new placings if this finishes it.
+        let whose_turn = update_game_whose_turn(game_id, whose_turn, trans)?;
+        let eliminated = update_game_eliminated(game_id, eliminated, trans)?;
+        let winners = update_game_placings(game_id, placings, trans)?;
+        Ok(UpdatedGame {
+               game: update_game(game_id, update, trans)?,
+               whose_turn: whose_turn,
+               eliminated: eliminated,
+               winners: winners,
+           })
+    })
+}
+
+pub struct Standing {
+    pub player: GamePlayer,
+    pub user: User,
+}
+
+/// Finds the players of a game in order of their final place, then score. Players without a place
+/// are last.
+pub fn find_game_standings(game_id: &Uuid, conn: &GenericConnection) -> Result<Vec<Standing>> {
+    let mut standings: Vec<Standing> = vec![];
+    for row in &Select::from::<GamePlayer>("gp")
+                    .cols::<GamePlayer>("gp", "gp_")
+                    .cols::<User>("u", "u_")
+                    .join::<User>("u", "u.id = gp.user_id", &[])
+                    .filter("gp.game_id = ?", &[game_id])
+                    .order_by("gp.place NULLS LAST")
+                    .order_by("gp.score DESC NULLS LAST")
+                    .order_by("gp.position")
+                    .query(conn)? {
+        standings.push(Standing {
+                           player: GamePlayer::from_row(&row, "gp_"),
+                           user: User::from_row(&row, "u_"),
+                       });
+    }
+    Ok(standings)
+}
+
 pub fn create_game_logs_from_cli(game_id: &Uuid,
                                  logs: Vec<CliLog>,
                                  conn: &GenericConnection)
                                  -> Result<Vec<CreatedGameLog>> {
-    let mut player_id_by_position: HashMap<usize, Uuid> = HashMap::new();
-    let trans = conn.transaction()?;
-    for p in find_game_players_by_game(game_id, &trans)? {
-        player_id_by_position.insert(p.position as usize, p.id);
-    }
-    let mut created: Vec<CreatedGameLog> = vec![];
-    for l in logs {
-        let mut player_to: Vec<Uuid> = vec![];
-        for t in l.to {
-            player_to.push(player_id_by_position
-                               .get(&t)
-                               .ok_or_else::<Error, _>(|| {
-                                                           "no player with that position exists"
-                                                               .into()
-                                                       })?
-                               .to_owned());
-        }
-        created.push(create_game_log(&NewGameLog {
-                                          game_id: game_id,
-                                          body: &l.content,
-                                          is_public: l.public,
-                                          logged_at: &l.at,
-                                      },
-                                     &player_to,
-                                     &trans)?);
-    }
-    trans.commit()?;
-    Ok(created)
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let mut player_id_by_position: HashMap<usize, Uuid> = HashMap::new();
+        for p in find_game_players_by_game(game_id, trans)? {
+            player_id_by_position.insert(p.position as usize, p.id);
+        }
+        let mut new_logs: Vec<(NewGameLog, Vec<Uuid>)> = vec![];
+        for l in &logs {
+            let mut player_to: Vec<Uuid> = vec![];
+            for t in &l.to {
+                player_to.push(player_id_by_position
+                                   .get(t)
+                                   .ok_or_else::<Error, _>(|| {
+                                                               "no player with that position \
+                                                                exists"
+                                                                       .into()
+                                                           })?
+                                   .to_owned());
+            }
+            new_logs.push((NewGameLog {
+                               game_id: game_id,
+                               body: &l.content,
+                               is_public: l.public,
+                               logged_at: &l.at,
+                           },
+                           player_to));
+        }
+        create_game_logs(&new_logs, trans)
+    })
 }
 
 pub fn find_game_players_by_game(game_id: &Uuid,
                                  conn: &GenericConnection)
                                  -> Result<Vec<GamePlayer>> {
     let mut players: Vec<GamePlayer> = vec![];
-    for row in &conn.query("
+    for row in &conn.prepare_cached("
         SELECT *
         FROM game_players
-        WHERE game_id=$1",
-                           &[game_id])? {
+        WHERE game_id=$1")?
+                    .query(&[game_id])? {
         players.push(GamePlayer::from_row(&row, ""));
     }
     Ok(players)
 }
 
+pub fn find_game_player_by_user(game_id: &Uuid,
+                                user_id: &Uuid,
+                                conn: &GenericConnection)
+                                -> Result<Option<GamePlayer>> {
+    for row in &conn.prepare_cached("
+        SELECT *
+        FROM game_players
+        WHERE game_id=$1
+        AND user_id=$2
+        LIMIT 1")?
+                    .query(&[game_id, user_id])? {
+        return Ok(Some(GamePlayer::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub struct CreatedGameLog {
     pub game_log: GameLog,
     pub targets: Vec<GameLogTarget>,
 }
-pub fn create_game_log(log: &NewGameLog,
-                       to: &[Uuid],
-                       conn: &GenericConnection)
-                       -> Result<CreatedGameLog> {
+/// Creates logs along with the players each is targeted to, using one statement for the logs and
+/// one for the targets.
+pub fn create_game_logs(logs: &[(NewGameLog, Vec<Uuid>)],
+                        conn: &GenericConnection)
+                        -> Result<Vec<CreatedGameLog>> {
     let trans = conn.transaction()?;
-    let mut created_log: Option<GameLog> = None;
-    for row in &trans
-                    .query("
-        INSERT INTO game_logs (
-            game_id,
-            body,
-            is_public,
-            logged_at
-        ) VALUES (
-            $1,
-            $2,
-            $3,
-            $4
-        )
-        RETURNING *",
-                           &[&log.game_id, &log.body, &log.is_public, &log.logged_at])? {
+    let game_logs =
+        insert_all_with_ids(&logs.iter().map(|&(ref l, _)| l).collect::<Vec<&NewGameLog>>(),
+                            &trans,
+                            |row| GameLog::from_row(row, ""),
+                            |gl| gl.id)?;
+    let mut new_targets: Vec<NewGameLogTarget> = vec![];
+    for (gl, &(_, ref to)) in game_logs.iter().zip(logs) {
+        for player_id in to {
+            new_targets.push(NewGameLogTarget {
+                                 game_log_id: &gl.id,
+                                 player_id: player_id,
+                             });
+        }
+    }
+    let mut targets_by_log: HashMap<Uuid, Vec<GameLogTarget>> = HashMap::new();
+    for t in insert_all(&new_targets.iter().collect::<Vec<&NewGameLogTarget>>(),
+                        &trans,
+                        |row| GameLogTarget::from_row(row, ""))? {
+        targets_by_log
+            .entry(t.game_log_id)
+            .or_insert_with(Vec::new)
+            .push(t);
+    }
+    let mut created: Vec<CreatedGameLog> = vec![];
+    for gl in game_logs {
+        created.push(CreatedGameLog {
+                         targets: targets_by_log.remove(&gl.id).unwrap_or_default(),
+                         game_log: gl,
+                     });
+    }
+    trans.commit()?;
+    Ok(created)
+}
+
+pub fn create_game_log(log: &NewGameLog,
+                       to: &[Uuid],
+                       conn: &GenericConnection)
+                       -> Result<CreatedGameLog> {
+    let trans = conn.transaction()?;
+    let mut created_log: Option<GameLog> = None;
+    for row in &trans.query(&NewGameLog::insert_sql(), &log.params())? {
         created_log = Some(GameLog::from_row(&row, ""));
     }
     let gl = created_log
@@ -479,72 +1295,161 @@ pub fn create_game_log_targets(log_id: &Uuid,
                                player_ids: &[Uuid],
                                conn: &GenericConnection)
                                -> Result<Vec<GameLogTarget>> {
-    let trans = conn.transaction()?;
-    let mut created = vec![];
-    for id in player_ids {
-        created.push(create_game_log_target(&NewGameLogTarget {
-                                                 game_log_id: log_id,
-                                                 player_id: id,
-                                             },
-                                            &trans)?);
-    }
-    trans.commit()?;
-    Ok(created)
+    let new_targets: Vec<NewGameLogTarget> = player_ids
+        .iter()
+        .map(|id| {
+                 NewGameLogTarget {
+                     game_log_id: log_id,
+                     player_id: id,
+                 }
+             })
+        .collect();
+    insert_all(&new_targets.iter().collect::<Vec<&NewGameLogTarget>>(),
+               conn,
+               |row| GameLogTarget::from_row(row, ""))
 }
 
 pub fn create_game_log_target(new_target: &NewGameLogTarget,
                               conn: &GenericConnection)
                               -> Result<GameLogTarget> {
-    for row in &conn.query("
-        INSERT INTO game_log_targets (
-            game_log_id,
-            player_id
-        ) VALUES (
-            $1,
-            $2
-        )
-        RETURNING *",
-                           &[&new_target.game_log_id, &new_target.player_id])? {
+    for row in &conn.query(&NewGameLogTarget::insert_sql(), &new_target.params())? {
         return Ok(GameLogTarget::from_row(&row, ""));
     }
     Err("error creating game log target".into())
 
 }
 
+/// Finds users by id, then by email, creating users for emails which aren't found. Users are
+/// returned in the order they are given, using a fixed number of queries.
 pub fn create_game_users(ids: &[Uuid],
                          emails: &[String],
                          conn: &GenericConnection)
                          -> Result<Vec<UserByEmail>> {
     let trans = conn.transaction()?;
     let mut users: Vec<UserByEmail> = vec![];
-    for id in ids.iter() {
-        users.push(find_user_with_primary_email(id, &trans)?
+    let by_id: HashMap<Uuid, UserByEmail> =
+        HashMap::from_iter(find_users_with_primary_email(ids, &trans)?
+                               .into_iter()
+                               .map(|u| (u.user.id, u)));
+    for id in ids {
+        users.push(by_id
+                       .get(id)
+                       .cloned()
                        .ok_or_else::<Error, _>(|| "unable to find user".into())?);
     }
-    for email in emails.iter() {
-        users.push(match find_user_with_primary_email_by_email(email, &trans)? {
-                       Some(ube) => ube,
-                       None => create_user_by_email(email, &trans)?,
-                   });
+    let mut by_email = find_users_with_primary_email_by_emails(emails, &trans)?;
+    let mut missing: Vec<&str> = vec![];
+    for email in emails {
+        if !by_email.contains_key(email) && !missing.contains(&email.as_str()) {
+            missing.push(email);
+        }
+    }
+    for created in create_users_by_emails(&missing, &trans)? {
+        by_email.insert(created.user_email.email.clone(), created);
     }
+    for email in emails {
+        users.push(by_email
+                       .get(email)
+                       .cloned()
+                       .ok_or_else::<Error, _>(|| "unable to create user".into())?);
+    }
+    trans.commit()?;
+    Ok(users)
+}
+
+/// Creates a user for each email, named after the email, using one statement for the users and
+/// one for the emails, and returns them in the same order as `emails`.
+pub fn create_users_by_emails(emails: &[&str],
+                              conn: &GenericConnection)
+                              -> Result<Vec<UserByEmail>> {
+    let trans = conn.transaction()?;
+    let new_users: Vec<NewUser> = emails
+        .iter()
+        .map(|email| {
+                 NewUser {
+                     name: email,
+                     pref_colors: &[],
+                     login_confirmation: None,
+                     login_confirmation_at: None,
+                 }
+             })
+        .collect();
+    let users = insert_all_with_ids(&new_users.iter().collect::<Vec<&NewUser>>(),
+                                    &trans,
+                                    |row| User::from_row(row, ""),
+                                    |u| u.id)?;
+    let new_emails: Vec<NewUserEmail> = users
+        .iter()
+        .zip(emails)
+        .map(|(u, email)| {
+                 NewUserEmail {
+                     user_id: &u.id,
+                     email: email,
+                     is_primary: true,
+                 }
+             })
+        .collect();
+    let user_emails = insert_all_with_ids(&new_emails.iter().collect::<Vec<&NewUserEmail>>(),
+                                          &trans,
+                                          |row| UserEmail::from_row(row, ""),
+                                          |ue| ue.id)?;
     trans.commit()?;
+    Ok(users
+           .into_iter()
+           .zip(user_emails)
+           .map(|(u, ue)| {
+                    UserByEmail {
+                        user: u,
+                        user_email: ue,
+                    }
+                })
+           .collect())
+}
+
+pub fn find_users_with_primary_email(ids: &[Uuid],
+                                     conn: &GenericConnection)
+                                     -> Result<Vec<UserByEmail>> {
+    let mut users: Vec<UserByEmail> = vec![];
+    for row in &users_with_emails()
+                    .filter("u.id = ANY(?)", &[&ids])
+                    .filter("ue.is_primary = TRUE", &[])
+                    .query(conn)? {
+        users.push(UserByEmail {
+                       user: User::from_row(&row, "u_"),
+                       user_email: UserEmail::from_row(&row, "ue_"),
+                   });
+    }
+    Ok(users)
+}
+
+/// Finds users with any of the emails, keyed by the email they were found by.
+pub fn find_users_with_primary_email_by_emails(emails: &[String],
+                                               conn: &GenericConnection)
+                                               -> Result<HashMap<String, UserByEmail>> {
+    let mut users: HashMap<String, UserByEmail> = HashMap::new();
+    for row in &users_with_emails()
+                    .col("uef.email AS uef_email")
+                    .join::<UserEmail>("uef", "u.id = uef.user_id", &[])
+                    .filter("uef.email = ANY(?)", &[&emails])
+                    .filter("ue.is_primary = TRUE", &[])
+                    .query(conn)? {
+        users.insert(row.get("uef_email"),
+                     UserByEmail {
+                         user: User::from_row(&row, "u_"),
+                         user_email: UserEmail::from_row(&row, "ue_"),
+                     });
+    }
     Ok(users)
 }
 
 pub fn find_user_with_primary_email(id: &Uuid,
                                     conn: &GenericConnection)
                                     -> Result<Option<UserByEmail>> {
-    for row in &conn.query(&format!("
-        SELECT {}, {}
-        FROM users u
-        INNER JOIN user_emails ue
-        ON (u.id = ue.user_id)
-        WHERE u.id = $1
-        AND ue.is_primary = TRUE
-        LIMIT 1",
-                                    User::select_cols("u", "u_"),
-                                    UserEmail::select_cols("ue", "ue_")),
-                           &[id])? {
+    for row in &users_with_emails()
+                    .filter("u.id = ?", &[id])
+                    .filter("ue.is_primary = TRUE", &[])
+                    .limit(1)
+                    .query(conn)? {
         return Ok(Some(UserByEmail {
                            user: User::from_row(&row, "u_"),
                            user_email: UserEmail::from_row(&row, "ue_"),
@@ -556,19 +1461,12 @@ pub fn find_user_with_primary_email(id: &Uuid,
 pub fn find_user_with_primary_email_by_email(email: &str,
                                              conn: &GenericConnection)
                                              -> Result<Option<UserByEmail>> {
-    for row in &conn.query(&format!("
-        SELECT {}, {}
-        FROM users u
-        INNER JOIN user_emails ue
-        ON (u.id = ue.user_id)
-        INNER JOIN user_emails uef
-        ON (u.id = uef.user_id)
-        WHERE uef.email = $1
-        AND ue.is_primary = TRUE
-        LIMIT 1",
-                                    User::select_cols("u", "u_"),
-                                    UserEmail::select_cols("ue", "ue_")),
-                           &[&email])? {
+    for row in &users_with_emails()
+                    .join::<UserEmail>("uef", "u.id = uef.user_id", &[])
+                    .filter("uef.email = ?", &[&email])
+                    .filter("ue.is_primary = TRUE", &[])
+                    .limit(1)
+                    .query(conn)? {
         return Ok(Some(UserByEmail {
                            user: User::from_row(&row, "u_"),
                            user_email: UserEmail::from_row(&row, "ue_"),
@@ -578,20 +1476,7 @@ pub fn find_user_with_primary_email_by_email(email: &str,
 }
 
 pub fn create_game(new_game: &NewGame, conn: &GenericConnection) -> Result<Game> {
-    for row in &conn.query("
-        INSERT INTO games (
-            game_version_id,
-            is_finished,
-            game_state
-        ) VALUES (
-            $1,
-            $2,
-            $3
-        )
-        RETURNING *",
-                           &[&new_game.game_version_id,
-                             &new_game.is_finished,
-                             &new_game.game_state])? {
+    for row in &conn.query(&NewGame::insert_sql(), &new_game.params())? {
         return Ok(Game::from_row(&row, ""));
     }
     Err("error creating game".into())
@@ -600,90 +1485,417 @@ pub fn create_game(new_game: &NewGame, conn: &GenericConnection) -> Result<Game>
 pub fn create_game_version(new_game_version: &NewGameVersion,
                            conn: &GenericConnection)
                            -> Result<GameVersion> {
-    for row in &conn.query("
-        INSERT INTO game_versions (
-            game_type_id,
-            uri,
-            name,
-            is_public,
-            is_deprecated
-        ) VALUES (
-            $1,
-            $2,
-            $3,
-            $4,
-            $5
-        )
-        RETURNING *",
-                           &[&new_game_version.game_type_id,
-                             &new_game_version.uri,
-                             &new_game_version.name,
-                             &new_game_version.is_public,
-                             &new_game_version.is_deprecated])? {
+    for row in &conn.query(&NewGameVersion::insert_sql(), &new_game_version.params())? {
         return Ok(GameVersion::from_row(&row, ""));
     }
     Err("error creating game version".into())
 }
 
-pub fn create_game_type(new_game_type: &NewGameType, conn: &GenericConnection) -> Result<GameType> {
+/// Finds game types with a public, non-deprecated version which can be played with the given
+/// number of players.
+pub fn find_game_types_by_player_count(count: usize,
+                                       conn: &GenericConnection)
+                                       -> Result<Vec<GameType>> {
+    let mut game_types: Vec<GameType> = vec![];
     for row in &conn.query("
-        INSERT INTO game_types (
-            name
-        ) VALUES (
-            $1
-        )
-        RETURNING *",
-                           &[&new_game_type.name])? {
+        SELECT DISTINCT gt.*
+        FROM game_types gt
+        INNER JOIN game_versions gv
+        ON (gv.game_type_id = gt.id)
+        WHERE gv.is_public = TRUE
+        AND gv.is_deprecated = FALSE
+        AND gv.min_players <= $1
+        AND (gv.max_players IS NULL OR gv.max_players >= $1)
+        ORDER BY gt.name",
+                           &[&(count as i32)])? {
+        game_types.push(GameType::from_row(&row, ""));
+    }
+    Ok(game_types)
+}
+
+pub fn create_game_type(new_game_type: &NewGameType, conn: &GenericConnection) -> Result<GameType> {
+    for row in &conn.query(&NewGameType::insert_sql(), &new_game_type.params())? {
         return Ok(GameType::from_row(&row, ""));
     }
     Err("error creating game type".into())
 }
 
+/// Creates the players in one statement, returning them in the same order.
 pub fn create_game_players(players: &[NewGamePlayer],
                            conn: &GenericConnection)
                            -> Result<Vec<GamePlayer>> {
+    insert_all_with_ids(&players.iter().collect::<Vec<&NewGamePlayer>>(),
+                        conn,
+                        |row| GamePlayer::from_row(row, ""),
+                        |p| p.id)
+}
+
+pub fn create_game_player(player: &NewGamePlayer, conn: &GenericConnection) -> Result<GamePlayer> {
+    for row in &conn.query(&NewGamePlayer::insert_sql(), &player.params())? {
+        return Ok(GamePlayer::from_row(&row, ""));
+    }
+    Err("error creating game type".into())
+}
+
+pub fn request_game_color_swap(game_id: &Uuid,
+                               from_user_id: &Uuid,
+                               to_user_id: &Uuid,
+                               conn: &GenericConnection)
+                               -> Result<GameColorSwap> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let game = find_game(game_id, trans)?
+            .ok_or_else::<Error, _>(|| "could not find game".into())?;
+        if game.is_finished {
+            return Err("game is already finished".into());
+        }
+        let from = find_game_player_by_user(game_id, from_user_id, trans)?
+            .ok_or_else::<Error, _>(|| "requesting user is not in the game".into())?;
+        let to = find_game_player_by_user(game_id, to_user_id, trans)?
+            .ok_or_else::<Error, _>(|| "target user is not in the game".into())?;
+        if from.id == to.id {
+            return Err("cannot swap colors with yourself".into());
+        }
+        create_game_color_swap(&NewGameColorSwap {
+                                    game_id: game_id,
+                                    from_player_id: &from.id,
+                                    to_player_id: &to.id,
+                                    game_revision: game.revision,
+                                },
+                               trans)
+    })
+}
+
+pub fn create_game_color_swap(swap: &NewGameColorSwap,
+                              conn: &GenericConnection)
+                              -> Result<GameColorSwap> {
+    for row in &conn.query(&NewGameColorSwap::insert_sql(), &swap.params())? {
+        return Ok(GameColorSwap::from_row(&row, ""));
+    }
+    Err("error creating game color swap".into())
+}
+
+// A swap is pending until it is accepted or declined, or until the game it was requested in has
+// progressed past the revision it was requested at.
+pub fn pending_game_color_swaps<'a>() -> Select<'a> {
+    Select::from::<GameColorSwap>("gcs")
+        .cols::<GameColorSwap>("gcs", "")
+        .join::<Game>("g", "gcs.game_id = g.id", &[])
+        .filter("gcs.accepted_at IS NULL", &[])
+        .filter("gcs.declined_at IS NULL", &[])
+        .filter("gcs.game_revision = g.revision", &[])
+        .filter("g.is_finished = FALSE", &[])
+}
+
+pub fn find_pending_game_color_swaps(game_id: &Uuid,
+                                     conn: &GenericConnection)
+                                     -> Result<Vec<GameColorSwap>> {
+    let mut swaps: Vec<GameColorSwap> = vec![];
+    for row in &pending_game_color_swaps()
+                    .filter("gcs.game_id = ?", &[game_id])
+                    .order_by("gcs.created_at")
+                    .query(conn)? {
+        swaps.push(GameColorSwap::from_row(&row, ""));
+    }
+    Ok(swaps)
+}
+
+/// Accepts a pending color swap on behalf of the target user, swapping the colors of both players
+/// atomically and ranking their new colors against their preferences. Returns `None` if there is no
+/// pending swap with that id targeting the user.
+pub fn accept_game_color_swap(id: &Uuid,
+                              user_id: &Uuid,
+                              conn: &GenericConnection)
+                              -> Result<Option<Vec<GamePlayer>>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        // Lock the game as well as the swap so the game can't progress until we've committed.
+        let mut swap: Option<GameColorSwap> = None;
+        for row in &pending_game_color_swaps()
+                        .join::<GamePlayer>("tp", "gcs.to_player_id = tp.id", &[])
+                        .filter("gcs.id = ?", &[id])
+                        .filter("tp.user_id = ?", &[user_id])
+                        .limit(1)
+                        .for_update_of(&["gcs", "g"])
+                        .query(trans)? {
+            swap = Some(GameColorSwap::from_row(&row, ""));
+        }
+        let swap = match swap {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        // Both rows are updated in a single statement, but uniqueness is still checked row by
+        // row unless the constraint is deferred to the end of the transaction.
+        trans
+            .execute("SET CONSTRAINTS game_players_game_id_color_key DEFERRED",
+                     &[])?;
+        let mut players: Vec<GamePlayer> = vec![];
+        for row in &trans
+                        .query("
+            UPDATE game_players gp
+            SET
+                color = other.color,
+                color_pref_rank = array_position(u.pref_colors, other.color) - 1,
+                color_pref_known = TRUE
+            FROM game_players other, users u
+            WHERE gp.id IN ($1, $2)
+            AND other.id IN ($1, $2)
+            AND other.id <> gp.id
+            AND u.id = gp.user_id
+            RETURNING gp.*",
+                               &[&swap.from_player_id, &swap.to_player_id])? {
+            players.push(GamePlayer::from_row(&row, ""));
+        }
+        trans
+            .execute("
+            UPDATE game_color_swaps
+            SET accepted_at=(now() AT TIME ZONE 'utc')
+            WHERE id=$1",
+                     &[id])?;
+        Ok(Some(players))
+    })
+}
+
+/// Declines a pending color swap on behalf of the target user. Returns `None` if there is no
+/// pending swap with that id targeting the user, including swaps which have expired.
+pub fn decline_game_color_swap(id: &Uuid,
+                               user_id: &Uuid,
+                               conn: &GenericConnection)
+                               -> Result<Option<GameColorSwap>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let mut swap: Option<GameColorSwap> = None;
+        for row in &pending_game_color_swaps()
+                        .join::<GamePlayer>("tp", "gcs.to_player_id = tp.id", &[])
+                        .filter("gcs.id = ?", &[id])
+                        .filter("tp.user_id = ?", &[user_id])
+                        .limit(1)
+                        .for_update_of(&["gcs", "g"])
+                        .query(trans)? {
+            swap = Some(GameColorSwap::from_row(&row, ""));
+        }
+        if swap.is_none() {
+            return Ok(None);
+        }
+        for row in &trans.query("
+            UPDATE game_color_swaps
+            SET declined_at=(now() AT TIME ZONE 'utc')
+            WHERE id=$1
+            RETURNING *",
+                                &[id])? {
+            return Ok(Some(GameColorSwap::from_row(&row, "")));
+        }
+        Ok(None)
+    })
+}
+
+/// Selects games as `g`, as a base for game listings which `find_games` can read.
+pub fn games_select<'a>() -> Select<'a> {
+    Select::from::<Game>("g").cols::<Game>("g", "")
+}
+
+pub fn find_games(select: &Select, conn: &GenericConnection) -> Result<Vec<Game>> {
+    let mut games: Vec<Game> = vec![];
+    for row in &select.query(conn)? {
+        games.push(Game::from_row(&row, ""));
+    }
+    Ok(games)
+}
+
+pub fn find_unfinished_games_by_game_version(game_version_id: &Uuid,
+                                             conn: &GenericConnection)
+                                             -> Result<Vec<Game>> {
+    find_games(&games_select()
+                    .filter("g.game_version_id = ?", &[game_version_id])
+                    .filter("g.is_finished = FALSE", &[])
+                    .order_by("g.created_at"),
+               conn)
+}
+
+pub struct MigratedGameVersion {
+    pub run_id: Uuid,
+    pub migrations: Vec<GameVersionMigration>,
+}
+
+/// Moves every unfinished game on one game version to another version of the same game type,
+/// committing in batches of `batch_size` games. `transform` is given each game and returns the
+/// game state to store for the new version. Each migrated game is recorded against a single run
+/// id so the run can be audited and rolled back with `rollback_game_version_migration_run`.
+///
+/// If a batch fails after earlier batches were committed, the error is an
+/// `IncompleteGameVersionMigration` carrying the run id and the migrations already made.
+pub fn migrate_game_version<F>(from_game_version_id: &Uuid,
+                               to_game_version_id: &Uuid,
+                               batch_size: usize,
+                               transform: F,
+                               conn: &GenericConnection)
+                               -> Result<MigratedGameVersion>
+    where F: Fn(&Game) -> Result<String>
+{
+    if from_game_version_id == to_game_version_id {
+        return Err("cannot migrate games to the version they are already on".into());
+    }
+    if batch_size == 0 {
+        return Err("batch size must be greater than zero".into());
+    }
+    let from = find_game_version(from_game_version_id, conn)?
+        .ok_or_else::<Error, _>(|| "could not find game version to migrate from".into())?;
+    let to = find_game_version(to_game_version_id, conn)?
+        .ok_or_else::<Error, _>(|| "could not find game version to migrate to".into())?;
+    if from.game_type_id != to.game_type_id {
+        return Err("cannot migrate games to a version of a different game type".into());
+    }
+    if to.is_deprecated {
+        return Err("cannot migrate games to a deprecated version".into());
+    }
+    for row in &conn.query("
+        SELECT COUNT(gp.id) AS player_count
+        FROM games g
+        LEFT OUTER JOIN game_players gp
+        ON gp.game_id = g.id
+        WHERE g.game_version_id=$1
+        AND g.is_finished=FALSE
+        GROUP BY g.id",
+                           &[from_game_version_id])? {
+        let player_count: i64 = row.get("player_count");
+        if !to.supports_player_count(player_count as usize) {
+            return Err(ErrorKind::InvalidPlayerCount(player_count as usize,
+                                                     to.min_players,
+                                                     to.max_players)
+                               .into());
+        }
+    }
+
+    let run_id = Uuid::new_v4();
+    let mut migrations: Vec<GameVersionMigration> = vec![];
+    loop {
+        match migrate_game_version_batch(&run_id, &from, &to, batch_size, &transform, conn) {
+            Ok(ref batch) if batch.is_empty() => break,
+            Ok(batch) => migrations.extend(batch),
+            Err(e) => {
+                if migrations.is_empty() {
+                    return Err(e);
+                }
+                return Err(e.chain_err(|| {
+                                           ErrorKind::IncompleteGameVersionMigration(run_id,
+                                                                                     migrations)
+                                       }));
+            }
+        }
+    }
+    Ok(MigratedGameVersion {
+           run_id: run_id,
+           migrations: migrations,
+       })
+}
+
+fn migrate_game_version_batch<F>(run_id: &Uuid,
+                                 from: &GameVersion,
+                                 to: &GameVersion,
+                                 batch_size: usize,
+                                 transform: &F,
+                                 conn: &GenericConnection)
+                                 -> Result<Vec<GameVersionMigration>>
+    where F: Fn(&Game) -> Result<String>
+{
     let trans = conn.transaction()?;
-    let mut created: Vec<GamePlayer> = vec![];
-    for p in players.iter() {
-        created.push(create_game_player(p, &trans)?);
+    let mut games: Vec<Game> = vec![];
+    for row in &trans
+                    .query("
+        SELECT *
+        FROM games
+        WHERE game_version_id=$1
+        AND is_finished=FALSE
+        ORDER BY created_at
+        LIMIT $2
+        FOR UPDATE",
+                           &[&from.id, &(batch_size as i64)])? {
+        games.push(Game::from_row(&row, ""));
+    }
+    let mut migrations: Vec<GameVersionMigration> = vec![];
+    for game in &games {
+        let game_state = transform(game)
+            .chain_err(|| format!("could not transform game state for game {}", game.id))?;
+        trans
+            .execute("
+            UPDATE games
+            SET
+                game_version_id=$1,
+                game_state=$2,
+                revision=revision+1
+            WHERE id=$3",
+                     &[&to.id, &game_state, &game.id])?;
+        migrations.push(create_game_version_migration(&NewGameVersionMigration {
+                                                           run_id: run_id,
+                                                           game_id: &game.id,
+                                                           from_game_version_id: &from.id,
+                                                           to_game_version_id: &to.id,
+                                                           from_game_state: &game.game_state,
+                                                           to_game_state: &game_state,
+                                                           game_revision: game.revision + 1,
+                                                       },
+                                                      &trans)?);
     }
     trans.commit()?;
-    Ok(created)
+    Ok(migrations)
 }
 
-pub fn create_game_player(player: &NewGamePlayer, conn: &GenericConnection) -> Result<GamePlayer> {
+pub fn create_game_version_migration(migration: &NewGameVersionMigration,
+                                     conn: &GenericConnection)
+                                     -> Result<GameVersionMigration> {
+    for row in &conn.query(&NewGameVersionMigration::insert_sql(), &migration.params())? {
+        return Ok(GameVersionMigration::from_row(&row, ""));
+    }
+    Err("error creating game version migration".into())
+}
+
+pub fn find_game_version_migrations_by_game(game_id: &Uuid,
+                                            conn: &GenericConnection)
+                                            -> Result<Vec<GameVersionMigration>> {
+    let mut migrations: Vec<GameVersionMigration> = vec![];
     for row in &conn.query("
-        INSERT INTO game_players (
-            game_id,
-            user_id,
-            position,
-            color,
-            has_accepted,
-            is_turn,
-            is_eliminated,
-            is_winner
-        ) VALUES (
-            $1,
-            $2,
-            $3,
-            $4,
-            $5,
-            $6,
-            $7,
-            $8
-        )
-        RETURNING *",
-                           &[&player.game_id,
-                             &player.user_id,
-                             &player.position,
-                             &player.color,
-                             &player.has_accepted,
-                             &player.is_turn,
-                             &player.is_eliminated,
-                             &player.is_winner])? {
-        return Ok(GamePlayer::from_row(&row, ""));
+        SELECT *
+        FROM game_version_migrations
+        WHERE game_id=$1
+        ORDER BY created_at",
+                           &[game_id])? {
+        migrations.push(GameVersionMigration::from_row(&row, ""));
     }
-    Err("error creating game type".into())
+    Ok(migrations)
+}
+
+/// Restores the version and state of every game migrated in a run. Games which have been played
+/// since they were migrated are left alone, and are not included in the returned migrations.
+pub fn rollback_game_version_migration_run(run_id: &Uuid,
+                                           conn: &GenericConnection)
+                                           -> Result<Vec<GameVersionMigration>> {
+    let trans = conn.transaction()?;
+    let mut rolled_back: Vec<GameVersionMigration> = vec![];
+    for row in &trans
+                    .query(&format!("
+        UPDATE game_version_migrations gvm
+        SET rolled_back_at=(now() AT TIME ZONE 'utc')
+        FROM games g
+        WHERE gvm.game_id = g.id
+        AND gvm.run_id = $1
+        AND gvm.rolled_back_at IS NULL
+        AND g.game_version_id = gvm.to_game_version_id
+        AND g.revision = gvm.game_revision
+        RETURNING {}",
+                                    GameVersionMigration::select_cols("gvm", "")),
+                           &[run_id])? {
+        rolled_back.push(GameVersionMigration::from_row(&row, ""));
+    }
+    for m in &rolled_back {
+        trans
+            .execute("
+            UPDATE games
+            SET
+                game_version_id=$1,
+                game_state=$2,
+                revision=revision+1
+            WHERE id=$3",
+                     &[&m.from_game_version_id, &m.from_game_state, &m.game_id])?;
+    }
+    trans.commit()?;
+    Ok(rolled_back)
 }
 
 #[cfg(test)]
@@ -692,12 +1904,9 @@ mod tests {
     use color::Color;
     use models::NewUserEmail;
     use postgres::GenericConnection;
-    use Connections;
-    use connect_env;
-
-    lazy_static! {
-        static ref CONN: Connections = connect_env().unwrap();
-    }
+    use test_support::with_db;
+    use fixtures::{GameFixture, UserFixture};
+    use repository::PgRepository;
 
     #[test]
     fn rand_code_works() {
@@ -708,15 +1917,7 @@ mod tests {
         }
     }
 
-    fn with_db<F>(closure: F)
-        where F: Fn(&GenericConnection)
-    {
-        let ref conn = *CONN.w.get().unwrap();
-        closure(&conn.transaction().unwrap());
-    }
-
     #[test]
-    #[ignore]
     fn create_user_by_name_works() {
         with_db(|conn| {
                     assert!(create_user_by_name("beefsack", conn).is_ok());
@@ -724,7 +1925,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn find_user_works() {
         with_db(|conn| {
                     assert_eq!(find_user(&Uuid::new_v4(), conn).unwrap(), None);
@@ -734,7 +1934,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn create_user_email_works() {
         with_db(|conn| {
             assert_eq!(find_user(&Uuid::new_v4(), conn).unwrap(), None);
@@ -750,10 +1949,14 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn login_works() {
         with_db(|conn| {
             let confirmation = user_login_request("beefsack@gmail.com", conn).unwrap();
+            let messages = outbox::claim(10, Duration::minutes(5), conn).unwrap();
+            assert_eq!(1, messages.len());
+            assert_eq!(OutboxMessageKind::LoginCode, messages[0].kind);
+            assert_eq!("beefsack@gmail.com", messages[0].recipient);
+            assert_eq!(Some(confirmation.as_str()), messages[0].payload["code"].as_str());
             let uat = user_login_confirm("beefsack@gmail.com", &confirmation, conn)
                 .unwrap()
                 .unwrap();
@@ -767,7 +1970,19 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
+    fn set_user_pref_colors_works() {
+        with_db(|conn| {
+            let u = create_user_by_name("beefsack", conn).unwrap();
+            let u = set_user_pref_colors(&u.id, &["Purple", "Green"], conn).unwrap();
+            assert_eq!(vec![Color::Purple, Color::Green], u.pref_colors);
+            assert!(set_user_pref_colors(&u.id, &["Purple", "Purple"], conn).is_err());
+            assert!(set_user_pref_colors(&u.id, &["Pink"], conn).is_err());
+            assert_eq!(vec![Color::Purple, Color::Green],
+                       find_user(&u.id, conn).unwrap().unwrap().pref_colors);
+        });
+    }
+
+    #[test]
     fn find_user_with_primary_email_works() {
         with_db(|conn| {
                     let ube = create_user_by_email("beefsack@gmail.com", conn).unwrap();
@@ -780,7 +1995,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn find_user_with_primary_email_by_email_works() {
         with_db(|conn| {
             let ube = create_user_by_email("beefsack@gmail.com", conn).unwrap();
@@ -800,7 +2014,6 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
     fn create_game_works() {
         with_db(|conn| {
             let game_type = create_game_type(&NewGameType { name: "Lost Cities" }, conn).unwrap();
@@ -810,6 +2023,8 @@ mod tests {
                                                         name: "v1",
                                                         is_public: true,
                                                         is_deprecated: false,
+                                                        min_players: 2,
+                                                        max_players: Some(2),
                                                     },
                                                    conn)
                     .unwrap();
@@ -817,6 +2032,7 @@ mod tests {
                                      game_version_id: &game_version.id,
                                      is_finished: false,
                                      game_state: "blah",
+                                     parent_game_id: None,
                                  },
                                 conn)
                             .is_ok());
@@ -824,7 +2040,47 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
+    fn game_catalogue_works() {
+        with_db(|conn| {
+            let game_type = create_game_type(&NewGameType { name: "Lost Cities" }, conn).unwrap();
+            assert!(find_game_types(conn)
+                        .unwrap()
+                        .iter()
+                        .any(|gt| gt.id == game_type.id));
+            assert_eq!(None,
+                       find_latest_public_game_version(&game_type.id, conn)
+                           .unwrap()
+                           .map(|gv| gv.id));
+            let game_version = create_game_version(&NewGameVersion {
+                                                        game_type_id: &game_type.id,
+                                                        uri: "https://example.com/lost-cities-1",
+                                                        name: "v1",
+                                                        is_public: true,
+                                                        is_deprecated: false,
+                                                        min_players: 2,
+                                                        max_players: Some(2),
+                                                    },
+                                                   conn)
+                    .unwrap();
+            assert_eq!(Some(game_version.id),
+                       find_latest_public_game_version(&game_type.id, conn)
+                           .unwrap()
+                           .map(|gv| gv.id));
+            assert_eq!(1,
+                       find_public_game_versions_by_game_type(&game_type.id, conn)
+                           .unwrap()
+                           .len());
+            let updated = update_game_version_flags(&game_version.id, true, true, conn)
+                .unwrap()
+                .unwrap();
+            assert!(updated.is_deprecated);
+            assert!(find_public_game_versions_by_game_type(&game_type.id, conn)
+                        .unwrap()
+                        .is_empty());
+        });
+    }
+
+    #[test]
     fn create_players_works() {
         with_db(|conn| {
             let p1 = create_user_by_email("beefsack@gmail.com", conn).unwrap();
@@ -836,6 +2092,8 @@ mod tests {
                                                         name: "v1",
                                                         is_public: true,
                                                         is_deprecated: false,
+                                                        min_players: 2,
+                                                        max_players: Some(2),
                                                     },
                                                    conn)
                     .unwrap();
@@ -843,6 +2101,7 @@ mod tests {
                                         game_version_id: &game_version.id,
                                         is_finished: false,
                                         game_state: "egg",
+                                        parent_game_id: None,
                                     },
                                    conn)
                     .unwrap();
@@ -855,6 +2114,7 @@ mod tests {
                                       is_turn: false,
                                       is_eliminated: false,
                                       is_winner: false,
+                                      color_pref_rank: None,
                                   },
                                   NewGamePlayer {
                                       game_id: &game.id,
@@ -865,9 +2125,693 @@ mod tests {
                                       is_turn: true,
                                       is_eliminated: false,
                                       is_winner: false,
+                                      color_pref_rank: None,
                                   }],
                                 conn)
                     .unwrap();
         });
     }
+
+    #[test]
+    fn create_game_with_users_checks_player_count() {
+        with_db(|conn| {
+            let p1 = create_user_by_email("beefsack@gmail.com", conn).unwrap();
+            let game_type = create_game_type(&NewGameType { name: "Lost Cities" }, conn).unwrap();
+            let game_version = create_game_version(&NewGameVersion {
+                                                        game_type_id: &game_type.id,
+                                                        uri: "https://example.com/lost-cities-1",
+                                                        name: "v1",
+                                                        is_public: true,
+                                                        is_deprecated: false,
+                                                        min_players: 2,
+                                                        max_players: Some(2),
+                                                    },
+                                                   conn)
+                    .unwrap();
+            match create_game_with_users(&NewGame {
+                                              game_version_id: &game_version.id,
+                                              is_finished: false,
+                                              game_state: "egg",
+                                              parent_game_id: None,
+                                          },
+                                         &[0],
+                                         &[],
+                                         &[],
+                                         &p1.user.id,
+                                         &[],
+                                         &["beefsack+two@gmail.com".to_string(),
+                                           "beefsack+three@gmail.com".to_string()],
+                                         conn) {
+                Err(Error(ErrorKind::InvalidPlayerCount(3, 2, Some(2)), _)) => {}
+                _ => panic!("expected invalid player count error"),
+            }
+            assert!(find_user_by_email("beefsack+two@gmail.com", conn)
+                        .unwrap()
+                        .is_none());
+
+            let supported = find_game_types_by_player_count(2, conn).unwrap();
+            assert!(supported.iter().any(|gt| gt.id == game_type.id));
+            let supported = find_game_types_by_player_count(3, conn).unwrap();
+            assert!(!supported.iter().any(|gt| gt.id == game_type.id));
+        });
+    }
+
+    #[test]
+    fn game_color_swap_works() {
+        with_db(|conn| {
+            let p1 = create_user_by_email("beefsack@gmail.com", conn).unwrap();
+            let p2 = create_user_by_email("beefsack+two@gmail.com", conn).unwrap();
+            set_user_pref_colors(&p1.user.id, &["Green", "Red"], conn).unwrap();
+            set_user_pref_colors(&p2.user.id, &["Red"], conn).unwrap();
+            let game_type = create_game_type(&NewGameType { name: "Lost Cities" }, conn).unwrap();
+            let game_version = create_game_version(&NewGameVersion {
+                                                        game_type_id: &game_type.id,
+                                                        uri: "https://example.com/lost-cities-1",
+                                                        name: "v1",
+                                                        is_public: true,
+                                                        is_deprecated: false,
+                                                        min_players: 2,
+                                                        max_players: Some(2),
+                                                    },
+                                                   conn)
+                    .unwrap();
+            let created = create_game_with_users(&NewGame {
+                                                      game_version_id: &game_version.id,
+                                                      is_finished: false,
+                                                      game_state: "egg",
+                                                      parent_game_id: None,
+                                                  },
+                                                 &[0],
+                                                 &[],
+                                                 &[],
+                                                 &p1.user.id,
+                                                 &[p2.user.id],
+                                                 &[],
+                                                 conn)
+                    .unwrap();
+            assert_eq!(vec!["beefsack+two@gmail.com".to_string()],
+                       outbox::claim(10, Duration::minutes(5), conn)
+                           .unwrap()
+                           .into_iter()
+                           .filter(|m| m.kind == OutboxMessageKind::GameInvite)
+                           .map(|m| m.recipient)
+                           .collect::<Vec<String>>());
+            let player_of = |user_id: &Uuid| {
+                find_game_player_by_user(&created.game.id, user_id, conn)
+                    .unwrap()
+                    .unwrap()
+            };
+            let color_of = |user_id: &Uuid| player_of(user_id).color;
+            let (p1_color, p2_color) = (color_of(&p1.user.id), color_of(&p2.user.id));
+
+            let swap = request_game_color_swap(&created.game.id, &p1.user.id, &p2.user.id, conn)
+                .unwrap();
+            assert_eq!(1,
+                       find_pending_game_color_swaps(&created.game.id, conn)
+                           .unwrap()
+                           .len());
+            // Only the target can accept.
+            assert!(accept_game_color_swap(&swap.id, &p1.user.id, conn)
+                        .unwrap()
+                        .is_none());
+            assert_eq!(2,
+                       accept_game_color_swap(&swap.id, &p2.user.id, conn)
+                           .unwrap()
+                           .unwrap()
+                           .len());
+            assert_eq!(p2_color, color_of(&p1.user.id));
+            assert_eq!(p1_color, color_of(&p2.user.id));
+            // Ranks follow the swapped colors.
+            for u in &[&p1, &p2] {
+                let player = player_of(&u.user.id);
+                let prefs = find_user(&u.user.id, conn).unwrap().unwrap().pref_colors;
+                assert_eq!(prefs
+                               .iter()
+                               .position(|c| c == &player.color)
+                               .map(|r| r as i32),
+                           player.color_pref_rank);
+            }
+
+            // Requests expire once the game progresses.
+            let swap = request_game_color_swap(&created.game.id, &p1.user.id, &p2.user.id, conn)
+                .unwrap();
+            update_game(&created.game.id,
+                        &NewGame {
+                             game_version_id: &game_version.id,
+                             is_finished: false,
+                             game_state: "bacon",
+                             parent_game_id: None,
+                         },
+                        conn)
+                    .unwrap();
+            assert!(find_pending_game_color_swaps(&created.game.id, conn)
+                        .unwrap()
+                        .is_empty());
+            assert!(accept_game_color_swap(&swap.id, &p2.user.id, conn)
+                        .unwrap()
+                        .is_none());
+            assert!(decline_game_color_swap(&swap.id, &p2.user.id, conn)
+                        .unwrap()
+                        .is_none());
+
+            // Only the target can decline, and declined swaps are no longer pending.
+            let swap = request_game_color_swap(&created.game.id, &p1.user.id, &p2.user.id, conn)
+                .unwrap();
+            assert!(decline_game_color_swap(&swap.id, &p1.user.id, conn)
+                        .unwrap()
+                        .is_none());
+            assert!(decline_game_color_swap(&swap.id, &p2.user.id, conn)
+                        .unwrap()
+                        .unwrap()
+                        .declined_at
+                        .is_some());
+            assert!(find_pending_game_color_swaps(&created.game.id, conn)
+                        .unwrap()
+                        .is_empty());
+            assert!(accept_game_color_swap(&swap.id, &p2.user.id, conn)
+                        .unwrap()
+                        .is_none());
+        });
+    }
+
+    #[test]
+    fn find_user_color_pref_stats_works() {
+        with_db(|conn| {
+            let repo = PgRepository::new(conn);
+            let user = UserFixture::new()
+                .pref_colors(&[Color::Green])
+                .create(&repo)
+                .unwrap();
+            let mut player_ids = vec![];
+            for _ in 0..3 {
+                let created = GameFixture::new().players(2).create(&repo).unwrap();
+                player_ids.push(created.players[0].id);
+            }
+            // Simulate players from before ranks were recorded, and players given an unlisted
+            // color.
+            conn.execute("UPDATE game_players
+                          SET user_id = $1, color_pref_rank = NULL, color_pref_known = FALSE
+                          WHERE id = $2",
+                         &[&user.user.id, &player_ids[0]])
+                .unwrap();
+            conn.execute("UPDATE game_players
+                          SET user_id = $1, color_pref_rank = NULL
+                          WHERE id = ANY($2)",
+                         &[&user.user.id, &&player_ids[1..]])
+                .unwrap();
+
+            let stats = find_user_color_pref_stats(conn).unwrap();
+            let stats = stats
+                .iter()
+                .find(|s| s.user_id == user.user.id)
+                .unwrap();
+            assert_eq!(3, stats.games);
+            assert_eq!(0, stats.first_choice);
+            assert_eq!(2, stats.unlisted);
+            assert_eq!(1, stats.unknown);
+        });
+    }
+
+    #[test]
+    fn migrate_game_version_works() {
+        with_db(|conn| {
+            let p1 = create_user_by_email("beefsack@gmail.com", conn).unwrap();
+            let p2 = create_user_by_email("beefsack+two@gmail.com", conn).unwrap();
+            let game_type = create_game_type(&NewGameType { name: "Lost Cities" }, conn).unwrap();
+            let mut versions: Vec<GameVersion> = vec![];
+            for &(name, is_deprecated, max_players) in
+                &[("v1", false, 2), ("v2", false, 2), ("v3", true, 2), ("solo", false, 1)] {
+                versions.push(create_game_version(&NewGameVersion {
+                                                       game_type_id: &game_type.id,
+                                                       uri: "https://example.com/lost-cities",
+                                                       name: name,
+                                                       is_public: true,
+                                                       is_deprecated: is_deprecated,
+                                                       min_players: 1,
+                                                       max_players: Some(max_players),
+                                                   },
+                                                  conn)
+                                      .unwrap());
+            }
+            let mut games: Vec<Game> = vec![];
+            for state in &["egg", "bacon", "cheese"] {
+                let game = create_game(&NewGame {
+                                            game_version_id: &versions[0].id,
+                                            is_finished: false,
+                                            game_state: state,
+                                            parent_game_id: None,
+                                        },
+                                       conn)
+                        .unwrap();
+                create_game_players(&[NewGamePlayer {
+                                          game_id: &game.id,
+                                          user_id: &p1.user.id,
+                                          position: 0,
+                                          color: &Color::Green,
+                                          has_accepted: true,
+                                          is_turn: true,
+                                          is_eliminated: false,
+                                          is_winner: false,
+                                          color_pref_rank: None,
+                                      },
+                                      NewGamePlayer {
+                                          game_id: &game.id,
+                                          user_id: &p2.user.id,
+                                          position: 1,
+                                          color: &Color::Red,
+                                          has_accepted: true,
+                                          is_turn: false,
+                                          is_eliminated: false,
+                                          is_winner: false,
+                                          color_pref_rank: None,
+                                      }],
+                                    conn)
+                        .unwrap();
+                games.push(game);
+            }
+
+            // Deprecated versions and versions which can't take the players are rejected.
+            for to in &versions[2..] {
+                assert!(migrate_game_version(&versions[0].id,
+                                             &to.id,
+                                             2,
+                                             |g| Ok(g.game_state.to_owned()),
+                                             conn)
+                                .is_err());
+            }
+            assert_eq!(3,
+                       find_unfinished_games_by_game_version(&versions[0].id, conn)
+                           .unwrap()
+                           .len());
+
+            let migrated = migrate_game_version(&versions[0].id,
+                                                &versions[1].id,
+                                                2,
+                                                |g| Ok(format!("{}-v2", g.game_state)),
+                                                conn)
+                    .unwrap();
+            assert_eq!(3, migrated.migrations.len());
+            assert!(find_unfinished_games_by_game_version(&versions[0].id, conn)
+                        .unwrap()
+                        .is_empty());
+            let game = find_game(&games[0].id, conn).unwrap().unwrap();
+            assert_eq!(versions[1].id, game.game_version_id);
+            assert_eq!("egg-v2", game.game_state);
+            assert_eq!(games[0].revision + 1, game.revision);
+            assert_eq!(1,
+                       find_game_version_migrations_by_game(&games[0].id, conn)
+                           .unwrap()
+                           .len());
+
+            // Games played since the migration aren't rolled back.
+            update_game(&games[1].id,
+                        &NewGame {
+                             game_version_id: &versions[1].id,
+                             is_finished: false,
+                             game_state: "bacon-v2-played",
+                             parent_game_id: None,
+                         },
+                        conn)
+                    .unwrap();
+            assert_eq!(2,
+                       rollback_game_version_migration_run(&migrated.run_id, conn)
+                           .unwrap()
+                           .len());
+            let game = find_game(&games[0].id, conn).unwrap().unwrap();
+            assert_eq!(versions[0].id, game.game_version_id);
+            assert_eq!("egg", game.game_state);
+            assert_eq!(games[0].revision + 2, game.revision);
+            let game = find_game(&games[1].id, conn).unwrap().unwrap();
+            assert_eq!(versions[1].id, game.game_version_id);
+
+            // A failure after a batch was committed reports what was already migrated.
+            let err = migrate_game_version(&versions[0].id,
+                                           &versions[1].id,
+                                           1,
+                                           |g| if g.game_state == "cheese" {
+                                               Err("bad cheese".into())
+                                           } else {
+                                               Ok(format!("{}-v2", g.game_state))
+                                           },
+                                           conn)
+                    .unwrap_err();
+            match *err.kind() {
+                ErrorKind::IncompleteGameVersionMigration(ref run_id, ref migrations) => {
+                    assert_eq!(1, migrations.len());
+                    assert_eq!(games[0].id, migrations[0].game_id);
+                    assert_eq!(*run_id, migrations[0].run_id);
+                }
+                _ => panic!("expected an incomplete migration error, got {}", err),
+            }
+        });
+    }
+
+    #[test]
+    fn update_game_whose_turn_works() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .players(3)
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let mut players = update_game_whose_turn(&created.game.id, &[1, 2], conn).unwrap();
+            players.sort_by_key(|p| p.position);
+            assert_eq!(vec![1, 2],
+                       players
+                           .iter()
+                           .filter(|p| p.is_turn)
+                           .map(|p| p.position)
+                           .collect::<Vec<i32>>());
+            let players = update_game_whose_turn(&created.game.id, &[], conn).unwrap();
+            assert!(players.iter().all(|p| !p.is_turn));
+            let revision = find_game(&created.game.id, conn).unwrap().unwrap().revision;
+            assert_eq!(created.game.revision + 2, revision);
+            // Setting the same turn again doesn't change the game.
+            update_game_whose_turn(&created.game.id, &[], conn).unwrap();
+            assert_eq!(revision,
+                       find_game(&created.game.id, conn).unwrap().unwrap().revision);
+
+            let seen = [(created.game.id, created.game.revision), (Uuid::new_v4(), 0)];
+            assert_eq!(vec![created.game.id],
+                       find_games_changed_since(&seen, conn)
+                           .unwrap()
+                           .iter()
+                           .map(|g| g.id)
+                           .collect::<Vec<Uuid>>());
+            assert!(find_games_changed_since(&[(created.game.id, revision)], conn)
+                        .unwrap()
+                        .is_empty());
+        });
+    }
+
+    #[test]
+    fn find_games_works() {
+        with_db(|conn| {
+            let repo = PgRepository::new(conn);
+            let created = GameFixture::new().create(&repo).unwrap();
+            GameFixture::new().create(&repo).unwrap();
+            let user_id = created.users[0].user.id;
+            let games = find_games(&games_select()
+                                        .join::<GamePlayer>("gp", "gp.game_id = g.id", &[])
+                                        .filter("gp.user_id = ?", &[&user_id]),
+                                   conn)
+                    .unwrap();
+            assert_eq!(vec![created.game], games);
+        });
+    }
+
+    #[test]
+    fn create_game_logs_works() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .players(3)
+                .no_logs()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let ids: Vec<Uuid> = created.players.iter().map(|p| p.id).collect();
+            let logged_at = UTC::now().naive_utc();
+            let new_log = |body| {
+                NewGameLog {
+                    game_id: &created.game.id,
+                    body: body,
+                    is_public: false,
+                    logged_at: &logged_at,
+                }
+            };
+            let logs = create_game_logs(&[(new_log("one"), vec![ids[0], ids[2]]),
+                                          (new_log("two"), vec![]),
+                                          (new_log("three"), vec![ids[1]])],
+                                        conn)
+                    .unwrap();
+            assert_eq!(vec!["one", "two", "three"],
+                       logs.iter()
+                           .map(|l| l.game_log.body.as_ref())
+                           .collect::<Vec<&str>>());
+            assert_eq!(vec![vec![ids[0], ids[2]], vec![], vec![ids[1]]],
+                       logs.iter()
+                           .map(|l| l.targets.iter().map(|t| t.player_id).collect())
+                           .collect::<Vec<Vec<Uuid>>>());
+            assert!(logs.iter()
+                        .all(|l| l.targets.iter().all(|t| t.game_log_id == l.game_log.id)));
+        });
+    }
+
+    #[test]
+    fn create_game_users_works() {
+        with_db(|conn| {
+            let by_id = create_user_by_email("one@example.com", conn).unwrap();
+            let by_email = create_user_by_email("two@example.com", conn).unwrap();
+            let users = create_game_users(&[by_id.user.id],
+                                          &["two@example.com".to_string(),
+                                            "three@example.com".to_string(),
+                                            "three@example.com".to_string()],
+                                          conn)
+                    .unwrap();
+            assert_eq!(4, users.len());
+            assert_eq!(by_id, users[0]);
+            assert_eq!(by_email, users[1]);
+            assert_eq!("three@example.com", users[2].user_email.email);
+            assert_eq!(users[2], users[3]);
+            assert!(create_game_users(&[Uuid::new_v4()], &[], conn).is_err());
+        });
+    }
+
+    fn public_log_bodies(game_id: &Uuid, conn: &GenericConnection) -> Vec<String> {
+        conn.query("
+            SELECT body
+            FROM game_logs
+            WHERE game_id=$1
+            AND is_public=TRUE
+            ORDER BY logged_at",
+                   &[game_id])
+            .unwrap()
+            .iter()
+            .map(|row| row.get(0))
+            .collect()
+    }
+
+    #[test]
+    fn concede_game_works() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .players(3)
+                .no_logs()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let user_id = created.players[0].user_id;
+            let players = concede_game(&created.game.id, &user_id, conn)
+                .unwrap()
+                .unwrap();
+            let player = players.iter().find(|p| p.user_id == user_id).unwrap();
+            assert!(player.is_eliminated);
+            assert_eq!(Some(EliminationReason::Conceded), player.eliminated_reason);
+            // It was their turn, so it passes to the next player.
+            assert!(!player.is_turn);
+            assert_eq!(vec![1],
+                       players
+                           .iter()
+                           .filter(|p| p.is_turn)
+                           .map(|p| p.position)
+                           .collect::<Vec<i32>>());
+            assert_eq!(vec!["{{player 0}} conceded".to_string()],
+                       public_log_bodies(&created.game.id, conn));
+            assert_eq!(None, concede_game(&created.game.id, &user_id, conn).unwrap());
+
+            // The last player left wins.
+            let players = concede_game(&created.game.id, &created.players[1].user_id, conn)
+                .unwrap()
+                .unwrap();
+            assert!(find_game(&created.game.id, conn)
+                        .unwrap()
+                        .unwrap()
+                        .is_finished);
+            assert!(players.iter().all(|p| !p.is_turn));
+            assert_eq!(vec![2],
+                       players
+                           .iter()
+                           .filter(|p| p.is_winner)
+                           .map(|p| p.position)
+                           .collect::<Vec<i32>>());
+            assert_eq!(None,
+                       concede_game(&created.game.id, &created.players[2].user_id, conn)
+                           .unwrap());
+
+            // Reversing the elimination clears the reason.
+            let players = update_game_eliminated(&created.game.id, &[], conn).unwrap();
+            assert!(players.iter().all(|p| p.eliminated_reason.is_none()));
+        });
+    }
+
+    #[test]
+    fn abandon_game_works() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .winners(&[1])
+                .no_logs()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let game = abandon_game(&created.game.id, "the server crashed", conn)
+                .unwrap()
+                .unwrap();
+            assert!(game.is_finished);
+            assert!(game.abandoned_at.is_some());
+            assert_eq!(Some("the server crashed".to_string()), game.abandon_reason);
+            assert_eq!(created.game.revision + 1, game.revision);
+            let players = find_game_players_by_game(&created.game.id, conn).unwrap();
+            assert!(players.iter().all(|p| !p.is_turn && !p.is_winner));
+            assert_eq!(vec!["The game was abandoned: the server crashed".to_string()],
+                       public_log_bodies(&created.game.id, conn));
+
+            assert_eq!(None,
+                       abandon_game(&created.game.id, "again", conn).unwrap());
+            assert_eq!(None,
+                       concede_game(&created.game.id, &players[0].user_id, conn).unwrap());
+        });
+    }
+
+    #[test]
+    fn create_rematch_works() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .players(3)
+                .finished(true)
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let v2 = create_game_version(&NewGameVersion {
+                                              game_type_id: &created.game_type.id,
+                                              name: "v2",
+                                              uri: "https://example.com/game",
+                                              is_public: true,
+                                              is_deprecated: false,
+                                              min_players: 2,
+                                              max_players: None,
+                                          },
+                                         conn)
+                    .unwrap();
+            let requested_by = created.players[1].user_id;
+            assert!(create_rematch(&created.game.id, &Uuid::new_v4(), conn)
+                        .unwrap()
+                        .is_none());
+
+            let rematch = create_rematch(&created.game.id, &requested_by, conn)
+                .unwrap()
+                .unwrap();
+            assert_eq!(Some(created.game.id), rematch.game.parent_game_id);
+            assert_eq!(v2.id, rematch.game.game_version_id);
+            assert!(!rematch.game.is_finished);
+            let mut user_ids: Vec<Uuid> = rematch.players.iter().map(|p| p.user_id).collect();
+            let mut expected: Vec<Uuid> = created.players.iter().map(|p| p.user_id).collect();
+            user_ids.sort();
+            expected.sort();
+            assert_eq!(expected, user_ids);
+            assert_eq!(vec![requested_by],
+                       rematch
+                           .players
+                           .iter()
+                           .filter(|p| p.has_accepted)
+                           .map(|p| p.user_id)
+                           .collect::<Vec<Uuid>>());
+            assert!(rematch.players.iter().all(|p| !p.is_turn));
+            let mut invited: Vec<String> = outbox::claim(10, Duration::minutes(5), conn)
+                .unwrap()
+                .into_iter()
+                .filter(|m| m.kind == OutboxMessageKind::GameInvite)
+                .map(|m| m.recipient)
+                .collect();
+            invited.sort();
+            let mut expected: Vec<String> = created
+                .users
+                .iter()
+                .filter(|u| u.user.id != requested_by)
+                .map(|u| u.user_email.email.clone())
+                .collect();
+            expected.sort();
+            assert_eq!(expected, invited);
+
+            // Asking again gives the same rematch.
+            let again = create_rematch(&created.game.id, &created.players[0].user_id, conn)
+                .unwrap()
+                .unwrap();
+            assert_eq!(rematch.game, again.game);
+            assert!(outbox::claim(10, Duration::minutes(5), conn)
+                        .unwrap()
+                        .is_empty());
+            assert_eq!(Some(rematch.game.clone()),
+                       find_rematch(&created.game.id, conn).unwrap());
+
+            let accepted = accept_rematch(&created.game.id, &created.players[0].user_id, conn)
+                .unwrap()
+                .unwrap();
+            assert!(accepted.has_accepted);
+            assert_eq!(rematch.game.id, accepted.game_id);
+            assert!(find_game(&rematch.game.id, conn).unwrap().unwrap().revision >
+                    rematch.game.revision);
+            assert!(accept_rematch(&created.game.id, &Uuid::new_v4(), conn)
+                        .unwrap()
+                        .is_none());
+            assert!(accept_rematch(&again.game.id, &requested_by, conn)
+                        .unwrap()
+                        .is_none());
+
+            // Unfinished games can't be rematched.
+            assert!(create_rematch(&again.game.id, &requested_by, conn)
+                        .unwrap()
+                        .is_none());
+        });
+    }
+
+    #[test]
+    fn update_game_placings_works() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .players(3)
+                .winners(&[0])
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let game_id = created.game.id;
+            let placing = |position, place, score| {
+                Placing {
+                    position: position,
+                    place: place,
+                    score: score,
+                }
+            };
+            let players = update_game_placings(&game_id,
+                                               &[placing(0, 2, Some(30)),
+                                                 placing(2, 1, Some(47)),
+                                                 placing(1, 1, Some(47))],
+                                               conn)
+                    .unwrap();
+            assert_eq!(3, players.len());
+            let standings = find_game_standings(&game_id, conn).unwrap();
+            assert_eq!(vec![(1, Some(1), Some(47), true),
+                            (2, Some(1), Some(47), true),
+                            (0, Some(2), Some(30), false)],
+                       standings
+                           .iter()
+                           .map(|s| {
+                                    (s.player.position,
+                                     s.player.place,
+                                     s.player.score,
+                                     s.player.is_winner)
+                                })
+                           .collect::<Vec<(i32, Option<i32>, Option<i32>, bool)>>());
+            assert_eq!(created.users[1].user, standings[0].user);
+
+            let duplicate = [placing(0, 1, None), placing(0, 2, None)];
+            assert!(update_game_placings(&game_id, &duplicate, conn).is_err());
+            assert!(update_game_placings(&game_id, &[placing(0, 0, None)], conn).is_err());
+            assert!(update_game_placings(&game_id, &[placing(3, 1, None)], conn).is_err());
+
+            let players = update_game_placings(&game_id, &[placing(2, 1, None)], conn).unwrap();
+            assert!(players
+                        .iter()
+                        .all(|p| p.is_winner == (p.position == 2) && p.score.is_none()));
+
+            // Setting winners directly clears places.
+            let players = update_game_winners(&game_id, &[0], conn).unwrap();
+            assert!(players
+                        .iter()
+                        .all(|p| p.is_winner == (p.position == 0) && p.place.is_none()));
+        });
+    }
 }
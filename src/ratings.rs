@@ -0,0 +1,379 @@
+use postgres::GenericConnection;
+use uuid::Uuid;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use errors::*;
+use models::*;
+use query;
+use sql::Select;
+use transaction::{retry_transaction, IsolationLevel};
+
+/// The rating players start with for each game type.
+pub static DEFAULT_RATING: f64 = 1500.0;
+/// The most a player's rating can change from a single game.
+pub static K_FACTOR: f64 = 32.0;
+
+/// Calculates new Elo ratings for the players of a game from their current ratings and how they
+/// placed, where a lower placing is better and equal placings are ties.
+///
+/// Games with more than two players are rated as a match between every pair of players, scaled so
+/// a game is worth the same as a two player game. Rating changes always sum to zero.
+pub fn elo<P: Ord>(results: &[(f64, P)]) -> Vec<f64> {
+    if results.len() < 2 {
+        return results.iter().map(|&(rating, _)| rating).collect();
+    }
+    let opponents = (results.len() - 1) as f64;
+    results
+        .iter()
+        .enumerate()
+        .map(|(i, &(rating, ref placing))| {
+            let mut change = 0.0;
+            for (j, &(other_rating, ref other_placing)) in results.iter().enumerate() {
+                if i == j {
+                    continue;
+                }
+                let expected = 1.0 / (1.0 + 10f64.powf((other_rating - rating) / 400.0));
+                let actual = match placing.cmp(other_placing) {
+                    Ordering::Less => 1.0,
+                    Ordering::Equal => 0.5,
+                    Ordering::Greater => 0.0,
+                };
+                change += actual - expected;
+            }
+            rating + K_FACTOR * change / opponents
+        })
+        .collect()
+}
+
+/// How a player placed in a game, for comparing with the other players. Final places are used if
+/// the game has them, otherwise winners beat the other players, who beat eliminated players.
+pub fn placing(player: &GamePlayer) -> (bool, Option<i32>, bool, bool) {
+    (player.place.is_none(), player.place, !player.is_winner, player.is_eliminated)
+}
+
+/// Updates the ratings of the players of a finished game, creating ratings for players who don't
+/// have one for the game type yet. Abandoned games aren't rated, and rating a game again does
+/// nothing, so an empty list is returned in both cases.
+///
+/// Games should be rated in the order they finished, which `rebuild_ratings` does if they aren't.
+pub fn rate_game(game_id: &Uuid, conn: &GenericConnection) -> Result<Vec<RatingChange>> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let mut game: Option<Game> = None;
+        for row in &trans.query("
+            SELECT *
+            FROM games
+            WHERE id=$1
+            FOR UPDATE",
+                                &[game_id])? {
+            game = Some(Game::from_row(&row, ""));
+        }
+        let game = game.ok_or_else::<Error, _>(|| "could not find game".into())?;
+        if !game.is_finished || game.abandoned_at.is_some() {
+            return Ok(vec![]);
+        }
+        if !trans
+                .query("SELECT 1 FROM rating_changes WHERE game_id=$1 LIMIT 1",
+                       &[game_id])?
+                .is_empty() {
+            return Ok(vec![]);
+        }
+        let game_type_id = query::find_game_version(&game.game_version_id, trans)?
+            .ok_or_else::<Error, _>(|| "could not find game version".into())?
+            .game_type_id;
+        let mut players = query::find_game_players_by_game(game_id, trans)?;
+        if players.len() < 2 {
+            return Ok(vec![]);
+        }
+        players.sort_by_key(|p| p.position);
+
+        let ratings = lock_ratings(&game_type_id,
+                                   &players.iter().map(|p| p.user_id).collect::<Vec<Uuid>>(),
+                                   trans)?;
+        let results: Vec<(f64, (bool, Option<i32>, bool, bool))> = players
+            .iter()
+            .map(|p| (ratings[&p.user_id].rating, placing(p)))
+            .collect();
+        let mut changes: Vec<RatingChange> = vec![];
+        for (p, new_rating) in players.iter().zip(elo(&results)) {
+            let rating = &ratings[&p.user_id];
+            trans
+                .execute("
+                UPDATE ratings
+                SET
+                    rating=$2,
+                    games_played=games_played+1
+                WHERE id=$1",
+                         &[&rating.id, &new_rating])?;
+            for row in &trans.query(&NewRatingChange::insert_sql(),
+                                    &NewRatingChange {
+                                             rating_id: &rating.id,
+                                             game_id: game_id,
+                                             rating_before: rating.rating,
+                                             rating_after: new_rating,
+                                         }
+                                         .params())? {
+                changes.push(RatingChange::from_row(&row, ""));
+            }
+        }
+        Ok(changes)
+    })
+}
+
+/// Finds and locks the ratings of users for a game type, creating the ratings which don't exist
+/// yet. Rows are locked in a consistent order so games rated concurrently can't deadlock.
+fn lock_ratings(game_type_id: &Uuid,
+                user_ids: &[Uuid],
+                conn: &GenericConnection)
+                -> Result<HashMap<Uuid, Rating>> {
+    conn.execute("
+        INSERT INTO ratings (user_id, game_type_id, rating)
+        SELECT user_id, $1, $3
+        FROM unnest($2::uuid[]) AS u (user_id)
+        ON CONFLICT (user_id, game_type_id) DO NOTHING",
+                 &[game_type_id, &user_ids, &DEFAULT_RATING])?;
+    let mut ratings: HashMap<Uuid, Rating> = HashMap::new();
+    for row in &conn.query("
+        SELECT *
+        FROM ratings
+        WHERE game_type_id=$1
+        AND user_id = ANY($2)
+        ORDER BY user_id
+        FOR UPDATE",
+                           &[game_type_id, &user_ids])? {
+        let rating = Rating::from_row(&row, "");
+        ratings.insert(rating.user_id, rating);
+    }
+    Ok(ratings)
+}
+
+/// Recalculates every rating from scratch by rating all finished games in the order they finished,
+/// returning the number of games rated. The result only depends on game history, so it is the same
+/// each time it is run.
+pub fn rebuild_ratings(conn: &GenericConnection) -> Result<usize> {
+    let trans = conn.transaction()?;
+    trans.batch_execute("
+        LOCK TABLE ratings, rating_changes IN EXCLUSIVE MODE;
+        DELETE FROM rating_changes;
+        DELETE FROM ratings;")?;
+    let mut game_ids: Vec<Uuid> = vec![];
+    for row in &trans.query("
+        SELECT id
+        FROM games
+        WHERE is_finished=TRUE
+        AND abandoned_at IS NULL
+        ORDER BY finished_at, id",
+                            &[])? {
+        game_ids.push(row.get(0));
+    }
+    let mut rated = 0;
+    for id in &game_ids {
+        if !rate_game(id, &trans)?.is_empty() {
+            rated += 1;
+        }
+    }
+    trans.commit()?;
+    Ok(rated)
+}
+
+pub fn find_rating(user_id: &Uuid,
+                   game_type_id: &Uuid,
+                   conn: &GenericConnection)
+                   -> Result<Option<Rating>> {
+    for row in &conn.query("
+        SELECT *
+        FROM ratings
+        WHERE user_id=$1
+        AND game_type_id=$2",
+                           &[user_id, game_type_id])? {
+        return Ok(Some(Rating::from_row(&row, "")));
+    }
+    Ok(None)
+}
+
+/// Finds the highest ratings for a game type.
+pub fn find_top_ratings(game_type_id: &Uuid,
+                        limit: i64,
+                        conn: &GenericConnection)
+                        -> Result<Vec<Rating>> {
+    let mut ratings: Vec<Rating> = vec![];
+    for row in &Select::from::<Rating>("r")
+                    .filter("r.game_type_id = ?", &[game_type_id])
+                    .order_by("r.rating DESC")
+                    .order_by("r.games_played DESC")
+                    .limit(limit)
+                    .query(conn)? {
+        ratings.push(Rating::from_row(&row, ""));
+    }
+    Ok(ratings)
+}
+
+/// Finds how a user's rating for a game type has changed, in the order games were rated.
+pub fn find_rating_history(user_id: &Uuid,
+                           game_type_id: &Uuid,
+                           conn: &GenericConnection)
+                           -> Result<Vec<RatingChange>> {
+    let mut changes: Vec<RatingChange> = vec![];
+    for row in &Select::from::<RatingChange>("rc")
+                    .cols::<RatingChange>("rc", "")
+                    .join::<Rating>("r", "r.id = rc.rating_id", &[])
+                    .join::<Game>("g", "g.id = rc.game_id", &[])
+                    .filter("r.user_id = ?", &[user_id])
+                    .filter("r.game_type_id = ?", &[game_type_id])
+                    .order_by("g.finished_at")
+                    .order_by("g.id")
+                    .query(conn)? {
+        changes.push(RatingChange::from_row(&row, ""));
+    }
+    Ok(changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fixtures::GameFixture;
+    use memory::MemoryRepository;
+    use repository::PgRepository;
+    use test_support::with_db;
+
+    #[test]
+    fn elo_works() {
+        assert_eq!(vec![1516.0, 1484.0], elo(&[(1500.0, 1), (1500.0, 2)]));
+        assert_eq!(vec![1500.0, 1500.0], elo(&[(1500.0, 1), (1500.0, 1)]));
+        assert_eq!(vec![1500.0], elo(&[(1500.0, 1)]));
+
+        // Beating a much stronger player gains more than beating an equal one.
+        let upset = elo(&[(1200.0, 1), (1800.0, 2)]);
+        assert!(upset[0] - 1200.0 > 30.0);
+
+        let multi = elo(&[(1500.0, 2), (1600.0, 1), (1400.0, 3), (1500.0, 2)]);
+        assert!(multi[1] > 1600.0);
+        assert!(multi[2] < 1400.0);
+        assert!((multi.iter().sum::<f64>() - 6000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn placing_works() {
+        let created = GameFixture::new()
+            .players(4)
+            .winners(&[2])
+            .eliminated(&[0])
+            .create(&MemoryRepository::new())
+            .unwrap();
+        let mut players = created.players.clone();
+        players.sort_by_key(|p| placing(p));
+        assert_eq!(vec![2, 1, 3, 0],
+                   players.iter().map(|p| p.position).collect::<Vec<i32>>());
+
+        // Places take priority over winners.
+        players[3].place = Some(1);
+        players[0].place = Some(2);
+        assert!(placing(&players[3]) < placing(&players[0]));
+        assert!(placing(&players[0]) < placing(&players[1]));
+    }
+
+    #[test]
+    fn ratings_work() {
+        with_db(|conn| {
+            let repo = PgRepository::new(conn);
+            let first = GameFixture::new()
+                .finished(true)
+                .winners(&[0])
+                .create(&repo)
+                .unwrap();
+            let game_type_id = first.game_type.id;
+            let winner = first.players[0].user_id;
+            let loser = first.players[1].user_id;
+
+            let changes = rate_game(&first.game.id, conn).unwrap();
+            assert_eq!(2, changes.len());
+            assert!(rate_game(&first.game.id, conn).unwrap().is_empty());
+            let rating = find_rating(&winner, &game_type_id, conn)
+                .unwrap()
+                .unwrap();
+            assert_eq!(DEFAULT_RATING + K_FACTOR / 2.0, rating.rating);
+            assert_eq!(1, rating.games_played);
+            assert_eq!(vec![winner, loser],
+                       find_top_ratings(&game_type_id, 10, conn)
+                           .unwrap()
+                           .iter()
+                           .map(|r| r.user_id)
+                           .collect::<Vec<Uuid>>());
+
+            // Unfinished games aren't rated.
+            let unfinished = GameFixture::new().create(&repo).unwrap();
+            assert!(rate_game(&unfinished.game.id, conn).unwrap().is_empty());
+
+            // Games are rated when they're finished.
+            let second = GameFixture::new().create(&repo).unwrap();
+            conn.execute("UPDATE games SET game_version_id=$2 WHERE id=$1",
+                         &[&second.game.id, &first.game.game_version_id])
+                .unwrap();
+            conn.execute("UPDATE game_players SET user_id=$2 WHERE id=$1",
+                         &[&second.players[0].id, &loser])
+                .unwrap();
+            conn.execute("UPDATE game_players SET user_id=$2 WHERE id=$1",
+                         &[&second.players[1].id, &winner])
+                .unwrap();
+            let placings = [query::Placing {
+                                position: 0,
+                                place: 1,
+                                score: Some(10),
+                            },
+                            query::Placing {
+                                position: 1,
+                                place: 2,
+                                score: Some(5),
+                            }];
+            let finished = NewGame {
+                game_version_id: &first.game.game_version_id,
+                is_finished: true,
+                game_state: "done",
+                parent_game_id: None,
+            };
+            query::update_game_and_players_with_placings(&second.game.id,
+                                                         &finished,
+                                                         &[],
+                                                         &[],
+                                                         &placings,
+                                                         conn)
+                    .unwrap();
+            let history = find_rating_history(&winner, &game_type_id, conn).unwrap();
+            assert_eq!(vec![first.game.id, second.game.id],
+                       history.iter().map(|c| c.game_id).collect::<Vec<Uuid>>());
+            assert_eq!(history[0].rating_after, history[1].rating_before);
+            assert!(history[1].rating_after < history[1].rating_before);
+
+            // Games finished with a plain update are rated too.
+            let third = GameFixture::new().create(&repo).unwrap();
+            conn.execute("UPDATE games SET game_version_id=$2 WHERE id=$1",
+                         &[&third.game.id, &first.game.game_version_id])
+                .unwrap();
+            for (p, user_id) in third.players.iter().zip(&[winner, loser]) {
+                conn.execute("UPDATE game_players SET user_id=$2 WHERE id=$1",
+                             &[&p.id, user_id])
+                    .unwrap();
+            }
+            query::update_game_winners(&third.game.id, &[0], conn).unwrap();
+            query::update_game(&third.game.id, &finished, conn).unwrap();
+            assert_eq!(3,
+                       find_rating_history(&winner, &game_type_id, conn)
+                           .unwrap()
+                           .len());
+
+            // Rebuilding from the recorded places gives the same ratings.
+            let before: Vec<Rating> = find_top_ratings(&game_type_id, 10, conn).unwrap();
+            assert_eq!(3, rebuild_ratings(conn).unwrap());
+            let after: Vec<Rating> = find_top_ratings(&game_type_id, 10, conn).unwrap();
+            assert_eq!(before
+                           .iter()
+                           .map(|r| (r.user_id, r.rating, r.games_played))
+                           .collect::<Vec<(Uuid, f64, i32)>>(),
+                       after
+                           .iter()
+                           .map(|r| (r.user_id, r.rating, r.games_played))
+                           .collect::<Vec<(Uuid, f64, i32)>>());
+        });
+    }
+}
@@ -0,0 +1,146 @@
+use postgres::GenericConnection;
+use postgres::error::SqlState;
+use uuid::Uuid;
+
+use brdgme_cmd::cli::CliLog;
+
+use errors::*;
+use models::*;
+use query::{self, UserByEmail, CreatedGame, UpdatedGame, CreatedGameLog, Placing};
+
+/// Repository is the query surface for users, emails, tokens, games, players and logs, so code
+/// built on it can run against Postgres or against `memory::MemoryRepository` in tests.
+///
+/// Constraint violations are reported as `ErrorKind::UniqueViolation` and
+/// `ErrorKind::ForeignKeyViolation` using Postgres' constraint names, whichever backend is used.
+pub trait Repository {
+    fn create_user(&self, new_user: &NewUser) -> Result<User>;
+    fn find_user(&self, id: &Uuid) -> Result<Option<User>>;
+    fn set_user_pref_colors(&self, user_id: &Uuid, colors: &[&str]) -> Result<User>;
+
+    fn create_user_email(&self, ue: &NewUserEmail) -> Result<UserEmail>;
+    fn find_user_by_email(&self, email: &str) -> Result<Option<UserByEmail>>;
+    fn find_user_with_primary_email(&self, id: &Uuid) -> Result<Option<UserByEmail>>;
+    fn find_user_with_primary_email_by_email(&self, email: &str) -> Result<Option<UserByEmail>>;
+
+    fn user_login_request(&self, email: &str) -> Result<String>;
+    fn user_login_confirm(&self, email: &str, confirmation: &str)
+                          -> Result<Option<UserAuthToken>>;
+    fn create_auth_token(&self, user_id: &Uuid) -> Result<UserAuthToken>;
+    fn authenticate(&self, email: &str, token: &Uuid) -> Result<Option<UserByEmail>>;
+
+    fn create_game_type(&self, new_game_type: &NewGameType) -> Result<GameType>;
+    fn create_game_version(&self, new_game_version: &NewGameVersion) -> Result<GameVersion>;
+    fn find_game_version(&self, id: &Uuid) -> Result<Option<GameVersion>>;
+
+    fn create_game(&self, new_game: &NewGame) -> Result<Game>;
+    fn find_game(&self, id: &Uuid) -> Result<Option<Game>>;
+    fn update_game(&self, id: &Uuid, update: &NewGame) -> Result<Option<Game>>;
+    fn create_game_with_users(&self,
+                              new_game: &NewGame,
+                              whose_turn: &[usize],
+                              eliminated: &[usize],
+                              winners: &[usize],
+                              creator_id: &Uuid,
+                              opponent_ids: &[Uuid],
+                              opponent_emails: &[String])
+                              -> Result<CreatedGame>;
+    fn update_game_and_players(&self,
+                               game_id: &Uuid,
+                               update: &NewGame,
+                               whose_turn: &[usize],
+                               eliminated: &[usize],
+                               winners: &[usize])
+                               -> Result<UpdatedGame>;
+
+    fn create_game_player(&self, player: &NewGamePlayer) -> Result<GamePlayer>;
+    fn create_game_players(&self, players: &[NewGamePlayer]) -> Result<Vec<GamePlayer>>;
+    fn find_game_players_by_game(&self, game_id: &Uuid) -> Result<Vec<GamePlayer>>;
+    fn update_game_whose_turn(&self, id: &Uuid, positions: &[usize]) -> Result<Vec<GamePlayer>>;
+    fn update_game_eliminated(&self, id: &Uuid, positions: &[usize]) -> Result<Vec<GamePlayer>>;
+    fn update_game_winners(&self, id: &Uuid, positions: &[usize]) -> Result<Vec<GamePlayer>>;
+    fn update_game_placings(&self, id: &Uuid, placings: &[Placing]) -> Result<Vec<GamePlayer>>;
+
+    fn create_game_log(&self, log: &NewGameLog, to: &[Uuid]) -> Result<CreatedGameLog>;
+    fn create_game_logs_from_cli(&self,
+                                 game_id: &Uuid,
+                                 logs: Vec<CliLog>)
+                                 -> Result<Vec<CreatedGameLog>>;
+}
+
+pub struct PgRepository<'a> {
+    pub conn: &'a GenericConnection,
+}
+
+impl<'a> PgRepository<'a> {
+    pub fn new(conn: &'a GenericConnection) -> Self {
+        PgRepository { conn: conn }
+    }
+}
+
+/// Converts constraint violations from Postgres into the error kinds shared by all repositories.
+fn map_constraint_err(e: Error) -> Error {
+    let kind = match (sql_state(&e), constraint(&e)) {
+        (Some(&SqlState::UniqueViolation), Some(c)) => ErrorKind::UniqueViolation(c.to_string()),
+        (Some(&SqlState::ForeignKeyViolation), Some(c)) => {
+            ErrorKind::ForeignKeyViolation(c.to_string())
+        }
+        _ => return e,
+    };
+    Error::with_chain(e, kind)
+}
+
+macro_rules! pg_repository {
+    ($($name:ident($($arg:ident: $arg_ty:ty),*) -> $ret:ty;)*) => {
+        impl<'a> Repository for PgRepository<'a> {
+            $(
+                fn $name(&self, $($arg: $arg_ty),*) -> Result<$ret> {
+                    query::$name($($arg,)* self.conn).map_err(map_constraint_err)
+                }
+            )*
+        }
+    }
+}
+
+pg_repository! {
+    create_user(new_user: &NewUser) -> User;
+    find_user(id: &Uuid) -> Option<User>;
+    set_user_pref_colors(user_id: &Uuid, colors: &[&str]) -> User;
+    create_user_email(ue: &NewUserEmail) -> UserEmail;
+    find_user_by_email(email: &str) -> Option<UserByEmail>;
+    find_user_with_primary_email(id: &Uuid) -> Option<UserByEmail>;
+    find_user_with_primary_email_by_email(email: &str) -> Option<UserByEmail>;
+    user_login_request(email: &str) -> String;
+    user_login_confirm(email: &str, confirmation: &str) -> Option<UserAuthToken>;
+    create_auth_token(user_id: &Uuid) -> UserAuthToken;
+    authenticate(email: &str, token: &Uuid) -> Option<UserByEmail>;
+    create_game_type(new_game_type: &NewGameType) -> GameType;
+    create_game_version(new_game_version: &NewGameVersion) -> GameVersion;
+    find_game_version(id: &Uuid) -> Option<GameVersion>;
+    create_game(new_game: &NewGame) -> Game;
+    find_game(id: &Uuid) -> Option<Game>;
+    update_game(id: &Uuid, update: &NewGame) -> Option<Game>;
+    create_game_with_users(new_game: &NewGame,
+                           whose_turn: &[usize],
+                           eliminated: &[usize],
+                           winners: &[usize],
+                           creator_id: &Uuid,
+                           opponent_ids: &[Uuid],
+                           opponent_emails: &[String])
+                           -> CreatedGame;
+    update_game_and_players(game_id: &Uuid,
+                            update: &NewGame,
+                            whose_turn: &[usize],
+                            eliminated: &[usize],
+                            winners: &[usize])
+                            -> UpdatedGame;
+    create_game_player(player: &NewGamePlayer) -> GamePlayer;
+    create_game_players(players: &[NewGamePlayer]) -> Vec<GamePlayer>;
+    find_game_players_by_game(game_id: &Uuid) -> Vec<GamePlayer>;
+    update_game_whose_turn(id: &Uuid, positions: &[usize]) -> Vec<GamePlayer>;
+    update_game_eliminated(id: &Uuid, positions: &[usize]) -> Vec<GamePlayer>;
+    update_game_winners(id: &Uuid, positions: &[usize]) -> Vec<GamePlayer>;
+    update_game_placings(id: &Uuid, placings: &[Placing]) -> Vec<GamePlayer>;
+    create_game_log(log: &NewGameLog, to: &[Uuid]) -> CreatedGameLog;
+    create_game_logs_from_cli(game_id: &Uuid, logs: Vec<CliLog>) -> Vec<CreatedGameLog>;
+}
@@ -0,0 +1,206 @@
+use postgres::GenericConnection;
+use r2d2::PooledConnection;
+use r2d2_postgres::PostgresConnectionManager;
+use uuid::Uuid;
+use chrono::Duration;
+
+use std::cell::Cell;
+
+use Connections;
+use errors::*;
+use models::*;
+use query::{self, UserByEmail, UserColorPrefStats};
+
+lazy_static! {
+    pub static ref DEFAULT_MAX_REPLICA_LAG: Duration = Duration::seconds(5);
+}
+
+type Conn = PooledConnection<PostgresConnectionManager>;
+
+/// A Session routes queries for a single request between the primary and the replica. Reads go
+/// to the replica unless it is unavailable or lagging, or the session has already written to the
+/// primary, in which case they go to the primary so the request can read its own writes.
+pub struct Session<'a> {
+    conns: &'a Connections,
+    max_replica_lag: Duration,
+    use_primary: Cell<bool>,
+    replica_ok: Cell<Option<bool>>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(conns: &'a Connections, max_replica_lag: Duration) -> Self {
+        Session {
+            conns: conns,
+            max_replica_lag: max_replica_lag,
+            use_primary: Cell::new(false),
+            replica_ok: Cell::new(None),
+        }
+    }
+
+    /// Sends all following reads in this session to the primary.
+    pub fn use_primary(&self) {
+        self.use_primary.set(true);
+    }
+
+    pub fn read<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&GenericConnection) -> Result<T>
+    {
+        let conn = self.read_conn()?;
+        f(&*conn)
+    }
+
+    pub fn write<T, F>(&self, f: F) -> Result<T>
+        where F: FnOnce(&GenericConnection) -> Result<T>
+    {
+        self.use_primary();
+        let conn = self.primary_conn()?;
+        f(&*conn)
+    }
+
+    fn primary_conn(&self) -> Result<Conn> {
+        self.conns
+            .w
+            .get()
+            .chain_err(|| "unable to get connection to primary")
+    }
+
+    fn read_conn(&self) -> Result<Conn> {
+        if !self.use_primary.get() {
+            if let Some(conn) = self.replica_conn() {
+                return Ok(conn);
+            }
+        }
+        self.primary_conn()
+    }
+
+    fn replica_conn(&self) -> Option<Conn> {
+        if self.replica_ok.get() == Some(false) {
+            return None;
+        }
+        let conn = match self.conns.r.get() {
+            Ok(c) => c,
+            Err(_) => {
+                self.replica_ok.set(Some(false));
+                return None;
+            }
+        };
+        if self.replica_ok.get().is_none() {
+            // The lag is only checked once per session so requests see a consistent view.
+            let ok = match replica_lag(&*conn) {
+                Ok(Some(lag)) => lag <= self.max_replica_lag,
+                _ => false,
+            };
+            self.replica_ok.set(Some(ok));
+            if !ok {
+                return None;
+            }
+        }
+        Some(conn)
+    }
+}
+
+/// Finds how far a replica is behind its primary. A server which isn't in recovery is not a
+/// replica and has no lag, and a replica which has replayed everything it has received is
+/// considered up to date even if nothing has been written recently.
+fn replica_lag(conn: &GenericConnection) -> Result<Option<Duration>> {
+    let mut version: i32 = 0;
+    for row in &conn.query("SELECT current_setting('server_version_num')::INT AS version", &[])? {
+        version = row.get("version");
+    }
+    // Postgres 10 renamed the xlog functions to wal, and locations to LSNs.
+    let (receive_fn, replay_fn) = if version >= 100000 {
+        ("pg_last_wal_receive_lsn", "pg_last_wal_replay_lsn")
+    } else {
+        ("pg_last_xlog_receive_location", "pg_last_xlog_replay_location")
+    };
+    for row in &conn.query(&format!("
+        SELECT CASE
+            WHEN NOT pg_is_in_recovery() THEN 0
+            WHEN {}() = {}() THEN 0
+            ELSE EXTRACT(EPOCH FROM (now() - pg_last_xact_replay_timestamp()))
+        END::FLOAT8 AS lag",
+                                    receive_fn,
+                                    replay_fn),
+                           &[])? {
+        let lag: Option<f64> = row.get("lag");
+        return Ok(lag.map(|l| Duration::milliseconds((l * 1000.0) as i64)));
+    }
+    Ok(None)
+}
+
+macro_rules! read_queries {
+    ($($name:ident($($arg:ident: $arg_ty:ty),*) -> $ret:ty;)*) => {
+        impl<'a> Session<'a> {
+            $(
+                pub fn $name(&self, $($arg: $arg_ty),*) -> Result<$ret> {
+                    self.read(|conn| query::$name($($arg,)* conn))
+                }
+            )*
+        }
+    }
+}
+
+read_queries! {
+    find_user(id: &Uuid) -> Option<User>;
+    find_user_by_email(email: &str) -> Option<UserByEmail>;
+    find_user_with_primary_email(id: &Uuid) -> Option<UserByEmail>;
+    find_user_with_primary_email_by_email(email: &str) -> Option<UserByEmail>;
+    find_user_color_pref_stats() -> Vec<UserColorPrefStats>;
+    authenticate(email: &str, token: &Uuid) -> Option<UserByEmail>;
+    find_game_type(id: &Uuid) -> Option<GameType>;
+    find_game_types() -> Vec<GameType>;
+    find_game_types_by_player_count(count: usize) -> Vec<GameType>;
+    find_game_version(id: &Uuid) -> Option<GameVersion>;
+    find_public_game_versions_by_game_type(game_type_id: &Uuid) -> Vec<GameVersion>;
+    find_latest_public_game_version(game_type_id: &Uuid) -> Option<GameVersion>;
+    find_game(id: &Uuid) -> Option<Game>;
+    find_unfinished_games_by_game_version(game_version_id: &Uuid) -> Vec<Game>;
+    find_game_version_migrations_by_game(game_id: &Uuid) -> Vec<GameVersionMigration>;
+    find_game_players_by_game(game_id: &Uuid) -> Vec<GamePlayer>;
+    find_game_player_by_user(game_id: &Uuid, user_id: &Uuid) -> Option<GamePlayer>;
+    find_pending_game_color_swaps(game_id: &Uuid) -> Vec<GameColorSwap>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::with_test_db;
+
+    #[test]
+    fn session_reads_own_writes() {
+        with_test_db(|conns| {
+            let session = conns.session();
+            assert!(session.find_user(&Uuid::new_v4()).unwrap().is_none());
+            assert!(!session.use_primary.get());
+            session.write(|_| Ok(())).unwrap();
+            assert!(session.use_primary.get());
+            assert!(session.find_user(&Uuid::new_v4()).unwrap().is_none());
+        });
+    }
+
+    fn application_name(conn: &GenericConnection) -> Result<String> {
+        for row in &conn.query("SELECT current_setting('application_name') AS name", &[])? {
+            return Ok(row.get("name"));
+        }
+        Err("could not find application name".into())
+    }
+
+    #[test]
+    fn session_reads_from_replica() {
+        with_test_db(|conns| {
+            // The test replica pool holds a single connection, so tagging it marks every read
+            // which goes to the replica.
+            conns
+                .r
+                .get()
+                .unwrap()
+                .batch_execute("SET application_name = 'replica'")
+                .unwrap();
+            let session = conns.session();
+            assert_eq!(Some(Duration::zero()), replica_lag(&*conns.r.get().unwrap()).unwrap());
+            assert_eq!("replica", session.read(application_name).unwrap());
+            session.use_primary();
+            assert!(session.read(application_name).unwrap() != "replica");
+        });
+    }
+}
@@ -0,0 +1,227 @@
+use postgres::GenericConnection;
+use postgres::rows::Rows;
+use postgres::types::ToSql;
+
+use errors::*;
+use models::Model;
+
+/// Select builds a SELECT statement from models, so queries can be composed from parts instead of
+/// assembling SQL strings with `format!`.
+///
+/// Clauses mark parameters with `?`, which are bound rather than interpolated and are numbered in
+/// the order clauses are added, so `?` can't otherwise be used in clauses. A clause given a
+/// different number of parameters to its `?`s makes `sql` and `query` return an error.
+///
+/// ```ignore
+/// let rows = Select::from::<Game>("g")
+///     .cols::<Game>("g", "")
+///     .join::<GamePlayer>("gp", "gp.game_id = g.id", &[])
+///     .filter("gp.user_id = ?", &[&user_id])
+///     .order_by("g.updated_at DESC")
+///     .limit(10)
+///     .query(conn)?;
+/// ```
+pub struct Select<'a> {
+    distinct: bool,
+    cols: Vec<String>,
+    from: String,
+    joins: Vec<String>,
+    filters: Vec<String>,
+    order: Vec<String>,
+    limit: Option<i64>,
+    lock: Option<String>,
+    params: Vec<&'a ToSql>,
+    bind_error: Option<String>,
+}
+
+impl<'a> Select<'a> {
+    pub fn from<M: Model>(alias: &str) -> Self {
+        Select {
+            distinct: false,
+            cols: vec![],
+            from: format!("{} {}", M::table(), alias),
+            joins: vec![],
+            filters: vec![],
+            order: vec![],
+            limit: None,
+            lock: None,
+            params: vec![],
+            bind_error: None,
+        }
+    }
+
+    pub fn distinct(mut self) -> Self {
+        self.distinct = true;
+        self
+    }
+
+    /// Selects the columns of a model in the table with the given alias, with each column name
+    /// prefixed so it can be read with the model's `from_row`.
+    pub fn cols<M: Model>(mut self, alias: &str, prefix: &str) -> Self {
+        self.cols.push(M::select_cols(alias, prefix));
+        self
+    }
+
+    /// Selects an expression, such as an aggregate.
+    pub fn col(mut self, expr: &str) -> Self {
+        self.cols.push(expr.to_string());
+        self
+    }
+
+    pub fn join<M: Model>(self, alias: &str, on: &str, params: &[&'a ToSql]) -> Self {
+        self.join_kind::<M>("INNER JOIN", alias, on, params)
+    }
+
+    pub fn left_join<M: Model>(self, alias: &str, on: &str, params: &[&'a ToSql]) -> Self {
+        self.join_kind::<M>("LEFT JOIN", alias, on, params)
+    }
+
+    fn join_kind<M: Model>(mut self,
+                           kind: &str,
+                           alias: &str,
+                           on: &str,
+                           params: &[&'a ToSql])
+                           -> Self {
+        let on = self.bind(on, params);
+        self.joins
+            .push(format!("{} {} {} ON ({})", kind, M::table(), alias, on));
+        self
+    }
+
+    /// Adds a condition to the WHERE clause. Conditions are combined with AND.
+    pub fn filter(mut self, clause: &str, params: &[&'a ToSql]) -> Self {
+        let clause = self.bind(clause, params);
+        self.filters.push(format!("({})", clause));
+        self
+    }
+
+    pub fn order_by(mut self, expr: &str) -> Self {
+        self.order.push(expr.to_string());
+        self
+    }
+
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Locks the matching rows of the tables with the given aliases until the transaction ends.
+    pub fn for_update_of(mut self, aliases: &[&str]) -> Self {
+        self.lock = Some(format!("FOR UPDATE OF {}", aliases.join(", ")));
+        self
+    }
+
+    /// Replaces each `?` in `clause` with the number of the parameter bound for it.
+    fn bind(&mut self, clause: &str, params: &[&'a ToSql]) -> String {
+        let parts: Vec<&str> = clause.split('?').collect();
+        if parts.len() - 1 != params.len() && self.bind_error.is_none() {
+            self.bind_error = Some(format!("clause has {} parameters but was given {}: {}",
+                                           parts.len() - 1,
+                                           params.len(),
+                                           clause));
+        }
+        let mut bound = parts[0].to_string();
+        for (part, param) in parts[1..].iter().zip(params) {
+            self.params.push(*param);
+            bound.push_str(&format!("${}", self.params.len()));
+            bound.push_str(part);
+        }
+        bound
+    }
+
+    pub fn sql(&self) -> Result<String> {
+        if let Some(ref e) = self.bind_error {
+            return Err(e.to_owned().into());
+        }
+        let mut sql = format!("SELECT {}{} FROM {}",
+                              if self.distinct { "DISTINCT " } else { "" },
+                              if self.cols.is_empty() {
+                                  "*".to_string()
+                              } else {
+                                  self.cols.join(", ")
+                              },
+                              self.from);
+        for j in &self.joins {
+            sql.push_str(&format!(" {}", j));
+        }
+        if !self.filters.is_empty() {
+            sql.push_str(&format!(" WHERE {}", self.filters.join(" AND ")));
+        }
+        if !self.order.is_empty() {
+            sql.push_str(&format!(" ORDER BY {}", self.order.join(", ")));
+        }
+        if self.limit.is_some() {
+            sql.push_str(&format!(" LIMIT ${}", self.params.len() + 1));
+        }
+        if let Some(ref lock) = self.lock {
+            sql.push_str(&format!(" {}", lock));
+        }
+        Ok(sql)
+    }
+
+    /// The parameters to bind, in the order they are numbered in `sql`.
+    pub fn params(&self) -> Vec<&ToSql> {
+        let mut params: Vec<&ToSql> = self.params.clone();
+        if let Some(ref limit) = self.limit {
+            params.push(limit);
+        }
+        params
+    }
+
+    /// Runs the query without caching the statement, as composed queries can take many shapes
+    /// and each cached statement is held by the connection until it closes.
+    pub fn query(&self, conn: &GenericConnection) -> Result<Rows> {
+        Ok(conn.query(&self.sql()?, &self.params())?)
+    }
+
+    /// Runs the query with a cached statement. Only use this for selects which are always built
+    /// the same way, such as those behind hot paths like authentication, so that few statements
+    /// end up cached.
+    pub fn query_cached(&self, conn: &GenericConnection) -> Result<Rows> {
+        Ok(conn.prepare_cached(&self.sql()?)?
+               .query(&self.params())?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use models::*;
+
+    #[test]
+    fn select_works() {
+        let user_id = 5;
+        let select = Select::from::<Game>("g")
+            .cols::<Game>("g", "")
+            .join::<GamePlayer>("gp", "gp.game_id = g.id", &[])
+            .filter("gp.user_id = ?", &[&user_id])
+            .filter("g.is_finished = ? OR g.revision > ?", &[&false, &3])
+            .order_by("g.updated_at DESC")
+            .limit(10);
+        assert_eq!(format!("SELECT {} FROM games g \
+                            INNER JOIN game_players gp ON (gp.game_id = g.id) \
+                            WHERE (gp.user_id = $1) AND (g.is_finished = $2 OR g.revision > $3) \
+                            ORDER BY g.updated_at DESC LIMIT $4",
+                           Game::select_cols("g", "")),
+                   select.sql().unwrap());
+        assert_eq!(4, select.params().len());
+    }
+
+    #[test]
+    fn select_defaults_to_all_columns() {
+        assert_eq!("SELECT * FROM game_types gt",
+                   Select::from::<GameType>("gt").sql().unwrap());
+    }
+
+    #[test]
+    fn filter_checks_param_count() {
+        assert!(Select::from::<Game>("g")
+                    .filter("g.id = ?", &[])
+                    .sql()
+                    .is_err());
+        assert!(Select::from::<Game>("g")
+                    .filter("g.id = ?", &[&1, &2])
+                    .sql()
+                    .is_err());
+    }
+}
@@ -0,0 +1,186 @@
+use postgres::{Connection, GenericConnection, TlsMode};
+use uuid::Uuid;
+
+use std::env;
+
+use errors::*;
+use config::DbConfig;
+use {Connections, connect_config};
+
+/// The bundled migrations in the order they are applied, as `(name, up.sql)` pairs.
+pub static MIGRATIONS: &'static [(&'static str, &'static str)] =
+    &[("20170326234713_create_initial_tables",
+       include_str!("../migrations/20170326234713_create_initial_tables/up.sql")),
+      ("20170402091512_create_game_color_swaps",
+       include_str!("../migrations/20170402091512_create_game_color_swaps/up.sql")),
+      ("20170405213044_add_game_players_color_pref_rank",
+       include_str!("../migrations/20170405213044_add_game_players_color_pref_rank/up.sql")),
+      ("20170409120530_add_game_versions_player_counts",
+       include_str!("../migrations/20170409120530_add_game_versions_player_counts/up.sql")),
+      ("20170411082217_add_game_types_name_unique",
+       include_str!("../migrations/20170411082217_add_game_types_name_unique/up.sql")),
+      ("20170414150902_create_game_version_migrations",
+       include_str!("../migrations/20170414150902_create_game_version_migrations/up.sql")),
+      ("20170418103511_create_game_event_notifications",
+       include_str!("../migrations/20170418103511_create_game_event_notifications/up.sql")),
+      ("20170420094217_create_outbox_messages",
+       include_str!("../migrations/20170420094217_create_outbox_messages/up.sql")),
+      ("20170422141305_create_jobs",
+       include_str!("../migrations/20170422141305_create_jobs/up.sql")),
+      ("20170424110932_add_turn_deadlines",
+       include_str!("../migrations/20170424110932_add_turn_deadlines/up.sql")),
+      ("20170426093318_add_concede_and_abandon",
+       include_str!("../migrations/20170426093318_add_concede_and_abandon/up.sql")),
+      ("20170428152740_add_games_parent_game_id",
+       include_str!("../migrations/20170428152740_add_games_parent_game_id/up.sql")),
+      ("20170430101206_add_game_players_place_and_score",
+       include_str!("../migrations/20170430101206_add_game_players_place_and_score/up.sql")),
+      ("20170502084415_create_ratings",
+       include_str!("../migrations/20170502084415_create_ratings/up.sql")),
+      ("20170504091127_add_game_players_color_pref_known",
+       include_str!("../migrations/20170504091127_add_game_players_color_pref_known/up.sql")),
+      ("20170506102341_fill_new_players_time_banks",
+       include_str!("../migrations/20170506102341_fill_new_players_time_banks/up.sql"))];
+
+lazy_static! {
+    /// The server test databases are created on, which can be overridden with
+    /// `TEST_DATABASE_URL`. The database in the URL is only used to create and drop test
+    /// databases.
+    pub static ref SERVER_URL: String = env::var("TEST_DATABASE_URL")
+        .unwrap_or_else(|_| "postgres://postgres@localhost/postgres".to_string());
+}
+
+/// A uniquely named database with all migrations applied, which is dropped along with the
+/// TestDb.
+pub struct TestDb {
+    pub name: String,
+    pub url: String,
+    conns: Option<Connections>,
+}
+
+impl TestDb {
+    /// Creates a test database, or returns `None` if there is no server at `SERVER_URL`.
+    pub fn new() -> Result<Option<TestDb>> {
+        let admin = match Connection::connect(SERVER_URL.as_str(), TlsMode::None) {
+            Ok(c) => c,
+            Err(_) => return Ok(None),
+        };
+        let name = format!("brdgme_test_{}", Uuid::new_v4().simple());
+        admin
+            .batch_execute(&format!("CREATE DATABASE {}", name))
+            .chain_err(|| "unable to create test database")?;
+        let url = database_url(&SERVER_URL, &name);
+        // Created before migrating so the database is dropped if anything below fails.
+        let mut db = TestDb {
+            name: name,
+            url: url,
+            conns: None,
+        };
+        migrate(&Connection::connect(db.url.as_str(), TlsMode::None)?)?;
+        let mut config = DbConfig::new(&db.url, &db.url);
+        config.w_pool.size = 2;
+        config.r_pool.size = 1;
+        db.conns = Some(connect_config(&config)?);
+        Ok(Some(db))
+    }
+
+    pub fn conns(&self) -> &Connections {
+        self.conns.as_ref().expect("test database connections already closed")
+    }
+}
+
+impl Drop for TestDb {
+    fn drop(&mut self) {
+        // The pools have to be closed before the database can be dropped.
+        self.conns.take();
+        if let Ok(admin) = Connection::connect(SERVER_URL.as_str(), TlsMode::None) {
+            let _ = admin.execute("
+                SELECT pg_terminate_backend(pid)
+                FROM pg_stat_activity
+                WHERE datname = $1
+                AND pid <> pg_backend_pid()",
+                                  &[&self.name]);
+            let _ = admin.batch_execute(&format!("DROP DATABASE IF EXISTS {}", self.name));
+        }
+    }
+}
+
+/// Applies the bundled migrations to an empty database.
+pub fn migrate(conn: &GenericConnection) -> Result<()> {
+    for &(name, sql) in MIGRATIONS {
+        conn.batch_execute(sql)
+            .chain_err(|| format!("unable to apply migration {}", name))?;
+    }
+    Ok(())
+}
+
+/// Creates a test database for a test, panicking if there is no server at `SERVER_URL` so that
+/// database tests can't silently pass. Setting `SKIP_DB_TESTS` skips them instead, returning
+/// `None`.
+pub fn test_db() -> Option<TestDb> {
+    match TestDb::new().expect("unable to create test database") {
+        Some(db) => Some(db),
+        None if env::var_os("SKIP_DB_TESTS").is_some() => {
+            println!("skipping, no Postgres server at {}", *SERVER_URL);
+            None
+        }
+        None => {
+            panic!("no Postgres server at {}, set TEST_DATABASE_URL to use another server or \
+                    SKIP_DB_TESTS to skip database tests",
+                   *SERVER_URL)
+        }
+    }
+}
+
+/// Runs `f` against a fresh test database, see `test_db`.
+pub fn with_test_db<F>(f: F)
+    where F: FnOnce(&Connections)
+{
+    if let Some(db) = test_db() {
+        f(db.conns());
+    }
+}
+
+/// Like `with_test_db`, but gives `f` a single connection to the primary.
+pub fn with_db<F>(f: F)
+    where F: FnOnce(&GenericConnection)
+{
+    with_test_db(|conns| f(&*conns.w.get().unwrap()));
+}
+
+fn database_url(server_url: &str, name: &str) -> String {
+    let (base, params) = match server_url.find('?') {
+        Some(i) => server_url.split_at(i),
+        None => (server_url, ""),
+    };
+    let host_start = base.find("://").map(|i| i + 3).unwrap_or(0);
+    let base = match base[host_start..].find('/') {
+        Some(i) => &base[..host_start + i],
+        None => base,
+    };
+    format!("{}/{}{}", base, name, params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_url_works() {
+        assert_eq!("postgres://postgres@localhost/brdgme_test",
+                   database_url("postgres://postgres@localhost/postgres", "brdgme_test"));
+        assert_eq!("postgres://u:p@db:5432/brdgme_test?sslmode=require",
+                   database_url("postgres://u:p@db:5432/postgres?sslmode=require",
+                                "brdgme_test"));
+        assert_eq!("postgres://localhost/brdgme_test",
+                   database_url("postgres://localhost", "brdgme_test"));
+    }
+
+    #[test]
+    fn migrations_are_bundled_in_order() {
+        let names: Vec<&str> = MIGRATIONS.iter().map(|&(name, _)| name).collect();
+        let mut sorted = names.clone();
+        sorted.sort();
+        assert_eq!(sorted, names);
+    }
+}
@@ -0,0 +1,163 @@
+use postgres::GenericConnection;
+use postgres::error::SqlState;
+use rand::{self, Rng};
+
+use std::thread;
+use std::time::Duration;
+
+use errors::*;
+
+/// Number of times a transaction is attempted before its error is returned.
+pub static MAX_ATTEMPTS: u32 = 5;
+/// Base delay before the first retry, doubled for each following retry.
+pub static BASE_BACKOFF_MS: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IsolationLevel {
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn sql(&self) -> &'static str {
+        match *self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// Runs `f` in a transaction at the given isolation level, retrying with backoff when the
+/// transaction fails because of a serialisation failure or a deadlock.
+///
+/// Isolation and retries belong to the outermost transaction, so when `conn` is already in a
+/// transaction which has run queries, `f` is run once in a nested transaction and any failure is
+/// left for the outer transaction to handle.
+pub fn retry_transaction<T, F>(isolation: IsolationLevel,
+                               conn: &GenericConnection,
+                               f: F)
+                               -> Result<T>
+    where F: Fn(&GenericConnection) -> Result<T>
+{
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let trans = conn.transaction()?;
+        if let Err(e) = trans.batch_execute(&format!("SET TRANSACTION ISOLATION LEVEL {}",
+                                                     isolation.sql())) {
+            let e = Error::from(e);
+            if sql_state(&e) != Some(&SqlState::ActiveSqlTransaction) {
+                return Err(e);
+            }
+            // Postgres refuses to change isolation once a transaction has started, which means
+            // we're nested inside one.
+            drop(trans);
+            let trans = conn.transaction()?;
+            let result = f(&trans)?;
+            trans.commit()?;
+            return Ok(result);
+        }
+        let result = f(&trans);
+        let result = match result {
+            Ok(r) => trans.commit().map(|_| r).map_err(Error::from),
+            Err(e) => {
+                drop(trans);
+                Err(e)
+            }
+        };
+        match result {
+            Err(ref e) if attempt < MAX_ATTEMPTS && is_retryable(e) => {
+                thread::sleep(backoff(attempt));
+            }
+            r => return r,
+        }
+    }
+}
+
+/// How long to wait before retrying after a failed attempt, starting from 1.
+pub fn backoff(attempt: u32) -> Duration {
+    let max = BASE_BACKOFF_MS << (attempt - 1);
+    // Jitter stops transactions which conflicted from retrying in lockstep.
+    Duration::from_millis(max / 2 + rand::thread_rng().gen_range(0, max / 2 + 1))
+}
+
+/// Whether a transaction which failed with `e` can succeed if it is run again.
+pub fn is_retryable(e: &Error) -> bool {
+    match sql_state(e) {
+        Some(&SqlState::TRSerializationFailure) |
+        Some(&SqlState::TRDeadlockDetected) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::with_test_db;
+
+    use std::cell::Cell;
+
+    fn raise(code: &str, conn: &GenericConnection) -> Result<()> {
+        conn.batch_execute(&format!("DO $$ BEGIN RAISE EXCEPTION 'failed' USING ERRCODE = '{}'; \
+                                     END $$",
+                                    code))?;
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_works() {
+        for attempt in 1..MAX_ATTEMPTS {
+            let max = BASE_BACKOFF_MS << (attempt - 1);
+            let d = backoff(attempt);
+            assert!(d >= Duration::from_millis(max / 2));
+            assert!(d <= Duration::from_millis(max));
+        }
+    }
+
+    #[test]
+    fn is_retryable_works() {
+        let e: Error = "not a database error".into();
+        assert!(!is_retryable(&e));
+        let e: Result<()> = Err::<(), Error>(e).chain_err(|| "wrapped");
+        assert!(!is_retryable(&e.unwrap_err()));
+    }
+
+    #[test]
+    fn is_retryable_works_for_database_errors() {
+        with_test_db(|conns| {
+            let conn = conns.w.get().unwrap();
+            // Serialisation failures and deadlocks are retryable, unique violations aren't.
+            for &(code, retryable) in &[("40001", true), ("40P01", true), ("23505", false)] {
+                let e = raise(code, &*conn).unwrap_err();
+                assert_eq!(retryable, is_retryable(&e), "{}", code);
+                let e: Result<()> = Err::<(), Error>(e).chain_err(|| "wrapped");
+                assert_eq!(retryable, is_retryable(&e.unwrap_err()), "{}", code);
+            }
+        });
+    }
+
+    #[test]
+    fn retry_transaction_retries_serialization_failures() {
+        with_test_db(|conns| {
+            let conn = conns.w.get().unwrap();
+            let attempts = Cell::new(0);
+            let result = retry_transaction(IsolationLevel::Serializable, &*conn, |trans| {
+                attempts.set(attempts.get() + 1);
+                if attempts.get() < 3 {
+                    raise("40001", trans)?;
+                }
+                Ok(attempts.get())
+            });
+            assert_eq!(3, result.unwrap());
+            attempts.set(0);
+            assert!(retry_transaction(IsolationLevel::Serializable, &*conn, |trans| {
+                                          attempts.set(attempts.get() + 1);
+                                          raise("23505", trans)
+                                      })
+                            .is_err());
+            assert_eq!(1, attempts.get());
+        });
+    }
+}
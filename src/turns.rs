@@ -0,0 +1,426 @@
+use postgres::GenericConnection;
+use chrono::{Duration, NaiveDateTime};
+use uuid::Uuid;
+
+use errors::*;
+use models::*;
+use outbox::{self, OutboxMessageKind};
+use query;
+use sql::Select;
+use transaction::{retry_transaction, IsolationLevel};
+
+#[derive(Debug, ToSql, FromSql, PartialEq, Eq, Clone, Copy)]
+#[postgres(name = "time_limit_kind")]
+pub enum TimeLimitKind {
+    PerTurn,
+    TimeBank,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimeLimit {
+    /// Each turn must be taken within the duration.
+    PerTurn(Duration),
+    /// Each player has the duration to spend across all of their turns, like a chess clock.
+    TimeBank(Duration),
+}
+
+impl TimeLimit {
+    pub fn kind(&self) -> TimeLimitKind {
+        match *self {
+            TimeLimit::PerTurn(_) => TimeLimitKind::PerTurn,
+            TimeLimit::TimeBank(_) => TimeLimitKind::TimeBank,
+        }
+    }
+
+    pub fn duration(&self) -> Duration {
+        match *self {
+            TimeLimit::PerTurn(d) |
+            TimeLimit::TimeBank(d) => d,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TimeoutPolicy {
+    /// Sends the player a reminder, once per turn.
+    Remind,
+    /// Passes the turn to the next player.
+    Skip,
+    /// Eliminates the player and passes the turn to the next player, finishing the game if only
+    /// one player is left.
+    Eliminate,
+}
+
+/// Sets or clears the time limit for a game. Setting a time bank fills every player's bank with
+/// the full duration, and players whose turn it is start their turn again so they aren't overdue
+/// straight away. Players who join later start with a full bank.
+///
+/// The duration must be at least a second, and fit in an `i32` number of seconds.
+pub fn set_time_limit(game_id: &Uuid,
+                      limit: Option<TimeLimit>,
+                      conn: &GenericConnection)
+                      -> Result<Option<Game>> {
+    let kind = limit.map(|l| l.kind());
+    let secs = match limit.map(|l| l.duration().num_seconds()) {
+        Some(s) if s < 1 || s > i32::max_value() as i64 => {
+            return Err(format!("time limit of {} seconds is out of range", s).into());
+        }
+        s => s.map(|s| s as i32),
+    };
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let mut game: Option<Game> = None;
+        for row in &trans.query("
+            UPDATE games
+            SET
+                time_limit_kind=$2,
+                time_limit_secs=$3
+            WHERE id=$1
+            RETURNING *",
+                                &[game_id, &kind, &secs])? {
+            game = Some(Game::from_row(&row, ""));
+        }
+        if game.is_none() {
+            return Ok(None);
+        }
+        let bank_secs = match limit {
+            Some(TimeLimit::TimeBank(_)) => secs,
+            _ => None,
+        };
+        trans.execute("
+            UPDATE game_players
+            SET
+                time_bank_secs=$2,
+                turn_started_at=CASE WHEN is_turn THEN (now() AT TIME ZONE 'utc') END,
+                turn_reminded_at=NULL
+            WHERE game_id=$1",
+                      &[game_id, &bank_secs])?;
+        Ok(game)
+    })
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct OverduePlayer {
+    pub game: Game,
+    pub player: GamePlayer,
+    pub deadline: NaiveDateTime,
+}
+
+static DEADLINE: &'static str = "gp.turn_started_at + CASE g.time_limit_kind
+    WHEN 'TimeBank' THEN gp.time_bank_secs
+    ELSE g.time_limit_secs
+END * INTERVAL '1 second'";
+
+/// Finds players across all unfinished games whose turn has gone past its time limit, most
+/// overdue first. Players stay overdue until their turn ends, so reminded players are included
+/// too, and `turn_reminded_at` can be used to escalate to skipping or eliminating them.
+pub fn find_overdue_players(limit: i64, conn: &GenericConnection) -> Result<Vec<OverduePlayer>> {
+    let mut overdue: Vec<OverduePlayer> = vec![];
+    for row in &Select::from::<GamePlayer>("gp")
+                    .cols::<Game>("g", "g_")
+                    .cols::<GamePlayer>("gp", "gp_")
+                    .col(&format!("{} AS deadline", DEADLINE))
+                    .join::<Game>("g", "g.id = gp.game_id", &[])
+                    .filter("g.time_limit_kind IS NOT NULL", &[])
+                    .filter("g.is_finished = FALSE", &[])
+                    .filter("gp.is_turn = TRUE", &[])
+                    .filter("gp.is_eliminated = FALSE", &[])
+                    .filter(&format!("{} <= (now() AT TIME ZONE 'utc')", DEADLINE), &[])
+                    .order_by("deadline")
+                    .limit(limit)
+                    .query(conn)? {
+        overdue.push(OverduePlayer {
+                         game: Game::from_row(&row, "g_"),
+                         player: GamePlayer::from_row(&row, "gp_"),
+                         deadline: row.get("deadline"),
+                     });
+    }
+    Ok(overdue)
+}
+
+/// Applies a timeout policy to an overdue player. Returns false without changing anything if the
+/// player's turn has ended since they were found to be overdue, if they have already been
+/// reminded this turn, or if there is nobody to pass the turn to.
+pub fn apply_timeout_policy(overdue: &OverduePlayer,
+                            policy: TimeoutPolicy,
+                            conn: &GenericConnection)
+                            -> Result<bool> {
+    retry_transaction(IsolationLevel::Serializable, conn, |trans| {
+        let players = query::lock_game_players(&overdue.game.id, trans)?;
+        let player = match players.iter().find(|p| p.id == overdue.player.id) {
+            Some(p) => p.clone(),
+            None => return Ok(false),
+        };
+        if !player.is_turn || player.turn_started_at != overdue.player.turn_started_at {
+            return Ok(false);
+        }
+
+        match policy {
+            TimeoutPolicy::Remind => {
+                if !remind(&overdue.game, &player, &overdue.deadline, trans)? {
+                    return Ok(false);
+                }
+            }
+            TimeoutPolicy::Skip => {
+                let whose_turn = next_turn(&players, &player);
+                if whose_turn.is_empty() {
+                    return Ok(false);
+                }
+                query::update_game_whose_turn(&overdue.game.id, &whose_turn, trans)?;
+            }
+            TimeoutPolicy::Eliminate => {
+                if next_turn(&players, &player).is_empty() {
+                    return Ok(false);
+                }
+                query::eliminate_game_player(&overdue.game.id,
+                                             &player.id,
+                                             EliminationReason::TimedOut,
+                                             trans)?;
+            }
+        }
+        Ok(true)
+    })
+}
+
+fn remind(game: &Game,
+          player: &GamePlayer,
+          deadline: &NaiveDateTime,
+          conn: &GenericConnection)
+          -> Result<bool> {
+    if conn.execute("
+        UPDATE game_players
+        SET turn_reminded_at=(now() AT TIME ZONE 'utc')
+        WHERE id=$1
+        AND turn_reminded_at IS NULL",
+                    &[&player.id])? == 0 {
+        return Ok(false);
+    }
+    let user = query::find_user_with_primary_email(&player.user_id, conn)?
+        .ok_or_else::<Error, _>(|| format!("no primary email for user {}", player.user_id).into())?;
+    outbox::enqueue(&NewOutboxMessage {
+                         kind: OutboxMessageKind::YourTurn,
+                         recipient: &user.user_email.email,
+                         payload: &json!({
+                             "game_id": game.id.to_string(),
+                             "deadline": deadline.to_string(),
+                         }),
+                         max_attempts: outbox::DEFAULT_MAX_ATTEMPTS,
+                     },
+                    conn)?;
+    Ok(true)
+}
+
+/// Works out whose turn it is once `skipped` loses their turn. Other players who have the turn
+/// keep it, otherwise it passes to the next player by position who isn't eliminated.
+pub fn next_turn(players: &[GamePlayer], skipped: &GamePlayer) -> Vec<usize> {
+    let others: Vec<usize> = players
+        .iter()
+        .filter(|p| p.is_turn && p.id != skipped.id)
+        .map(|p| p.position as usize)
+        .collect();
+    if !others.is_empty() {
+        return others;
+    }
+    let mut active: Vec<&GamePlayer> = players
+        .iter()
+        .filter(|p| !p.is_eliminated && p.id != skipped.id)
+        .collect();
+    active.sort_by_key(|p| p.position);
+    active
+        .iter()
+        .find(|p| p.position > skipped.position)
+        .or_else(|| active.first())
+        .map(|p| vec![p.position as usize])
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use color::Color;
+    use fixtures::{GameFixture, UserFixture};
+    use memory::MemoryRepository;
+    use postgres::GenericConnection;
+    use repository::PgRepository;
+    use test_support::with_db;
+
+    fn backdate_turn(player_id: &Uuid, secs: i32, conn: &GenericConnection) {
+        conn.execute("
+            UPDATE game_players
+            SET turn_started_at=turn_started_at - $2::int * INTERVAL '1 second'
+            WHERE id=$1",
+                     &[player_id, &secs])
+            .unwrap();
+    }
+
+    fn find_player(id: &Uuid, conn: &GenericConnection) -> GamePlayer {
+        for row in &conn.query("SELECT * FROM game_players WHERE id=$1", &[id]).unwrap() {
+            return GamePlayer::from_row(&row, "");
+        }
+        panic!("no game player {}", id);
+    }
+
+    #[test]
+    fn next_turn_works() {
+        let created = GameFixture::new()
+            .players(4)
+            .whose_turn(&[2])
+            .eliminated(&[3])
+            .create(&MemoryRepository::new())
+            .unwrap();
+        let players = &created.players;
+        assert_eq!(vec![0], next_turn(players, &players[2]));
+        assert_eq!(vec![2], next_turn(players, &players[1]));
+
+        let created = GameFixture::new()
+            .players(3)
+            .whose_turn(&[0, 1])
+            .create(&MemoryRepository::new())
+            .unwrap();
+        assert_eq!(vec![1], next_turn(&created.players, &created.players[0]));
+    }
+
+    #[test]
+    fn per_turn_limits_work() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let p0 = &created.players[0];
+            assert!(p0.turn_started_at.is_some());
+            assert_eq!(None, created.players[1].turn_started_at);
+
+            let game = set_time_limit(&created.game.id,
+                                      Some(TimeLimit::PerTurn(Duration::minutes(1))),
+                                      conn)
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(Some(TimeLimitKind::PerTurn), game.time_limit_kind);
+            assert_eq!(Some(60), game.time_limit_secs);
+            assert!(find_overdue_players(10, conn).unwrap().is_empty());
+
+            backdate_turn(&p0.id, 120, conn);
+            let overdue = find_overdue_players(10, conn).unwrap();
+            assert_eq!(vec![p0.id],
+                       overdue.iter().map(|o| o.player.id).collect::<Vec<Uuid>>());
+            let overdue = overdue[0].clone();
+
+            assert!(apply_timeout_policy(&overdue, TimeoutPolicy::Remind, conn).unwrap());
+            assert!(!apply_timeout_policy(&overdue, TimeoutPolicy::Remind, conn).unwrap());
+            assert_eq!(1,
+                       outbox::claim(10, Duration::minutes(5), conn)
+                           .unwrap()
+                           .iter()
+                           .filter(|m| m.kind == OutboxMessageKind::YourTurn)
+                           .count());
+
+            assert!(apply_timeout_policy(&overdue, TimeoutPolicy::Skip, conn).unwrap());
+            let p0 = find_player(&p0.id, conn);
+            let p1 = find_player(&created.players[1].id, conn);
+            assert!(!p0.is_turn);
+            assert_eq!(None, p0.turn_started_at);
+            assert_eq!(None, p0.turn_reminded_at);
+            assert!(p1.is_turn);
+            assert!(p1.turn_started_at.is_some());
+            assert!(find_overdue_players(10, conn).unwrap().is_empty());
+            assert_eq!(vec![created.users[1].user_email.email.clone()],
+                       outbox::claim(10, Duration::minutes(5), conn)
+                           .unwrap()
+                           .into_iter()
+                           .filter(|m| m.kind == OutboxMessageKind::YourTurn)
+                           .map(|m| m.recipient)
+                           .collect::<Vec<String>>());
+
+            // The turn has moved on, so the policy isn't applied again.
+            assert!(!apply_timeout_policy(&overdue, TimeoutPolicy::Skip, conn).unwrap());
+        });
+    }
+
+    #[test]
+    fn time_banks_work() {
+        with_db(|conn| {
+            let created = GameFixture::new()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let p0 = &created.players[0];
+            let p1 = &created.players[1];
+            set_time_limit(&created.game.id,
+                           Some(TimeLimit::TimeBank(Duration::minutes(10))),
+                           conn)
+                    .unwrap();
+            assert_eq!(Some(600), find_player(&p1.id, conn).time_bank_secs);
+
+            // Time taken is charged to the player's bank when their turn ends.
+            backdate_turn(&p0.id, 180, conn);
+            query::update_game_whose_turn(&created.game.id, &[1], conn).unwrap();
+            let bank = find_player(&p0.id, conn).time_bank_secs.unwrap();
+            assert!(bank <= 420 && bank > 410);
+
+            backdate_turn(&p1.id, 900, conn);
+            let overdue = find_overdue_players(10, conn).unwrap();
+            assert_eq!(vec![p1.id],
+                       overdue.iter().map(|o| o.player.id).collect::<Vec<Uuid>>());
+            assert!(apply_timeout_policy(&overdue[0], TimeoutPolicy::Eliminate, conn).unwrap());
+            let p0 = find_player(&p0.id, conn);
+            let p1 = find_player(&p1.id, conn);
+            assert!(p1.is_eliminated);
+            assert_eq!(Some(EliminationReason::TimedOut), p1.eliminated_reason);
+            assert!(!p1.is_turn);
+            assert_eq!(Some(0), p1.time_bank_secs);
+
+            // Only one player is left, so they win rather than keeping the turn forever.
+            assert!(p0.is_winner);
+            assert!(!p0.is_turn);
+            assert!(query::find_game(&created.game.id, conn)
+                        .unwrap()
+                        .unwrap()
+                        .is_finished);
+            assert!(find_overdue_players(10, conn).unwrap().is_empty());
+            let logs: Vec<String> = conn.query("
+                SELECT body
+                FROM game_logs
+                WHERE game_id=$1
+                AND is_public=TRUE",
+                                               &[&created.game.id])
+                .unwrap()
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            assert_eq!(vec!["{{player 1}} ran out of time and was eliminated".to_string()],
+                       logs);
+
+            // Players who join later start with a full bank.
+            let other = GameFixture::new()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            set_time_limit(&other.game.id,
+                           Some(TimeLimit::TimeBank(Duration::minutes(10))),
+                           conn)
+                    .unwrap();
+            let user = UserFixture::new()
+                .create(&PgRepository::new(conn))
+                .unwrap();
+            let p2 = query::create_game_player(&NewGamePlayer {
+                                                    game_id: &other.game.id,
+                                                    user_id: &user.user.id,
+                                                    position: 2,
+                                                    color: &Color::Blue,
+                                                    has_accepted: true,
+                                                    is_turn: false,
+                                                    is_eliminated: false,
+                                                    is_winner: false,
+                                                    color_pref_rank: None,
+                                                },
+                                               conn)
+                    .unwrap();
+            assert_eq!(Some(600), p2.time_bank_secs);
+
+            set_time_limit(&created.game.id, None, conn).unwrap();
+            assert_eq!(None, find_player(&p0.id, conn).time_bank_secs);
+
+            for d in &[Duration::zero(), Duration::seconds(i32::max_value() as i64 + 1)] {
+                assert!(set_time_limit(&created.game.id, Some(TimeLimit::PerTurn(*d)), conn)
+                            .is_err());
+            }
+        });
+    }
+}